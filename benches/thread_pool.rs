@@ -0,0 +1,79 @@
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStore, KvsClient, KvsServer, NaiveThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool};
+use tempfile::TempDir;
+
+const THREADS: usize = 4;
+const JOBS: usize = 1000;
+const CLIENTS: usize = 4;
+const REQUESTS: usize = 50;
+
+fn run_jobs(pool: &dyn ThreadPool) {
+    let (done, finished) = mpsc::channel();
+    for _ in 0..JOBS {
+        let done = done.clone();
+        pool.execute(Box::new(move || done.send(()).expect("send")));
+    }
+    for _ in 0..JOBS {
+        finished.recv().expect("job ran");
+    }
+}
+
+/// `CLIENTS` clients each setting and then reading back `REQUESTS` keys at the same time
+fn run_clients(addr: std::net::SocketAddr) {
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|c| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).expect("connect");
+                for i in 0..REQUESTS {
+                    let key = format!("client{}.key{}", c, i);
+                    client.set("", key.clone(), "value".to_owned()).expect("set");
+                    assert!(client.get("", key).expect("get").is_some());
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().expect("client panicked");
+    }
+}
+
+fn pools() -> Vec<(&'static str, Box<dyn ThreadPool>)> {
+    vec![
+        ("naive", Box::new(NaiveThreadPool::new(THREADS).expect("pool"))),
+        ("shared-queue", Box::new(SharedQueueThreadPool::new(THREADS).expect("pool"))),
+        ("work-stealing", Box::new(WorkStealingThreadPool::new(THREADS).expect("pool"))),
+    ]
+}
+
+fn jobs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("jobs");
+    for (name, pool) in pools() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| b.iter(|| run_jobs(&*pool)));
+    }
+    group.finish();
+}
+
+fn server(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server");
+    group.sample_size(10);
+    for (name, pool) in pools() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).expect("open");
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+
+        // the server runs for the rest of the benchmark, so its pool has to as well
+        let pool: &'static dyn ThreadPool = Box::leak(pool);
+        thread::spawn(move || KvsServer::new(store).serve_on(listener, pool));
+
+        group.bench_function(BenchmarkId::from_parameter(name), |b| b.iter(|| run_clients(addr)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, jobs, server);
+criterion_main!(benches);
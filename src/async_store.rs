@@ -0,0 +1,115 @@
+//! An async front end for `KvStore`, for use from tokio
+//!
+//! Every operation runs on tokio's blocking thread pool so file io and fsync never stall the
+//! executor. Operations on one store are applied one at a time, in the order they get hold of the
+//! store, just as with a `KvStore` behind a mutex.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{KvStore, KvsError, OpenOptions, Result};
+
+/// A `KvStore` that can be shared between tasks, with async versions of its methods
+///
+/// Dropping the future of an operation cancels it if it hasn't started yet. An operation that
+/// has started always runs to completion, so a cancelled write has either been made in full or
+/// not at all.
+///
+/// ```
+/// # let dir = tempfile::TempDir::new().unwrap();
+/// let rt = tokio::runtime::Runtime::new().unwrap();
+/// rt.block_on(async {
+///     let store = kvs::AsyncKvStore::open(dir.path()).await?;
+///     store.set("answer".to_owned(), "42".to_owned()).await?;
+///     assert_eq!(store.get("answer".to_owned()).await?.as_deref(), Some("42"));
+///     Ok::<(), kvs::KvsError>(())
+/// })?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct AsyncKvStore {
+    store: Arc<Mutex<KvStore>>,
+}
+
+// sets the flag when the future waiting on an operation is dropped
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+impl AsyncKvStore {
+    /// wrap an already opened store
+    pub fn new(store: KvStore) -> Self {
+        AsyncKvStore { store: Arc::new(Mutex::new(store)) }
+    }
+
+    /// open existing or create KvStore from path
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(OpenOptions::new(), path).await
+    }
+
+    /// open existing or create KvStore from path, configured by `options`
+    pub async fn open_with(options: OpenOptions, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let store = Self::blocking(move || options.open(path)).await?;
+        Ok(Self::new(store))
+    }
+
+    /// Get the value of `key` in the default namespace
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.with(move |store| store.get(key)).await
+    }
+
+    /// Set `key` in the default namespace to `value`
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.with(move |store| store.set(key, value)).await
+    }
+
+    /// Remove `key` from the default namespace, failing if it isn't present
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.with(move |store| store.remove(key)).await
+    }
+
+    /// Every key and value in the default namespace whose key starts with `prefix`, in key order
+    pub async fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.with(move |store| store.scan(&prefix)).await
+    }
+
+    /// Run `f` with the store on the blocking thread pool, for anything without an async
+    /// version here such as namespaces and batches. `f` is cancelled like any other operation.
+    pub async fn with<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut KvStore) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let store = self.store.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _cancel = CancelOnDrop(cancelled.clone());
+
+        Self::blocking(move || {
+            let mut store = store.lock().unwrap_or_else(|e| e.into_inner());
+            // checked with the store held, nothing can start the operation after this
+            if cancelled.load(Ordering::Acquire) {
+                return Err(KvsError::Cancelled);
+            }
+            f(&mut store)
+        }).await
+    }
+
+    async fn blocking<F, R>(f: F) -> Result<R>
+    where
+        F: FnOnce() -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        match tokio::task::spawn_blocking(f).await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // the runtime is shutting down
+            Err(_) => Err(KvsError::Cancelled),
+        }
+    }
+}
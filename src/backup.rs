@@ -0,0 +1,285 @@
+//! Consistent copies of a store, taken while it's in use
+//!
+//! The log only grows until compaction writes a new one and renames it over the old, and blob
+//! files only grow until they are deleted. So the bytes of a file up to its current length never
+//! change, and a file that is open stays readable after it is replaced or deleted (on Unix at
+//! least). Taking a backup only needs the store long enough to open its files and note their
+//! lengths; copying them can happen while writes continue.
+//!
+//! A backup directory holds copies of the files and `backup.manifest`, which lists each one with
+//! its length and CRC-32. The manifest is written last, so a directory without one holds an
+//! incomplete backup. Backing up into a directory that already holds a backup of the same files
+//! only copies what has been appended to each of them since: a copy is extended as long as the
+//! checksums show it is intact and still the start of the file. Restoring checks every file against the
+//! manifest before opening the store.
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crc32fast::Hasher;
+use snafu::ResultExt;
+use speedy::{Readable, Writable};
+
+use crate::{BackupChecksum, BackupIo, BackupManifest, GetPosition, KvStore, OpenLog, OpenOptions, RestoreTarget, Result};
+
+const MANIFEST: &str = "backup.manifest";
+const LOG: &str = "kvs.db";
+const MAGIC: [u8; 4] = *b"KVB\0";
+
+#[derive(Debug, Clone, Readable, Writable)]
+struct BackupFile {
+    name: String,
+    len: u64,
+    crc: u32,
+}
+
+/// `backup.manifest`, followed in the file by the CRC-32 of its encoding
+#[derive(Debug, Readable, Writable)]
+struct Manifest {
+    magic: [u8; 4],
+    // the version of the log the backup was taken from, see `KvStore::log_id`
+    log_id: u64,
+    files: Vec<BackupFile>,
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest> {
+    let bytes = fs::read(path).context(BackupIo { filename: path.to_owned() })?;
+    let damaged = || BackupChecksum { filename: path.to_owned() }.fail();
+    if bytes.len() < 4 {
+        return damaged();
+    }
+
+    let (body, crc) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().expect("4 bytes")) {
+        return damaged();
+    }
+    let manifest = Manifest::read_from_buffer(body).context(BackupManifest { filename: path.to_owned() })?;
+    if manifest.magic != MAGIC {
+        return damaged();
+    }
+    Ok(manifest)
+}
+
+fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let path = dir.join(MANIFEST);
+    let tmp = dir.join(format!("{}.tmp", MANIFEST));
+    let mut bytes = manifest.write_to_vec().context(BackupManifest { filename: path.clone() })?;
+    bytes.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+
+    let mut f = File::create(&tmp).context(BackupIo { filename: tmp.clone() })?;
+    f.write_all(&bytes).and_then(|_| f.sync_all()).context(BackupIo { filename: tmp.clone() })?;
+    fs::rename(&tmp, &path).context(BackupIo { filename: path })?;
+    #[cfg(unix)]
+    File::open(dir).and_then(|d| d.sync_all()).context(BackupIo { filename: dir.to_owned() })?;
+    Ok(())
+}
+
+/// Copy `len` bytes from the current position of `from` (the file `source`) to `to` (the file
+/// `target`), adding them to `crc`
+fn copy(from: &mut File, source: &Path, len: u64, to: &mut dyn Write, target: &Path, crc: &mut Hasher) -> Result<()> {
+    let mut r = from.take(len);
+    let mut buf = vec![0; 64 << 10];
+    let mut copied = 0;
+    loop {
+        let n = r.read(&mut buf).context(BackupIo { filename: source.to_owned() })?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buf[..n]);
+        to.write_all(&buf[..n]).context(BackupIo { filename: target.to_owned() })?;
+        copied += n as u64;
+    }
+
+    if copied < len {
+        let e = io::Error::new(io::ErrorKind::UnexpectedEof, "file is shorter than expected");
+        return Err(e).context(BackupIo { filename: source.to_owned() });
+    }
+    Ok(())
+}
+
+/// the CRC-32 of the file at `path`, if it is `len` bytes long, `None` if it isn't or is missing
+fn file_crc(path: &Path, len: u64) -> Result<Option<u32>> {
+    let mut f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(BackupIo { filename: path.to_owned() }),
+    };
+    if f.metadata().context(BackupIo { filename: path.to_owned() })?.len() != len {
+        return Ok(None);
+    }
+
+    let mut crc = Hasher::new();
+    copy(&mut f, path, len, &mut io::sink(), path, &mut crc)?;
+    Ok(Some(crc.finalize()))
+}
+
+/// What `Backup::write_to` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupStats {
+    /// files in the backup
+    pub files: usize,
+    /// bytes copied this time
+    pub bytes_copied: u64,
+    /// bytes in the backup altogether
+    pub bytes_total: u64,
+}
+
+/// The files of a store as they were at one moment, from `KvStore::backup`
+///
+/// This doesn't borrow the store: it can carry on being written to, compacted or even dropped
+/// while the files are copied.
+#[derive(Debug)]
+pub struct Backup {
+    log_id: u64,
+    // (name in the backup, path in the store, the open file, length to copy)
+    files: Vec<(String, PathBuf, File, u64)>,
+}
+
+impl Backup {
+    /// Copy the files into the directory `path`, creating it if needed. If it already holds a
+    /// backup of this store, only what has been added since is copied and files the store no
+    /// longer has are removed. The backup can be restored once this returns; one that is
+    /// interrupted can't be, and the next backup into the same directory copies everything.
+    pub fn write_to(self, path: impl AsRef<Path>) -> Result<BackupStats> {
+        let dir = path.as_ref();
+        fs::create_dir_all(dir).context(BackupIo { filename: dir.to_owned() })?;
+
+        let manifest_path = dir.join(MANIFEST);
+        // a damaged manifest just means starting over
+        let previous = if manifest_path.exists() { read_manifest(&manifest_path).ok() } else { None };
+        if previous.is_some() {
+            fs::remove_file(&manifest_path).context(BackupIo { filename: manifest_path.clone() })?;
+        }
+
+        let mut stats = BackupStats { files: self.files.len(), bytes_copied: 0, bytes_total: 0 };
+        let mut copied = Vec::with_capacity(self.files.len());
+        for (name, source, mut f, len) in self.files {
+            let target = dir.join(&name);
+            let earlier = previous.as_ref()
+                .and_then(|m| m.files.iter().find(|b| b.name == name).map(|b| (m.log_id, b.clone())))
+                .filter(|(_, b)| b.len <= len);
+
+            // the copy can be extended if it is intact and still the start of the file: certainly
+            // so if it came from this version of the log, otherwise if the checksums say so
+            let extend = match earlier {
+                Some((log_id, b)) if file_crc(&target, b.len)? == Some(b.crc) => {
+                    if name == LOG && log_id == self.log_id {
+                        Some(b)
+                    } else {
+                        let mut crc = Hasher::new();
+                        copy(&mut f, &source, b.len, &mut io::sink(), &target, &mut crc)?;
+                        Some(b).filter(|b| crc.finalize() == b.crc)
+                    }
+                }
+                _ => None,
+            };
+
+            let (mut out, mut crc, from) = match extend {
+                Some(b) => {
+                    let mut out = fs::OpenOptions::new().write(true).open(&target)
+                        .context(BackupIo { filename: target.clone() })?;
+                    out.set_len(b.len).and_then(|_| out.seek(io::SeekFrom::End(0)))
+                        .context(BackupIo { filename: target.clone() })?;
+                    (out, Hasher::new_with_initial_len(b.crc, b.len), b.len)
+                }
+                None => (File::create(&target).context(BackupIo { filename: target.clone() })?, Hasher::new(), 0),
+            };
+
+            f.seek(io::SeekFrom::Start(from)).context(GetPosition { filename: source.clone() })?;
+            copy(&mut f, &source, len - from, &mut out, &target, &mut crc)?;
+            out.sync_all().context(BackupIo { filename: target.clone() })?;
+
+            stats.bytes_copied += len - from;
+            stats.bytes_total += len;
+            copied.push(BackupFile { name, len, crc: crc.finalize() });
+        }
+
+        for b in previous.map(|m| m.files).unwrap_or_default() {
+            if !copied.iter().any(|c| c.name == b.name) {
+                let stale = dir.join(&b.name);
+                fs::remove_file(&stale).context(BackupIo { filename: stale })?;
+            }
+        }
+
+        write_manifest(dir, &Manifest { magic: MAGIC, log_id: self.log_id, files: copied })?;
+        Ok(stats)
+    }
+}
+
+impl KvStore {
+    /// Note the store's files as they are right now, to copy with `Backup::write_to` without
+    /// holding up further writes
+    pub fn backup(&mut self) -> Result<Backup> {
+        let log_len = self.log_f.seek(io::SeekFrom::End(0))
+            .context(GetPosition { filename: self.log_f_name.clone() })?;
+        // a handle of our own, the store's is moved around by every read
+        let log = File::open(&self.log_f_name).context(OpenLog { filename: self.log_f_name.clone() })?;
+
+        let mut files = vec![(LOG.to_owned(), self.log_f_name.clone(), log, log_len)];
+        for (path, len) in self.blobs.file_ends() {
+            let f = File::open(&path).context(BackupIo { filename: path.clone() })?;
+            let name = path.file_name().expect("blob files have names").to_string_lossy().into_owned();
+            files.push((name, path, f, len));
+        }
+
+        Ok(Backup { log_id: self.log_id, files })
+    }
+
+    /// Back the store up into the directory `path`, see `Backup::write_to`
+    pub fn backup_to(&mut self, path: impl AsRef<Path>) -> Result<BackupStats> {
+        self.backup()?.write_to(path)
+    }
+
+    /// Restore the backup in `backup` into `path` and open it, see `OpenOptions::restore`
+    pub fn restore(backup: impl AsRef<Path>, path: impl Into<PathBuf>) -> Result<KvStore> {
+        OpenOptions::new().restore(backup, path)
+    }
+}
+
+impl OpenOptions {
+    /// Restore the backup in the directory `backup` into `path`, which must not hold a store
+    /// already, and open it with these options. Every file is checked against the size and
+    /// checksum in the backup's manifest first. If anything fails, the files copied so far are
+    /// removed again.
+    pub fn restore(&self, backup: impl AsRef<Path>, path: impl Into<PathBuf>) -> Result<KvStore> {
+        let backup = backup.as_ref();
+        let path = path.into();
+        let manifest = read_manifest(&backup.join(MANIFEST))?;
+
+        let log = path.join(LOG);
+        if log.exists() {
+            return RestoreTarget { filename: log }.fail();
+        }
+        fs::create_dir_all(&path).context(BackupIo { filename: path.clone() })?;
+
+        let mut restored = Vec::new();
+        let result = (|| {
+            for b in &manifest.files {
+                let source = backup.join(&b.name);
+                let target = path.join(&b.name);
+                let mut from = File::open(&source).context(BackupIo { filename: source.clone() })?;
+                let size = from.metadata().context(BackupIo { filename: source.clone() })?.len();
+                let mut to = File::create(&target).context(BackupIo { filename: target.clone() })?;
+                restored.push(target.clone());
+
+                let mut crc = Hasher::new();
+                copy(&mut from, &source, b.len, &mut to, &target, &mut crc)?;
+                if size != b.len || crc.finalize() != b.crc {
+                    return BackupChecksum { filename: source }.fail();
+                }
+                to.sync_all().context(BackupIo { filename: target })?;
+            }
+
+            self.open(path.clone())
+        })();
+
+        if result.is_err() {
+            for f in restored {
+                let _ = fs::remove_file(f);
+            }
+        }
+        result
+    }
+}
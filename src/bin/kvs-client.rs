@@ -0,0 +1,127 @@
+#![warn(rust_2018_idioms)]
+#![deny(unsafe_code)]
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+struct Opt {
+    /// Address of the server
+    #[structopt(long, global = true, default_value = "127.0.0.1:4000")]
+    addr: String,
+
+    /// Spread keys over these servers instead, as addr or addr=weight separated by commas
+    #[structopt(long, global = true, use_delimiter = true, parse(try_from_str = parse_shard))]
+    shards: Vec<(String, u32)>,
+
+    #[structopt(subcommand)]
+    cmd: KvsOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum KvsOpt {
+    Set {
+        key: String,
+        value: String,
+        /// Namespace to set the key in
+        #[structopt(long, default_value = "")]
+        ns: String,
+    },
+    Get {
+        key: String,
+        /// Namespace to look the key up in
+        #[structopt(long, default_value = "")]
+        ns: String,
+    },
+    Rm {
+        key: String,
+        /// Namespace to remove the key from
+        #[structopt(long, default_value = "")]
+        ns: String,
+    },
+    /// Add a node to the cluster; `--addr` must be the leader
+    AddMember {
+        /// address the new node listens on for other nodes
+        raft_addr: String,
+        /// address the new node listens on for clients
+        client_addr: String,
+    },
+    /// Remove a node from the cluster; `--addr` must be the leader
+    RemoveMember {
+        /// address the node listens on for other nodes
+        raft_addr: String,
+    },
+    /// Move keys between servers after changing the servers they are spread over
+    Migrate {
+        /// servers the keys are spread over now, as addr or addr=weight separated by commas
+        #[structopt(long, use_delimiter = true, required = true, parse(try_from_str = parse_shard))]
+        from: Vec<(String, u32)>,
+        /// servers to spread the keys over instead
+        #[structopt(long, use_delimiter = true, required = true, parse(try_from_str = parse_shard))]
+        to: Vec<(String, u32)>,
+    },
+}
+
+fn parse_shard(s: &str) -> Result<(String, u32), String> {
+    match s.split_once('=') {
+        Some((addr, weight)) => match weight.parse() {
+            Ok(weight) => Ok((addr.to_owned(), weight)),
+            Err(_) => Err(format!("expected a weight after =, not {}", weight)),
+        },
+        None => Ok((s.to_owned(), 1)),
+    }
+}
+
+fn ring(shards: &[(String, u32)]) -> kvs::HashRing {
+    let mut ring = kvs::HashRing::new();
+    for (addr, weight) in shards {
+        ring.add(addr.clone(), *weight);
+    }
+    ring
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opt = Opt::from_args();
+
+    // a single server is just a ring with one server on it
+    let shards = if opt.shards.is_empty() { vec![(opt.addr.clone(), 1)] } else { opt.shards.clone() };
+    let mut client = kvs::ShardedClient::new(ring(&shards));
+    match opt.cmd {
+        KvsOpt::Set { key, value, ns } => {
+            client.set(&ns, key, value)?;
+        }
+        KvsOpt::Get { key, ns } => {
+            match client.get(&ns, key)? {
+                None => {
+                    println!("Key not found");
+                }
+                Some(v) => {
+                    println!("{}", v);
+                }
+            }
+        }
+        KvsOpt::Rm { key, ns } => {
+            match client.remove(&ns, key) {
+                Err(kvs::KvsError::RemoveNonexistentKey { key: _ }) => {
+                    println!("Key not found");
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+                Ok(_) => {},
+            }
+        }
+        KvsOpt::AddMember { raft_addr, client_addr } => {
+            kvs::KvsClient::connect(&opt.addr)?.add_member(raft_addr, client_addr)?;
+        }
+        KvsOpt::RemoveMember { raft_addr } => {
+            kvs::KvsClient::connect(&opt.addr)?.remove_member(raft_addr)?;
+        }
+        KvsOpt::Migrate { from, to } => {
+            let moved = ring(&from).migrate_to(&ring(&to))?;
+            println!("Moved {} keys", moved);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+#![warn(rust_2018_idioms)]
+#![deny(unsafe_code)]
+use std::path::PathBuf;
+
+use kvs::ThreadPool;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+struct Opt {
+    /// Address to listen on
+    #[structopt(long, default_value = "127.0.0.1:4000")]
+    addr: String,
+
+    /// Protocol to speak: kvs (for kvs-client), resp (for Redis clients) or http
+    #[structopt(long, default_value = "kvs", possible_values = &["kvs", "resp", "http"])]
+    protocol: String,
+
+    /// Thread pool for connections: naive (a thread each), shared-queue or work-stealing
+    #[structopt(long, default_value = "naive", possible_values = &["naive", "shared-queue", "work-stealing"])]
+    pool: String,
+
+    /// Threads in the pool, defaults to one per cpu. Each open connection holds one.
+    #[structopt(long)]
+    threads: Option<usize>,
+
+    /// Encrypt the store with the key in this file (32 raw bytes or 64 hex digits)
+    #[structopt(long, parse(from_os_str))]
+    key_file: Option<PathBuf>,
+
+    /// Cipher used with `--key-file`: chacha20poly1305 or aes256gcm
+    #[structopt(long, default_value = "chacha20poly1305")]
+    cipher: kvs::Cipher,
+
+    /// Run as a node of a replicated cluster, listening for the other nodes on this address
+    #[structopt(long)]
+    raft_addr: Option<String>,
+
+    /// Members to start a new cluster with, as raft-addr=client-addr pairs separated by commas
+    /// and including this node. Leave out to join an existing cluster.
+    #[structopt(long, use_delimiter = true, parse(try_from_str = parse_member))]
+    cluster: Vec<kvs::Member>,
+
+    /// Serve a read-only copy of the kvs-server at this address, kept up to date by log shipping
+    #[structopt(long, conflicts_with = "raft-addr")]
+    follow: Option<String>,
+
+    /// In a cluster, take a snapshot after this many writes
+    #[structopt(long, default_value = "10000")]
+    snapshot_after: u64,
+}
+
+fn parse_member(s: &str) -> Result<kvs::Member, String> {
+    match s.split_once('=') {
+        Some((raft_addr, client_addr)) => Ok(kvs::Member::new(raft_addr, client_addr)),
+        None => Err(format!("expected raft-addr=client-addr, not {}", s)),
+    }
+}
+
+/// prints what the library reports about failed connections and the like to stderr
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opt = Opt::from_args();
+    log::set_logger(&LOGGER).map_err(|e| e.to_string())?;
+    log::set_max_level(log::LevelFilter::Warn);
+
+    let mut open_opts = kvs::OpenOptions::new();
+    if let Some(key_file) = &opt.key_file {
+        open_opts.key_file(opt.cipher, key_file);
+    }
+
+    let threads = opt.threads
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(4);
+    let pool: Box<dyn ThreadPool> = match opt.pool.as_str() {
+        "shared-queue" => Box::new(kvs::SharedQueueThreadPool::new(threads)?),
+        "work-stealing" => Box::new(kvs::WorkStealingThreadPool::new(threads)?),
+        _ => Box::new(kvs::NaiveThreadPool::new(threads)?),
+    };
+
+    if let Some(raft_addr) = opt.raft_addr {
+        if opt.protocol != "kvs" || opt.key_file.is_some() {
+            return Err("a cluster node only speaks the kvs protocol, unencrypted".into());
+        }
+
+        let me = kvs::Member::new(raft_addr, opt.addr.clone());
+        let node = kvs::RaftOptions::new().snapshot_after(opt.snapshot_after).start(".", me, opt.cluster)?;
+        eprintln!("kvs-server {} cluster node listening on {} ({} pool)", env!("CARGO_PKG_VERSION"), opt.addr, opt.pool);
+        node.run_on(&opt.addr, &*pool)?;
+        return Ok(());
+    }
+
+    let store = open_opts.open(".")?;
+    if let Some(primary) = opt.follow {
+        if opt.protocol != "kvs" {
+            return Err("a follower only speaks the kvs protocol".into());
+        }
+
+        let follower = kvs::Follower::start(store, primary)?;
+        eprintln!("kvs-server {} following {} on {} ({} pool)", env!("CARGO_PKG_VERSION"), follower.primary(), opt.addr, opt.pool);
+        follower.run_on(&opt.addr, &*pool)?;
+        return Ok(());
+    }
+
+    eprintln!("kvs-server {} listening on {} ({}, {} pool)", env!("CARGO_PKG_VERSION"), opt.addr, opt.protocol, opt.pool);
+    match opt.protocol.as_str() {
+        "resp" => kvs::RespServer::new(store).run_on(&opt.addr, &*pool)?,
+        "http" => kvs::HttpServer::new(store).run_on(&opt.addr, &*pool)?,
+        _ => kvs::KvsServer::new(store).run_on(&opt.addr, &*pool)?,
+    }
+
+    Ok(())
+}
@@ -0,0 +1,348 @@
+//! Separate storage for large values
+//!
+//! Values at or above the configured threshold are appended to blob files (`kvs.blob.<id>`) and
+//! the main log only records a `BlobPtr` to them. Compacting the main log then copies pointers
+//! instead of the values themselves.
+//!
+//! Each blob record holds the key along with the value so that garbage collection can tell
+//! whether a record is still referenced. Blob files that are mostly garbage are collected by
+//! moving their live records to the active blob file and then deleting them.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use snafu::ResultExt;
+use speedy::{Readable, Writable};
+
+use crate::crypto::{self, LogCipher};
+use crate::log::{read_record, read_record_at, write_record, ReadError};
+use crate::{sync_dir, EncryptionKey, KvsError, Result};
+use crate::{BlobOpen, BlobRemove, BlobSync, BlobWrite, GetPosition, LogHeader, NonceGeneration};
+
+const BLOB_PREFIX: &str = "kvs.blob.";
+
+/// Location of a value stored in a blob file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Readable, Writable)]
+pub(crate) struct BlobPtr {
+    /// id of the blob file
+    pub file: u64,
+    /// offset of the record in the blob file
+    pub offs: u64,
+    /// size of the record in the blob file
+    pub len: u64,
+}
+
+#[derive(Debug, Readable, Writable)]
+struct BlobRecord {
+    key: String,
+    value: String,
+}
+
+#[derive(Debug)]
+struct BlobFile {
+    path: PathBuf,
+    f: File,
+    cipher: Option<LogCipher>,
+    // offset just past the last record
+    end: u64,
+    // bytes of records that are still referenced by the index
+    live: u64,
+}
+
+impl BlobFile {
+    fn first_record(&self) -> u64 {
+        if self.cipher.is_some() { crypto::HEADER_LEN } else { 0 }
+    }
+
+    /// bytes used by records, live or not
+    fn total(&self) -> u64 {
+        self.end - self.first_record()
+    }
+}
+
+/// The set of blob files belonging to a store
+#[derive(Debug)]
+pub(crate) struct BlobStore {
+    dir: PathBuf,
+    key: Option<EncryptionKey>,
+    file_size: u64,
+    files: BTreeMap<u64, BlobFile>,
+    // file new blobs are appended to
+    active: Option<u64>,
+    // files appended to since the last sync
+    unsynced: BTreeSet<u64>,
+}
+
+fn blob_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}{}", BLOB_PREFIX, id))
+}
+
+fn read_error(e: ReadError, path: &Path, offs: u64) -> KvsError {
+    let filename = path.to_owned();
+    match e {
+        ReadError::Parse(source) => KvsError::BlobRead { filename, offs, source },
+        ReadError::Authentication => KvsError::Authentication { filename, offs },
+        ReadError::Checksum => KvsError::Checksum { filename, offs },
+    }
+}
+
+impl BlobStore {
+    /// Open the blob files in `dir`. `refs` are all the pointers reachable from the index, blob
+    /// files that none of them point into are deleted without being read, or just left alone if
+    /// the files are opened `read_only`.
+    pub fn open<'a>(dir: &Path, key: Option<EncryptionKey>, file_size: u64, refs: impl Iterator<Item = &'a BlobPtr>, read_only: bool) -> Result<Self> {
+        let mut live = HashMap::new();
+        for ptr in refs {
+            *live.entry(ptr.file).or_insert(0u64) += ptr.len;
+        }
+
+        let mut files = BTreeMap::new();
+        let mut removed = false;
+        let entries = fs::read_dir(dir).context(BlobOpen { filename: dir.to_owned() })?;
+        for entry in entries {
+            let entry = entry.context(BlobOpen { filename: dir.to_owned() })?;
+            let id = match entry.file_name().to_str()
+                .and_then(|n| n.strip_prefix(BLOB_PREFIX))
+                .and_then(|id| id.parse::<u64>().ok()) {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let path = entry.path();
+            let live = match live.get(&id) {
+                Some(&live) => live,
+                // a store opened at an earlier point may not refer to files written since
+                None if read_only => continue,
+                None => {
+                    // left behind by garbage collection or a write that never made it into the log
+                    fs::remove_file(&path).context(BlobRemove { filename: path })?;
+                    removed = true;
+                    continue;
+                }
+            };
+
+            let mut f = fs::OpenOptions::new().read(true).write(!read_only).open(&path)
+                .context(BlobOpen { filename: path.clone() })?;
+            let cipher = match &key {
+                Some(key) => Some(LogCipher::read_header(key, &mut f)
+                    .map_err(|e| e.into_error(&path, key))?),
+                None => None,
+            };
+            let end = f.seek(io::SeekFrom::End(0))
+                .context(GetPosition { filename: path.clone() })?;
+
+            files.insert(id, BlobFile { path, f, cipher, end, live });
+        }
+        if removed {
+            sync_dir(dir).context(BlobSync { filename: dir.to_owned() })?;
+        }
+
+        let active = files.iter()
+            .next_back()
+            .filter(|(_, bf)| bf.end < file_size)
+            .map(|(&id, _)| id);
+
+        Ok(BlobStore { dir: dir.to_owned(), key, file_size, files, active, unsynced: BTreeSet::new() })
+    }
+
+    fn new_file(&mut self) -> Result<u64> {
+        let id = self.files.keys().next_back().map_or(0, |id| id + 1);
+        let path = blob_path(&self.dir, id);
+        let mut f = fs::OpenOptions::new().create(true).truncate(true).read(true).write(true).open(&path)
+            .context(BlobOpen { filename: path.clone() })?;
+
+        let cipher = match &self.key {
+            Some(key) => {
+                let c = LogCipher::generate(key)
+                    .context(NonceGeneration { filename: path.clone() })?;
+                c.write_header(&mut f)
+                    .context(LogHeader { filename: path.clone() })?;
+                Some(c)
+            }
+            None => None,
+        };
+        sync_dir(&self.dir).context(BlobSync { filename: self.dir.clone() })?;
+
+        let end = f.stream_position()
+            .context(GetPosition { filename: path.clone() })?;
+        self.files.insert(id, BlobFile { path, f, cipher, end, live: 0 });
+        self.active = Some(id);
+        Ok(id)
+    }
+
+    /// Store `value` for `key` in the active blob file
+    pub fn append(&mut self, key: &str, value: &str) -> Result<BlobPtr> {
+        let id = match self.active {
+            Some(id) if self.files[&id].end < self.file_size => id,
+            _ => self.new_file()?,
+        };
+
+        let BlobFile { path, f, cipher, end, live } = self.files.get_mut(&id).expect("active blob file exists");
+        let offs = f.seek(io::SeekFrom::End(0))
+            .context(GetPosition { filename: path.clone() })?;
+
+        let record = BlobRecord { key: key.to_owned(), value: value.to_owned() };
+        let mut w = io::BufWriter::new(f);
+        write_record(&mut w, cipher.as_ref(), offs, &record)
+            .with_context(|| BlobWrite { filename: path.clone() })?;
+        *end = w.stream_position()
+            .context(GetPosition { filename: path.clone() })?;
+        let end = *end;
+
+        *live += end - offs;
+        self.unsynced.insert(id);
+        Ok(BlobPtr { file: id, offs, len: end - offs })
+    }
+
+    /// read the record at `offs`, returning it along with its size
+    fn read_at(bf: &mut BlobFile, offs: u64) -> Result<(BlobRecord, u64)> {
+        bf.f.seek(io::SeekFrom::Start(offs))
+            .context(GetPosition { filename: bf.path.clone() })?;
+        let mut r = io::BufReader::with_capacity(8192, &mut bf.f);
+        let path = &bf.path;
+        let record = read_record(&mut r, bf.cipher.as_ref(), offs)
+            .map_err(|e| read_error(e, path, offs))?;
+        let end = r.stream_position()
+            .context(GetPosition { filename: bf.path.clone() })?;
+        Ok((record, end - offs))
+    }
+
+    /// Fetch the value for `key` stored at `ptr`
+    pub fn read(&mut self, key: &str, ptr: &BlobPtr) -> Result<String> {
+        let dir = &self.dir;
+        let bf = self.files.get_mut(&ptr.file).ok_or_else(|| KvsError::BlobMissing { filename: blob_path(dir, ptr.file) })?;
+        let (record, _) = Self::read_at(bf, ptr.offs)?;
+        if record.key != key {
+            return Err(KvsError::LogEntryKeyMismatch { key: key.to_owned(), found_key: record.key, filename: bf.path.clone(), offs: ptr.offs });
+        }
+
+        Ok(record.value)
+    }
+
+    /// `ptr` is no longer referenced by the index
+    pub fn release(&mut self, ptr: &BlobPtr) {
+        if let Some(bf) = self.files.get_mut(&ptr.file) {
+            bf.live -= ptr.len;
+        }
+    }
+
+    /// Blob files other than the active one where at least half the bytes are garbage, along
+    /// with how many bytes in them are still live
+    pub fn gc_candidates(&self) -> Vec<(u64, u64)> {
+        self.files.iter()
+            .filter(|(&id, bf)| Some(id) != self.active && bf.live * 2 <= bf.total())
+            .map(|(&id, bf)| (id, bf.live))
+            .collect()
+    }
+
+    /// All records in blob file `id` along with where they are, referenced or not
+    pub fn records(&mut self, id: u64) -> Result<Vec<(BlobPtr, String, String)>> {
+        let bf = self.files.get_mut(&id).expect("blob file exists");
+        let mut offs = bf.first_record();
+        let mut records = Vec::new();
+        while offs < bf.end {
+            let (record, len) = Self::read_at(bf, offs)?;
+            records.push((BlobPtr { file: id, offs, len }, record.key, record.value));
+            offs += len;
+        }
+
+        Ok(records)
+    }
+
+    /// Delete blob file `id`, none of its records may be referenced
+    pub fn remove_file(&mut self, id: u64) -> Result<()> {
+        if let Some(bf) = self.files.remove(&id) {
+            fs::remove_file(&bf.path).context(BlobRemove { filename: bf.path })?;
+            sync_dir(&self.dir).context(BlobSync { filename: self.dir.clone() })?;
+        }
+        self.unsynced.remove(&id);
+        if self.active == Some(id) {
+            self.active = None;
+        }
+        Ok(())
+    }
+
+    /// Make sure everything appended to blob files since the last sync is on disk, including
+    /// files that are no longer active
+    pub fn sync(&mut self) -> Result<()> {
+        while let Some(id) = self.unsynced.iter().next().copied() {
+            if let Some(bf) = self.files.get_mut(&id) {
+                bf.f.flush().context(BlobSync { filename: bf.path.clone() })?;
+                bf.f.sync_all().context(BlobSync { filename: bf.path.clone() })?;
+            }
+            self.unsynced.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Every blob file and the offset just past its last record
+    pub fn file_ends(&self) -> Vec<(PathBuf, u64)> {
+        self.files.values().map(|bf| (bf.path.clone(), bf.end)).collect()
+    }
+
+    /// New blobs will be encrypted with `key`. Returns the ids of the existing blob files, which
+    /// still use the old key and need to have their live records moved before being removed.
+    pub fn set_key(&mut self, key: Option<EncryptionKey>) -> Vec<u64> {
+        self.key = key;
+        self.active = None;
+        self.files.keys().copied().collect()
+    }
+}
+
+/// Reads values from blob files through handles of its own, for any number of threads at once
+///
+/// Files stay open once opened, so a value can still be read after garbage collection deletes
+/// its file, for as long as the reader is kept.
+#[derive(Debug)]
+pub(crate) struct BlobReader {
+    dir: PathBuf,
+    key: Option<EncryptionKey>,
+    files: RwLock<HashMap<u64, (File, Option<LogCipher>)>>,
+}
+
+impl BlobStore {
+    /// A reader for every blob file there is now
+    pub fn reader(&self) -> Result<BlobReader> {
+        let reader = BlobReader { dir: self.dir.clone(), key: self.key.clone(), files: RwLock::default() };
+        for &id in self.files.keys() {
+            reader.open(id)?;
+        }
+        Ok(reader)
+    }
+}
+
+impl BlobReader {
+    /// Make sure blob file `id` can be read from
+    pub fn open(&self, id: u64) -> Result<()> {
+        if self.files.read().unwrap_or_else(|e| e.into_inner()).contains_key(&id) {
+            return Ok(());
+        }
+
+        let path = blob_path(&self.dir, id);
+        let mut f = File::open(&path).context(BlobOpen { filename: path.clone() })?;
+        let cipher = match &self.key {
+            Some(key) => Some(LogCipher::read_header(key, &mut f)
+                .map_err(|e| e.into_error(&path, key))?),
+            None => None,
+        };
+        self.files.write().unwrap_or_else(|e| e.into_inner()).insert(id, (f, cipher));
+        Ok(())
+    }
+
+    /// Fetch the value for `key` stored at `ptr`, which must be in a file that has been opened
+    pub fn read(&self, key: &str, ptr: &BlobPtr) -> Result<String> {
+        let path = blob_path(&self.dir, ptr.file);
+        let files = self.files.read().unwrap_or_else(|e| e.into_inner());
+        let (f, cipher) = files.get(&ptr.file).ok_or_else(|| KvsError::BlobMissing { filename: path.clone() })?;
+        let record: BlobRecord = read_record_at(f, cipher.as_ref(), ptr.offs)
+            .map_err(|e| read_error(e, &path, ptr.offs))?;
+        if record.key != key {
+            return Err(KvsError::LogEntryKeyMismatch { key: key.to_owned(), found_key: record.key, filename: path, offs: ptr.offs });
+        }
+
+        Ok(record.value)
+    }
+}
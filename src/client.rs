@@ -0,0 +1,173 @@
+//! Talking to a `KvsServer`
+
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use snafu::ResultExt;
+use speedy::{Readable, Writable};
+
+use crate::protocol::{read_message, write_message, ErrorCode, Hello, LogPosition, Request, Response, PROTOCOL_VERSION};
+use crate::{Connect, KvsError, Network, Protocol, Result};
+
+// keys asked for at once by `scan`
+const PAGE: u32 = 1000;
+
+/// A connection to a `KvsServer`
+///
+/// ```no_run
+/// # use kvs::KvsClient;
+/// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+/// client.set("", "key1".to_owned(), "value1".to_owned())?;
+/// assert_eq!(client.get("", "key1".to_owned())?, Some("value1".to_owned()));
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug)]
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// connect to the server at `addr` and check it speaks our protocol version
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).context(Connect)?;
+        let mut client = KvsClient {
+            reader: BufReader::new(stream.try_clone().context(Network)?),
+            writer: BufWriter::new(stream),
+        };
+
+        Hello::new().write_to_stream(&mut client.writer).context(Protocol)?;
+        client.writer.flush().context(Network)?;
+        let hello = Hello::read_from_stream(&mut client.reader).context(Protocol)?;
+        if !hello.is_kvs() || hello.version != PROTOCOL_VERSION {
+            return Err(KvsError::ProtocolVersion { server: hello.version, client: PROTOCOL_VERSION });
+        }
+
+        Ok(client)
+    }
+
+    fn call(&mut self, request: &Request) -> Result<Response> {
+        write_message(&mut self.writer, request)?;
+        self.writer.flush().context(Network)?;
+        // the server hung up without answering
+        read_message(&mut self.reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof)).context(Network)
+    }
+
+    /// retrieve the value of `key` in namespace `ns`. if no value, return None
+    pub fn get(&mut self, ns: &str, key: String) -> Result<Option<String>> {
+        match self.call(&Request::Get { ns: ns.to_owned(), key: key.clone() })? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response, key)),
+        }
+    }
+
+    /// set `key` in namespace `ns` to `value`
+    pub fn set(&mut self, ns: &str, key: String, value: String) -> Result<()> {
+        match self.call(&Request::Set { ns: ns.to_owned(), key: key.clone(), value })? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response, key)),
+        }
+    }
+
+    /// remove `key` from namespace `ns`
+    pub fn remove(&mut self, ns: &str, key: String) -> Result<()> {
+        match self.call(&Request::Remove { ns: ns.to_owned(), key: key.clone() })? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response, key)),
+        }
+    }
+
+    /// Every key and value in namespace `ns` whose key starts with `prefix`, in key order. They
+    /// are fetched a page at a time, so writes made meanwhile may or may not show up.
+    pub fn scan(&mut self, ns: &str, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut found = Vec::new();
+        let mut after = None;
+        loop {
+            let (pairs, more) = self.scan_page(ns, prefix, after, PAGE)?;
+            after = pairs.last().map(|(key, _)| key.clone());
+            found.extend(pairs);
+            if !more || after.is_none() {
+                return Ok(found);
+            }
+        }
+    }
+
+    /// the keys and values in `ns` starting with `prefix`, after `after`, at most `limit` of
+    /// them, and whether there are more
+    pub(crate) fn scan_page(&mut self, ns: &str, prefix: &str, after: Option<String>, limit: u32) -> Result<(Vec<(String, String)>, bool)> {
+        let request = Request::Scan { ns: ns.to_owned(), prefix: prefix.to_owned(), after, limit };
+        match self.call(&request)? {
+            Response::Pairs { pairs, more } => Ok((pairs, more)),
+            response => Err(unexpected(response, prefix.to_owned())),
+        }
+    }
+
+    /// the keys and values in `ns` whose hashes are in `ranges`, after `after`, at most `limit`
+    /// of them, and whether there may be more
+    pub(crate) fn scan_hashes(&mut self, ns: &str, ranges: &[(u64, u64)], after: Option<String>, limit: u32) -> Result<(Vec<(String, String)>, bool)> {
+        let request = Request::ScanHashes { ns: ns.to_owned(), ranges: ranges.to_vec(), after, limit };
+        match self.call(&request)? {
+            Response::Pairs { pairs, more } => Ok((pairs, more)),
+            response => Err(unexpected(response, String::new())),
+        }
+    }
+
+    /// Names of the namespaces that hold at least one key, in order
+    pub fn namespaces(&mut self) -> Result<Vec<String>> {
+        match self.call(&Request::Namespaces)? {
+            Response::Names(names) => Ok(names),
+            response => Err(unexpected(response, String::new())),
+        }
+    }
+
+    /// Add the node listening for peers on `raft_addr` and for clients on `client_addr` to the
+    /// cluster. Only the leader of a cluster accepts this.
+    pub fn add_member(&mut self, raft_addr: String, client_addr: String) -> Result<()> {
+        match self.call(&Request::AddMember { raft_addr: raft_addr.clone(), client_addr })? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response, raft_addr)),
+        }
+    }
+
+    /// Remove the node listening for peers on `raft_addr` from the cluster. Only the leader of a
+    /// cluster accepts this.
+    pub fn remove_member(&mut self, raft_addr: String) -> Result<()> {
+        match self.call(&Request::RemoveMember { raft_addr: raft_addr.clone() })? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response, raft_addr)),
+        }
+    }
+
+    /// fetch what the server's log holds past `from`, for a `Follower`
+    pub(crate) fn replicate(&mut self, from: Option<LogPosition>) -> Result<Response> {
+        match self.call(&Request::Replicate { from })? {
+            response @ Response::Records { .. } | response @ Response::Snapshot { .. } => Ok(response),
+            response => Err(unexpected(response, String::new())),
+        }
+    }
+
+    /// fetch the page of a snapshot after `after`, for a `Follower`
+    pub(crate) fn snapshot_page(&mut self, after: (String, String)) -> Result<Response> {
+        match self.call(&Request::SnapshotPage { after })? {
+            response @ Response::Snapshot { .. } => Ok(response),
+            response => Err(unexpected(response, String::new())),
+        }
+    }
+}
+
+/// turn a response that isn't the answer we asked for into an error, `key` is the key the
+/// request was about
+fn unexpected(response: Response, key: String) -> KvsError {
+    match response {
+        Response::Error { code, message } => match ErrorCode::from_code(code) {
+            ErrorCode::KeyNotFound => KvsError::RemoveNonexistentKey { key },
+            ErrorCode::ReadOnly => KvsError::ReadOnly,
+            ErrorCode::Cancelled => KvsError::Cancelled,
+            code => KvsError::Server { code, message },
+        },
+        response => KvsError::Server {
+            code: ErrorCode::BadRequest,
+            message: format!("unexpected response {:?}", response),
+        },
+    }
+}
@@ -0,0 +1,273 @@
+//! Encryption at rest for log files
+//!
+//! An encrypted log starts with a small header (see `LogCipher::write_header`) followed by
+//! framed records (see `log`) whose contents are the AEAD ciphertext of a speedy encoded
+//! `LogEntry`. Logs written before framing hold length prefixed ciphertexts instead.
+//!
+//! Every log file gets a fresh random base nonce when it is created. The nonce for a record is
+//! the base nonce with the record's offset xored into its low 8 bytes, so nonces are unique
+//! within a file (offsets never repeat) and across files (base nonces are random).
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::KvsError;
+
+/// Magic bytes that start every encrypted log file
+const MAGIC: &[u8; 8] = b"KVSENC\x00\x01";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Size of the header at the start of an encrypted log: magic, cipher id, base nonce, key check
+pub(crate) const HEADER_LEN: u64 = (MAGIC.len() + 1 + NONCE_LEN + TAG_LEN) as u64;
+
+/// AEAD algorithm used to encrypt log records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    /// ChaCha20-Poly1305 (RFC 8439)
+    ChaCha20Poly1305,
+    /// AES-256 in Galois/Counter Mode
+    Aes256Gcm,
+}
+
+impl Cipher {
+    fn id(self) -> u8 {
+        match self {
+            Cipher::ChaCha20Poly1305 => 1,
+            Cipher::Aes256Gcm => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Cipher::ChaCha20Poly1305),
+            2 => Some(Cipher::Aes256Gcm),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chacha20poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            "aes256gcm" => Ok(Cipher::Aes256Gcm),
+            _ => Err(format!("unknown cipher {:?}, expected chacha20poly1305 or aes256gcm", s)),
+        }
+    }
+}
+
+/// A 256 bit key along with the cipher it is used with
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey {
+    cipher: Cipher,
+    key: [u8; 32],
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key material
+        f.debug_struct("EncryptionKey")
+            .field("cipher", &self.cipher)
+            .finish()
+    }
+}
+
+impl EncryptionKey {
+    /// use `key` with `cipher`
+    pub fn new(cipher: Cipher, key: [u8; 32]) -> Self {
+        EncryptionKey { cipher, key }
+    }
+
+    /// Load a key from a file containing either 32 raw bytes or 64 hex digits
+    pub fn from_file(cipher: Cipher, path: impl AsRef<Path>) -> io::Result<Self> {
+        let data = std::fs::read(path.as_ref())?;
+        if data.len() == 32 {
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&data);
+            return Ok(Self::new(cipher, key));
+        }
+
+        let text = std::str::from_utf8(&data).map_err(|_| bad_key_file())?.trim();
+        if text.len() != 64 || !text.is_ascii() {
+            return Err(bad_key_file());
+        }
+        let mut key = [0u8; 32];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).map_err(|_| bad_key_file())?;
+        }
+        Ok(Self::new(cipher, key))
+    }
+
+    /// The cipher this key is used with
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+}
+
+fn bad_key_file() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "key file must contain 32 raw bytes or 64 hex digits")
+}
+
+enum Aead256 {
+    ChaCha(Box<ChaCha20Poly1305>),
+    Aes(Box<Aes256Gcm>),
+}
+
+/// Encryption state for a single log file
+pub(crate) struct LogCipher {
+    key: EncryptionKey,
+    aead: Aead256,
+    base_nonce: [u8; NONCE_LEN],
+}
+
+impl fmt::Debug for LogCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogCipher")
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+/// Why an encrypted log header could not be used
+#[derive(Debug)]
+pub(crate) enum HeaderError {
+    /// Reading the header failed
+    Io(io::Error),
+    /// The header names a cipher we don't know about
+    UnknownCipher(u8),
+    /// The header was written for a different cipher than the key we were given
+    CipherMismatch(Cipher),
+    /// The key check in the header did not verify
+    WrongKey,
+}
+
+impl HeaderError {
+    /// describe the problem with the header of `filename`, which we tried to use with `key`
+    pub fn into_error(self, filename: &Path, key: &EncryptionKey) -> KvsError {
+        let filename = filename.to_owned();
+        match self {
+            HeaderError::Io(source) => KvsError::LogHeader { filename, source },
+            HeaderError::UnknownCipher(id) => KvsError::UnknownCipher { filename, id },
+            HeaderError::CipherMismatch(found) => KvsError::CipherMismatch { filename, expected: key.cipher(), found },
+            HeaderError::WrongKey => KvsError::Authentication { filename, offs: 0 },
+        }
+    }
+}
+
+impl LogCipher {
+    fn with_nonce(key: &EncryptionKey, base_nonce: [u8; NONCE_LEN]) -> Self {
+        let aead = match key.cipher {
+            Cipher::ChaCha20Poly1305 => Aead256::ChaCha(Box::new(ChaCha20Poly1305::new(&key.key.into()))),
+            Cipher::Aes256Gcm => Aead256::Aes(Box::new(Aes256Gcm::new(&key.key.into()))),
+        };
+
+        LogCipher { key: key.clone(), aead, base_nonce }
+    }
+
+    /// Create the cipher for a brand new log file
+    pub fn generate(key: &EncryptionKey) -> io::Result<Self> {
+        let mut base_nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut base_nonce).map_err(io::Error::from)?;
+        Ok(Self::with_nonce(key, base_nonce))
+    }
+
+    /// The key this log is encrypted with
+    pub fn key(&self) -> &EncryptionKey {
+        &self.key
+    }
+
+    /// does `prefix` (the first bytes of a log) look like an encrypted log header?
+    pub fn is_encrypted(prefix: &[u8]) -> bool {
+        prefix.starts_with(MAGIC)
+    }
+
+    fn nonce(&self, offs: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = self.base_nonce;
+        for (n, o) in nonce[NONCE_LEN - 8..].iter_mut().zip(offs.to_le_bytes().iter()) {
+            *n ^= o;
+        }
+        nonce
+    }
+
+    /// Encrypt a record that will be placed at `offs` in the log
+    pub fn seal(&self, offs: u64, plaintext: &[u8]) -> Option<Vec<u8>> {
+        let nonce = self.nonce(offs);
+        let aad = offs.to_le_bytes();
+        let payload = Payload { msg: plaintext, aad: &aad };
+        match &self.aead {
+            Aead256::ChaCha(a) => a.encrypt(&nonce.into(), payload),
+            Aead256::Aes(a) => a.encrypt(&nonce.into(), payload),
+        }.ok()
+    }
+
+    /// Decrypt the record found at `offs`, returning `None` if authentication fails
+    pub fn open(&self, offs: u64, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let nonce = self.nonce(offs);
+        let aad = offs.to_le_bytes();
+        let payload = Payload { msg: ciphertext, aad: &aad };
+        match &self.aead {
+            Aead256::ChaCha(a) => a.decrypt(&nonce.into(), payload),
+            Aead256::Aes(a) => a.decrypt(&nonce.into(), payload),
+        }.ok()
+    }
+
+    /// Write the header for a new log file. The key check is the tag over an empty message using
+    /// a nonce no record can use, so a wrong key is reported before any record is read.
+    pub fn write_header(&self, mut w: impl Write) -> io::Result<()> {
+        let check = self.seal(u64::MAX, &[]).expect("sealing an empty message cannot fail");
+        w.write_all(MAGIC)?;
+        w.write_all(&[self.key.cipher.id()])?;
+        w.write_all(&self.base_nonce)?;
+        w.write_all(&check)?;
+        Ok(())
+    }
+
+    /// Read and verify the header of an existing log file using `key`
+    pub fn read_header(key: &EncryptionKey, mut r: impl Read) -> Result<Self, HeaderError> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        r.read_exact(&mut header).map_err(HeaderError::Io)?;
+        if !Self::is_encrypted(&header) {
+            return Err(HeaderError::Io(io::Error::new(io::ErrorKind::InvalidData, "missing encrypted log magic")));
+        }
+
+        let id = header[MAGIC.len()];
+        let cipher = Cipher::from_id(id).ok_or(HeaderError::UnknownCipher(id))?;
+        if cipher != key.cipher {
+            return Err(HeaderError::CipherMismatch(cipher));
+        }
+
+        let mut base_nonce = [0u8; NONCE_LEN];
+        let nonce_start = MAGIC.len() + 1;
+        base_nonce.copy_from_slice(&header[nonce_start..nonce_start + NONCE_LEN]);
+        let c = Self::with_nonce(key, base_nonce);
+        if c.open(u64::MAX, &header[nonce_start + NONCE_LEN..]).is_none() {
+            return Err(HeaderError::WrongKey);
+        }
+
+        Ok(c)
+    }
+}
+
+/// Where a key for `OpenOptions` comes from
+#[derive(Debug, Clone)]
+pub(crate) enum KeySource {
+    Key(EncryptionKey),
+    File(Cipher, PathBuf),
+}
+
+impl KeySource {
+    pub fn load(&self) -> io::Result<EncryptionKey> {
+        match self {
+            KeySource::Key(k) => Ok(k.clone()),
+            KeySource::File(cipher, path) => EncryptionKey::from_file(*cipher, path),
+        }
+    }
+}
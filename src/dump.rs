@@ -0,0 +1,312 @@
+//! Decoding the log for people to read
+//!
+//! `KvStore::dump` walks the log from the start and describes each change in it: where its record
+//! is, what kind of record it is, the key, the start of the value, and whether the index still
+//! points at it. Nothing is changed, and values in blob files aren't read.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Seek};
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+
+use snafu::ResultExt;
+
+use crate::crypto::{self, LogCipher};
+use crate::log::{read_record, LogEntry, LogReader, NsKeys, Op, ReadError};
+use crate::{GetPosition, KvStore, KvsError, LogLookup, OpenLog, Result};
+
+// characters of a value shown in a dump
+const PREVIEW_CHARS: usize = 40;
+
+/// What a change in the log does, see `DumpRecord`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// sets a key to a value held in the log
+    Set,
+    /// sets a key to a value held in a blob file
+    SetBlob,
+    /// removes a key
+    Remove,
+    /// marks the start of a compacted log, and changes no key
+    Checkpoint,
+}
+
+impl FromStr for ChangeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "set" => Ok(ChangeKind::Set),
+            "blob" => Ok(ChangeKind::SetBlob),
+            "remove" => Ok(ChangeKind::Remove),
+            "checkpoint" => Ok(ChangeKind::Checkpoint),
+            _ => Err(format!("unknown kind {:?}, expected set, blob, remove or checkpoint", s)),
+        }
+    }
+}
+
+/// Whether a change in the log is still in effect, see `DumpRecord`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// the key's current value, or for a remove, the key has no value now
+    Live,
+    /// a later change to the key replaced it
+    Superseded,
+    /// an earlier version kept by compaction for the key's history
+    History,
+}
+
+/// One change in the log, from `KvStore::dump`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpRecord {
+    /// offset of the record holding the change in `kvs.db`
+    pub offs: u64,
+    /// size of that record in bytes
+    pub size: u64,
+    /// the kind of record: `Stamped` for an ordinary write; compaction copies each value as a
+    /// single `Set`, `NsSet`, `SetBlob` or `NsSetBlob`, or as a `Stamped` or `Retained` one for
+    /// keys that keep their history. Logs written by older versions also hold `Batch` records.
+    pub record: &'static str,
+    /// sequence number of the write the record belongs to, if it carries one
+    pub seq: Option<u64>,
+    /// what the change does
+    pub kind: ChangeKind,
+    /// namespace of the key
+    pub ns: String,
+    /// the key, empty for a checkpoint
+    pub key: String,
+    /// the start of the value, or where it is for a value in a blob file
+    pub preview: String,
+    /// whether the change is still in effect, `None` for a checkpoint
+    pub status: Option<Status>,
+}
+
+/// Which changes `KvStore::dump` describes
+///
+/// ```
+/// # use kvs::{ChangeKind, DumpOptions};
+/// let mut removes = DumpOptions::new();
+/// removes.kind(ChangeKind::Remove).namespace("users");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DumpOptions {
+    key: Option<String>,
+    ns: Option<String>,
+    offsets: Option<Range<u64>>,
+    kinds: Vec<ChangeKind>,
+}
+
+impl DumpOptions {
+    /// Every change in the log
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only changes to `key`
+    pub fn key(&mut self, key: &str) -> &mut Self {
+        self.key = Some(key.to_owned());
+        self
+    }
+
+    /// Only changes in namespace `ns`
+    pub fn namespace(&mut self, ns: &str) -> &mut Self {
+        self.ns = Some(ns.to_owned());
+        self
+    }
+
+    /// Only records starting at an offset in `offsets`
+    pub fn offsets(&mut self, offsets: Range<u64>) -> &mut Self {
+        self.offsets = Some(offsets);
+        self
+    }
+
+    /// Only changes of this kind. Call more than once to include several kinds.
+    pub fn kind(&mut self, kind: ChangeKind) -> &mut Self {
+        self.kinds.push(kind);
+        self
+    }
+
+    fn wants(&self, ns: &str, key: &str, kind: ChangeKind) -> bool {
+        self.wants_key(ns, key) && (self.kinds.is_empty() || self.kinds.contains(&kind))
+    }
+
+    fn wants_key(&self, ns: &str, key: &str) -> bool {
+        self.ns.as_ref().is_none_or(|n| n == ns) && self.key.as_ref().is_none_or(|k| k == key)
+    }
+}
+
+/// Call `f` with the offset of each record in the log `filename` before `end`, the offset just
+/// past it, and the record
+fn for_each_record<F>(filename: &Path, cipher: Option<&LogCipher>, end: u64, mut f: F) -> Result<()>
+    where F: FnMut(u64, u64, LogEntry) -> Result<()>
+{
+    let mut log = File::open(filename)
+        .context(OpenLog { filename: filename.to_owned() })?;
+    let mut offs = if cipher.is_some() { crypto::HEADER_LEN } else { 0 };
+    log.seek(io::SeekFrom::Start(offs))
+        .context(GetPosition { filename: filename.to_owned() })?;
+    let mut r = BufReader::with_capacity(8192, log);
+
+    while offs < end {
+        let entry: LogEntry = match read_record(&mut r, cipher, offs) {
+            Ok(entry) => entry,
+            Err(ReadError::Parse(e)) => {
+                return Err(e).context(LogLookup { key: String::new(), filename: filename.to_owned(), offs });
+            }
+            Err(ReadError::Authentication) => {
+                return Err(KvsError::Authentication { filename: filename.to_owned(), offs });
+            }
+            Err(ReadError::Checksum) => {
+                return Err(KvsError::Checksum { filename: filename.to_owned(), offs });
+            }
+        };
+        let next = r.stream_position()
+            .context(GetPosition { filename: filename.to_owned() })?;
+        f(offs, next, entry)?;
+        offs = next;
+    }
+
+    Ok(())
+}
+
+fn record_name(entry: &LogEntry) -> &'static str {
+    match entry {
+        LogEntry::Set { .. } => "Set",
+        LogEntry::Remove { .. } => "Remove",
+        LogEntry::SetBlob { .. } => "SetBlob",
+        LogEntry::NsSet { .. } => "NsSet",
+        LogEntry::NsRemove { .. } => "NsRemove",
+        LogEntry::NsSetBlob { .. } => "NsSetBlob",
+        LogEntry::Batch { .. } => "Batch",
+        LogEntry::Stamped { .. } => "Stamped",
+        LogEntry::Checkpoint { .. } => "Checkpoint",
+        LogEntry::Retained { .. } => "Retained",
+    }
+}
+
+fn preview(value: &str) -> String {
+    match value.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}...", &value[..end]),
+        None => value.to_owned(),
+    }
+}
+
+impl KvStore {
+    /// Call `f` with each change in the log that `options` asks for, in the order they were
+    /// written. Stops at the first record that can't be read, with the error for it.
+    ///
+    /// ```
+    /// # use kvs::{DumpOptions, KvStore, Status};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let mut store = KvStore::open(dir.path())?;
+    /// store.set("key1".to_owned(), "first".to_owned())?;
+    /// store.set("key1".to_owned(), "second".to_owned())?;
+    ///
+    /// let mut statuses = Vec::new();
+    /// store.dump(&DumpOptions::new(), |change| {
+    ///     statuses.push((change.preview, change.status));
+    ///     Ok(())
+    /// })?;
+    /// assert_eq!(statuses, vec![
+    ///     ("first".to_owned(), Some(Status::Superseded)),
+    ///     ("second".to_owned(), Some(Status::Live)),
+    /// ]);
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn dump<F>(&mut self, options: &DumpOptions, mut f: F) -> Result<()>
+        where F: FnMut(DumpRecord) -> Result<()>
+    {
+        let filename = self.log_f_name.clone();
+        let end = File::open(&filename)
+            .context(OpenLog { filename: filename.clone() })?
+            .seek(io::SeekFrom::End(0))
+            .context(GetPosition { filename: filename.clone() })?;
+        let range = options.offsets.clone().unwrap_or(0..end);
+
+        // where each key that has been removed was last changed: a remove is only live if
+        // nothing came after it, and the index can't tell which remove was the last
+        let mut last_change: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        if options.kinds.is_empty() || options.kinds.contains(&ChangeKind::Remove) {
+            for_each_record(&filename, self.cipher.as_ref(), end, |offs, _, entry| {
+                if let LogEntry::Retained { .. } = entry {
+                    return Ok(());
+                }
+                for (ns, key, op) in entry.ops() {
+                    if !options.wants_key(ns, key) {
+                        continue;
+                    }
+                    match (op, last_change.get_mut(ns).and_then(|keys| keys.get_mut(key))) {
+                        (Op::Remove, _) => {
+                            last_change.entry(ns.to_owned()).or_default().insert(key.to_owned(), offs);
+                        }
+                        (_, Some(last)) => *last = offs,
+                        (_, None) => {}
+                    }
+                }
+                Ok(())
+            })?;
+        }
+
+        let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &filename };
+        let spaces = &self.spaces;
+        for_each_record(&filename, self.cipher.as_ref(), end.min(range.end), |offs, next, entry| {
+            if offs < range.start {
+                return Ok(());
+            }
+
+            let record = record_name(&entry);
+            let seq = match entry {
+                LogEntry::Stamped { seq, .. } | LogEntry::Checkpoint { seq, .. } | LogEntry::Retained { seq, .. } => Some(seq),
+                _ => None,
+            };
+            let describe = |kind, ns: &str, key: &str, preview, status| DumpRecord {
+                offs,
+                size: next - offs,
+                record,
+                seq,
+                kind,
+                ns: ns.to_owned(),
+                key: key.to_owned(),
+                preview,
+                status,
+            };
+
+            if let LogEntry::Checkpoint { .. } = entry {
+                if options.key.is_none() && options.wants("", "", ChangeKind::Checkpoint) {
+                    f(describe(ChangeKind::Checkpoint, "", "", String::new(), None))?;
+                }
+            }
+
+            let retained = matches!(entry, LogEntry::Retained { .. });
+            for (ns, key, op) in entry.ops() {
+                let (kind, value) = match op {
+                    Op::Set(value) => (ChangeKind::Set, preview(value)),
+                    Op::SetBlob(blob) => (ChangeKind::SetBlob, format!("blob file {} at {}, {} bytes", blob.file, blob.offs, blob.len)),
+                    Op::Remove => (ChangeKind::Remove, String::new()),
+                };
+                if !options.wants(ns, key, kind) {
+                    continue;
+                }
+
+                let current = match spaces.get(ns) {
+                    Some(space) => space.index.get(key, &mut NsKeys { reader: &mut reader, ns })?,
+                    None => None,
+                };
+                let last = last_change.get(ns).and_then(|keys| keys.get(key));
+                let status = match (retained, op, current) {
+                    (true, _, _) => Status::History,
+                    (false, Op::Remove, None) if last == Some(&offs) => Status::Live,
+                    (false, Op::Remove, _) => Status::Superseded,
+                    (false, _, Some(current)) if current == offs => Status::Live,
+                    (false, _, _) => Status::Superseded,
+                };
+                f(describe(kind, ns, key, value, Some(status)))?;
+            }
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+}
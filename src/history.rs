@@ -0,0 +1,167 @@
+//! Earlier versions of keys, for namespaces that keep history
+//!
+//! Every write to a key is a version of it, numbered by the write's sequence number. The index
+//! only points at the latest, so namespaces that keep history also note where in the log each
+//! version was written and when, and compaction copies every version it keeps instead of only
+//! the latest. A value in a blob file stays there while any version points at it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::blob::BlobPtr;
+use crate::log::{Change, LogReader, Op};
+use crate::{KvStore, KvsError, Namespace, NamespaceOptions, Result, Until};
+
+/// Where one version of a key was written
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct VersionAt {
+    pub offs: u64,
+    // milliseconds since the Unix epoch
+    pub time: u64,
+    pub blob: Option<BlobPtr>,
+    pub removed: bool,
+}
+
+/// every version of each key, by sequence number
+pub(crate) type Versions = HashMap<String, BTreeMap<u64, VersionAt>>;
+
+/// One version of a key, from `KvStore::history`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// sequence number of the write that made it, see `KvStore::last_seq`
+    pub seq: u64,
+    /// when it was written
+    pub time: SystemTime,
+    /// the value, `None` if the key was removed
+    pub value: Option<String>,
+}
+
+/// Call `f` with each version of each key in namespace `ns` that compaction should keep, oldest
+/// first, as (key, change, sequence number, time, whether it's the key's current value). Returns
+/// the blobs of the versions that aren't kept.
+pub(crate) fn for_each_version<F>(ns: &str, opts: &NamespaceOptions, versions: &Versions, reader: &mut LogReader<'_>, now: u64, mut f: F) -> Result<Vec<BlobPtr>>
+    where F: FnMut(&str, Op<'_>, u64, u64, bool) -> Result<()>
+{
+    let window = opts.keep_for.map(|w| w.as_millis() as u64);
+    let mut dropped = Vec::new();
+    let mut keys: Vec<&String> = versions.keys().collect();
+    keys.sort_unstable();
+
+    for key in keys {
+        // counting back from the latest version, which is always kept while the key exists
+        let mut kept = Vec::new();
+        for (age, (&seq, v)) in versions[key].iter().rev().enumerate() {
+            let live = age == 0 && !v.removed;
+            let recent = window.is_some_and(|w| now.saturating_sub(v.time) <= w);
+            if live || age < opts.keep_versions || recent {
+                kept.push((seq, v, live));
+            } else {
+                dropped.extend(v.blob);
+            }
+        }
+
+        for (seq, v, live) in kept.into_iter().rev() {
+            let entry = reader.read_at(v.offs, key)?;
+            let op = entry.find(ns, key).ok_or_else(|| KvsError::LogEntryKeyMismatch {
+                key: key.clone(),
+                found_key: entry.first_key().to_owned(),
+                filename: reader.filename.to_owned(),
+                offs: v.offs,
+            })?;
+            f(key, op, seq, v.time, live)?;
+        }
+    }
+
+    Ok(dropped)
+}
+
+impl KvStore {
+    /// The value `key` had at `at`: just after the write with that sequence number, or at that
+    /// time. `None` if it had no value then, or if compaction has since dropped that version.
+    /// Only works if the default namespace keeps history, see `NamespaceOptions::keep_versions`.
+    ///
+    /// ```
+    /// # use kvs::{OpenOptions, Until};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let mut store = OpenOptions::new().keep_versions(10).open(dir.path())?;
+    /// store.set("key1".to_owned(), "first".to_owned())?;
+    /// let first = store.last_seq();
+    /// store.set("key1".to_owned(), "second".to_owned())?;
+    ///
+    /// assert_eq!(store.get_at("key1".to_owned(), Until::Seq(first))?, Some("first".to_owned()));
+    /// assert_eq!(store.history("key1".to_owned())?.len(), 2);
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn get_at(&mut self, key: String, at: Until) -> Result<Option<String>> {
+        self.get_at_in("", key, at)
+    }
+
+    /// Every version of `key` that is still kept, oldest first. Only works if the default
+    /// namespace keeps history, see `NamespaceOptions::keep_versions`.
+    pub fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        self.history_in("", key)
+    }
+
+    /// the versions of `key` in `ns`
+    fn versions_of(&self, ns: &str, key: &str) -> Result<Vec<(u64, VersionAt)>> {
+        let no_history = || KvsError::NoHistory { ns: ns.to_owned() };
+        let versions = match self.spaces.get(ns) {
+            Some(space) => space.versions.as_ref().ok_or_else(no_history)?,
+            // a namespace nothing has been written to yet
+            None if self.ns_config.get(ns).keeps_history() => return Ok(Vec::new()),
+            None => return Err(no_history()),
+        };
+
+        Ok(versions.get(key).map_or_else(Vec::new, |v| v.iter().map(|(&seq, &v)| (seq, v)).collect()))
+    }
+
+    fn get_at_in(&mut self, ns: &str, key: String, at: Until) -> Result<Option<String>> {
+        let version = self.versions_of(ns, &key)?
+            .into_iter()
+            .rev()
+            .find(|&(seq, v)| at.includes(seq, v.time));
+
+        match version {
+            Some((_, v)) => self.version_value(ns, &key, &v),
+            None => Ok(None),
+        }
+    }
+
+    fn history_in(&mut self, ns: &str, key: String) -> Result<Vec<Version>> {
+        let mut history = Vec::new();
+        for (seq, v) in self.versions_of(ns, &key)? {
+            let value = self.version_value(ns, &key, &v)?;
+            history.push(Version { seq, time: UNIX_EPOCH + Duration::from_millis(v.time), value });
+        }
+
+        Ok(history)
+    }
+
+    fn version_value(&mut self, ns: &str, key: &str, v: &VersionAt) -> Result<Option<String>> {
+        if v.removed {
+            return Ok(None);
+        }
+
+        let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+        let entry = reader.read_at(v.offs, key)?;
+        let first_key = entry.first_key().to_owned();
+        match entry.take(ns, key) {
+            Some(Change::Set(value)) => Ok(Some(value)),
+            Some(Change::SetBlob(blob)) => self.blobs.read(key, &blob).map(Some),
+            Some(Change::Remove) => Ok(None),
+            None => Err(KvsError::LogEntryKeyMismatch { key: key.to_owned(), found_key: first_key, filename: self.log_f_name.clone(), offs: v.offs }),
+        }
+    }
+}
+
+impl Namespace<'_> {
+    /// Like `KvStore::get_at`, for the keys in this namespace
+    pub fn get_at(&mut self, key: String, at: Until) -> Result<Option<String>> {
+        self.store.get_at_in(&self.name, key, at)
+    }
+
+    /// Like `KvStore::history`, for the keys in this namespace
+    pub fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        self.store.history_in(&self.name, key)
+    }
+}
@@ -0,0 +1,543 @@
+//! HTTP/JSON front end
+//!
+//! Routes:
+//!
+//!  - `GET /keys/{key}` returns `{"key": .., "value": ..}` with an `ETag`
+//!  - `PUT /keys/{key}` with a body of `{"value": ..}` sets the key, `201` if it is new
+//!  - `DELETE /keys/{key}` removes the key
+//!  - `GET /keys?prefix=..&limit=..&cursor=..` lists keys and values in key order, a page at a
+//!    time. `next` in the response is the cursor for the following page, `null` on the last.
+//!  - `GET /stats` describes the store
+//!
+//! Every key route takes an optional `ns` query parameter naming the namespace. Writes honour
+//! `If-Match` (and `If-None-Match: *` to only create), answering `412` when the current value
+//! doesn't match. Errors are returned as `{"error": .., "kind": ..}` with a status picked by
+//! `error_status`.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value};
+use snafu::ResultExt;
+
+use crate::thread_pool::{NaiveThreadPool, ThreadPool};
+use crate::{Bind, KvStore, KvsError, Network, Result};
+
+// longest request line or header, and the most headers we accept
+const MAX_LINE: u64 = 16 << 10;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY: usize = 64 << 20;
+
+const DEFAULT_PAGE: usize = 100;
+const MAX_PAGE: usize = 1000;
+
+/// Serves a store over HTTP
+#[derive(Debug, Clone)]
+pub struct HttpServer {
+    store: Arc<Mutex<KvStore>>,
+}
+
+impl HttpServer {
+    /// serve `store`
+    pub fn new(store: KvStore) -> Self {
+        HttpServer { store: Arc::new(Mutex::new(store)) }
+    }
+
+    /// Listen on `addr` and serve clients until accepting a connection fails
+    pub fn run(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Bind)?;
+        self.serve(listener)
+    }
+
+    /// Listen on `addr` and serve clients with `pool` until accepting a connection fails
+    pub fn run_on(&self, addr: impl ToSocketAddrs, pool: &dyn ThreadPool) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Bind)?;
+        self.serve_on(listener, pool)
+    }
+
+    /// Serve clients connecting to `listener`, each on its own thread. Requests are applied to
+    /// the store one at a time, so a conditional write can't race with another write.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        self.serve_on(listener, &NaiveThreadPool)
+    }
+
+    /// Serve clients connecting to `listener`, each connection as a job on `pool`. A connection
+    /// keeps its thread until it closes, so with a fixed size pool further clients wait for one.
+    pub fn serve_on(&self, listener: TcpListener, pool: &dyn ThreadPool) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream.context(Network)?;
+            let server = self.clone();
+            pool.execute(Box::new(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = server.handle(stream) {
+                    log::warn!("connection from {:?} failed: {}", peer, e);
+                }
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// answer requests on `stream` until either side closes the connection
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone().context(Network)?);
+        let mut writer = BufWriter::new(stream);
+
+        loop {
+            let request = match Request::read(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let response = Response::error(400, "BadRequest", &e.to_string());
+                    response.write(&mut writer, false).context(Network)?;
+                    return writer.flush().context(Network);
+                }
+                Err(e) => return Err(e).context(Network),
+            };
+
+            let response = {
+                let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+                route(&mut store, &request)
+            };
+            response.write(&mut writer, request.keep_alive).context(Network)?;
+            writer.flush().context(Network)?;
+
+            if !request.keep_alive {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    // names are lower case
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+fn read_line(r: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    r.by_ref().take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line).map(Some).map_err(|_| invalid("request is not valid UTF-8"))
+}
+
+/// decode `%xx` escapes, and `+` as a space if `plus` is set (as in query strings)
+fn percent_decode(s: &str, plus: bool) -> io::Result<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let byte = std::str::from_utf8(&hex).ok()
+                    .filter(|h| h.len() == 2)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| invalid("bad percent escape"))?;
+                out.push(byte);
+            }
+            b'+' if plus => out.push(b' '),
+            b => out.push(b),
+        }
+    }
+
+    String::from_utf8(out).map_err(|_| invalid("escaped text is not valid UTF-8"))
+}
+
+impl Request {
+    /// the next request on the connection, `None` if the client closed it
+    fn read(r: &mut impl BufRead) -> io::Result<Option<Self>> {
+        let line = match read_line(r)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        let mut parts = line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v), None) if v.starts_with("HTTP/1.") => (m, t, v),
+            _ => return Err(invalid("malformed request line")),
+        };
+
+        let mut headers = HashMap::new();
+        loop {
+            let line = read_line(r)?.ok_or_else(|| invalid("unexpected end of headers"))?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADERS {
+                return Err(invalid("too many headers"));
+            }
+
+            let (name, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+        }
+
+        if headers.contains_key("transfer-encoding") {
+            return Err(invalid("chunked bodies are not supported, send Content-Length"));
+        }
+        let len = match headers.get("content-length") {
+            Some(len) => len.parse::<usize>().ok().filter(|&l| l <= MAX_BODY)
+                .ok_or_else(|| invalid("bad Content-Length"))?,
+            None => 0,
+        };
+        let mut body = vec![0; len];
+        r.read_exact(&mut body)?;
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut params = HashMap::new();
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            params.insert(percent_decode(k, true)?, percent_decode(v, true)?);
+        }
+
+        let connection = headers.get("connection").map(|c| c.to_ascii_lowercase());
+        let keep_alive = match connection.as_deref() {
+            Some("close") => false,
+            Some("keep-alive") => true,
+            _ => version == "HTTP/1.1",
+        };
+
+        Ok(Some(Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            query: params,
+            headers,
+            body,
+            keep_alive,
+        }))
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    etag: Option<String>,
+    allow: Option<&'static str>,
+    body: Option<Value>,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        Response { status, etag: None, allow: None, body: Some(body) }
+    }
+
+    fn empty(status: u16) -> Self {
+        Response { status, etag: None, allow: None, body: None }
+    }
+
+    fn error(status: u16, kind: &str, message: &str) -> Self {
+        Response::json(status, json!({ "error": message, "kind": kind }))
+    }
+
+    fn with_etag(mut self, etag: String) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
+    fn write(&self, w: &mut impl Write, keep_alive: bool) -> io::Result<()> {
+        let body = match &self.body {
+            Some(body) => body.to_string().into_bytes(),
+            None => Vec::new(),
+        };
+
+        write!(w, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        if self.body.is_some() {
+            w.write_all(b"Content-Type: application/json\r\n")?;
+        }
+        if let Some(etag) = &self.etag {
+            write!(w, "ETag: {}\r\n", etag)?;
+        }
+        if let Some(allow) = self.allow {
+            write!(w, "Allow: {}\r\n", allow)?;
+        }
+        if !keep_alive {
+            w.write_all(b"Connection: close\r\n")?;
+        }
+        write!(w, "Content-Length: {}\r\n\r\n", body.len())?;
+        w.write_all(&body)
+    }
+}
+
+impl From<KvsError> for Response {
+    fn from(e: KvsError) -> Self {
+        let (status, kind) = error_status(&e);
+        Response::error(status, kind, &e.to_string())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        412 => "Precondition Failed",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// The HTTP status and a short name for each error
+///
+/// Errors not listed here, including ones added later, are `500` with the name `Internal`.
+pub fn error_status(e: &KvsError) -> (u16, &'static str) {
+    use KvsError::*;
+    match e {
+        RemoveNonexistentKey { .. } => (404, "KeyNotFound"),
+        UnknownIndex { .. } => (404, "UnknownIndex"),
+        IndexHashBytes { .. } => (400, "IndexHashBytes"),
+        RewindCompacted { .. } => (400, "RewindCompacted"),
+        NoHistory { .. } => (400, "NoHistory"),
+        ImportRead { .. } => (400, "ImportRead"),
+        ImportRecord { .. } => (400, "ImportRecord"),
+        ReadOnly => (405, "ReadOnly"),
+        WatchLagged { .. } => (503, "WatchLagged"),
+        Cancelled => (503, "Cancelled"),
+        // the store is locked or was opened with the wrong key
+        EncryptionKeyRequired { .. } => (503, "EncryptionKeyRequired"),
+        LogNotEncrypted { .. } => (503, "LogNotEncrypted"),
+        UnknownCipher { .. } => (503, "UnknownCipher"),
+        CipherMismatch { .. } => (503, "CipherMismatch"),
+        KeyLoad { .. } => (503, "KeyLoad"),
+        // talking to another server on our behalf failed
+        Connect { .. } => (502, "Connect"),
+        ProtocolVersion { .. } => (502, "ProtocolVersion"),
+        Server { .. } => (502, "Server"),
+        EmptyRing { .. } => (502, "EmptyRing"),
+        // the log or blob files are damaged or unreadable
+        LogParse { .. } => (500, "LogParse"),
+        #[cfg(feature = "capnproto")]
+        LogParseGetRoot { .. } => (500, "LogParse"),
+        LogLookup { .. } => (500, "LogLookup"),
+        LogEntryKindInvalid { .. } => (500, "LogEntryKindInvalid"),
+        LogEntryKeyMismatch { .. } => (500, "LogEntryKeyMismatch"),
+        LogHeader { .. } => (500, "LogHeader"),
+        Authentication { .. } => (500, "Authentication"),
+        Checksum { .. } => (500, "Checksum"),
+        BlobMissing { .. } => (500, "BlobMissing"),
+        BlobRead { .. } => (500, "BlobRead"),
+        BackupManifest { .. } => (500, "BackupManifest"),
+        BackupChecksum { .. } => (500, "BackupChecksum"),
+        // restoring over a store that's already there
+        RestoreTarget { .. } => (409, "RestoreTarget"),
+        // local io failed
+        BackupIo { .. } => (500, "BackupIo"),
+        ExportWrite { .. } => (500, "ExportWrite"),
+        Repair { .. } => (500, "Repair"),
+        OpenLog { .. } => (500, "OpenLog"),
+        LogAppendSet { .. } => (500, "LogAppend"),
+        LogAppendRemove { .. } => (500, "LogAppend"),
+        LogAppendBatch { .. } => (500, "LogAppend"),
+        LogAppendBlob { .. } => (500, "LogAppend"),
+        LogSync { .. } => (500, "LogSync"),
+        GetPosition { .. } => (500, "GetPosition"),
+        CompactionFlushFailed { .. } => (500, "Compaction"),
+        CompactionSyncFailed { .. } => (500, "Compaction"),
+        CompactionRenameFailed { .. } => (500, "Compaction"),
+        NonceGeneration { .. } => (500, "NonceGeneration"),
+        BlobOpen { .. } => (500, "BlobOpen"),
+        BlobWrite { .. } => (500, "BlobWrite"),
+        BlobSync { .. } => (500, "BlobSync"),
+        BlobRemove { .. } => (500, "BlobRemove"),
+        Mmap { .. } => (500, "Mmap"),
+        Bind { .. } => (500, "Bind"),
+        ThreadSpawn { .. } => (500, "ThreadSpawn"),
+        Network { .. } => (500, "Network"),
+        Protocol { .. } => (500, "Protocol"),
+        RaftStorage { .. } => (500, "RaftStorage"),
+        RaftDecode { .. } => (500, "RaftDecode"),
+        RaftLogGap { .. } => (500, "RaftLogGap"),
+        _ => (500, "Internal"),
+    }
+}
+
+/// A strong entity tag for the value written by write number `seq`. Every write gets a new one,
+/// even if it sets the value it replaces. Compaction can move a value to the number of its
+/// checkpoint, which only ever fails a conditional write that could have gone ahead.
+fn etag(seq: u64) -> String {
+    format!("\"{}\"", seq)
+}
+
+/// the entity tag of the current value of `key`, `None` if it has none
+fn current_etag(store: &mut KvStore, ns: &str, key: &str) -> Result<Option<String>> {
+    Ok(store.value_seq(ns, key)?.map(etag))
+}
+
+/// does the `If-Match` or `If-None-Match` header `header` list `current`?
+fn etag_listed(header: &str, current: Option<&str>) -> bool {
+    match current {
+        Some(current) => header.split(',').map(str::trim).any(|t| t == "*" || t == current),
+        None => false,
+    }
+}
+
+fn route(store: &mut KvStore, request: &Request) -> Response {
+    let segments: Vec<&str> = request.path.trim_start_matches('/').splitn(2, '/').collect();
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["stats"]) => Ok(stats(store)),
+        ("GET", ["keys"]) => list(store, request),
+        (_, ["stats"]) | (_, ["keys"]) => {
+            return Response { allow: Some("GET"), ..Response::error(405, "MethodNotAllowed", "method not allowed") };
+        }
+        (method, ["keys", key]) => match percent_decode(key, false) {
+            Ok(key) => {
+                let ns = request.query.get("ns").map_or("", String::as_str);
+                match method {
+                    "GET" => get(store, request, ns, key),
+                    "PUT" => put(store, request, ns, key),
+                    "DELETE" => delete(store, request, ns, key),
+                    _ => return Response { allow: Some("GET, PUT, DELETE"), ..Response::error(405, "MethodNotAllowed", "method not allowed") },
+                }
+            }
+            Err(e) => return Response::error(400, "BadRequest", &e.to_string()),
+        },
+        _ => return Response::error(404, "NotFound", "no such route"),
+    };
+
+    result.unwrap_or_else(Response::from)
+}
+
+fn get(store: &mut KvStore, request: &Request, ns: &str, key: String) -> Result<Response> {
+    let value = match store.namespace(ns).get(key.clone())? {
+        Some(value) => value,
+        None => return Ok(Response::error(404, "KeyNotFound", &format!("Key not found: {}", key))),
+    };
+
+    let tag = current_etag(store, ns, &key)?.expect("the key has a value");
+    if request.header("if-none-match").is_some_and(|h| etag_listed(h, Some(&tag))) {
+        return Ok(Response::empty(304).with_etag(tag));
+    }
+
+    Ok(Response::json(200, json!({ "key": key, "value": value })).with_etag(tag))
+}
+
+/// check `If-Match` and `If-None-Match` against the current value of `key`, returning the
+/// response to send instead if they fail
+fn preconditions(store: &mut KvStore, request: &Request, ns: &str, key: &str) -> Result<std::result::Result<bool, Response>> {
+    let current = current_etag(store, ns, key)?;
+    let failed = |message: &str| Err(Response::error(412, "PreconditionFailed", message));
+
+    if let Some(h) = request.header("if-match") {
+        if !etag_listed(h, current.as_deref()) {
+            return Ok(failed("If-Match does not match the current value"));
+        }
+    }
+    if let Some(h) = request.header("if-none-match") {
+        if current.is_some() && (h.trim() == "*" || etag_listed(h, current.as_deref())) {
+            return Ok(failed("If-None-Match matches the current value"));
+        }
+    }
+
+    Ok(Ok(current.is_some()))
+}
+
+fn put(store: &mut KvStore, request: &Request, ns: &str, key: String) -> Result<Response> {
+    let value = match serde_json::from_slice::<Value>(&request.body) {
+        Ok(Value::Object(mut body)) => match body.remove("value") {
+            Some(Value::String(value)) => value,
+            _ => return Ok(Response::error(400, "BadRequest", "expected {\"value\": string}")),
+        },
+        Ok(_) => return Ok(Response::error(400, "BadRequest", "expected {\"value\": string}")),
+        Err(e) => return Ok(Response::error(400, "BadRequest", &format!("invalid JSON: {}", e))),
+    };
+
+    let existed = match preconditions(store, request, ns, &key)? {
+        Ok(existed) => existed,
+        Err(response) => return Ok(response),
+    };
+
+    store.namespace(ns).set(key.clone(), value.clone())?;
+    let tag = etag(store.last_seq());
+    let status = if existed { 200 } else { 201 };
+    Ok(Response::json(status, json!({ "key": key, "value": value })).with_etag(tag))
+}
+
+fn delete(store: &mut KvStore, request: &Request, ns: &str, key: String) -> Result<Response> {
+    if let Err(response) = preconditions(store, request, ns, &key)? {
+        return Ok(response);
+    }
+
+    store.namespace(ns).remove(key)?;
+    Ok(Response::empty(204))
+}
+
+/// `GET /keys?prefix=..&limit=..&cursor=..`, the cursor being the last key of the previous page
+fn list(store: &mut KvStore, request: &Request) -> Result<Response> {
+    let ns = request.query.get("ns").map_or("", String::as_str);
+    let prefix = request.query.get("prefix").map_or("", String::as_str);
+    let limit = match request.query.get("limit").map(|l| l.parse::<usize>()) {
+        None => DEFAULT_PAGE,
+        Some(Ok(limit)) if (1..=MAX_PAGE).contains(&limit) => limit,
+        Some(_) => {
+            let message = format!("limit must be between 1 and {}", MAX_PAGE);
+            return Ok(Response::error(400, "BadRequest", &message));
+        }
+    };
+
+    let keys = store.namespace(ns).keys(prefix)?;
+    let start = match request.query.get("cursor") {
+        Some(cursor) => keys.partition_point(|k| k <= cursor),
+        None => 0,
+    };
+    let page = &keys[start..(start + limit).min(keys.len())];
+
+    let mut items = Vec::with_capacity(page.len());
+    for key in page {
+        // keys are read before values, but nothing else can write while we hold the store
+        if let Some(value) = store.namespace(ns).get(key.clone())? {
+            items.push(json!({ "key": key, "value": value }));
+        }
+    }
+    let next = if start + page.len() < keys.len() { page.last().cloned() } else { None };
+
+    Ok(Response::json(200, json!({ "keys": items, "next": next })))
+}
+
+fn stats(store: &mut KvStore) -> Response {
+    let index = store.index_stats();
+    let cache = store.value_cache_stats();
+    Response::json(200, json!({
+        "keys": index.keys,
+        "namespaces": store.namespaces(),
+        "index": {
+            "mode": format!("{:?}", index.mode),
+            "memory_bytes": index.memory_bytes,
+        },
+        "value_cache": cache.map(|c| json!({
+            "hits": c.hits,
+            "misses": c.misses,
+            "entries": c.entries,
+            "bytes": c.bytes,
+            "capacity": c.capacity,
+        })),
+    }))
+}
@@ -0,0 +1,311 @@
+//! In memory index from keys to the offset of their latest log record
+//!
+//! The `Full` index keeps every key in memory. The `Compact` index only keeps a few bytes of each
+//! key's hash next to its offset, in an open addressing table with linear probing. When several
+//! keys share a hash the candidates are told apart by reading the key back from the log, which is
+//! what `KeyAt` is for.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::Result;
+
+/// How the in memory index stores keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexMode {
+    /// Keep every key in memory. Lookups never touch the disk.
+    #[default]
+    Full,
+    /// Keep only `hash_bytes` (4 to 8) bytes of each key's hash along with a 6 byte offset. Uses
+    /// roughly `(hash_bytes + 6) / 0.875` bytes per key, but keys whose hashes collide need to be
+    /// read back from disk to be told apart, which is more likely with fewer bytes.
+    Compact {
+        /// bytes of hash kept per key
+        hash_bytes: usize,
+    },
+}
+
+/// Memory used by the index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexStats {
+    /// how the index is stored
+    pub mode: IndexMode,
+    /// number of live keys
+    pub keys: usize,
+    /// approximate bytes of memory used by the index
+    pub memory_bytes: usize,
+}
+
+impl IndexStats {
+    /// average memory used per live key
+    pub fn bytes_per_key(&self) -> f64 {
+        if self.keys == 0 {
+            0.0
+        } else {
+            self.memory_bytes as f64 / self.keys as f64
+        }
+    }
+}
+
+/// Check keys against the records they point to
+pub(crate) trait KeyAt {
+    /// does the record at `offs` hold `key`?
+    fn has_key(&mut self, offs: u64, key: &str) -> Result<bool>;
+}
+
+#[derive(Debug)]
+pub(crate) enum Index {
+    Full(HashMap<String, u64>),
+    Compact(CompactIndex),
+}
+
+impl Index {
+    pub fn new(mode: IndexMode) -> Self {
+        match mode {
+            IndexMode::Full => Index::Full(HashMap::new()),
+            IndexMode::Compact { hash_bytes } => Index::Compact(CompactIndex::new(hash_bytes)),
+        }
+    }
+
+    pub fn mode(&self) -> IndexMode {
+        match self {
+            Index::Full(_) => IndexMode::Full,
+            Index::Compact(c) => IndexMode::Compact { hash_bytes: c.hash_bytes },
+        }
+    }
+
+    /// offset of the latest record for `key`
+    pub fn get(&self, key: &str, keys: &mut impl KeyAt) -> Result<Option<u64>> {
+        match self {
+            Index::Full(m) => Ok(m.get(key).copied()),
+            Index::Compact(c) => Ok(c.find(key, None, keys)?.map(|slot| c.offs(slot))),
+        }
+    }
+
+    /// point `key` at `offs`, returning the previous offset if the key was present
+    ///
+    /// A record may hold several keys, but never the same key twice, so when `key` is inserted
+    /// any slot already pointing at `offs` belongs to one of the others.
+    pub fn insert(&mut self, key: &str, offs: u64, keys: &mut impl KeyAt) -> Result<Option<u64>> {
+        match self {
+            Index::Full(m) => Ok(m.insert(key.to_owned(), offs)),
+            Index::Compact(c) => match c.find(key, Some(offs), keys)? {
+                Some(slot) => {
+                    let old = c.offs(slot);
+                    c.set_offs(slot, offs);
+                    Ok(Some(old))
+                }
+                None => {
+                    c.insert_new(key, offs);
+                    Ok(None)
+                }
+            },
+        }
+    }
+
+    /// insert a key that is known not to be in the index yet
+    pub fn insert_new(&mut self, key: &str, offs: u64) {
+        match self {
+            Index::Full(m) => { m.insert(key.to_owned(), offs); }
+            Index::Compact(c) => c.insert_new(key, offs),
+        }
+    }
+
+    /// drop `key` from the index, returning its offset if it was present
+    pub fn remove(&mut self, key: &str, keys: &mut impl KeyAt) -> Result<Option<u64>> {
+        match self {
+            Index::Full(m) => Ok(m.remove(key)),
+            Index::Compact(c) => match c.find(key, None, keys)? {
+                Some(slot) => {
+                    let old = c.offs(slot);
+                    c.remove_slot(slot);
+                    Ok(Some(old))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// every live record, in log order. Keys are only known for the `Full` index.
+    pub fn entries(&self) -> Vec<(u64, Option<&str>)> {
+        let mut entries: Vec<_> = match self {
+            Index::Full(m) => m.iter().map(|(k, &offs)| (offs, Some(k.as_str()))).collect(),
+            Index::Compact(c) => c.offsets().map(|offs| (offs, None)).collect(),
+        };
+        entries.sort_unstable_by_key(|&(offs, _)| offs);
+        entries
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Full(m) => m.len(),
+            Index::Compact(c) => c.len,
+        }
+    }
+
+    pub fn stats(&self) -> IndexStats {
+        let memory_bytes = match self {
+            Index::Full(m) => {
+                // the table itself (plus a control byte per bucket) and the key allocations
+                let table = m.capacity() * (std::mem::size_of::<(String, u64)>() + 1);
+                table + m.keys().map(|k| k.capacity()).sum::<usize>()
+            }
+            Index::Compact(c) => c.slots.len(),
+        };
+
+        IndexStats { mode: self.mode(), keys: self.len(), memory_bytes }
+    }
+}
+
+const OFFS_BYTES: usize = 6;
+const EMPTY: u64 = (1 << (OFFS_BYTES * 8)) - 1;
+
+/// Open addressing table of (hash tag, offset) pairs packed into bytes
+#[derive(Debug)]
+pub(crate) struct CompactIndex {
+    hash_bytes: usize,
+    slots: Vec<u8>,
+    // number of slots, always a power of two
+    capacity: usize,
+    len: usize,
+}
+
+impl CompactIndex {
+    fn new(hash_bytes: usize) -> Self {
+        let mut c = CompactIndex { hash_bytes, slots: Vec::new(), capacity: 0, len: 0 };
+        c.resize(16);
+        c
+    }
+
+    fn slot_len(&self) -> usize {
+        self.hash_bytes + OFFS_BYTES
+    }
+
+    fn tag_of(&self, key: &str) -> u64 {
+        let mut h = DefaultHasher::new();
+        key.hash(&mut h);
+        h.finish() >> (64 - 8 * self.hash_bytes)
+    }
+
+    fn home(&self, tag: u64) -> usize {
+        tag as usize & (self.capacity - 1)
+    }
+
+    fn slot(&self, slot: usize) -> &[u8] {
+        let l = self.slot_len();
+        &self.slots[slot * l..(slot + 1) * l]
+    }
+
+    fn tag(&self, slot: usize) -> u64 {
+        let mut b = [0u8; 8];
+        b[..self.hash_bytes].copy_from_slice(&self.slot(slot)[..self.hash_bytes]);
+        u64::from_le_bytes(b)
+    }
+
+    fn offs(&self, slot: usize) -> u64 {
+        let mut b = [0u8; 8];
+        b[..OFFS_BYTES].copy_from_slice(&self.slot(slot)[self.hash_bytes..]);
+        u64::from_le_bytes(b)
+    }
+
+    fn is_empty(&self, slot: usize) -> bool {
+        self.offs(slot) == EMPTY
+    }
+
+    fn write(&mut self, slot: usize, tag: u64, offs: u64) {
+        let (l, hb) = (self.slot_len(), self.hash_bytes);
+        let s = &mut self.slots[slot * l..(slot + 1) * l];
+        s[..hb].copy_from_slice(&tag.to_le_bytes()[..hb]);
+        s[hb..].copy_from_slice(&offs.to_le_bytes()[..OFFS_BYTES]);
+    }
+
+    fn set_offs(&mut self, slot: usize, offs: u64) {
+        assert!(offs < EMPTY, "log offset {} too large for compact index", offs);
+        let tag = self.tag(slot);
+        self.write(slot, tag, offs);
+    }
+
+    fn clear(&mut self, slot: usize) {
+        self.write(slot, 0, EMPTY);
+    }
+
+    /// find the slot holding `key`, checking every slot with a matching tag against the log.
+    /// Slots pointing at `skip` are passed over.
+    ///
+    /// Two keys written by the same record can share a tag, and then either of their slots
+    /// answers for both. That is harmless for lookups and removals, which only care about the
+    /// offset, but `insert` has to skip them to not take over another key's slot.
+    fn find(&self, key: &str, skip: Option<u64>, keys: &mut impl KeyAt) -> Result<Option<usize>> {
+        let tag = self.tag_of(key);
+        let mut slot = self.home(tag);
+        while !self.is_empty(slot) {
+            let offs = self.offs(slot);
+            if self.tag(slot) == tag && Some(offs) != skip && keys.has_key(offs, key)? {
+                return Ok(Some(slot));
+            }
+            slot = (slot + 1) & (self.capacity - 1);
+        }
+
+        Ok(None)
+    }
+
+    fn insert_new(&mut self, key: &str, offs: u64) {
+        assert!(offs < EMPTY, "log offset {} too large for compact index", offs);
+        // keep the load factor at or below 7/8
+        if (self.len + 1) * 8 > self.capacity * 7 {
+            self.resize(self.capacity * 2);
+        }
+
+        let tag = self.tag_of(key);
+        self.place(tag, offs);
+        self.len += 1;
+    }
+
+    fn place(&mut self, tag: u64, offs: u64) {
+        let mut slot = self.home(tag);
+        while !self.is_empty(slot) {
+            slot = (slot + 1) & (self.capacity - 1);
+        }
+        self.write(slot, tag, offs);
+    }
+
+    /// backward shift deletion, so lookups never need tombstones
+    fn remove_slot(&mut self, mut hole: usize) {
+        let mask = self.capacity - 1;
+        self.clear(hole);
+        self.len -= 1;
+
+        let mut slot = (hole + 1) & mask;
+        while !self.is_empty(slot) {
+            let home = self.home(self.tag(slot));
+            // can the entry in `slot` move back to `hole` without passing its home?
+            if (slot.wrapping_sub(home) & mask) >= (slot.wrapping_sub(hole) & mask) {
+                let (tag, offs) = (self.tag(slot), self.offs(slot));
+                self.write(hole, tag, offs);
+                self.clear(slot);
+                hole = slot;
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+
+    fn resize(&mut self, capacity: usize) {
+        let old: Vec<(u64, u64)> = (0..self.capacity)
+            .filter(|&s| !self.is_empty(s))
+            .map(|s| (self.tag(s), self.offs(s)))
+            .collect();
+
+        self.capacity = capacity;
+        self.slots = vec![0xff; capacity * self.slot_len()];
+        for (tag, offs) in old {
+            self.place(tag, offs);
+        }
+    }
+
+    fn offsets(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.capacity)
+            .filter(move |&s| !self.is_empty(s))
+            .map(move |s| self.offs(s))
+    }
+}
@@ -11,14 +11,73 @@
 //  - thiserror
 //  - err-derive
 
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::fs::{self, File};
-use std::io::{self, Seek, Write};
+use std::io::{self, Read, Seek, Write};
+use std::sync::Arc;
+use std::time::Duration;
 
 use snafu::{ResultExt, Snafu};
 
-use speedy::{Readable, Writable};
+#[cfg(feature = "async")]
+mod async_store;
+mod backup;
+mod blob;
+mod client;
+mod crypto;
+mod dump;
+mod history;
+mod http;
+mod index;
+mod log;
+mod mmap;
+mod protocol;
+mod raft;
+mod replica;
+mod resp;
+mod rewind;
+mod secondary;
+mod server;
+mod shard;
+mod shared;
+mod thread_pool;
+mod transfer;
+mod value_cache;
+mod verify;
+mod watch;
+
+#[cfg(feature = "async")]
+pub use async_store::AsyncKvStore;
+pub use backup::{Backup, BackupStats};
+pub use client::KvsClient;
+pub use crypto::{Cipher, EncryptionKey};
+pub use dump::{ChangeKind, DumpOptions, DumpRecord, Status};
+pub use history::Version;
+pub use http::{error_status, HttpServer};
+pub use index::{IndexMode, IndexStats};
+pub use protocol::{ErrorCode, PROTOCOL_VERSION};
+pub use raft::{Member, RaftNode, RaftOptions};
+pub use replica::{Follower, REPLICA_NS};
+pub use resp::{RespServer, EXPIRY_NS};
+pub use rewind::Until;
+pub use server::KvsServer;
+pub use shard::{HashRing, ShardedClient};
+pub use shared::{SharedKvStore, SharedNamespace};
+pub use thread_pool::{Job, NaiveThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool};
+pub use transfer::{Encoding, Format};
+pub use value_cache::ValueCacheStats;
+pub use verify::{Problem, VerifyReport};
+pub use watch::{WatchEvent, Watcher};
+use blob::{BlobPtr, BlobStore};
+use crypto::{KeySource, LogCipher};
+use history::{VersionAt, Versions};
+use index::{Index, KeyAt};
+use log::{read_record, write_record, Change, LogEntry, LogReader, NsKeys, Op, ReadError};
+use mmap::LogMap;
+use secondary::{IndexDef, IndexFn, SecondaryIndex};
+use value_cache::ValueCache;
+use watch::Subscribers;
 
 /// error
 #[derive(Debug, Snafu)]
@@ -155,151 +214,1299 @@ pub enum KvsError {
         /// io error
         source: io::Error,
     },
+
+    /// Loading the encryption key failed
+    #[snafu(display("Could not load encryption key: {}", source))]
+    KeyLoad {
+        /// io error
+        source: io::Error,
+    },
+
+    /// Generating the random nonce for a new encrypted log failed
+    #[snafu(display("Could not generate nonce for {}: {}", filename.display(), source))]
+    NonceGeneration {
+        /// the log being created
+        filename: PathBuf,
+        /// io error
+        source: io::Error,
+    },
+
+    /// Reading or writing the header of an encrypted log failed
+    #[snafu(display("Could not access encrypted log header in {}: {}", filename.display(), source))]
+    LogHeader {
+        /// the log
+        filename: PathBuf,
+        /// io error
+        source: io::Error,
+    },
+
+    /// The log is encrypted, but no key was supplied
+    #[snafu(display("Log {} is encrypted and no key was supplied", filename.display()))]
+    EncryptionKeyRequired {
+        /// the log
+        filename: PathBuf,
+    },
+
+    /// A key was supplied, but the log is stored in plain text
+    #[snafu(display("Log {} is not encrypted, use `KvStore::rekey` to encrypt it", filename.display()))]
+    LogNotEncrypted {
+        /// the log
+        filename: PathBuf,
+    },
+
+    /// The log header names a cipher we don't support
+    #[snafu(display("Log {} uses unknown cipher id {}", filename.display(), id))]
+    UnknownCipher {
+        /// the log
+        filename: PathBuf,
+        /// cipher id from the header
+        id: u8,
+    },
+
+    /// The key supplied is for a different cipher than the log was written with
+    #[snafu(display("Log {} is encrypted with {:?}, but the key is for {:?}", filename.display(), found, expected))]
+    CipherMismatch {
+        /// the log
+        filename: PathBuf,
+        /// cipher of the supplied key
+        expected: Cipher,
+        /// cipher named by the log header
+        found: Cipher,
+    },
+
+    /// A record (or the key check in the header, at offset 0) failed authentication. Either the
+    /// key is wrong or the log was modified.
+    #[snafu(display("Authentication failed for record at offset {} in {}", offs, filename.display()))]
+    Authentication {
+        /// the log
+        filename: PathBuf,
+        /// offset of the record
+        offs: u64,
+    },
+
+    /// A record's contents don't match the checksum in its frame, so it was damaged on disk
+    #[snafu(display("Record at offset {} in {} fails its checksum", offs, filename.display()))]
+    Checksum {
+        /// the log or blob file
+        filename: PathBuf,
+        /// offset of the record
+        offs: u64,
+    },
+
+    /// append of a batch of changes failed
+    #[snafu(display("Could not append batch of {} changes to log: {}", len, source))]
+    LogAppendBatch {
+        /// number of changes in the batch
+        len: usize,
+        /// speedy error
+        source: speedy::Error,
+    },
+
+    /// append of a pointer to a blob failed
+    #[snafu(display("Could not append blob pointer for {} to log: {}", key, source))]
+    LogAppendBlob {
+        /// set's Key
+        key: String,
+        /// speedy error
+        source: speedy::Error,
+    },
+
+    /// Opening a blob file (or listing the directory they are in) failed
+    #[snafu(display("Could not open blob file {}: {}", filename.display(), source))]
+    BlobOpen {
+        /// the blob file
+        filename: PathBuf,
+        /// io error
+        source: io::Error,
+    },
+
+    /// A pointer in the log refers to a blob file that does not exist
+    #[snafu(display("Blob file {} is missing", filename.display()))]
+    BlobMissing {
+        /// the blob file
+        filename: PathBuf,
+    },
+
+    /// Reading a value from a blob file failed
+    #[snafu(display("Could not read blob at offset {} in {}: {}", offs, filename.display(), source))]
+    BlobRead {
+        /// the blob file
+        filename: PathBuf,
+        /// offset of the blob
+        offs: u64,
+        /// speedy error
+        source: speedy::Error,
+    },
+
+    /// Appending a value to a blob file failed
+    #[snafu(display("Could not append to blob file {}: {}", filename.display(), source))]
+    BlobWrite {
+        /// the blob file
+        filename: PathBuf,
+        /// speedy error
+        source: speedy::Error,
+    },
+
+    /// Syncing a blob file failed
+    #[snafu(display("Could not sync blob file {}: {}", filename.display(), source))]
+    BlobSync {
+        /// the blob file
+        filename: PathBuf,
+        /// io error
+        source: io::Error,
+    },
+
+    /// The compact index was asked to keep an unsupported number of hash bytes per key
+    #[snafu(display("Compact index needs between 4 and 8 hash bytes per key, not {}", hash_bytes))]
+    IndexHashBytes {
+        /// requested hash bytes
+        hash_bytes: usize,
+    },
+
+    /// Memory mapping the log failed
+    #[snafu(display("Could not map {}: {}", filename.display(), source))]
+    Mmap {
+        /// the log
+        filename: PathBuf,
+        /// io error
+        source: io::Error,
+    },
+
+    /// A secondary index was queried without being registered
+    #[snafu(display("No secondary index named {}", name))]
+    UnknownIndex {
+        /// the index
+        name: String,
+    },
+
+    /// A watcher fell behind and missed changes
+    #[snafu(display("Watcher fell more than {} events behind and needs to resync", capacity))]
+    WatchLagged {
+        /// events the watcher could hold
+        capacity: usize,
+    },
+
+    /// Listening for clients failed
+    #[snafu(display("Could not listen for connections: {}", source))]
+    Bind {
+        /// io error
+        source: io::Error,
+    },
+
+    /// Connecting to a server failed
+    #[snafu(display("Could not connect to server: {}", source))]
+    Connect {
+        /// io error
+        source: io::Error,
+    },
+
+    /// Sending or receiving over a connection failed
+    #[snafu(display("Network error: {}", source))]
+    Network {
+        /// io error
+        source: io::Error,
+    },
+
+    /// A message on a connection couldn't be encoded or decoded
+    #[snafu(display("Protocol error: {}", source))]
+    Protocol {
+        /// speedy error
+        source: speedy::Error,
+    },
+
+    /// A message on a connection was longer than any side sends
+    #[snafu(display("Message of {} bytes is over the limit of {}", len, max))]
+    MessageTooLarge {
+        /// length of the message
+        len: u64,
+        /// the longest message allowed
+        max: u32,
+    },
+
+    /// The server speaks a different version of the protocol
+    #[snafu(display("Server speaks protocol version {}, but we speak {}", server, client))]
+    ProtocolVersion {
+        /// the server's version
+        server: u32,
+        /// our version
+        client: u32,
+    },
+
+    /// The server failed a request
+    #[snafu(display("Server error {:?}: {}", code, message))]
+    Server {
+        /// what went wrong
+        code: ErrorCode,
+        /// the server's description of the error
+        message: String,
+    },
+
+    /// Deleting a garbage blob file failed
+    #[snafu(display("Could not remove blob file {}: {}", filename.display(), source))]
+    BlobRemove {
+        /// the blob file
+        filename: PathBuf,
+        /// io error
+        source: io::Error,
+    },
+
+    /// A thread pool could not start one of its threads
+    #[snafu(display("Could not start a thread: {}", source))]
+    ThreadSpawn {
+        /// io error
+        source: io::Error,
+    },
+
+    /// Reading or writing one of a cluster node's own files failed
+    #[snafu(display("Could not access Raft file {}: {}", filename.display(), source))]
+    RaftStorage {
+        /// the file
+        filename: PathBuf,
+        /// io error
+        source: io::Error,
+    },
+
+    /// One of a cluster node's own files could not be encoded or decoded
+    #[snafu(display("Could not decode Raft file {}: {}", filename.display(), source))]
+    RaftDecode {
+        /// the file
+        filename: PathBuf,
+        /// encoding error
+        source: speedy::Error,
+    },
+
+    /// The Raft log skips an entry
+    #[snafu(display("Raft log {} is missing entries before {}", filename.display(), index))]
+    RaftLogGap {
+        /// the log
+        filename: PathBuf,
+        /// the first entry after the gap
+        index: u64,
+    },
+
+    /// An async operation was cancelled before it ran
+    #[snafu(display("Operation was cancelled before it ran"))]
+    Cancelled,
+
+    /// Reading or writing a file of a backup failed
+    #[snafu(display("Could not access backup file {}: {}", filename.display(), source))]
+    BackupIo {
+        /// the file
+        filename: PathBuf,
+        /// io error
+        source: io::Error,
+    },
+
+    /// A backup's manifest could not be decoded
+    #[snafu(display("Backup manifest {} is damaged: {}", filename.display(), source))]
+    BackupManifest {
+        /// the manifest
+        filename: PathBuf,
+        /// speedy error
+        source: speedy::Error,
+    },
+
+    /// A file in a backup doesn't have the size and checksum recorded for it
+    #[snafu(display("Backup file {} is damaged: it doesn't match its checksum", filename.display()))]
+    BackupChecksum {
+        /// the file
+        filename: PathBuf,
+    },
+
+    /// A backup can only be restored into a directory without a store
+    #[snafu(display("{} already holds a store", filename.display()))]
+    RestoreTarget {
+        /// the store's log
+        filename: PathBuf,
+    },
+
+    /// Stores opened at an earlier point with `open_at` can't be written to
+    #[snafu(display("The store was opened read-only to look at an earlier point"))]
+    ReadOnly,
+
+    /// The log has been compacted since the point `open_at` was asked to rewind to
+    #[snafu(display("{} can't be rewound past write {}, where it was last compacted", filename.display(), seq))]
+    RewindCompacted {
+        /// the log
+        filename: PathBuf,
+        /// the earliest write it can be rewound to
+        seq: u64,
+    },
+
+    /// Only namespaces that keep history can be asked about earlier versions
+    #[snafu(display("Namespace {:?} doesn't keep history", ns))]
+    NoHistory {
+        /// the namespace
+        ns: String,
+    },
+
+    /// A sharded client has no servers to send a key to
+    #[snafu(display("No servers in the ring to hold {}", key))]
+    EmptyRing {
+        /// the key
+        key: String,
+    },
+
+    /// Writing an export failed
+    #[snafu(display("Could not write the export: {}", source))]
+    ExportWrite {
+        /// the underlying error
+        source: std::io::Error,
+    },
+
+    /// Reading an import failed
+    #[snafu(display("Could not read the import: {}", source))]
+    ImportRead {
+        /// the underlying error
+        source: std::io::Error,
+    },
+
+    /// A record in an import couldn't be understood
+    #[snafu(display("Could not import record {}: {}", record, reason))]
+    ImportRecord {
+        /// the record, counting from 1, or 0 for a CSV header
+        record: u64,
+        /// what was wrong with it
+        reason: String,
+    },
+
+    /// Replacing a damaged log with what could be salvaged from it failed
+    #[snafu(display("Could not repair {}: {}", filename.display(), source))]
+    Repair {
+        /// the file being written or renamed
+        filename: PathBuf,
+        /// the underlying error
+        source: std::io::Error,
+    },
+}
+
+/// After 20 modifications to existing keys run compaction
+const COMPACT_MODIFICATION_CT: u64 = 20;
+
+/// Start a new blob file once the current one reaches 64 MiB
+const DEFAULT_BLOB_FILE_SIZE: u64 = 64 << 20;
+
+/// Remap the log once 1 MiB has been appended past the end of the current map
+const REMAP_BYTES: u64 = 1 << 20;
+
+/// Watchers can fall 1024 events behind before they have to resync
+const DEFAULT_WATCH_CAPACITY: usize = 1024;
+
+/// result
+pub type Result<T> = std::result::Result<T, KvsError>;
+
+/// Settings for a single namespace
+///
+/// ```no_run
+/// # use kvs::{IndexMode, NamespaceOptions, OpenOptions};
+/// let mut sessions = NamespaceOptions::new();
+/// sessions.index_mode(IndexMode::Compact { hash_bytes: 4 }).compact_after(1000);
+/// let mut store = OpenOptions::new().namespace("sessions", sessions).open("/tmp/kvs")?;
+/// store.namespace("sessions").set("abc".to_owned(), "alice".to_owned())?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamespaceOptions {
+    index_mode: IndexMode,
+    compact_after: u64,
+    // history is kept if either is set
+    keep_versions: usize,
+    keep_for: Option<Duration>,
+}
+
+impl Default for NamespaceOptions {
+    fn default() -> Self {
+        NamespaceOptions {
+            index_mode: IndexMode::Full,
+            compact_after: COMPACT_MODIFICATION_CT,
+            keep_versions: 0,
+            keep_for: None,
+        }
+    }
+}
+
+impl NamespaceOptions {
+    /// The settings namespaces get unless told otherwise
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How this namespace's keys are kept in memory, see `IndexMode`
+    pub fn index_mode(&mut self, mode: IndexMode) -> &mut Self {
+        self.index_mode = mode;
+        self
+    }
+
+    /// Compact the log once `modifications` overwrites or removals have piled up in this
+    /// namespace
+    pub fn compact_after(&mut self, modifications: u64) -> &mut Self {
+        self.compact_after = modifications;
+        self
+    }
+
+    /// Keep the history of every key in this namespace, see `KvStore::history`, and have
+    /// compaction keep the last `versions` values of each key, removals included. Combined with
+    /// `keep_for`, a version is kept if either says so.
+    ///
+    /// History only lives in the log, so this has to be set every time the store is opened: a
+    /// store opened without it forgets every earlier version once it compacts.
+    pub fn keep_versions(&mut self, versions: usize) -> &mut Self {
+        self.keep_versions = versions;
+        self
+    }
+
+    /// Like `keep_versions`, but have compaction keep every version written within `window` of
+    /// it, as well as each key's latest value
+    pub fn keep_for(&mut self, window: Duration) -> &mut Self {
+        self.keep_for = Some(window);
+        self
+    }
+
+    fn keeps_history(&self) -> bool {
+        self.keep_versions > 0 || self.keep_for.is_some()
+    }
+}
+
+/// Settings for every namespace: the named ones, and the defaults for the rest
+#[derive(Debug, Clone, Default)]
+struct NamespaceConfig {
+    defaults: NamespaceOptions,
+    named: HashMap<String, NamespaceOptions>,
+    indexes: Vec<IndexDef>,
+}
+
+impl NamespaceConfig {
+    fn get(&self, ns: &str) -> NamespaceOptions {
+        self.named.get(ns).copied().unwrap_or(self.defaults)
+    }
+}
+
+/// Options used to open a `KvStore`
+///
+/// ```no_run
+/// # use kvs::{Cipher, EncryptionKey, OpenOptions};
+/// let key = EncryptionKey::new(Cipher::ChaCha20Poly1305, [7; 32]);
+/// let store = OpenOptions::new().encryption_key(key).open("/tmp/kvs")?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    key: Option<KeySource>,
+    blob_threshold: Option<usize>,
+    blob_file_size: u64,
+    namespaces: NamespaceConfig,
+    value_cache: Option<usize>,
+    mmap: bool,
+    watch_capacity: usize,
+    sync_writes: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            key: None,
+            blob_threshold: None,
+            blob_file_size: DEFAULT_BLOB_FILE_SIZE,
+            namespaces: NamespaceConfig::default(),
+            value_cache: None,
+            mmap: false,
+            watch_capacity: DEFAULT_WATCH_CAPACITY,
+            sync_writes: false,
+        }
+    }
+}
+
+impl OpenOptions {
+    /// Options matching `KvStore::open`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store values of `threshold` bytes or more in separate blob files, leaving only a pointer
+    /// in the main log. By default all values are stored in the log.
+    pub fn blob_threshold(&mut self, threshold: usize) -> &mut Self {
+        self.blob_threshold = Some(threshold);
+        self
+    }
+
+    /// Start a new blob file once the current one reaches `size` bytes. Only blob files that are
+    /// no longer being appended to are garbage collected.
+    pub fn blob_file_size(&mut self, size: u64) -> &mut Self {
+        self.blob_file_size = size;
+        self
+    }
+
+    /// Encrypt the log with `key`. A new store is created encrypted, and an existing store must
+    /// have been encrypted with the same key.
+    pub fn encryption_key(&mut self, key: EncryptionKey) -> &mut Self {
+        self.key = Some(KeySource::Key(key));
+        self
+    }
+
+    /// Like `encryption_key`, but the key is loaded from `path` when the store is opened. The
+    /// file contains 32 raw bytes or 64 hex digits.
+    pub fn key_file(&mut self, cipher: Cipher, path: impl Into<PathBuf>) -> &mut Self {
+        self.key = Some(KeySource::File(cipher, path.into()));
+        self
+    }
+
+    /// How keys are kept in memory, see `IndexMode`. Applies to every namespace without its own
+    /// `NamespaceOptions`.
+    pub fn index_mode(&mut self, mode: IndexMode) -> &mut Self {
+        self.namespaces.defaults.index_mode = mode;
+        self
+    }
+
+    /// Keep the history of every namespace without its own `NamespaceOptions`, see
+    /// `NamespaceOptions::keep_versions`
+    pub fn keep_versions(&mut self, versions: usize) -> &mut Self {
+        self.namespaces.defaults.keep_versions = versions;
+        self
+    }
+
+    /// Keep the history of every namespace without its own `NamespaceOptions`, see
+    /// `NamespaceOptions::keep_for`
+    pub fn keep_for(&mut self, window: Duration) -> &mut Self {
+        self.namespaces.defaults.keep_for = Some(window);
+        self
+    }
+
+    /// Use `opts` for the namespace `name`. The default namespace is `""`.
+    pub fn namespace(&mut self, name: impl Into<String>, opts: NamespaceOptions) -> &mut Self {
+        self.namespaces.named.insert(name.into(), opts);
+        self
+    }
+
+    /// Index the values in namespace `ns` by the keys `f` extracts from each key and value, so
+    /// they can be looked up with `get_by_index(name, ..)`. The index is built from the log when
+    /// the store is opened.
+    pub fn secondary_index<F>(&mut self, ns: &str, name: &str, f: F) -> &mut Self
+        where F: Fn(&str, &str) -> Vec<String> + Send + Sync + 'static
+    {
+        self.namespaces.indexes.push(IndexDef { ns: ns.to_owned(), name: name.to_owned(), f: Arc::new(f) });
+        self
+    }
+
+    /// Keep up to `bytes` of recently read keys and values in memory so repeated `get`s of the
+    /// same keys don't need to go to disk
+    pub fn value_cache(&mut self, bytes: usize) -> &mut Self {
+        self.value_cache = Some(bytes);
+        self
+    }
+
+    /// Read records through a memory map of the log instead of seeking and reading. The map
+    /// covers the log as of the last compaction and is extended as it grows.
+    pub fn mmap(&mut self, mmap: bool) -> &mut Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Let each `Watcher` queue up to `events` changes it hasn't received yet. A watcher that
+    /// falls further behind gets a `WatchLagged` error and has to resync.
+    pub fn watch_capacity(&mut self, events: usize) -> &mut Self {
+        self.watch_capacity = events;
+        self
+    }
+
+    /// Sync the log (and any blob files written to) before a write returns, so that it survives
+    /// a crash and watchers only hear about durable changes. Off by default.
+    pub fn sync_writes(&mut self, sync: bool) -> &mut Self {
+        self.sync_writes = sync;
+        self
+    }
+
+    /// open existing or create KvStore from path
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        self.open_until(path, None)
+    }
+
+    /// open the store in `path`, read-only and replaying the log only as far as `until` if given
+    fn open_until(&self, path: impl Into<PathBuf>, until: Option<Until>) -> Result<KvStore> {
+        let ns = &self.namespaces;
+        for opts in std::iter::once(&ns.defaults).chain(ns.named.values()) {
+            if let IndexMode::Compact { hash_bytes } = opts.index_mode {
+                if !(4..=8).contains(&hash_bytes) {
+                    return Err(KvsError::IndexHashBytes { hash_bytes });
+                }
+            }
+        }
+
+        let key = match &self.key {
+            Some(k) => Some(k.load().context(KeyLoad)?),
+            None => None,
+        };
+
+        KvStore::open_with(path.into(), key, self, until)
+    }
+}
+
+/// Changes to keys in any number of namespaces, applied all together or not at all by
+/// `KvStore::write`
+///
+/// ```no_run
+/// # use kvs::{KvStore, WriteBatch};
+/// # let mut store = KvStore::open("/tmp/kvs")?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("users", "alice".to_owned(), "1".to_owned())
+///     .remove("pending", "alice".to_owned());
+/// store.write(batch)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    // (namespace, key, value), `None` removes
+    ops: Vec<(String, String, Option<String>)>,
+}
+
+impl WriteBatch {
+    /// An empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set `key` in namespace `ns` to `value`
+    pub fn set(&mut self, ns: &str, key: String, value: String) -> &mut Self {
+        self.ops.push((ns.to_owned(), key, Some(value)));
+        self
+    }
+
+    /// remove `key` from namespace `ns`. The whole batch fails if the key doesn't exist by the
+    /// time this change is reached.
+    pub fn remove(&mut self, ns: &str, key: String) -> &mut Self {
+        self.ops.push((ns.to_owned(), key, None));
+        self
+    }
+
+    /// number of changes in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// does the batch have no changes?
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// The keys of one namespace
+#[derive(Debug)]
+struct Space {
+    opts: NamespaceOptions,
+    index: Index,
+    // which keys currently point at blobs
+    blob_ptrs: HashMap<String, BlobPtr>,
+    // track modifications to existing keys to determine when to compact
+    modification_ct: u64,
+    // by name
+    secondary: BTreeMap<String, SecondaryIndex>,
+    // every version of every key, if this namespace keeps history
+    versions: Option<Versions>,
+}
+
+impl Space {
+    fn new(opts: NamespaceOptions) -> Self {
+        Space {
+            opts,
+            index: Index::new(opts.index_mode),
+            blob_ptrs: HashMap::new(),
+            modification_ct: 0,
+            secondary: BTreeMap::new(),
+            versions: if opts.keeps_history() { Some(Versions::new()) } else { None },
+        }
+    }
+
+    /// remember that `op` on `key` was written at `offs` as part of write `seq`, made at `time`,
+    /// if this namespace keeps history
+    fn record_version(&mut self, key: &str, op: Op<'_>, offs: u64, seq: u64, time: u64) {
+        if let Some(versions) = &mut self.versions {
+            let blob = match op {
+                Op::SetBlob(blob) => Some(blob),
+                _ => None,
+            };
+            let version = VersionAt { offs, time, blob, removed: op == Op::Remove };
+            versions.entry(key.to_owned()).or_default().insert(seq, version);
+        }
+    }
+
+    /// every blob this namespace points at, for the current values and the versions before them
+    fn blob_refs(&self) -> impl Iterator<Item = &BlobPtr> {
+        let earlier = self.versions.iter()
+            .flat_map(|versions| versions.iter())
+            .flat_map(move |(key, versions)| {
+                let current = self.blob_ptrs.get(key);
+                versions.values().filter_map(move |v| v.blob.as_ref().filter(|&b| Some(b) != current))
+            });
+        self.blob_ptrs.values().chain(earlier)
+    }
+
+    /// the secondary keys of `value` for each secondary index, by index name
+    fn derive(&self, key: &str, value: &str) -> Vec<(String, Vec<String>)> {
+        self.secondary.iter()
+            .map(|(name, ix)| (name.clone(), ix.extract(key, value)))
+            .collect()
+    }
+
+    /// index `key` under `derived`, which is empty once the key is removed
+    fn index_secondary(&mut self, key: &str, derived: Vec<(String, Vec<String>)>) {
+        for ix in self.secondary.values_mut() {
+            ix.remove(key);
+        }
+        for (name, secondary) in derived {
+            if let Some(ix) = self.secondary.get_mut(&name) {
+                ix.insert(key, secondary);
+            }
+        }
+    }
+
+    /// record that `op` on `key` was written at `offs`, returning whether the key existed before
+    /// and the blob it no longer points at
+    fn apply(&mut self, key: &str, op: Op<'_>, offs: u64, keys: &mut impl KeyAt) -> Result<(bool, Option<BlobPtr>)> {
+        let old_blob = match op {
+            Op::SetBlob(blob) => self.blob_ptrs.insert(key.to_owned(), blob),
+            Op::Set(_) | Op::Remove => self.blob_ptrs.remove(key),
+        };
+
+        let existed = match op {
+            Op::Set(_) | Op::SetBlob(_) => self.index.insert(key, offs, keys)?.is_some(),
+            Op::Remove => self.index.remove(key, keys)?.is_some(),
+        };
+        if existed || op == Op::Remove {
+            self.modification_ct += 1;
+        }
+
+        Ok((existed, old_blob))
+    }
 }
 
-#[derive(Debug)]
-#[derive(Readable, Writable)]
-enum LogEntry {
-    Set { key: String, value: String },
-    Remove { key: String },
+/// make files created, removed or renamed in `dir` durable
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
 }
 
-/// After 20 modifications to existing keys run compaction
-const COMPACT_MODIFICATION_CT: u64 = 20;
+/// the space for `ns`, creating it if this is the first we've seen of it
+fn space_mut<'s>(spaces: &'s mut BTreeMap<String, Space>, config: &NamespaceConfig, ns: &str) -> &'s mut Space {
+    if !spaces.contains_key(ns) {
+        let mut space = Space::new(config.get(ns));
+        for def in config.indexes.iter().filter(|d| d.ns == ns) {
+            space.secondary.insert(def.name.clone(), SecondaryIndex::new(def.f.clone()));
+        }
+        spaces.insert(ns.to_owned(), space);
+    }
 
-/// result
-pub type Result<T> = std::result::Result<T, KvsError>;
+    spaces.get_mut(ns).expect("space was just created")
+}
+
+/// Call `f` with the offset of the record setting every live key in namespace `ns`, the key and
+/// the change that set it, in log order
+fn for_each_live<F>(ns: &str, space: &Space, reader: &mut LogReader<'_>, mut f: F) -> Result<()>
+    where F: FnMut(u64, &str, Op<'_>) -> Result<()>
+{
+    // records can hold several keys, group them so each record is read once
+    let mut live: BTreeMap<u64, Vec<Option<&str>>> = BTreeMap::new();
+    for (offs, key) in space.index.entries() {
+        live.entry(offs).or_default().push(key);
+    }
+
+    for (offs, keys) in live {
+        let entry = reader.read_at(offs, keys[0].unwrap_or_default())?;
+
+        // the compact index doesn't know keys, so the keys still pointing here need to be worked
+        // out from the record itself
+        match keys[0] {
+            Some(_) => {
+                for key in keys.into_iter().flatten() {
+                    match entry.find(ns, key) {
+                        None => {
+                            return Err(KvsError::LogEntryKeyMismatch { key: key.to_owned(), found_key: entry.first_key().to_owned(), filename: reader.filename.to_owned(), offs });
+                        }
+                        Some(Op::Remove) => {
+                            return Err(KvsError::LogEntryKindInvalid { offs, filename: reader.filename.to_owned(), key: key.to_owned(), found_key: key.to_owned() });
+                        }
+                        Some(op) => f(offs, key, op)?,
+                    }
+                }
+            }
+            None => {
+                let ops: Vec<_> = entry.ops().into_iter().filter(|&(n, _, _)| n == ns).collect();
+                let single = ops.len() == 1;
+                for (_, key, op) in ops {
+                    if let Op::Remove = op {
+                        if single {
+                            return Err(KvsError::LogEntryKindInvalid { offs, filename: reader.filename.to_owned(), key: key.to_owned(), found_key: key.to_owned() });
+                        }
+                        continue;
+                    }
+
+                    // other keys in a batch may have been overwritten since
+                    if single || space.index.get(key, &mut NsKeys { reader: &mut *reader, ns })? == Some(offs) {
+                        f(offs, key, op)?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
 
 /// A in memory key value store
 #[derive(Debug)]
 pub struct KvStore {
     log_dir: PathBuf,
     log_f_name: PathBuf,
-    log_f: File, 
-    cache: HashMap<String, u64>,
+    log_f: File,
     safe: bool,
 
-    // track modifications to existing keys to determine when to compact
-    modification_ct: u64,
+    // every namespace seen so far, all sharing the one log
+    spaces: BTreeMap<String, Space>,
+    ns_config: NamespaceConfig,
+
+    // present when the log is encrypted
+    cipher: Option<LogCipher>,
+
+    // values too large for the log
+    blobs: BlobStore,
+    blob_threshold: Option<usize>,
+
+    // recently read values, if enabled
+    values: Option<ValueCache>,
+
+    // map of the start of the log, if enabled
+    mmap: bool,
+    map: Option<LogMap>,
+
+    // everyone watching for changes
+    watchers: Subscribers,
+    watch_capacity: usize,
+
+    // changes whenever the log is rewritten, so replicas can tell their offset into it is stale
+    log_id: u64,
+    // how many times values have been moved, by compaction or blob garbage collection
+    rewrites: u64,
+
+    // sequence number and time of the last write in the log, see `KvStore::open_at`
+    last_seq: u64,
+    last_time: u64,
+    // sequence number of the log's checkpoint, the write that records compaction copied without
+    // a stamp of their own are as of
+    checkpoint_seq: u64,
+    // opened at an earlier point, so the log and blob files must not change
+    read_only: bool,
+    // importing, so compaction waits until the end
+    defer_compaction: bool,
+}
+
+/// A handle for reading and writing the keys of one namespace, from `KvStore::namespace`
+#[derive(Debug)]
+pub struct Namespace<'a> {
+    store: &'a mut KvStore,
+    name: String,
+}
+
+impl Namespace<'_> {
+    /// name of this namespace
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// set a `key` in this namespace to `value`
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.store.write_ops(vec![(self.name.clone(), key, Some(value))])
+    }
+
+    /// retrieve the value of `key` in this namespace. if no value, return None
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get_in(&self.name, key)
+    }
+
+    /// remove `key` from this namespace
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.store.write_ops(vec![(self.name.clone(), key, None)])
+    }
+
+    /// Memory used by this namespace's index
+    pub fn index_stats(&self) -> IndexStats {
+        self.store.index_stats_in(&self.name)
+    }
+
+    /// Like `KvStore::keys`, for the keys in this namespace
+    pub fn keys(&mut self, prefix: &str) -> Result<Vec<String>> {
+        self.store.keys_in(&self.name, prefix)
+    }
+
+    /// Like `KvStore::scan`, for the keys in this namespace
+    pub fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.store.scan_in(&self.name, prefix)
+    }
+
+    /// Like `KvStore::watch`, for the keys in this namespace
+    pub fn watch(&mut self, prefix: &str) -> Watcher {
+        let capacity = self.store.watch_capacity;
+        self.store.watchers.subscribe(&self.name, prefix, capacity)
+    }
+
+    /// Like `KvStore::register_index`, for the values in this namespace
+    pub fn register_index<F>(&mut self, name: &str, f: F) -> Result<()>
+        where F: Fn(&str, &str) -> Vec<String> + Send + Sync + 'static
+    {
+        self.store.register_index_in(&self.name, name, Arc::new(f))
+    }
+
+    /// Like `KvStore::get_by_index`, for the values in this namespace
+    pub fn get_by_index(&mut self, name: &str, secondary_key: &str) -> Result<Vec<(String, String)>> {
+        self.store.get_by_index_in(&self.name, name, secondary_key)
+    }
 }
 
 impl KvStore {
     /// open existing or create KvStore from path
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let log_dir = path.into();
+        OpenOptions::new().open(path)
+    }
+
+    /// Check the start of the log for an encryption header, writing one if the log is new and
+    /// we have a key.
+    fn open_cipher(log_f: &mut File, p: &Path, key: Option<EncryptionKey>) -> Result<Option<LogCipher>> {
+        let mut prefix = Vec::new();
+        (&mut *log_f).take(crypto::HEADER_LEN).read_to_end(&mut prefix)
+            .context(LogHeader { filename: p.to_owned() })?;
+        log_f.seek(io::SeekFrom::Start(0))
+            .context(GetPosition { filename: p.to_owned() })?;
+
+        match key {
+            None if LogCipher::is_encrypted(&prefix) => {
+                Err(KvsError::EncryptionKeyRequired { filename: p.to_owned() })
+            }
+            None => Ok(None),
+            Some(key) if prefix.is_empty() => {
+                let c = LogCipher::generate(&key)
+                    .context(NonceGeneration { filename: p.to_owned() })?;
+                c.write_header(&mut *log_f)
+                    .context(LogHeader { filename: p.to_owned() })?;
+                Ok(Some(c))
+            }
+            Some(key) if LogCipher::is_encrypted(&prefix) => {
+                LogCipher::read_header(&key, &mut *log_f)
+                    .map(Some)
+                    .map_err(|e| e.into_error(p, &key))
+            }
+            Some(_) => Err(KvsError::LogNotEncrypted { filename: p.to_owned() }),
+        }
+    }
+
+    fn open_with(log_dir: PathBuf, key: Option<EncryptionKey>, opts: &OpenOptions, until: Option<Until>) -> Result<Self> {
         let mut p = log_dir.clone();
         p.push("kvs.db");
-        let log_f = fs::OpenOptions::new().create(true).read(true).write(true).open(&p)
+        let read_only = until.is_some();
+        let mut log_f = fs::OpenOptions::new().create(!read_only).truncate(false).read(true).write(!read_only).open(&p)
             .context(OpenLog { filename: p.clone() })?;
 
-        let mut cache = HashMap::new();
+        let cipher = Self::open_cipher(&mut log_f, &p, key)?;
+
+        let mut spaces = BTreeMap::new();
+        space_mut(&mut spaces, &opts.namespaces, "");
+        // the compact index may need to look at earlier records while we read through the log
+        let mut verify_f = File::open(&p)
+            .context(OpenLog { filename: p.clone() })?;
         let mut log_f_r = std::io::BufReader::with_capacity(8192, log_f);
 
-        let mut modification_ct = 0;
+        // the stamp of the write the records being read belong to
+        let (mut last_seq, mut last_time) = (0, 0);
+        let mut checkpoint_seq = 0;
         {
             use speedy::IsEof;
             let mut entry_number = 0usize;
             loop {
-                let offs = log_f_r.seek(io::SeekFrom::Current(0))
+                let offs = log_f_r.stream_position()
                     .context(GetPosition { filename: p.clone() })?;
-                let entry = match LogEntry::read_from_stream(&mut log_f_r) {
+                let entry: LogEntry = match read_record(&mut log_f_r, cipher.as_ref(), offs) {
                     Ok(v) => v,
-                    Err(e) => {
+                    Err(ReadError::Parse(e)) => {
                        if e.is_eof() {
                            break;
                        }
 
-                       return Err(e).context(LogParse { entry_number })?;
+                       return Err(e).context(LogParse { entry_number });
+                    }
+                    Err(ReadError::Authentication) => {
+                        return Err(KvsError::Authentication { filename: p, offs });
+                    }
+                    Err(ReadError::Checksum) => {
+                        return Err(KvsError::Checksum { filename: p, offs });
                     }
                 };
 
-                match entry {
-                    LogEntry::Set { key, value: _ } => {
-                        let e = cache.entry(key);
-                        if let std::collections::hash_map::Entry::Occupied(_) = e {
-                            modification_ct += 1;
+                let stamp = entry.stamp();
+                if let Some((seq, time)) = stamp {
+                    match (&entry, until) {
+                        (LogEntry::Checkpoint { .. }, Some(until)) if !until.includes(seq, time) => {
+                            return Err(KvsError::RewindCompacted { filename: p, seq });
                         }
+                        (_, Some(until)) if !until.includes(seq, time) => break,
+                        _ => {}
+                    }
+                    if let LogEntry::Checkpoint { .. } = entry {
+                        checkpoint_seq = seq;
+                    }
+                    // versions copied by compaction keep their older stamps
+                    last_seq = last_seq.max(seq);
+                    last_time = last_time.max(time);
+                }
 
-                        // this amounts to `e.insert(offs)`
-                        e.and_modify(|v| *v = offs)
-                            .or_insert(offs);
-                    },
-                    LogEntry::Remove { key } => {
-                        modification_ct += 1;
-                        cache.remove(&key);
+                // earlier versions only go into the history
+                let (retained, (seq, time)) = match entry {
+                    LogEntry::Retained { seq, time, .. } => (true, (seq, time)),
+                    _ => (false, stamp.unwrap_or((last_seq, last_time))),
+                };
+                let mut reader = LogReader { f: &mut verify_f, map: None, cipher: cipher.as_ref(), filename: &p };
+                for (ns, key, op) in entry.ops() {
+                    let space = space_mut(&mut spaces, &opts.namespaces, ns);
+                    if !retained {
+                        space.apply(key, op, offs, &mut NsKeys { reader: &mut reader, ns })?;
                     }
+                    space.record_version(key, op, offs, seq, time);
                 }
 
                 entry_number += 1;
             }
         }
 
+        let refs = spaces.values().flat_map(|s| s.blob_refs());
+        let blobs = BlobStore::open(&log_dir, cipher.as_ref().map(|c| c.key().clone()), opts.blob_file_size, refs, read_only)?;
+
         let mut v = Self {
             log_dir,
             log_f: log_f_r.into_inner(),
             log_f_name: p,
-            cache,
-            safe: false,
-            modification_ct,
+            safe: opts.sync_writes,
+            spaces,
+            ns_config: opts.namespaces.clone(),
+            cipher,
+            blobs,
+            blob_threshold: opts.blob_threshold,
+            values: opts.value_cache.map(ValueCache::new),
+            mmap: opts.mmap,
+            map: None,
+            watchers: Subscribers::default(),
+            watch_capacity: opts.watch_capacity,
+            log_id: replica::new_log_id(),
+            rewrites: 0,
+            last_seq,
+            last_time,
+            checkpoint_seq,
+            read_only,
+            defer_compaction: false,
         };
 
-        v.maybe_compact()?;
+        v.remap()?;
+        if !read_only {
+            v.maybe_gc_blobs()?;
+            v.maybe_compact()?;
+        }
+
+        let indexed: Vec<String> = v.spaces.iter()
+            .filter(|(_, s)| !s.secondary.is_empty())
+            .map(|(ns, _)| ns.clone())
+            .collect();
+        for ns in indexed {
+            v.rebuild_indexes(&ns)?;
+        }
 
         Ok(v)
     }
 
+    /// map everything written to the log so far
+    fn remap(&mut self) -> Result<()> {
+        if self.mmap {
+            // drop the old map first so we never hold two
+            self.map = None;
+            self.map = LogMap::new(&self.log_f)
+                .context(Mmap { filename: self.log_f_name.clone() })?;
+        }
+
+        Ok(())
+    }
+
+    /// extend the map once enough has been appended past its end, `end` is the current end of the
+    /// log
+    fn maybe_remap(&mut self, end: u64) -> Result<()> {
+        let mapped = self.map.as_ref().map_or(0, |m| m.len());
+        if self.mmap && end - mapped >= REMAP_BYTES {
+            self.remap()?;
+        }
+
+        Ok(())
+    }
+
+    /// compact once any namespace has seen enough modifications
     fn maybe_compact(&mut self) -> Result<()> {
-        if self.modification_ct < COMPACT_MODIFICATION_CT {
+        if self.defer_compaction || self.spaces.values().all(|s| s.modification_ct < s.opts.compact_after) {
             return Ok(());
         }
 
+        let key = self.cipher.as_ref().map(|c| c.key().clone());
+        self.compact(key.as_ref())
+    }
+
+    /// Collect blob files that are mostly garbage: live values are moved to the active blob file,
+    /// the log is pointed at the new copies, and then the old file is deleted.
+    fn maybe_gc_blobs(&mut self) -> Result<()> {
+        for (id, live) in self.blobs.gc_candidates() {
+            if live > 0 {
+                for (ptr, key, value) in self.blobs.records(id)? {
+                    // the value may be the key's current one, one of its earlier versions, or both
+                    let owner = self.spaces.iter().find_map(|(ns, s)| {
+                        let live = s.blob_ptrs.get(&key) == Some(&ptr);
+                        let version = s.versions.as_ref()
+                            .and_then(|versions| versions.get(&key))
+                            .and_then(|versions| versions.iter().find(|(_, v)| v.blob == Some(ptr)))
+                            .map(|(&seq, v)| (seq, v.time));
+                        if live || version.is_some() { Some((ns.clone(), live, version)) } else { None }
+                    });
+                    let (ns, live, version) = match owner {
+                        Some(owner) => owner,
+                        None => continue,
+                    };
+
+                    let blob = self.blobs.append(&key, &value)?;
+                    let offs = self.log_f.seek(io::SeekFrom::End(0))
+                        .context(GetPosition { filename: self.log_f_name.clone() })?;
+                    let mut entry = LogEntry::single(ns.clone(), key.clone(), Change::SetBlob(blob));
+                    if let Some((seq, time)) = version {
+                        entry = entry.into_version(seq, time, live);
+                    }
+                    write_record(&mut std::io::BufWriter::new(&mut self.log_f), self.cipher.as_ref(), offs, &entry)
+                        .map_err(|e| entry.append_error(e))?;
+
+                    let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+                    let space = self.spaces.get_mut(&ns).expect("blob owner exists");
+                    if live {
+                        space.apply(&key, Op::SetBlob(blob), offs, &mut NsKeys { reader: &mut reader, ns: &ns })?;
+                    }
+                    if let Some((seq, time)) = version {
+                        space.record_version(&key, Op::SetBlob(blob), offs, seq, time);
+                    }
+                }
+
+                // the new pointers need to be durable before the old values go away
+                self.blobs.sync()?;
+                self.log_f.sync_all()
+                    .context(CompactionSyncFailed)?;
+            }
+
+            self.blobs.remove_file(id)?;
+            self.rewrites += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the log encrypted with `key`, or in plain text if `key` is `None`. Stale entries
+    /// are dropped just like in a regular compaction. Values in blob files are rewritten under the
+    /// new key as well.
+    pub fn rekey(&mut self, key: Option<EncryptionKey>) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        self.compact(key.as_ref())
+    }
+
+    /// write all live entries to a new log (encrypted with `key`, if any) and replace the current
+    /// log with it
+    fn compact(&mut self, key: Option<&EncryptionKey>) -> Result<()> {
         let mut tmp_path = self.log_dir.clone();
         tmp_path.push("kvs.db.tmp");
 
         // open a new file
-        let mut tmp_log = fs::OpenOptions::new().create(true).read(true).write(true).open(&tmp_path)
+        let mut tmp_log = fs::OpenOptions::new().create(true).truncate(true).read(true).write(true).open(&tmp_path)
             .context(OpenLog { filename: tmp_path.clone() })?;
 
-        let mut new_cache = HashMap::with_capacity(self.cache.len());
+        let new_cipher = match key {
+            Some(key) => Some(LogCipher::generate(key)
+                .context(NonceGeneration { filename: tmp_path.clone() })?),
+            None => None,
+        };
+
+        let mut new_spaces: BTreeMap<String, Space> = self.spaces.iter()
+            .map(|(ns, s)| (ns.clone(), Space::new(s.opts)))
+            .collect();
+
+        // normally only pointers to blobs are copied, but a new key means new blob files too
+        let old_blob_files = if self.cipher.as_ref().map(|c| c.key()) != key {
+            Some(self.blobs.set_key(key.cloned()))
+        } else {
+            None
+        };
+
+        // blobs only earlier versions that compaction doesn't keep pointed at
+        let mut dropped = Vec::new();
 
         // write all _active_ entries to it
-        // TODO: do this in disk order
         {
             let mut tmp_log_w = io::BufWriter::new(&mut tmp_log);
 
-            for (key, offs) in self.cache.iter_mut() {
-                // read from offset
-                // append into new log
-                self.log_f.seek(io::SeekFrom::Start(*offs))
-                    .context(GetPosition { filename: self.log_f_name.clone() })?;
-
-                let mut log_f_r = std::io::BufReader::with_capacity(8192, &mut self.log_f);
-                let entry = match LogEntry::read_from_stream(&mut log_f_r) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        return Err(e).context(LogLookup { offs: *offs, filename: self.log_f_name.clone(), key: key.clone() }).into();
-                    }
-                };
+            if let Some(c) = &new_cipher {
+                c.write_header(&mut tmp_log_w)
+                    .context(LogHeader { filename: tmp_path.clone() })?;
+            }
 
-                match entry {
-                    LogEntry::Set { key: found_key, value } => {
-                        if &found_key != key {
-                            return Err(KvsError::LogEntryKeyMismatch { key: key.clone(), found_key, filename: self.log_f_name.clone(), offs: *offs }).into();
+            // everything that follows is as of the last write
+            let offs = tmp_log_w.stream_position()
+                .context(GetPosition { filename: tmp_path.clone() })?;
+            let checkpoint = LogEntry::Checkpoint { seq: self.last_seq, time: self.last_time };
+            write_record(&mut tmp_log_w, new_cipher.as_ref(), offs, &checkpoint)
+                .map_err(|e| checkpoint.append_error(e))?;
+
+            let blobs = &mut self.blobs;
+            let relocate = old_blob_files.is_some();
+            let now = rewind::now_millis();
+            let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+            for (ns, space) in &self.spaces {
+                let new_space = new_spaces.get_mut(ns).expect("every space is copied");
+                // versions of keys with history keep their stamps, everything else is as of the
+                // checkpoint
+                let mut copy = |key: &str, op: Op<'_>, stamp: Option<(u64, u64)>, live: bool| -> Result<()> {
+                    let change = match op {
+                        Op::SetBlob(blob) if relocate => {
+                            let value = blobs.read(key, &blob)?;
+                            Change::SetBlob(blobs.append(key, &value)?)
                         }
+                        op => Change::from(op),
+                    };
 
-                        // hack to get new offset
-                        let new_offs = tmp_log_w.seek(io::SeekFrom::Current(0))
-                            .context(GetPosition { filename: tmp_path.clone() })?;
+                    // hack to get new offset
+                    let new_offs = tmp_log_w.stream_position()
+                        .context(GetPosition { filename: tmp_path.clone() })?;
+
+                    if live {
+                        if let Change::SetBlob(blob) = change {
+                            new_space.blob_ptrs.insert(key.to_owned(), blob);
+                        }
+                        new_space.index.insert_new(key, new_offs);
+                    }
 
-                        new_cache.insert(key.to_owned(), new_offs);
-                        // emit data
-                        LogEntry::Set { key: key.clone(), value }.write_to_stream(&mut tmp_log_w)
-                            .with_context(|| LogAppendRemove { key: key.clone() })?;
+                    // emit data
+                    let mut entry = LogEntry::single(ns.clone(), key.to_owned(), change);
+                    if let Some((seq, time)) = stamp {
+                        new_space.record_version(key, entry.ops()[0].2, new_offs, seq, time);
+                        entry = entry.into_version(seq, time, live);
+                    }
+                    write_record(&mut tmp_log_w, new_cipher.as_ref(), new_offs, &entry)
+                        .map_err(|e| entry.append_error(e))
+                };
 
-                    },
-                    LogEntry::Remove { key: found_key } => {
-                        return Err(KvsError::LogEntryKindInvalid { offs: *offs, filename: self.log_f_name.clone(), key: key.clone(), found_key }).into();
+                match &space.versions {
+                    Some(versions) => {
+                        let gone = history::for_each_version(ns, &space.opts, versions, &mut reader, now, |key, op, seq, time, live| {
+                            copy(key, op, Some((seq, time)), live)
+                        })?;
+                        dropped.extend(gone);
                     }
+                    None => for_each_live(ns, space, &mut reader, |_, key, op| copy(key, op, None, true))?,
                 }
             }
 
@@ -307,6 +1514,7 @@ impl KvStore {
                 .context(CompactionFlushFailed)?;
         }
 
+        self.blobs.sync()?;
         tmp_log.sync_all()
             .context(CompactionSyncFailed)?;
 
@@ -314,95 +1522,402 @@ impl KvStore {
         self.log_f = tmp_log;
         std::fs::rename(tmp_path, &self.log_f_name)
             .context(CompactionRenameFailed)?;
-        self.cache = new_cache;
+        sync_dir(&self.log_dir)
+            .context(CompactionSyncFailed)?;
+        // compaction doesn't change any values, so the secondary indexes carry over
+        for (ns, space) in &mut new_spaces {
+            if let Some(old) = self.spaces.get_mut(ns) {
+                space.secondary = std::mem::take(&mut old.secondary);
+            }
+        }
+        self.spaces = new_spaces;
+        self.cipher = new_cipher;
+        self.log_id = replica::new_log_id();
+        self.checkpoint_seq = self.last_seq;
+        self.rewrites += 1;
+        self.remap()?;
+
+        match old_blob_files {
+            Some(ids) => {
+                for id in ids {
+                    self.blobs.remove_file(id)?;
+                }
+            }
+            None => {
+                for blob in dropped {
+                    self.blobs.release(&blob);
+                }
+            }
+        }
 
         Ok(())
     }
 
-    /// set a `key` in the store to `value`
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+    /// Append `ops` to the log as one record and apply them. Removes of keys that don't exist
+    /// fail the whole write before anything is written.
+    fn write_ops(&mut self, ops: Vec<(String, String, Option<String>)>) -> Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+
+        // check removes against the index and the changes before them, and keep only the last
+        // change to each key since that is all a reader could ever see
+        let mut last: HashMap<(&str, &str), usize> = HashMap::new();
+        {
+            let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+            for (i, (ns, key, value)) in ops.iter().enumerate() {
+                let exists = match last.get(&(ns.as_str(), key.as_str())) {
+                    Some(&j) => ops[j].2.is_some(),
+                    None => match self.spaces.get(ns) {
+                        Some(space) => space.index.get(key, &mut NsKeys { reader: &mut reader, ns })?.is_some(),
+                        None => false,
+                    },
+                };
+
+                if value.is_none() && !exists {
+                    return Err(KvsError::RemoveNonexistentKey { key: key.clone() });
+                }
+                last.insert((ns, key), i);
+            }
+        }
+        let mut keep: Vec<usize> = last.into_values().collect();
+        keep.sort_unstable();
+
+        let mut changes = Vec::with_capacity(keep.len());
+        let mut derived = Vec::with_capacity(keep.len());
+        // new values of the keys someone is watching
+        let mut watched = Vec::with_capacity(keep.len());
+        let mut ops: Vec<_> = ops.into_iter().map(Some).collect();
+        for i in keep {
+            let (ns, key, value) = ops[i].take().expect("each change is kept once");
+            derived.push(match &value {
+                Some(value) => space_mut(&mut self.spaces, &self.ns_config, &ns).derive(&key, value),
+                None => Vec::new(),
+            });
+            watched.push(if self.watchers.wants(&ns, &key) { Some(value.clone()) } else { None });
+
+            let change = match (value, self.blob_threshold) {
+                (Some(value), Some(threshold)) if value.len() >= threshold => {
+                    Change::SetBlob(self.blobs.append(&key, &value)?)
+                }
+                (Some(value), _) => Change::Set(value),
+                (None, _) => Change::Remove,
+            };
+            changes.push((ns, key, change));
+        }
+
+        if self.safe && changes.iter().any(|(_, _, c)| matches!(c, Change::SetBlob(_))) {
+            self.blobs.sync()?;
+        }
+
         let offs = self.log_f.seek(io::SeekFrom::End(0))
             .context(GetPosition { filename: self.log_f_name.clone() })?;
+        self.maybe_remap(offs)?;
+
+        // the clock may go backwards, but stamps mustn't
+        self.last_seq += 1;
+        self.last_time = self.last_time.max(rewind::now_millis());
+        let entry = LogEntry::stamped(self.last_seq, self.last_time, changes);
+        write_record(&mut std::io::BufWriter::new(&mut self.log_f), self.cipher.as_ref(), offs, &entry)
+            .map_err(|e| entry.append_error(e))?;
+
+        // the index may read back older records, so only update it once the new one is written
+        let mut released = false;
+        let mut events = Vec::new();
+        for (((ns, key, op), derived), watched) in entry.ops().into_iter().zip(derived).zip(watched) {
+            if let Some(values) = &mut self.values {
+                values.invalidate(ns, key);
+            }
 
-        let e = self.cache.entry(key.clone());
-        if let std::collections::hash_map::Entry::Occupied(_) = e {
-            self.modification_ct += 1;
+            let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+            let space = space_mut(&mut self.spaces, &self.ns_config, ns);
+            space.index_secondary(key, derived);
+            let (existed, old_blob) = space.apply(key, op, offs, &mut NsKeys { reader: &mut reader, ns })?;
+            space.record_version(key, op, offs, self.last_seq, self.last_time);
+            // an old value that is part of the key's history is kept until compaction drops it
+            if let (Some(old_blob), None) = (old_blob, &space.versions) {
+                self.blobs.release(&old_blob);
+                released = true;
+            }
+
+            if let Some(value) = watched {
+                events.push((ns.to_owned(), WatchEvent { key: key.to_owned(), existed, value, seq: self.last_seq }));
+            }
         }
 
-        e.and_modify(|v| *v = offs)
-            .or_insert(offs);
+        if self.safe {
+            self.log_f.sync_all().with_context(|| LogSync { key: entry.first_key() })?;
+        }
+
+        // the write is done, whatever happens to the housekeeping below
+        if !events.is_empty() {
+            self.watchers.publish(&events);
+        }
 
-        LogEntry::Set { key: key.clone(), value: value.clone() }.write_to_stream(&mut std::io::BufWriter::new(&mut self.log_f))
-            .with_context(|| LogAppendSet { key: key.clone(), value: value.clone() })?;
+        if released {
+            self.maybe_gc_blobs()?;
+        }
 
         // FIXME: we may have written the previous entry to the file when we didn't need to
         self.maybe_compact()?;
 
-        if self.safe {
-            self.log_f.sync_all().with_context(|| LogSync { key })?;
-        }
         Ok(())
     }
 
-    /// retrieve the value of `key`. if no value, return None
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.cache.get(&key) {
-            Some(&offs) => {
-                self.log_f.seek(io::SeekFrom::Start(offs))
-                    .context(GetPosition { filename: self.log_f_name.clone() })?;
+    /// set a `key` in the store to `value`
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write_ops(vec![(String::new(), key, Some(value))])
+    }
 
-                let mut log_f_r = std::io::BufReader::with_capacity(8192, &mut self.log_f);
-                let entry = match LogEntry::read_from_stream(&mut log_f_r) {
-                    Ok(v) => v,
-                    Err(e) => {
-                       return Err(e).context(LogLookup { offs, filename: self.log_f_name.clone(), key: key.clone() }).into();
-                    }
-                };
+    /// Apply every change in `batch` atomically: after a crash either all of them are in the
+    /// store or none are
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        self.write_ops(batch.ops)
+    }
 
-                match entry {
-                    LogEntry::Set { key: found_key, value } => {
-                        if found_key != key {
-                            return Err(KvsError::LogEntryKeyMismatch { key: key.clone(), found_key, filename: self.log_f_name.clone(), offs }).into();
-                        }
+    /// Watch for changes to keys in the default namespace starting with `prefix`. Each `set`,
+    /// `remove` or batch sends its events once it has been written (and synced, with
+    /// `OpenOptions::sync_writes`), in the order the changes were made.
+    ///
+    /// ```no_run
+    /// # use kvs::KvStore;
+    /// let mut store = KvStore::open("/tmp/kvs")?;
+    /// let watcher = store.watch("config/");
+    /// store.set("config/port".to_owned(), "4000".to_owned())?;
+    /// let event = watcher.try_recv()?.expect("event was sent");
+    /// assert_eq!(event.value.as_deref(), Some("4000"));
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn watch(&mut self, prefix: &str) -> Watcher {
+        self.watchers.subscribe("", prefix, self.watch_capacity)
+    }
 
-                        Ok(Some(value))
-                    },
-                    LogEntry::Remove { key: found_key } => {
-                        return Err(KvsError::LogEntryKindInvalid { offs, filename: self.log_f_name.clone(), key: key.clone(), found_key }).into();
-                    }
+    /// A handle for the keys in namespace `name`. Namespaces are created by writing to them.
+    pub fn namespace(&mut self, name: &str) -> Namespace<'_> {
+        Namespace { store: self, name: name.to_owned() }
+    }
+
+    /// Names of the namespaces that hold at least one key, in order
+    pub fn namespaces(&self) -> Vec<String> {
+        self.spaces.iter()
+            .filter(|(_, s)| s.index.len() > 0)
+            .map(|(ns, _)| ns.clone())
+            .collect()
+    }
+
+    /// Index the values in the default namespace by the keys `f` extracts from each key and
+    /// value, replacing any index already called `name`. The index is built from the current
+    /// values straight away and kept up to date by every write. Use
+    /// `OpenOptions::secondary_index` to have it built when the store is opened.
+    ///
+    /// ```no_run
+    /// # use kvs::KvStore;
+    /// let mut store = KvStore::open("/tmp/kvs")?;
+    /// store.register_index("domain", |_key, email| {
+    ///     email.split('@').nth(1).map(str::to_owned).into_iter().collect()
+    /// })?;
+    /// store.set("alice".to_owned(), "alice@example.com".to_owned())?;
+    /// assert_eq!(store.get_by_index("domain", "example.com")?.len(), 1);
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn register_index<F>(&mut self, name: &str, f: F) -> Result<()>
+        where F: Fn(&str, &str) -> Vec<String> + Send + Sync + 'static
+    {
+        self.register_index_in("", name, Arc::new(f))
+    }
+
+    fn register_index_in(&mut self, ns: &str, name: &str, f: IndexFn) -> Result<()> {
+        let space = space_mut(&mut self.spaces, &self.ns_config, ns);
+        space.secondary.insert(name.to_owned(), SecondaryIndex::new(f));
+        self.rebuild_indexes(ns)
+    }
+
+    /// rebuild every secondary index in `ns` from the live values
+    fn rebuild_indexes(&mut self, ns: &str) -> Result<()> {
+        let space = match self.spaces.get(ns) {
+            Some(space) => space,
+            None => return Ok(()),
+        };
+
+        let mut found = Vec::new();
+        let blobs = &mut self.blobs;
+        let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+        for_each_live(ns, space, &mut reader, |_, key, op| {
+            let value = match op {
+                Op::Set(value) => value.to_owned(),
+                Op::SetBlob(blob) => blobs.read(key, &blob)?,
+                Op::Remove => return Ok(()),
+            };
+            found.push((key.to_owned(), space.derive(key, &value)));
+            Ok(())
+        })?;
+
+        let space = self.spaces.get_mut(ns).expect("space exists");
+        for ix in space.secondary.values_mut() {
+            ix.clear();
+        }
+        for (key, derived) in found {
+            space.index_secondary(&key, derived);
+        }
+
+        Ok(())
+    }
+
+    /// Every key in the default namespace starting with `prefix`, in order. With the compact
+    /// index this has to read every live record.
+    pub fn keys(&mut self, prefix: &str) -> Result<Vec<String>> {
+        self.keys_in("", prefix)
+    }
+
+    fn keys_in(&mut self, ns: &str, prefix: &str) -> Result<Vec<String>> {
+        let space = match self.spaces.get(ns) {
+            Some(space) => space,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut keys = Vec::new();
+        if let Index::Full(m) = &space.index {
+            keys.extend(m.keys().filter(|k| k.starts_with(prefix)).cloned());
+        } else {
+            let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+            for_each_live(ns, space, &mut reader, |_, key, _| {
+                if key.starts_with(prefix) {
+                    keys.push(key.to_owned());
                 }
-            },
-            None => {
-                Ok(None)
+                Ok(())
+            })?;
+        }
+
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Every key and value in the default namespace whose key starts with `prefix`, in key order
+    pub fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.scan_in("", prefix)
+    }
+
+    fn scan_in(&mut self, ns: &str, prefix: &str) -> Result<Vec<(String, String)>> {
+        let keys = self.keys_in(ns, prefix)?;
+        let mut found = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get_in(ns, key.clone())? {
+                found.push((key, value));
             }
         }
+
+        Ok(found)
     }
 
-    /// remove an entry by `key`
-    pub fn remove(&mut self, key: String) -> Result<()>{
+    /// Every key and value in the default namespace indexed under `secondary_key` by the
+    /// secondary index `name`, in key order
+    pub fn get_by_index(&mut self, name: &str, secondary_key: &str) -> Result<Vec<(String, String)>> {
+        self.get_by_index_in("", name, secondary_key)
+    }
 
-        let e = self.cache.get(&key);
-        if let Some(_) = e {
-            self.modification_ct += 1;
+    fn get_by_index_in(&mut self, ns: &str, name: &str, secondary_key: &str) -> Result<Vec<(String, String)>> {
+        let keys = match self.spaces.get(ns).and_then(|s| s.secondary.get(name)) {
+            Some(ix) => ix.get(secondary_key),
+            // registered when opening, but nothing has been written to the namespace yet
+            None if self.ns_config.indexes.iter().any(|d| d.ns == ns && d.name == name) => Vec::new(),
+            None => return Err(KvsError::UnknownIndex { name: name.to_owned() }),
+        };
+
+        let mut found = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get_in(ns, key.clone())? {
+                found.push((key, value));
+            }
         }
 
-        self.cache.remove(&key).ok_or(KvsError::RemoveNonexistentKey { key: key.clone() })?;
+        Ok(found)
+    }
 
-        {
-            self.log_f.seek(io::SeekFrom::End(0))
-                .context(GetPosition { filename: self.log_f_name.clone() })?;
-            LogEntry::Remove { key: key.clone() }.write_to_stream(&mut std::io::BufWriter::new(&mut self.log_f))
-                .with_context(|| LogAppendRemove { key: key.clone() })?;
+    /// Memory used by the index of live keys in the default namespace
+    pub fn index_stats(&self) -> IndexStats {
+        self.index_stats_in("")
+    }
+
+    fn index_stats_in(&self, ns: &str) -> IndexStats {
+        match self.spaces.get(ns) {
+            Some(space) => space.index.stats(),
+            None => Index::new(self.ns_config.get(ns).index_mode).stats(),
         }
+    }
 
-        // FIXME: we may have written the previous entry to the file when we didn't need to
-        self.maybe_compact()?;
+    /// Hit and miss counters for the value cache, if it is enabled
+    pub fn value_cache_stats(&self) -> Option<ValueCacheStats> {
+        self.values.as_ref().map(|v| v.stats())
+    }
 
-        if self.safe {
-            self.log_f.sync_all().with_context(|| LogSync { key })?;
+    /// retrieve the value of `key`. if no value, return None
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_in("", key)
+    }
+
+    fn get_in(&mut self, ns: &str, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.values.as_mut().and_then(|v| v.get(ns, &key)) {
+            return Ok(Some(value));
+        }
+
+        let value = self.read_value(ns, &key)?;
+        if let (Some(values), Some(value)) = (&mut self.values, &value) {
+            values.insert(ns.to_owned(), key, value.clone());
         }
 
+        Ok(value)
+    }
 
-        Ok(())
+    /// read the value of `key` in `ns` from disk
+    fn read_value(&mut self, ns: &str, key: &str) -> Result<Option<String>> {
+        let space = match self.spaces.get(ns) {
+            Some(space) => space,
+            None => return Ok(None),
+        };
+
+        let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+        let offs = match space.index.get(key, &mut NsKeys { reader: &mut reader, ns })? {
+            Some(offs) => offs,
+            None => return Ok(None),
+        };
+
+        let entry = reader.read_at(offs, key)?;
+        if entry.find(ns, key).is_none() {
+            return Err(KvsError::LogEntryKeyMismatch { key: key.to_owned(), found_key: entry.first_key().to_owned(), filename: self.log_f_name.clone(), offs });
+        }
+
+        match entry.take(ns, key) {
+            Some(Change::Set(value)) => Ok(Some(value)),
+            Some(Change::SetBlob(blob)) => Ok(Some(self.blobs.read(key, &blob)?)),
+            _ => Err(KvsError::LogEntryKindInvalid { offs, filename: self.log_f_name.clone(), key: key.to_owned(), found_key: key.to_owned() }),
+        }
+    }
+
+    /// Sequence number of the write that gave `key` in `ns` its current value, `None` if it has
+    /// none. A value compaction copied without its stamp is as of the log's checkpoint.
+    pub(crate) fn value_seq(&mut self, ns: &str, key: &str) -> Result<Option<u64>> {
+        let space = match self.spaces.get(ns) {
+            Some(space) => space,
+            None => return Ok(None),
+        };
+
+        let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+        let offs = match space.index.get(key, &mut NsKeys { reader: &mut reader, ns })? {
+            Some(offs) => offs,
+            None => return Ok(None),
+        };
+
+        match reader.read_at(offs, key)? {
+            LogEntry::Stamped { seq, .. } => Ok(Some(seq)),
+            _ => Ok(Some(self.checkpoint_seq)),
+        }
+    }
+
+    /// remove an entry by `key`
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.write_ops(vec![(String::new(), key, None)])
     }
 }
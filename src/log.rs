@@ -0,0 +1,373 @@
+//! Records in the log and how they are read and written
+//!
+//! Changes to the default namespace ("") use the original `Set`/`Remove`/`SetBlob` records so
+//! that logs written before namespaces existed are still readable. Other namespaces use the `Ns*`
+//! records, and a `Batch` groups several changes into one record so they are applied atomically.
+//!
+//! Every write is a `Stamped` record, which is a batch carrying the write's sequence number and
+//! the time it was made, so the log can be replayed up to a point, see `KvStore::open_at`. The
+//! records compaction writes aren't stamped; a compacted log starts with a `Checkpoint` giving the
+//! sequence number and time of the last write it includes, and the records after it belong to that
+//! write. Records from before stamps existed count as sequence number 0.
+//!
+//! In namespaces that keep history, compaction writes the latest version of each key as a
+//! `Stamped` record with its original stamp, and the earlier versions it keeps as `Retained`
+//! records. Those only add to a key's history and never change its current value.
+//!
+//! Each record, here and in blob files, is framed: a sync marker, the length of what follows, a
+//! CRC32 of the length and contents, then the encoded (and maybe sealed) record. The checksum
+//! catches damage the encoding wouldn't, and the marker lets a reader find the next record after
+//! a damaged one, see `verify`. Records written before framing are still read; they can't start
+//! with the marker, which no record kind and no sensible record length begins with.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use crc32fast::Hasher;
+use snafu::ResultExt;
+use speedy::{Readable, Writable};
+
+use crate::blob::BlobPtr;
+use crate::crypto::LogCipher;
+use crate::index::KeyAt;
+use crate::mmap::LogMap;
+use crate::{GetPosition, KvsError, Result};
+
+/// Start of every framed record
+pub(crate) const SYNC: [u8; 8] = *b"\xf5kvs\r\n\x1a\n";
+
+#[derive(Debug)]
+#[derive(Readable, Writable)]
+pub(crate) enum LogEntry {
+    Set { key: String, value: String },
+    Remove { key: String },
+    SetBlob { key: String, blob: BlobPtr },
+    NsSet { ns: String, key: String, value: String },
+    NsRemove { ns: String, key: String },
+    NsSetBlob { ns: String, key: String, blob: BlobPtr },
+    Batch { entries: Vec<LogEntry> },
+    // time is in milliseconds since the Unix epoch
+    Stamped { seq: u64, time: u64, entries: Vec<LogEntry> },
+    Checkpoint { seq: u64, time: u64 },
+    Retained { seq: u64, time: u64, entries: Vec<LogEntry> },
+}
+
+/// A change to a single key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Change {
+    Set(String),
+    SetBlob(BlobPtr),
+    Remove,
+}
+
+/// A change to a single key, borrowed from a `LogEntry`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op<'a> {
+    Set(&'a str),
+    SetBlob(BlobPtr),
+    Remove,
+}
+
+impl From<Op<'_>> for Change {
+    fn from(op: Op<'_>) -> Self {
+        match op {
+            Op::Set(value) => Change::Set(value.to_owned()),
+            Op::SetBlob(blob) => Change::SetBlob(blob),
+            Op::Remove => Change::Remove,
+        }
+    }
+}
+
+impl LogEntry {
+    /// the record for a single change to `key` in namespace `ns`
+    pub fn single(ns: String, key: String, change: Change) -> Self {
+        match (ns.is_empty(), change) {
+            (true, Change::Set(value)) => LogEntry::Set { key, value },
+            (true, Change::SetBlob(blob)) => LogEntry::SetBlob { key, blob },
+            (true, Change::Remove) => LogEntry::Remove { key },
+            (false, Change::Set(value)) => LogEntry::NsSet { ns, key, value },
+            (false, Change::SetBlob(blob)) => LogEntry::NsSetBlob { ns, key, blob },
+            (false, Change::Remove) => LogEntry::NsRemove { ns, key },
+        }
+    }
+
+    /// the record for write number `seq`, made at `time`, making `changes` in order
+    pub fn stamped(seq: u64, time: u64, changes: Vec<(String, String, Change)>) -> Self {
+        LogEntry::Stamped {
+            seq,
+            time,
+            entries: changes.into_iter()
+                .map(|(ns, key, change)| Self::single(ns, key, change))
+                .collect(),
+        }
+    }
+
+    /// this single change as version `seq` of its key, made at `time`, written by compaction:
+    /// `live` if it is the key's current value, otherwise an earlier version kept for its history
+    pub fn into_version(self, seq: u64, time: u64, live: bool) -> Self {
+        match live {
+            true => LogEntry::Stamped { seq, time, entries: vec![self] },
+            false => LogEntry::Retained { seq, time, entries: vec![self] },
+        }
+    }
+
+    /// The sequence number and time of the write this record is, or that it is as of for a
+    /// checkpoint. `Retained` records don't count, they aren't part of the order of writes.
+    pub fn stamp(&self) -> Option<(u64, u64)> {
+        match *self {
+            LogEntry::Stamped { seq, time, .. } | LogEntry::Checkpoint { seq, time } => Some((seq, time)),
+            _ => None,
+        }
+    }
+
+    /// every change in this record as (namespace, key, change), in the order they apply
+    pub fn ops(&self) -> Vec<(&str, &str, Op<'_>)> {
+        match self {
+            LogEntry::Set { key, value } => vec![("", key, Op::Set(value))],
+            LogEntry::Remove { key } => vec![("", key, Op::Remove)],
+            LogEntry::SetBlob { key, blob } => vec![("", key, Op::SetBlob(*blob))],
+            LogEntry::NsSet { ns, key, value } => vec![(ns, key, Op::Set(value))],
+            LogEntry::NsRemove { ns, key } => vec![(ns, key, Op::Remove)],
+            LogEntry::NsSetBlob { ns, key, blob } => vec![(ns, key, Op::SetBlob(*blob))],
+            LogEntry::Batch { entries } | LogEntry::Stamped { entries, .. } | LogEntry::Retained { entries, .. } => {
+                entries.iter().flat_map(|e| e.ops()).collect()
+            }
+            LogEntry::Checkpoint { .. } => Vec::new(),
+        }
+    }
+
+    /// the last change this record makes to `key` in `ns`
+    pub fn find(&self, ns: &str, key: &str) -> Option<Op<'_>> {
+        self.ops().into_iter()
+            .rev()
+            .find(|&(n, k, _)| n == ns && k == key)
+            .map(|(_, _, op)| op)
+    }
+
+    /// some key this record changes, for describing records that don't hold the key we wanted
+    pub fn first_key(&self) -> &str {
+        self.ops().first().map_or("", |&(_, key, _)| key)
+    }
+
+    /// like `find`, but takes the value out of the record instead of copying it
+    pub fn take(self, ns: &str, key: &str) -> Option<Change> {
+        match self {
+            LogEntry::Batch { entries } | LogEntry::Stamped { entries, .. } | LogEntry::Retained { entries, .. } => {
+                entries.into_iter().rev().find_map(|e| e.take(ns, key))
+            }
+            LogEntry::Checkpoint { .. } => None,
+            e => {
+                let (n, k, change) = e.into_single();
+                if n == ns && k == key { Some(change) } else { None }
+            }
+        }
+    }
+
+    /// the error for failing to append this record
+    pub fn append_error(&self, source: speedy::Error) -> KvsError {
+        match self {
+            LogEntry::Set { key, value } | LogEntry::NsSet { key, value, .. } => {
+                KvsError::LogAppendSet { key: key.clone(), value: value.clone(), source }
+            }
+            LogEntry::Remove { key } | LogEntry::NsRemove { key, .. } => {
+                KvsError::LogAppendRemove { key: key.clone(), source }
+            }
+            LogEntry::SetBlob { key, .. } | LogEntry::NsSetBlob { key, .. } => {
+                KvsError::LogAppendBlob { key: key.clone(), source }
+            }
+            LogEntry::Stamped { entries, .. } | LogEntry::Retained { entries, .. } if entries.len() == 1 => {
+                entries[0].append_error(source)
+            }
+            LogEntry::Batch { entries } | LogEntry::Stamped { entries, .. } | LogEntry::Retained { entries, .. } => {
+                KvsError::LogAppendBatch { len: entries.len(), source }
+            }
+            LogEntry::Checkpoint { .. } => KvsError::LogAppendBatch { len: 0, source },
+        }
+    }
+
+    fn into_single(self) -> (String, String, Change) {
+        match self {
+            LogEntry::Set { key, value } => (String::new(), key, Change::Set(value)),
+            LogEntry::Remove { key } => (String::new(), key, Change::Remove),
+            LogEntry::SetBlob { key, blob } => (String::new(), key, Change::SetBlob(blob)),
+            LogEntry::NsSet { ns, key, value } => (ns, key, Change::Set(value)),
+            LogEntry::NsRemove { ns, key } => (ns, key, Change::Remove),
+            LogEntry::NsSetBlob { ns, key, blob } => (ns, key, Change::SetBlob(blob)),
+            LogEntry::Batch { .. } | LogEntry::Stamped { .. } | LogEntry::Checkpoint { .. } | LogEntry::Retained { .. } => {
+                unreachable!("batches are handled by the caller")
+            }
+        }
+    }
+}
+
+/// Failure to read a single record from the log
+pub(crate) enum ReadError {
+    Parse(speedy::Error),
+    Authentication,
+    Checksum,
+}
+
+impl ReadError {
+    /// the error for failing to read the record at `offs` in `filename` while looking up `key`
+    pub fn lookup_error(self, filename: &Path, offs: u64, key: &str) -> KvsError {
+        let filename = filename.to_owned();
+        match self {
+            ReadError::Parse(source) => KvsError::LogLookup { offs, filename, key: key.to_owned(), source },
+            ReadError::Authentication => KvsError::Authentication { filename, offs },
+            ReadError::Checksum => KvsError::Checksum { filename, offs },
+        }
+    }
+}
+
+impl From<speedy::Error> for ReadError {
+    fn from(e: speedy::Error) -> Self {
+        ReadError::Parse(e)
+    }
+}
+
+/// the error speedy gives when the input ends early, for a frame that is cut short
+fn unexpected_eof() -> speedy::Error {
+    u8::read_from_stream(io::empty()).expect_err("nothing to read")
+}
+
+fn frame_crc(len: u32, body: &[u8]) -> u32 {
+    let mut h = Hasher::new();
+    h.update(&len.to_le_bytes());
+    h.update(body);
+    h.finalize()
+}
+
+/// read the record at the current position of `r`, which is `offs` in the file
+pub(crate) fn read_record<T>(mut r: impl Read, cipher: Option<&LogCipher>, offs: u64) -> std::result::Result<T, ReadError>
+    where T: for<'a> Readable<'a, speedy::LittleEndian>
+{
+    let start = u64::read_from_stream(&mut r)?.to_le_bytes();
+    if start != SYNC {
+        // written before records were framed
+        return read_unframed((&start[..]).chain(r), cipher, offs);
+    }
+
+    let len = u32::read_from_stream(&mut r)?;
+    let crc = u32::read_from_stream(&mut r)?;
+    let mut body = Vec::new();
+    r.take(len.into()).read_to_end(&mut body).map_err(speedy::Error::custom)?;
+    if body.len() < len as usize {
+        // a write cut short is the last thing in the log, so another record after this one
+        // means its length is damaged
+        if body.windows(SYNC.len()).any(|w| w == SYNC) {
+            return Err(ReadError::Checksum);
+        }
+        return Err(unexpected_eof().into());
+    }
+    let intact = frame_crc(len, &body) == crc;
+
+    match cipher {
+        None if !intact => Err(ReadError::Checksum),
+        None => Ok(T::read_from_buffer_owned(&body)?),
+        Some(c) => {
+            // authentication is the stronger check, so a sealed record fails it first
+            let plain = c.open(offs, &body).ok_or(ReadError::Authentication)?;
+            if !intact {
+                return Err(ReadError::Checksum);
+            }
+            Ok(T::read_from_buffer_owned(&plain)?)
+        }
+    }
+}
+
+fn read_unframed<T>(r: impl Read, cipher: Option<&LogCipher>, offs: u64) -> std::result::Result<T, ReadError>
+    where T: for<'a> Readable<'a, speedy::LittleEndian>
+{
+    match cipher {
+        None => Ok(T::read_from_stream(r)?),
+        Some(c) => {
+            let sealed = Vec::<u8>::read_from_stream(r)?;
+            let plain = c.open(offs, &sealed).ok_or(ReadError::Authentication)?;
+            Ok(T::read_from_buffer_owned(&plain)?)
+        }
+    }
+}
+
+/// write `entry` as the record at `offs` in the file
+pub(crate) fn write_record<T>(mut w: impl Write, cipher: Option<&LogCipher>, offs: u64, entry: &T) -> std::result::Result<(), speedy::Error>
+    where T: Writable<speedy::LittleEndian>
+{
+    let mut body = entry.write_to_vec()?;
+    if let Some(c) = cipher {
+        body = c.seal(offs, &body)
+            .ok_or_else(|| speedy::Error::custom("record too large to encrypt"))?;
+    }
+    let len = u32::try_from(body.len())
+        .map_err(|_| speedy::Error::custom("record too large"))?;
+
+    let mut frame = Vec::with_capacity(16 + body.len());
+    frame.extend_from_slice(&SYNC);
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&frame_crc(len, &body).to_le_bytes());
+    frame.extend_from_slice(&body);
+    w.write_all(&frame).map_err(speedy::Error::custom)
+}
+
+/// Reads a file from `offs` on without moving its cursor, so one `File` can be read by several
+/// threads at once
+struct ReadAt<'a> {
+    f: &'a File,
+    offs: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(self.f, buf, self.offs)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(self.f, buf, self.offs)?;
+        self.offs += n as u64;
+        Ok(n)
+    }
+}
+
+/// read the record at `offs` in `f` without moving its cursor
+pub(crate) fn read_record_at<T>(f: &File, cipher: Option<&LogCipher>, offs: u64) -> std::result::Result<T, ReadError>
+    where T: for<'a> Readable<'a, speedy::LittleEndian>
+{
+    read_record(io::BufReader::with_capacity(8192, ReadAt { f, offs }), cipher, offs)
+}
+
+/// Reads records back from the log, straight from the memory map when it covers them
+pub(crate) struct LogReader<'a> {
+    pub f: &'a mut File,
+    pub map: Option<&'a LogMap>,
+    pub cipher: Option<&'a LogCipher>,
+    pub filename: &'a Path,
+}
+
+impl LogReader<'_> {
+    /// read the record at `offs`, which we expect to be for `key`
+    pub fn read_at(&mut self, offs: u64, key: &str) -> Result<LogEntry> {
+        let entry = match self.map.and_then(|m| m.from(offs)) {
+            Some(bytes) => read_record(bytes, self.cipher, offs),
+            None => {
+                self.f.seek(io::SeekFrom::Start(offs))
+                    .context(GetPosition { filename: self.filename.to_owned() })?;
+                read_record(std::io::BufReader::with_capacity(8192, &mut *self.f), self.cipher, offs)
+            }
+        };
+
+        entry.map_err(|e| e.lookup_error(self.filename, offs, key))
+    }
+}
+
+/// Answers index lookups for the keys of one namespace
+pub(crate) struct NsKeys<'r, 'a> {
+    pub reader: &'r mut LogReader<'a>,
+    pub ns: &'r str,
+}
+
+impl KeyAt for NsKeys<'_, '_> {
+    fn has_key(&mut self, offs: u64, key: &str) -> Result<bool> {
+        let entry = self.reader.read_at(offs, key)?;
+        Ok(entry.find(self.ns, key).is_some())
+    }
+}
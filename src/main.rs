@@ -1,26 +1,219 @@
 #![warn(rust_2018_idioms)]
 #![deny(unsafe_code)]
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
 use structopt::StructOpt;
 
+#[derive(Debug, StructOpt)]
+struct Opt {
+    /// Encrypt the store with the key in this file (32 raw bytes or 64 hex digits)
+    #[structopt(long, global = true, parse(from_os_str))]
+    key_file: Option<PathBuf>,
+
+    /// Cipher used with `--key-file`: chacha20poly1305 or aes256gcm
+    #[structopt(long, global = true, default_value = "chacha20poly1305")]
+    cipher: kvs::Cipher,
+
+    /// Keep history, and have compaction keep this many versions of each key
+    #[structopt(long, global = true)]
+    keep_versions: Option<usize>,
+
+    /// Keep history, and have compaction keep versions written in the last this many seconds
+    #[structopt(long, global = true)]
+    keep_for: Option<u64>,
+
+    #[structopt(subcommand)]
+    cmd: KvsOpt,
+}
+
 #[derive(Debug, StructOpt)]
 enum KvsOpt {
-    Set { key: String, value: String },
-    Get { key: String },
-    Rm { key: String },
+    Set {
+        key: String,
+        value: String,
+        /// Namespace to set the key in
+        #[structopt(long, default_value = "")]
+        ns: String,
+    },
+    Get {
+        key: String,
+        /// Namespace to look the key up in
+        #[structopt(long, default_value = "")]
+        ns: String,
+    },
+    Rm {
+        key: String,
+        /// Namespace to remove the key from
+        #[structopt(long, default_value = "")]
+        ns: String,
+    },
+    /// List the kept versions of a key, oldest first; needs `--keep-versions` or `--keep-for`
+    History {
+        key: String,
+        /// Namespace the key is in
+        #[structopt(long, default_value = "")]
+        ns: String,
+    },
+    /// Back the store up into a directory, only copying what's new if it already holds a backup
+    Backup {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+    /// Restore a backup into the current directory, which must not hold a store yet
+    Restore {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+    /// Copy the store as it was at an earlier point into a new store in a directory
+    Rewind {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+        /// Go back to just after the write with this sequence number
+        #[structopt(long, required_unless = "time", conflicts_with = "time")]
+        seq: Option<u64>,
+        /// Go back to this time, in seconds since the Unix epoch
+        #[structopt(long)]
+        time: Option<u64>,
+    },
+    /// Write every key and value out, to a file or standard output
+    Export {
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+        /// json, jsonl or csv
+        #[structopt(long, default_value = "jsonl")]
+        format: kvs::Format,
+        /// Encode keys and values as base64
+        #[structopt(long)]
+        base64: bool,
+    },
+    /// Set every key in an export, read from a file or standard input
+    Import {
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+        /// json, jsonl or csv
+        #[structopt(long, default_value = "jsonl")]
+        format: kvs::Format,
+    },
+    /// Describe every change in the log, one per line, without changing the store
+    Dump {
+        /// Only changes to this key
+        #[structopt(long)]
+        key: Option<String>,
+        /// Only changes in this namespace
+        #[structopt(long)]
+        ns: Option<String>,
+        /// Only records starting at or after this offset
+        #[structopt(long)]
+        from: Option<u64>,
+        /// Only records starting before this offset
+        #[structopt(long)]
+        to: Option<u64>,
+        /// Only changes of this kind: set, blob, remove or checkpoint; may be repeated
+        #[structopt(long, number_of_values = 1)]
+        kind: Vec<kvs::ChangeKind>,
+    },
+    /// Check the store for damage, listing each problem found
+    Verify {
+        /// Replace a damaged log with every record that can still be read from it
+        #[structopt(long)]
+        repair: bool,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let opt = KvsOpt::from_args();
+    let opt = Opt::from_args();
+
+    let mut open_opts = kvs::OpenOptions::new();
+    if let Some(key_file) = opt.key_file {
+        open_opts.key_file(opt.cipher, key_file);
+    }
+    if let Some(versions) = opt.keep_versions {
+        open_opts.keep_versions(versions);
+    }
+    if let Some(secs) = opt.keep_for {
+        open_opts.keep_for(Duration::from_secs(secs));
+    }
+
+    // restoring creates the store, so it must not be opened first
+    if let KvsOpt::Restore { dir } = &opt.cmd {
+        open_opts.restore(dir, ".")?;
+        println!("Restored {}", dir.display());
+        return Ok(());
+    }
+
+    if let KvsOpt::Rewind { dir, seq, time } = &opt.cmd {
+        let until = match (seq, time) {
+            (Some(seq), _) => kvs::Until::Seq(*seq),
+            (None, Some(time)) => kvs::Until::Time(UNIX_EPOCH + Duration::from_secs(*time)),
+            (None, None) => unreachable!("clap requires one"),
+        };
+        let mut view = open_opts.open_at(".", until)?;
+        view.copy_to(&open_opts, dir)?;
+        println!("Rewound to write {} in {}", view.last_seq(), dir.display());
+        return Ok(());
+    }
+
+    // a damaged store may not open at all
+    if let KvsOpt::Verify { repair } = opt.cmd {
+        let report = if repair { open_opts.repair(".")? } else { open_opts.verify(".")? };
+        for problem in &report.problems {
+            println!("{}", problem);
+        }
+        for range in &report.skipped {
+            println!("Skipped bytes {}..{}", range.start, range.end);
+        }
+        println!("Checked {} records, found {} problems", report.records, report.problems.len());
+        if let (Some(damaged), Some(written)) = (&report.damaged_log, &report.repair_report) {
+            println!("Salvaged {} records into a fresh log, the damaged one is kept as {}", report.records, damaged.display());
+            println!("What was skipped is listed in {}", written.display());
+        }
+        if !report.is_clean() && !repair {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let KvsOpt::Dump { key, ns, from, to, kind } = &opt.cmd {
+        let mut options = kvs::DumpOptions::new();
+        if let Some(key) = key {
+            options.key(key);
+        }
+        if let Some(ns) = ns {
+            options.namespace(ns);
+        }
+        if from.is_some() || to.is_some() {
+            options.offsets(from.unwrap_or(0)..to.unwrap_or(u64::MAX));
+        }
+        for &kind in kind {
+            options.kind(kind);
+        }
 
-    let mut kvs = kvs::KvStore::open(".")?;
-    match opt {
-        KvsOpt::Set { key, value } => {
-            kvs.set(key.clone(), value.clone())?;
+        let mut view = open_opts.open_read_only(".")?;
+        view.dump(&options, |change| {
+            let seq = change.seq.map_or_else(|| "-".to_owned(), |seq| seq.to_string());
+            let status = change.status.map_or("-", |status| match status {
+                kvs::Status::Live => "live",
+                kvs::Status::Superseded => "superseded",
+                kvs::Status::History => "history",
+            });
+            println!("{}\t{}\t{}\t{}\t{:?}\t{:?}\t{:?}\t{}\t{:?}",
+                change.offs, change.size, change.record, seq, change.kind, change.ns, change.key, status, change.preview);
+            Ok(())
+        })?;
+        return Ok(());
+    }
+
+    let mut kvs = open_opts.open(".")?;
+    match opt.cmd {
+        KvsOpt::Set { key, value, ns } => {
+            kvs.namespace(&ns).set(key.clone(), value.clone())?;
         }
-        KvsOpt::Get { key } => {
+        KvsOpt::Get { key, ns } => {
             let k = key;
 
-            let r = kvs.get(k.to_owned())?;
+            let r = kvs.namespace(&ns).get(k.to_owned())?;
             match r {
                 None => {
                     println!("Key not found");
@@ -30,10 +223,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
         }
-        KvsOpt::Rm { key } => {
+        KvsOpt::Rm { key, ns } => {
             let k = key;
 
-            match kvs.remove(k.to_owned()) {
+            match kvs.namespace(&ns).remove(k.to_owned()) {
                 Err(kvs::KvsError::RemoveNonexistentKey { key: _ }) => {
                     println!("Key not found");
                     std::process::exit(1);
@@ -45,6 +238,39 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Ok(_) => {},
             }
         }
+        KvsOpt::History { key, ns } => {
+            for version in kvs.namespace(&ns).history(key)? {
+                let time = version.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let value = version.value.unwrap_or_else(|| "(removed)".to_owned());
+                println!("{}\t{}.{:03}\t{}", version.seq, time.as_secs(), time.subsec_millis(), value);
+            }
+        }
+        KvsOpt::Backup { dir } => {
+            let stats = kvs.backup_to(&dir)?;
+            println!("Backed up {} files, copied {} of {} bytes", stats.files, stats.bytes_copied, stats.bytes_total);
+        }
+        KvsOpt::Export { file, format, base64 } => {
+            let encoding = if base64 { kvs::Encoding::Base64 } else { kvs::Encoding::Text };
+            match file {
+                Some(file) => {
+                    let records = kvs.export(File::create(&file)?, format, encoding)?;
+                    println!("Exported {} records to {}", records, file.display());
+                }
+                None => {
+                    kvs.export(io::stdout().lock(), format, encoding)?;
+                }
+            }
+        }
+        KvsOpt::Import { file, format } => {
+            let records = match file {
+                Some(file) => kvs.import(File::open(file)?, format)?,
+                None => kvs.import(io::stdin().lock(), format)?,
+            };
+            println!("Imported {} records", records);
+        }
+        KvsOpt::Restore { .. } | KvsOpt::Rewind { .. } | KvsOpt::Dump { .. } | KvsOpt::Verify { .. } => {
+            unreachable!("handled before opening the store")
+        }
     }
 
     Ok(())
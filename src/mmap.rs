@@ -0,0 +1,53 @@
+//! Read only memory maps of the log
+//!
+//! This is the one place in the crate that uses `unsafe`. Mapping a file is only sound if nothing
+//! changes the mapped bytes while the map is alive. That holds for the log as long as it is only
+//! used through `KvStore`:
+//!
+//!  - records are only ever appended, and we only map the part of the file that has already been
+//!    written, so appends never touch mapped bytes.
+//!  - compaction writes a new file and renames it over the old one instead of rewriting the old
+//!    one in place, so an existing map keeps seeing the old (unchanged) file until it is replaced.
+//!
+//! Other processes modifying `kvs.db` in place while it is open would break this, just as they
+//! would break the index.
+#![allow(unsafe_code)]
+
+use std::fs::File;
+use std::io;
+
+use memmap2::Mmap;
+
+/// A map of the first `len()` bytes of a log file
+#[derive(Debug)]
+pub(crate) struct LogMap {
+    map: Mmap,
+}
+
+impl LogMap {
+    /// map everything that has been written to `f` so far. Returns `None` for empty files, which
+    /// can't be mapped.
+    pub fn new(f: &File) -> io::Result<Option<Self>> {
+        if f.metadata()?.len() == 0 {
+            return Ok(None);
+        }
+
+        // SAFETY: see the module documentation
+        let map = unsafe { Mmap::map(f)? };
+        Ok(Some(LogMap { map }))
+    }
+
+    /// how much of the file is mapped
+    pub fn len(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    /// the mapped bytes starting at `offs`, if `offs` is inside the map
+    pub fn from(&self, offs: u64) -> Option<&[u8]> {
+        if offs < self.len() {
+            Some(&self.map[offs as usize..])
+        } else {
+            None
+        }
+    }
+}
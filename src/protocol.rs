@@ -0,0 +1,191 @@
+//! Wire format spoken between `KvsServer` and `KvsClient`
+//!
+//! A connection starts with both sides sending a `Hello` carrying the protocol version they speak.
+//! If the versions differ the server closes the connection. After that the client sends
+//! `Request`s and reads one `Response` for each, in order. Every message is speedy encoded (little
+//! endian). The hellos have a fixed size; every message after them is preceded by its length as a
+//! little endian u32, at most `MAX_MESSAGE`, so a bad length is caught before anything is
+//! allocated for it.
+
+use std::io::{self, Read, Write};
+
+use snafu::ResultExt;
+use speedy::{LittleEndian, Readable, Writable};
+
+use crate::{KvsError, Network, Protocol, Result};
+
+/// Version of the protocol spoken by this build
+///
+/// Bumped with every change to the messages, since a peer speaking another version can't decode
+/// them: 2 added cluster membership, 3 log shipping, 4 `Scan`, `Namespaces` and the `ReadOnly`,
+/// `Cancelled` and `Raft` error codes, 5 paged snapshots and positions that carry the write's
+/// sequence number, 6 `ScanHashes`, 7 length prefixed messages.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// Largest message, in bytes, either side sends or accepts
+pub(crate) const MAX_MESSAGE: u32 = 64 << 20;
+
+const MAGIC: [u8; 4] = *b"KVS\0";
+
+#[derive(Debug, Readable, Writable)]
+pub(crate) struct Hello {
+    magic: [u8; 4],
+    pub version: u32,
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        Hello { magic: MAGIC, version: PROTOCOL_VERSION }
+    }
+
+    /// is this a hello from something speaking our protocol at all?
+    pub fn is_kvs(&self) -> bool {
+        self.magic == MAGIC
+    }
+}
+
+/// write `message` to `w`, preceded by its length
+pub(crate) fn write_message<T: Writable<LittleEndian>>(w: &mut impl Write, message: &T) -> Result<()> {
+    let bytes = message.write_to_vec().context(Protocol)?;
+    if bytes.len() > MAX_MESSAGE as usize {
+        return Err(KvsError::MessageTooLarge { len: bytes.len() as u64, max: MAX_MESSAGE });
+    }
+    w.write_all(&(bytes.len() as u32).to_le_bytes()).and_then(|_| w.write_all(&bytes)).context(Network)
+}
+
+/// read a message written by `write_message`, `None` if the connection was closed before it
+pub(crate) fn read_message<T: for<'a> Readable<'a, LittleEndian>>(r: &mut impl Read) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).context(Network),
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_MESSAGE {
+        return Err(KvsError::MessageTooLarge { len: len.into(), max: MAX_MESSAGE });
+    }
+
+    let mut bytes = vec![0; len as usize];
+    r.read_exact(&mut bytes).context(Network)?;
+    T::read_from_buffer(&bytes).map(Some).context(Protocol)
+}
+
+/// A place in one version of a server's log, for log shipping, and the sequence number of the
+/// last write before it, which still means something once that version of the log is gone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Readable, Writable)]
+pub(crate) struct LogPosition {
+    pub log_id: u64,
+    pub offset: u64,
+    pub seq: u64,
+}
+
+#[derive(Debug, Readable, Writable)]
+pub(crate) enum Request {
+    Get { ns: String, key: String },
+    Set { ns: String, key: String, value: String },
+    Remove { ns: String, key: String },
+    // cluster membership, only understood by `RaftNode`
+    AddMember { raft_addr: String, client_addr: String },
+    RemoveMember { raft_addr: String },
+    // log shipping to a `Follower`, `None` asks for a snapshot
+    Replicate { from: Option<LogPosition> },
+    // the next page of a snapshot, after (namespace, key)
+    SnapshotPage { after: (String, String) },
+    // keys starting with `prefix` in key order, starting after `after`
+    Scan { ns: String, prefix: String, after: Option<String>, limit: u32 },
+    // keys whose `HashRing` hash is in one of `ranges` (inclusive), the same way
+    ScanHashes { ns: String, ranges: Vec<(u64, u64)>, after: Option<String>, limit: u32 },
+    Namespaces,
+}
+
+#[derive(Debug, Readable, Writable)]
+pub(crate) enum Response {
+    Value(Option<String>),
+    Done,
+    Error { code: u16, message: String },
+    // the writes recorded in the log since the position asked for, each applied atomically
+    Records { to: LogPosition, writes: Vec<Vec<(String, String, Option<String>)>> },
+    // a page of the live (namespace, key, value)s in order, `more` if there are keys after it;
+    // `at` is where the log had got to when the page was read
+    Snapshot { at: LogPosition, pairs: Vec<(String, String, String)>, more: bool },
+    // a page of a scan, `more` if there are keys after it
+    Pairs { pairs: Vec<(String, String)>, more: bool },
+    Names(Vec<String>),
+}
+
+impl Response {
+    pub fn error(e: &KvsError) -> Self {
+        Response::Error { code: ErrorCode::of(e).code(), message: e.to_string() }
+    }
+}
+
+/// Why the server failed a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// the key to remove doesn't exist
+    KeyNotFound,
+    /// the client speaks a different protocol version
+    UnsupportedVersion,
+    /// the request couldn't be decoded
+    BadRequest,
+    /// the store failed to carry out the request
+    Storage,
+    /// the server isn't the leader of its cluster, or is a read-only `Follower`; the message is
+    /// the client address of the leader or primary, or empty if it isn't known right now
+    NotLeader,
+    /// the store can't be written to, it was opened at an earlier point
+    ReadOnly,
+    /// the request was cancelled before it ran
+    Cancelled,
+    /// the server couldn't read or write its own cluster state
+    Raft,
+    /// a code this client doesn't know about, sent by a newer server
+    Unknown(u16),
+}
+
+impl ErrorCode {
+    /// the code for `e`
+    pub fn of(e: &KvsError) -> Self {
+        match e {
+            KvsError::RemoveNonexistentKey { .. } => ErrorCode::KeyNotFound,
+            KvsError::ProtocolVersion { .. } => ErrorCode::UnsupportedVersion,
+            KvsError::Protocol { .. } | KvsError::MessageTooLarge { .. } => ErrorCode::BadRequest,
+            KvsError::Server { code, .. } => *code,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::Cancelled => ErrorCode::Cancelled,
+            KvsError::RaftStorage { .. } | KvsError::RaftDecode { .. } | KvsError::RaftLogGap { .. } => ErrorCode::Raft,
+            _ => ErrorCode::Storage,
+        }
+    }
+
+    /// the number sent on the wire
+    pub fn code(self) -> u16 {
+        match self {
+            ErrorCode::KeyNotFound => 1,
+            ErrorCode::UnsupportedVersion => 2,
+            ErrorCode::BadRequest => 3,
+            ErrorCode::Storage => 4,
+            ErrorCode::NotLeader => 5,
+            ErrorCode::ReadOnly => 6,
+            ErrorCode::Cancelled => 7,
+            ErrorCode::Raft => 8,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+
+    /// the code for a number received on the wire
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::KeyNotFound,
+            2 => ErrorCode::UnsupportedVersion,
+            3 => ErrorCode::BadRequest,
+            4 => ErrorCode::Storage,
+            5 => ErrorCode::NotLeader,
+            6 => ErrorCode::ReadOnly,
+            7 => ErrorCode::Cancelled,
+            8 => ErrorCode::Raft,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+}
@@ -0,0 +1,1281 @@
+//! Replicating a store across a cluster with Raft
+//!
+//! Every node keeps a Raft log (`raft.log`) of commands: the same changes `KvStore::write` makes,
+//! plus changes to the membership of the cluster. Once the leader has a command stored on a
+//! majority of the nodes it is committed, and each node applies it to its own `KvStore`, kept in
+//! `sm/` under the node's directory. That store is rebuilt from the latest snapshot and the log
+//! every time a node starts, so it never needs to be synced itself.
+//!
+//! A snapshot is the store's log straight after compaction: every live key and nothing else. Once
+//! enough commands have been applied since the last one, a node compacts its store, saves the
+//! result as `raft.snapshot` along with the membership at that point, and drops the log entries
+//! it covers. Followers too far behind the leader's log are sent the snapshot instead, a piece
+//! of the file at a time. The store is only locked long enough to note where its log ends; it's
+//! copied and compacted in the background, the same way `KvStore::backup` works.
+//!
+//! Membership changes one node at a time, and each change takes effect on a node as soon as it is
+//! in its log (section 4.1 of Diego Ongaro's thesis). Only one change can be in progress at once.
+//!
+//! Reads are answered by the leader from its own store, as long as a majority of the cluster has
+//! acknowledged it within the last election timeout. Followers don't vote for anyone else that
+//! soon after hearing from a leader, so no other leader can have been elected in the meantime.
+//! This relies on clocks on every node advancing at about the same rate.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use snafu::ResultExt;
+use speedy::{Readable, Writable};
+
+use crate::protocol::{read_message, write_message, ErrorCode, Request, Response};
+use crate::server::{read, serve_connection};
+use crate::thread_pool::{NaiveThreadPool, ThreadPool};
+use crate::{Bind, KvStore, KvsError, Network, RaftDecode, RaftStorage, Result};
+
+const STATE_FILE: &str = "raft.state";
+const LOG_FILE: &str = "raft.log";
+const SNAPSHOT_FILE: &str = "raft.snapshot";
+// a snapshot being taken, and one being received from the leader
+const SNAPSHOT_NEW: &str = "raft.snapshot.new";
+const SNAPSHOT_PART: &str = "raft.snapshot.part";
+// the copy of the store a snapshot is compacted in
+const SNAPSHOT_DIR: &str = "snapshot";
+const STORE_DIR: &str = "sm";
+
+// sent by a node when it connects to another, so a client connecting to the wrong port is caught
+const MAGIC: [u8; 4] = *b"KVR\0";
+
+// most entries sent to a follower at once
+const MAX_BATCH: usize = 256;
+// most bytes of a snapshot sent to a follower at once
+const SNAPSHOT_CHUNK: u64 = 1 << 20;
+// how long a client waits for its command to be committed
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A node of a cluster, as the other nodes and clients know it
+#[derive(Debug, Clone, PartialEq, Eq, Readable, Writable)]
+pub struct Member {
+    /// address the node listens on for other nodes, which also identifies it
+    pub raft_addr: String,
+    /// address the node listens on for clients
+    pub client_addr: String,
+}
+
+impl Member {
+    /// a node listening on `raft_addr` for other nodes and `client_addr` for clients
+    pub fn new(raft_addr: impl Into<String>, client_addr: impl Into<String>) -> Self {
+        Member { raft_addr: raft_addr.into(), client_addr: client_addr.into() }
+    }
+}
+
+// (namespace, key, value), `None` removes
+type Ops = Vec<(String, String, Option<String>)>;
+
+#[derive(Debug, Clone, Readable, Writable)]
+enum Command {
+    // appended by each new leader, so that it commits something in its own term
+    Noop,
+    Write(Ops),
+    // the complete new membership
+    Config(Vec<Member>),
+}
+
+#[derive(Debug, Clone, Readable, Writable)]
+struct Entry {
+    index: u64,
+    term: u64,
+    command: Command,
+}
+
+// what a node has to remember across restarts besides its log
+#[derive(Debug, Default, Readable, Writable)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+#[derive(Debug, Clone, Readable, Writable)]
+struct Snapshot {
+    // last entry the snapshot covers
+    index: u64,
+    term: u64,
+    config: Vec<Member>,
+    // the store's log, compacted
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Readable, Writable)]
+enum Message {
+    Vote { term: u64, candidate: String, last_index: u64, last_term: u64 },
+    Append { term: u64, leader: String, prev_index: u64, prev_term: u64, entries: Vec<Entry>, commit: u64 },
+    // the bytes of the leader's `raft.snapshot` from `offset`, for the snapshot of entries up to
+    // `index`, which has term `last_term`; `done` if they are the last
+    Snapshot { term: u64, leader: String, index: u64, last_term: u64, offset: u64, data: Vec<u8>, done: bool },
+}
+
+#[derive(Debug, Readable, Writable)]
+enum Reply {
+    Vote { term: u64, granted: bool },
+    // `next` is the index the leader should send from next
+    Append { term: u64, success: bool, next: u64 },
+    // `next` is the offset the follower wants next, unless it has `installed` the snapshot
+    Snapshot { term: u64, installed: bool, next: u64 },
+}
+
+/// write `value` to `name` in `dir` so that after a crash either the old or new contents are there
+fn save<T: Writable<speedy::LittleEndian>>(dir: &Path, name: &str, value: &T) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", name));
+    write_synced(&tmp, value)?;
+    replace(dir, &tmp, name)
+}
+
+/// write `value` to a new file at `path`, on disk before returning
+fn write_synced<T: Writable<speedy::LittleEndian>>(path: &Path, value: &T) -> Result<()> {
+    let bytes = value.write_to_vec().context(RaftDecode { filename: path.to_owned() })?;
+    let mut f = File::create(path).context(RaftStorage { filename: path.to_owned() })?;
+    f.write_all(&bytes).and_then(|_| f.sync_all()).context(RaftStorage { filename: path.to_owned() })
+}
+
+/// rename `from` over `name` in `dir`, durably
+fn replace(dir: &Path, from: &Path, name: &str) -> Result<()> {
+    let path = dir.join(name);
+    fs::rename(from, &path).context(RaftStorage { filename: path })?;
+    sync_dir(dir)
+}
+
+fn load<T: for<'a> Readable<'a, speedy::LittleEndian>>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(T::read_from_buffer(&bytes).context(RaftDecode { filename: path.to_owned() })?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context(RaftStorage { filename: path.to_owned() }),
+    }
+}
+
+/// make a rename in `dir` durable
+fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir).and_then(|d| d.sync_all()).context(RaftStorage { filename: dir.to_owned() })?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// The entries after the latest snapshot, in memory and appended to `raft.log`
+///
+/// Each record in the file is a little endian u32 length followed by a speedy encoded `Entry`. A
+/// record cut short by a crash is dropped when the log is opened.
+struct RaftLog {
+    path: PathBuf,
+    f: File,
+    // last entry covered by the snapshot
+    start_index: u64,
+    start_term: u64,
+    entries: Vec<Entry>,
+}
+
+impl RaftLog {
+    fn open(path: PathBuf, start_index: u64, start_term: u64) -> Result<Self> {
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).context(RaftStorage { filename: path }),
+        };
+
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut pos = 0;
+        while pos + 4 <= bytes.len() {
+            let mut len = [0; 4];
+            len.copy_from_slice(&bytes[pos..pos + 4]);
+            let end = pos + 4 + u32::from_le_bytes(len) as usize;
+            if end > bytes.len() {
+                break;
+            }
+
+            let entry = Entry::read_from_buffer(&bytes[pos + 4..end]).context(RaftDecode { filename: path.clone() })?;
+            pos = end;
+            // already covered by a snapshot saved just before a crash
+            if entry.index <= start_index {
+                continue;
+            }
+            if entry.index != start_index + entries.len() as u64 + 1 {
+                return Err(KvsError::RaftLogGap { filename: path, index: entry.index });
+            }
+            entries.push(entry);
+        }
+
+        let f = fs::OpenOptions::new().create(true).append(true).open(&path)
+            .context(RaftStorage { filename: path.clone() })?;
+        // drop a torn record from the end
+        if (pos as u64) < f.metadata().context(RaftStorage { filename: path.clone() })?.len() {
+            f.set_len(pos as u64).context(RaftStorage { filename: path.clone() })?;
+        }
+
+        Ok(RaftLog { path, f, start_index, start_term, entries })
+    }
+
+    fn last_index(&self) -> u64 {
+        self.start_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.start_term, |e| e.term)
+    }
+
+    /// term of the entry at `index`, `None` if it was dropped for a snapshot or isn't there yet
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.start_index {
+            Some(self.start_term)
+        } else {
+            self.get(index).map(|e| e.term)
+        }
+    }
+
+    fn get(&self, index: u64) -> Option<&Entry> {
+        index.checked_sub(self.start_index + 1).and_then(|i| self.entries.get(i as usize))
+    }
+
+    /// up to `max` entries starting at `index`
+    fn from(&self, index: u64, max: usize) -> Vec<Entry> {
+        let start = (index - self.start_index - 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    fn encode(entries: &[Entry], path: &Path) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            let bytes = entry.write_to_vec().context(RaftDecode { filename: path.to_owned() })?;
+            buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&bytes);
+        }
+        Ok(buf)
+    }
+
+    /// add `entries` to the end of the log, on disk before returning
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let buf = Self::encode(&entries, &self.path)?;
+        self.f.write_all(&buf).and_then(|_| self.f.sync_data())
+            .context(RaftStorage { filename: self.path.clone() })?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// drop the entry at `index` and everything after it
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        self.entries.truncate((index - self.start_index - 1) as usize);
+        self.rewrite()
+    }
+
+    /// drop everything up to and including `index`, which a snapshot now covers. Later entries
+    /// are kept if the log agrees with the snapshot about `index`.
+    fn compact(&mut self, index: u64, term: u64) -> Result<()> {
+        if self.term_at(index) == Some(term) {
+            self.entries.drain(..(index - self.start_index) as usize);
+        } else {
+            self.entries.clear();
+        }
+        self.start_index = index;
+        self.start_term = term;
+        self.rewrite()
+    }
+
+    fn rewrite(&mut self) -> Result<()> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new(".")).to_owned();
+        let tmp = self.path.with_extension("log.tmp");
+        let buf = Self::encode(&self.entries, &self.path)?;
+
+        let mut f = File::create(&tmp).context(RaftStorage { filename: tmp.clone() })?;
+        f.write_all(&buf).and_then(|_| f.sync_all()).context(RaftStorage { filename: tmp.clone() })?;
+        fs::rename(&tmp, &self.path).context(RaftStorage { filename: self.path.clone() })?;
+        sync_dir(&dir)?;
+
+        self.f = fs::OpenOptions::new().append(true).open(&self.path)
+            .context(RaftStorage { filename: self.path.clone() })?;
+        Ok(())
+    }
+}
+
+/// the store in `dir/sm`, emptied and then filled from a snapshot if there is one
+fn restore_store(dir: &Path, data: Option<&[u8]>) -> Result<KvStore> {
+    let sm = dir.join(STORE_DIR);
+    if sm.exists() {
+        fs::remove_dir_all(&sm).context(RaftStorage { filename: sm.clone() })?;
+    }
+    fs::create_dir_all(&sm).context(RaftStorage { filename: sm.clone() })?;
+    if let Some(data) = data {
+        let log = sm.join("kvs.db");
+        fs::write(&log, data).context(RaftStorage { filename: log })?;
+    }
+
+    KvStore::open(sm)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct State {
+    // kept on disk
+    term: u64,
+    voted_for: Option<String>,
+    log: RaftLog,
+
+    // membership as of the snapshot, and as of the latest entry with the index of that entry
+    snapshot_config: Vec<Member>,
+    config: Vec<Member>,
+    config_index: u64,
+
+    role: Role,
+    // raft address of the current leader, if known
+    leader: Option<String>,
+    commit: u64,
+    applied: u64,
+    store: KvStore,
+
+    // start an election if no leader is heard from by then
+    election_at: Instant,
+    heard_leader: Option<Instant>,
+    votes: HashSet<String>,
+
+    // leader only, by raft address: the next entry to send, the last entry known to be stored,
+    // when the last reply that acknowledged us as leader was sent and which replicator threads
+    // are running
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    acked: HashMap<String, Instant>,
+    replicating: HashSet<String>,
+
+    // entries clients are waiting on, and the response for each once applied
+    waiting: HashSet<u64>,
+    outcomes: HashMap<u64, Response>,
+
+    // a snapshot is being taken in the background
+    snapshotting: bool,
+    // follower only, the snapshot being received from the leader
+    receiving: Option<Receiving>,
+}
+
+/// A snapshot arriving in pieces, written to `raft.snapshot.part`
+struct Receiving {
+    index: u64,
+    term: u64,
+    f: File,
+    len: u64,
+}
+
+/// The leader's `raft.snapshot` as it's being sent to a follower, open so that it stays readable if
+/// a newer snapshot replaces it
+struct Sending {
+    index: u64,
+    term: u64,
+    f: File,
+    len: u64,
+    offset: u64,
+}
+
+impl State {
+    fn is_voter(&self, raft_addr: &str) -> bool {
+        self.config.iter().any(|m| m.raft_addr == raft_addr)
+    }
+
+    /// do the members in `agree` make up a majority of the cluster?
+    fn majority(&self, agree: impl Fn(&Member) -> bool) -> bool {
+        let count = self.config.iter().filter(|m| agree(m)).count();
+        !self.config.is_empty() && count * 2 > self.config.len()
+    }
+
+    /// the membership from the entries up to and including `index`
+    fn config_at(&self, index: u64) -> Vec<Member> {
+        self.log.entries.iter().rev()
+            .filter(|e| e.index <= index)
+            .find_map(|e| match &e.command {
+                Command::Config(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot_config.clone())
+    }
+
+    fn update_config(&mut self) {
+        let latest = self.log.entries.iter().rev().find_map(|e| match &e.command {
+            Command::Config(members) => Some((members.clone(), e.index)),
+            _ => None,
+        });
+        let (config, index) = latest.unwrap_or_else(|| (self.snapshot_config.clone(), self.log.start_index));
+        self.config = config;
+        self.config_index = index;
+    }
+}
+
+/// Settings for a node of a cluster, and the way to start one
+///
+/// ```no_run
+/// # use kvs::{Member, RaftOptions};
+/// let cluster = vec![
+///     Member::new("10.0.0.1:4100", "10.0.0.1:4000"),
+///     Member::new("10.0.0.2:4100", "10.0.0.2:4000"),
+///     Member::new("10.0.0.3:4100", "10.0.0.3:4000"),
+/// ];
+/// let node = RaftOptions::new().start("/var/lib/kvs", cluster[0].clone(), cluster.clone())?;
+/// node.run("10.0.0.1:4000")?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    heartbeat: Duration,
+    election_timeout: Duration,
+    snapshot_after: u64,
+}
+
+impl Default for RaftOptions {
+    fn default() -> Self {
+        RaftOptions {
+            heartbeat: Duration::from_millis(50),
+            election_timeout: Duration::from_millis(500),
+            snapshot_after: 10_000,
+        }
+    }
+}
+
+impl RaftOptions {
+    /// Default settings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How often the leader contacts followers when it has nothing new to send
+    pub fn heartbeat(&mut self, interval: Duration) -> &mut Self {
+        self.heartbeat = interval;
+        self
+    }
+
+    /// How long a follower waits to hear from a leader before starting an election. Each node
+    /// picks a random wait between this and twice this.
+    pub fn election_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.election_timeout = timeout;
+        self
+    }
+
+    /// Take a snapshot once this many commands have been applied since the last one
+    pub fn snapshot_after(&mut self, commands: u64) -> &mut Self {
+        self.snapshot_after = commands.max(1);
+        self
+    }
+
+    /// Start the node `me`, keeping its files in `dir`
+    ///
+    /// `cluster` is the membership to start a new cluster with, the same on every node and
+    /// including `me`. It is ignored once the node has joined a cluster. A node started with an
+    /// empty `cluster` waits to be added to an existing one with `KvsClient::add_member`.
+    ///
+    /// The node talks to other nodes from background threads for as long as the process runs;
+    /// serving clients is up to `RaftNode::run`.
+    pub fn start(&self, dir: impl Into<PathBuf>, me: Member, cluster: Vec<Member>) -> Result<RaftNode> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).context(RaftStorage { filename: dir.clone() })?;
+
+        let hard: HardState = load(&dir.join(STATE_FILE))?.unwrap_or_default();
+        let snapshot: Option<Snapshot> = load(&dir.join(SNAPSHOT_FILE))?;
+        let (start_index, start_term) = snapshot.as_ref().map_or((0, 0), |s| (s.index, s.term));
+        let mut log = RaftLog::open(dir.join(LOG_FILE), start_index, start_term)?;
+        if log.last_index() == 0 && !cluster.is_empty() {
+            // every node of a new cluster starts with the same first entry, so their logs agree
+            log.append(vec![Entry { index: 1, term: 0, command: Command::Config(cluster) }])?;
+        }
+        let store = restore_store(&dir, snapshot.as_ref().map(|s| s.data.as_slice()))?;
+
+        let listener = TcpListener::bind(&me.raft_addr).context(Bind)?;
+        let mut state = State {
+            term: hard.term,
+            voted_for: hard.voted_for,
+            log,
+            snapshot_config: snapshot.map(|s| s.config).unwrap_or_default(),
+            config: Vec::new(),
+            config_index: 0,
+            role: Role::Follower,
+            leader: None,
+            commit: start_index,
+            applied: start_index,
+            store,
+            election_at: Instant::now(),
+            heard_leader: None,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            acked: HashMap::new(),
+            replicating: HashSet::new(),
+            waiting: HashSet::new(),
+            outcomes: HashMap::new(),
+            snapshotting: false,
+            receiving: None,
+        };
+        state.update_config();
+
+        let node = Arc::new(Node { me, dir, opts: self.clone(), state: Mutex::new(state), changed: Condvar::new() });
+        {
+            let mut st = node.lock();
+            st.election_at = node.election_deadline();
+        }
+
+        let peers = node.clone();
+        thread::spawn(move || peers.listen(listener));
+        let ticker = node.clone();
+        thread::spawn(move || ticker.tick());
+
+        Ok(RaftNode { node })
+    }
+}
+
+struct Node {
+    me: Member,
+    dir: PathBuf,
+    opts: RaftOptions,
+    state: Mutex<State>,
+    // signalled whenever the state changes
+    changed: Condvar,
+}
+
+/// A connection to another node, reconnecting as needed
+struct Peer {
+    addr: String,
+    conn: Option<(BufReader<TcpStream>, BufWriter<TcpStream>)>,
+}
+
+impl Peer {
+    fn new(addr: &str) -> Self {
+        Peer { addr: addr.to_owned(), conn: None }
+    }
+
+    fn connect(&self) -> io::Result<(BufReader<TcpStream>, BufWriter<TcpStream>)> {
+        let addr = self.addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address did not resolve"))?;
+        let stream = TcpStream::connect_timeout(&addr, RPC_TIMEOUT)?;
+        stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+        stream.set_write_timeout(Some(RPC_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        writer.write_all(&MAGIC)?;
+        Ok((BufReader::new(stream), writer))
+    }
+
+    fn call(&mut self, message: &Message) -> Result<Reply> {
+        if self.conn.is_none() {
+            self.conn = Some(self.connect().context(Network)?);
+        }
+        let (reader, writer) = self.conn.as_mut().expect("just connected");
+
+        let reply = write_message(&mut *writer, message)
+            .and_then(|_| writer.flush().context(Network))
+            .and_then(|_| read_message(reader))
+            .and_then(|reply| reply.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof)).context(Network));
+        if reply.is_err() {
+            self.conn = None;
+        }
+        reply
+    }
+}
+
+impl Node {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // every change to the state is made so that it's consistent at each step
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn election_deadline(&self) -> Instant {
+        let mut random = [0; 8];
+        // without randomness nodes just collide more often
+        let _ = getrandom::getrandom(&mut random);
+        let range = self.opts.election_timeout.as_millis().max(1) as u64;
+        let jitter = Duration::from_millis(u64::from_le_bytes(random) % range);
+        Instant::now() + self.opts.election_timeout + jitter
+    }
+
+    fn persist(&self, st: &State) -> Result<()> {
+        save(&self.dir, STATE_FILE, &HardState { term: st.term, voted_for: st.voted_for.clone() })
+    }
+
+    /// move to `term` if it's newer than ours, as a follower
+    fn observe_term(&self, st: &mut State, term: u64) -> Result<()> {
+        if term > st.term {
+            st.term = term;
+            st.voted_for = None;
+            st.role = Role::Follower;
+            st.leader = None;
+            self.persist(st)?;
+        }
+        Ok(())
+    }
+
+    /// does a majority still follow us? see the module documentation
+    fn lease_valid(&self, st: &State) -> bool {
+        let now = Instant::now();
+        st.role == Role::Leader && st.majority(|m| {
+            m.raft_addr == self.me.raft_addr
+                || st.acked.get(&m.raft_addr).is_some_and(|&at| now.duration_since(at) < self.opts.election_timeout)
+        })
+    }
+
+    fn tick(self: Arc<Self>) {
+        let interval = (self.opts.heartbeat / 5).max(Duration::from_millis(5));
+        loop {
+            thread::sleep(interval);
+            let mut st = self.lock();
+            if st.role != Role::Leader && Instant::now() >= st.election_at && st.is_voter(&self.me.raft_addr) {
+                if let Err(e) = self.start_election(&mut st) {
+                    log::error!("starting an election failed: {}", e);
+                }
+            }
+
+            if !st.snapshotting && st.applied - st.log.start_index >= self.opts.snapshot_after {
+                st.snapshotting = true;
+                let node = self.clone();
+                thread::spawn(move || {
+                    if let Err(e) = node.snapshot() {
+                        log::error!("taking a snapshot failed: {}", e);
+                    }
+                    node.lock().snapshotting = false;
+                });
+            }
+        }
+    }
+
+    fn start_election(self: &Arc<Self>, st: &mut State) -> Result<()> {
+        st.term += 1;
+        st.voted_for = Some(self.me.raft_addr.clone());
+        st.role = Role::Candidate;
+        st.leader = None;
+        st.election_at = self.election_deadline();
+        self.persist(st)?;
+
+        st.votes = std::iter::once(self.me.raft_addr.clone()).collect();
+        if st.majority(|m| st.votes.contains(&m.raft_addr)) {
+            return self.become_leader(st);
+        }
+
+        let term = st.term;
+        let message = Message::Vote {
+            term,
+            candidate: self.me.raft_addr.clone(),
+            last_index: st.log.last_index(),
+            last_term: st.log.last_term(),
+        };
+        for member in st.config.iter().filter(|m| m.raft_addr != self.me.raft_addr) {
+            let node = self.clone();
+            let message = message.clone();
+            let addr = member.raft_addr.clone();
+            thread::spawn(move || {
+                if let Ok(Reply::Vote { term: reply_term, granted }) = Peer::new(&addr).call(&message) {
+                    let mut st = node.lock();
+                    if let Err(e) = node.on_vote(&mut st, term, &addr, reply_term, granted) {
+                        log::error!("counting a vote failed: {}", e);
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn on_vote(self: &Arc<Self>, st: &mut State, term: u64, from: &str, reply_term: u64, granted: bool) -> Result<()> {
+        self.observe_term(st, reply_term)?;
+        if st.role != Role::Candidate || st.term != term || !granted {
+            return Ok(());
+        }
+
+        st.votes.insert(from.to_owned());
+        if st.majority(|m| st.votes.contains(&m.raft_addr)) {
+            self.become_leader(st)?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(self: &Arc<Self>, st: &mut State) -> Result<()> {
+        st.role = Role::Leader;
+        st.leader = Some(self.me.raft_addr.clone());
+        st.next_index.clear();
+        st.match_index.clear();
+        st.acked.clear();
+        st.replicating.clear();
+        let next = st.log.last_index() + 1;
+        for member in &st.config {
+            st.next_index.insert(member.raft_addr.clone(), next);
+        }
+
+        self.append(st, Command::Noop)?;
+        Ok(())
+    }
+
+    /// append `command` to the leader's log, returning its index
+    fn append(self: &Arc<Self>, st: &mut State, command: Command) -> Result<u64> {
+        let index = st.log.last_index() + 1;
+        st.log.append(vec![Entry { index, term: st.term, command }])?;
+        st.update_config();
+        self.start_replicating(st);
+        self.advance_commit(st)?;
+        self.changed.notify_all();
+        Ok(index)
+    }
+
+    /// start sending entries to every member that isn't being sent them yet
+    fn start_replicating(self: &Arc<Self>, st: &mut State) {
+        let next = st.log.last_index() + 1;
+        let term = st.term;
+        for member in &st.config {
+            let addr = &member.raft_addr;
+            if *addr == self.me.raft_addr || st.replicating.contains(addr) {
+                continue;
+            }
+
+            st.replicating.insert(addr.clone());
+            st.next_index.entry(addr.clone()).or_insert(next);
+            let node = self.clone();
+            let addr = addr.clone();
+            thread::spawn(move || node.replicate(addr, term));
+        }
+    }
+
+    /// keep the follower at `addr` up to date for as long as we lead in `term`
+    fn replicate(self: Arc<Self>, addr: String, term: u64) {
+        let mut peer = Peer::new(&addr);
+        let mut last_sent: Option<Instant> = None;
+        let mut sending: Option<Sending> = None;
+
+        loop {
+            let mut st = self.lock();
+            loop {
+                if st.role != Role::Leader || st.term != term || !st.is_voter(&addr) {
+                    if st.term == term {
+                        st.replicating.remove(&addr);
+                    }
+                    return;
+                }
+
+                let behind = st.next_index[&addr] <= st.log.last_index();
+                let since = last_sent.map_or(self.opts.heartbeat, |at| at.elapsed());
+                if behind || since >= self.opts.heartbeat {
+                    break;
+                }
+                st = self.changed.wait_timeout(st, self.opts.heartbeat - since)
+                    .unwrap_or_else(|e| e.into_inner()).0;
+            }
+
+            let next = st.next_index[&addr];
+            let message = if next <= st.log.start_index {
+                // the snapshot file always covers the entries up to the start of the log
+                if sending.as_ref().is_none_or(|s| s.index != st.log.start_index) {
+                    sending = match self.open_snapshot(&st) {
+                        Ok(s) => Some(s),
+                        Err(e) => {
+                            log::error!("no snapshot to send to {}: {}", addr, e);
+                            drop(st);
+                            thread::sleep(self.opts.heartbeat);
+                            continue;
+                        }
+                    };
+                }
+                drop(st);
+
+                let s = sending.as_mut().expect("just opened");
+                match self.snapshot_chunk(term, s) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        log::error!("reading the snapshot to send to {} failed: {}", addr, e);
+                        sending = None;
+                        thread::sleep(self.opts.heartbeat);
+                        continue;
+                    }
+                }
+            } else {
+                sending = None;
+                let message = Message::Append {
+                    term,
+                    leader: self.me.raft_addr.clone(),
+                    prev_index: next - 1,
+                    prev_term: st.log.term_at(next - 1).expect("entries after the snapshot are in the log"),
+                    entries: st.log.from(next, MAX_BATCH),
+                    commit: st.commit,
+                };
+                drop(st);
+                message
+            };
+
+            let sent = Instant::now();
+            last_sent = Some(sent);
+            let reply = match peer.call(&message) {
+                Ok(reply) => reply,
+                Err(_) => {
+                    // down or unreachable, keep trying at the heartbeat rate
+                    thread::sleep(self.opts.heartbeat);
+                    continue;
+                }
+            };
+
+            if let (Some(s), Reply::Snapshot { installed: false, next, .. }) = (sending.as_mut(), &reply) {
+                s.offset = *next;
+            }
+            let mut st = self.lock();
+            if let Err(e) = self.on_reply(&mut st, term, &addr, &message, reply, sent) {
+                log::warn!("handling a reply from {} failed: {}", addr, e);
+            }
+        }
+    }
+
+    fn on_reply(self: &Arc<Self>, st: &mut State, term: u64, addr: &str, message: &Message, reply: Reply, sent: Instant) -> Result<()> {
+        let (reply_term, matched, next) = match (message, reply) {
+            (_, Reply::Append { term, success: true, next }) => (term, Some(next - 1), next),
+            (_, Reply::Append { term, success: false, next }) => {
+                let current = st.next_index.get(addr).copied().unwrap_or(1);
+                (term, None, next.min(current.saturating_sub(1)).max(1))
+            }
+            (Message::Snapshot { index, .. }, Reply::Snapshot { term, installed: true, .. }) => (term, Some(*index), index + 1),
+            (Message::Snapshot { .. }, Reply::Snapshot { term, installed: false, .. }) => {
+                (term, None, st.next_index.get(addr).copied().unwrap_or(1))
+            }
+            _ => return Ok(()),
+        };
+
+        self.observe_term(st, reply_term)?;
+        if st.role != Role::Leader || st.term != term {
+            return Ok(());
+        }
+
+        // any reply in our term acknowledges us as leader
+        st.acked.insert(addr.to_owned(), sent);
+        st.next_index.insert(addr.to_owned(), next);
+        if let Some(matched) = matched {
+            let current = st.match_index.entry(addr.to_owned()).or_insert(0);
+            *current = (*current).max(matched);
+            self.advance_commit(st)?;
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// commit the newest entry of our term that a majority has stored
+    fn advance_commit(self: &Arc<Self>, st: &mut State) -> Result<()> {
+        let last = st.log.last_index();
+        let me = &self.me.raft_addr;
+        let committed = (st.commit + 1..=last).rev()
+            .take_while(|&n| st.log.term_at(n) == Some(st.term))
+            .find(|&n| st.majority(|m| {
+                if m.raft_addr == *me { last >= n } else { st.match_index.get(&m.raft_addr).is_some_and(|&i| i >= n) }
+            }));
+
+        if let Some(index) = committed {
+            st.commit = index;
+            self.apply(st)?;
+
+            // a leader removed from the cluster hands over once that's committed
+            if st.config_index <= st.commit && !st.is_voter(me) {
+                st.role = Role::Follower;
+                st.leader = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// apply committed entries to the store
+    fn apply(&self, st: &mut State) -> Result<()> {
+        while st.applied < st.commit {
+            let index = st.applied + 1;
+            let command = st.log.get(index).expect("committed entries are in the log").command.clone();
+            let outcome = match command {
+                Command::Write(ops) => match st.store.write_ops(ops) {
+                    Ok(()) => Response::Done,
+                    Err(e) => Response::error(&e),
+                },
+                Command::Noop | Command::Config(_) => Response::Done,
+            };
+
+            st.applied = index;
+            if st.waiting.remove(&index) {
+                st.outcomes.insert(index, outcome);
+            }
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// snapshot everything applied so far, only holding the lock to note what that is and again
+    /// to swap the snapshot in
+    fn snapshot(&self) -> Result<()> {
+        let (backup, index, term, config) = {
+            let mut st = self.lock();
+            let index = st.applied;
+            let term = st.log.term_at(index).expect("applied entries are in the log");
+            (st.store.backup()?, index, term, st.config_at(index))
+        };
+
+        let copy = self.dir.join(SNAPSHOT_DIR);
+        if copy.exists() {
+            fs::remove_dir_all(&copy).context(RaftStorage { filename: copy.clone() })?;
+        }
+        backup.write_to(&copy)?;
+        let mut store = KvStore::open(&copy)?;
+        store.compact(None)?;
+        let filename = store.log_f_name.clone();
+        drop(store);
+        let data = fs::read(&filename).context(RaftStorage { filename })?;
+        let new = self.dir.join(SNAPSHOT_NEW);
+        write_synced(&new, &Snapshot { index, term, config: config.clone(), data })?;
+        fs::remove_dir_all(&copy).context(RaftStorage { filename: copy })?;
+
+        let mut st = self.lock();
+        // a newer snapshot may have arrived from the leader in the meantime
+        if index <= st.log.start_index {
+            return fs::remove_file(&new).context(RaftStorage { filename: new });
+        }
+        replace(&self.dir, &new, SNAPSHOT_FILE)?;
+        st.log.compact(index, term)?;
+        st.snapshot_config = config;
+        Ok(())
+    }
+
+    /// open the snapshot covering the entries up to the start of the log, to send to a follower
+    fn open_snapshot(&self, st: &State) -> Result<Sending> {
+        let filename = self.dir.join(SNAPSHOT_FILE);
+        let f = File::open(&filename).context(RaftStorage { filename: filename.clone() })?;
+        let len = f.metadata().context(RaftStorage { filename })?.len();
+        Ok(Sending { index: st.log.start_index, term: st.log.start_term, f, len, offset: 0 })
+    }
+
+    /// the next piece of the snapshot being sent
+    fn snapshot_chunk(&self, term: u64, s: &mut Sending) -> Result<Message> {
+        let filename = self.dir.join(SNAPSHOT_FILE);
+        let offset = s.offset.min(s.len);
+        let mut data = Vec::new();
+        s.f.seek(io::SeekFrom::Start(offset))
+            .and_then(|_| (&mut s.f).take(SNAPSHOT_CHUNK).read_to_end(&mut data))
+            .context(RaftStorage { filename })?;
+        let done = offset + data.len() as u64 >= s.len;
+        Ok(Message::Snapshot { term, leader: self.me.raft_addr.clone(), index: s.index, last_term: s.term, offset, data, done })
+    }
+
+    fn listen(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("accepting a connection from another node failed: {}", e);
+                    continue;
+                }
+            };
+
+            let node = self.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = node.serve_peer(stream) {
+                    log::warn!("connection from node {:?} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    fn serve_peer(&self, stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true).context(Network)?;
+        let mut reader = BufReader::new(stream.try_clone().context(Network)?);
+        let mut writer = BufWriter::new(stream);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).context(Network)?;
+        if magic != MAGIC {
+            return Ok(());
+        }
+
+        loop {
+            let message = match read_message::<Message>(&mut reader)? {
+                Some(message) => message,
+                None => return Ok(()),
+            };
+
+            let reply = {
+                let mut st = self.lock();
+                let reply = self.on_message(&mut st, message);
+                self.changed.notify_all();
+                reply?
+            };
+            write_message(&mut writer, &reply)?;
+            writer.flush().context(Network)?;
+        }
+    }
+
+    /// we've heard from the leader of `term`
+    fn follow(&self, st: &mut State, term: u64, leader: String) -> Result<()> {
+        self.observe_term(st, term)?;
+        st.role = Role::Follower;
+        st.leader = Some(leader);
+        st.heard_leader = Some(Instant::now());
+        st.election_at = self.election_deadline();
+        Ok(())
+    }
+
+    fn on_message(&self, st: &mut State, message: Message) -> Result<Reply> {
+        match message {
+            Message::Vote { term, candidate, last_index, last_term } => {
+                // stay with a leader we've heard from recently rather than let a node that
+                // can't hear it (or has been removed) start a new term
+                let recent = st.heard_leader.is_some_and(|at| at.elapsed() < self.opts.election_timeout);
+                if term > st.term && ((st.role == Role::Follower && recent) || self.lease_valid(st)) {
+                    return Ok(Reply::Vote { term: st.term, granted: false });
+                }
+
+                self.observe_term(st, term)?;
+                let up_to_date = (last_term, last_index) >= (st.log.last_term(), st.log.last_index());
+                let free = st.voted_for.as_ref().is_none_or(|v| *v == candidate);
+                let granted = term == st.term && free && up_to_date;
+                if granted {
+                    st.voted_for = Some(candidate);
+                    self.persist(st)?;
+                    st.election_at = self.election_deadline();
+                }
+                Ok(Reply::Vote { term: st.term, granted })
+            }
+
+            Message::Append { term, leader, prev_index, prev_term, entries, commit } => {
+                if term < st.term {
+                    return Ok(Reply::Append { term: st.term, success: false, next: 0 });
+                }
+                self.follow(st, term, leader)?;
+
+                let last_new = prev_index + entries.len() as u64;
+                if prev_index > st.log.last_index() {
+                    return Ok(Reply::Append { term: st.term, success: false, next: st.log.last_index() + 1 });
+                }
+                // entries a snapshot covers are committed, so they match the leader's
+                let (prev_index, prev_term) = if prev_index < st.log.start_index {
+                    (st.log.start_index, st.log.start_term)
+                } else {
+                    (prev_index, prev_term)
+                };
+                if st.log.term_at(prev_index) != Some(prev_term) {
+                    return Ok(Reply::Append { term: st.term, success: false, next: prev_index });
+                }
+
+                let mut new = Vec::new();
+                for entry in entries.into_iter().filter(|e| e.index > prev_index) {
+                    if new.is_empty() {
+                        match st.log.term_at(entry.index) {
+                            Some(t) if t == entry.term => continue,
+                            Some(_) => st.log.truncate(entry.index)?,
+                            None => {}
+                        }
+                    }
+                    new.push(entry);
+                }
+                if !new.is_empty() {
+                    st.log.append(new)?;
+                }
+                st.update_config();
+
+                if commit > st.commit {
+                    st.commit = commit.min(last_new).max(st.commit);
+                    self.apply(st)?;
+                }
+                Ok(Reply::Append { term: st.term, success: true, next: last_new + 1 })
+            }
+
+            Message::Snapshot { term, leader, index, last_term, offset, data, done } => {
+                if term < st.term {
+                    return Ok(Reply::Snapshot { term: st.term, installed: false, next: 0 });
+                }
+                self.follow(st, term, leader)?;
+                if index <= st.commit {
+                    return Ok(Reply::Snapshot { term: st.term, installed: true, next: 0 });
+                }
+
+                // pieces of any other snapshot are thrown away, and this one is asked for from
+                // the start
+                let have = st.receiving.as_ref().filter(|r| r.index == index && r.term == last_term).map_or(0, |r| r.len);
+                if offset != have {
+                    return Ok(Reply::Snapshot { term: st.term, installed: false, next: have });
+                }
+                let part = self.dir.join(SNAPSHOT_PART);
+                if offset == 0 {
+                    let f = File::create(&part).context(RaftStorage { filename: part.clone() })?;
+                    st.receiving = Some(Receiving { index, term: last_term, f, len: 0 });
+                }
+                let receiving = st.receiving.as_mut().expect("started above");
+                receiving.f.write_all(&data).context(RaftStorage { filename: part.clone() })?;
+                receiving.len += data.len() as u64;
+                if !done {
+                    return Ok(Reply::Snapshot { term: st.term, installed: false, next: receiving.len });
+                }
+
+                let receiving = st.receiving.take().expect("started above");
+                receiving.f.sync_all().context(RaftStorage { filename: part.clone() })?;
+                drop(receiving);
+                let snapshot: Snapshot = load(&part)?.expect("just written");
+                replace(&self.dir, &part, SNAPSHOT_FILE)?;
+                st.log.compact(snapshot.index, snapshot.term)?;
+                st.store = restore_store(&self.dir, Some(&snapshot.data))?;
+                st.snapshot_config = snapshot.config;
+                st.update_config();
+                st.commit = snapshot.index;
+                st.applied = snapshot.index;
+                Ok(Reply::Snapshot { term: st.term, installed: true, next: 0 })
+            }
+        }
+    }
+
+    fn not_leader(&self, st: &State) -> Response {
+        let leader = st.leader.as_ref()
+            .filter(|l| **l != self.me.raft_addr)
+            .and_then(|l| st.config.iter().find(|m| m.raft_addr == *l))
+            .map_or_else(String::new, |m| m.client_addr.clone());
+        Response::Error { code: ErrorCode::NotLeader.code(), message: leader }
+    }
+
+    fn respond(self: &Arc<Self>, request: Request) -> Response {
+        let mut st = self.lock();
+        if st.role != Role::Leader {
+            return self.not_leader(&st);
+        }
+
+        let command = match request {
+            request @ (Request::Get { .. } | Request::Scan { .. } | Request::ScanHashes { .. } | Request::Namespaces) => {
+                // wait until we've committed something in our term, so everything committed
+                // before we were elected has been applied, and know we're still leader
+                let deadline = Instant::now() + CLIENT_TIMEOUT;
+                while st.log.term_at(st.commit) != Some(st.term) || !self.lease_valid(&st) {
+                    let now = Instant::now();
+                    if st.role != Role::Leader || now >= deadline {
+                        return self.not_leader(&st);
+                    }
+                    st = self.changed.wait_timeout(st, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+                }
+                return read(&mut st.store, request).unwrap_or_else(|e| Response::error(&e));
+            }
+            Request::Set { ns, key, value } => Command::Write(vec![(ns, key, Some(value))]),
+            Request::Remove { ns, key } => Command::Write(vec![(ns, key, None)]),
+            Request::AddMember { raft_addr, client_addr } => {
+                if st.config_index > st.commit {
+                    return bad_request("another membership change is in progress");
+                }
+                if st.is_voter(&raft_addr) {
+                    return bad_request("already a member");
+                }
+                let mut config = st.config.clone();
+                config.push(Member { raft_addr, client_addr });
+                Command::Config(config)
+            }
+            Request::RemoveMember { raft_addr } => {
+                if st.config_index > st.commit {
+                    return bad_request("another membership change is in progress");
+                }
+                if !st.is_voter(&raft_addr) {
+                    return bad_request("not a member");
+                }
+                let config = st.config.iter().filter(|m| m.raft_addr != raft_addr).cloned().collect();
+                Command::Config(config)
+            }
+            Request::Replicate { .. } | Request::SnapshotPage { .. } => return bad_request("cluster nodes don't ship their logs"),
+        };
+
+        let term = st.term;
+        let index = st.log.last_index() + 1;
+        st.waiting.insert(index);
+        if let Err(e) = self.append(&mut st, command) {
+            st.waiting.remove(&index);
+            return Response::error(&e);
+        }
+
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        loop {
+            if let Some(outcome) = st.outcomes.remove(&index) {
+                return outcome;
+            }
+            // the entry was replaced by another leader's, or may be; either way we can't say
+            let now = Instant::now();
+            if st.term != term || st.log.term_at(index) != Some(term) || now >= deadline {
+                st.waiting.remove(&index);
+                return self.not_leader(&st);
+            }
+            st = self.changed.wait_timeout(st, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+}
+
+fn bad_request(message: &str) -> Response {
+    Response::Error { code: ErrorCode::BadRequest.code(), message: message.to_owned() }
+}
+
+/// A running node of a replicated cluster, from `RaftOptions::start`
+///
+/// Clients talk to it with `KvsClient`. Only the leader answers requests; the others fail them
+/// with `ErrorCode::NotLeader`, naming the leader's client address if they know it. A write is
+/// only acknowledged once it is committed, so it survives as long as a majority of the cluster
+/// does. A request that fails because leadership changed may still have been carried out.
+#[derive(Clone)]
+pub struct RaftNode {
+    node: Arc<Node>,
+}
+
+impl fmt::Debug for RaftNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RaftNode")
+            .field("me", &self.node.me)
+            .field("dir", &self.node.dir)
+            .finish()
+    }
+}
+
+impl RaftNode {
+    /// Is this node the leader right now?
+    pub fn is_leader(&self) -> bool {
+        self.node.lock().role == Role::Leader
+    }
+
+    /// The leader this node last heard from, if any
+    pub fn leader(&self) -> Option<Member> {
+        let st = self.node.lock();
+        let leader = st.leader.as_ref()?;
+        st.config.iter().find(|m| m.raft_addr == *leader).cloned()
+    }
+
+    /// The members of the cluster as far as this node knows
+    pub fn members(&self) -> Vec<Member> {
+        self.node.lock().config.clone()
+    }
+
+    /// Listen on `addr` and serve clients until accepting a connection fails
+    pub fn run(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Bind)?;
+        self.serve(listener)
+    }
+
+    /// Listen on `addr` and serve clients with `pool` until accepting a connection fails
+    pub fn run_on(&self, addr: impl ToSocketAddrs, pool: &dyn ThreadPool) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Bind)?;
+        self.serve_on(listener, pool)
+    }
+
+    /// Serve clients connecting to `listener`, each on its own thread
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        self.serve_on(listener, &NaiveThreadPool)
+    }
+
+    /// Serve clients connecting to `listener`, each connection as a job on `pool`
+    pub fn serve_on(&self, listener: TcpListener, pool: &dyn ThreadPool) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream.context(Network)?;
+            let node = self.node.clone();
+            pool.execute(Box::new(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = serve_connection(stream, |request| node.respond(request)) {
+                    log::warn!("connection from {:?} failed: {}", peer, e);
+                }
+            }));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,528 @@
+//! Asynchronous replication by shipping the log from a primary to followers
+//!
+//! A follower asks its primary for the writes recorded in the primary's log past the position it
+//! has reached, applies them to its own `KvStore`, and asks again, waiting a little whenever it
+//! has caught up. A position is an offset in one version of the log, together with the sequence
+//! number of the last write before it. Compaction rewrites the log and reopening starts a new
+//! version of it, so each version gets a random id; when a follower's version is gone the primary
+//! finds its place by sequence number instead, which only fails once compaction has folded the
+//! writes after it into a checkpoint.
+//!
+//! A follower that can't carry on, which includes one that has never followed this primary, is
+//! sent a snapshot of every live key, a page at a time. The pages are read while the primary keeps
+//! writing, so the follower then replays the log from where it was when the first page was read;
+//! a write applied again leaves its keys as the primary has them. Followers keep their position
+//! in their own store, in `REPLICA_NS` and in the same records as the writes they apply, so they
+//! carry on from it after a restart.
+//!
+//! Writes are shipped decoded, with values moved to blob files read back, so a follower's store
+//! can have its own settings and encryption key. Followers serve reads from their own store,
+//! which may be behind the primary's, and ship their own log to followers of their own.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Seek};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use snafu::ResultExt;
+
+use crate::crypto::{self, LogCipher};
+use crate::log::{read_record, LogEntry, LogReader, NsKeys, Op, ReadError};
+use crate::protocol::{ErrorCode, LogPosition, Request, Response};
+use crate::server::{read, serve_connection};
+use crate::thread_pool::{NaiveThreadPool, ThreadPool};
+use crate::{Bind, GetPosition, KvStore, KvsClient, KvsError, LogLookup, Network, Result, ThreadSpawn};
+
+/// Namespace in which a `Follower` keeps its position in each primary's log, under the
+/// primary's address. It isn't shipped on to followers of the follower.
+pub const REPLICA_NS: &str = "replica.position";
+
+// most records, snapshot pairs or changes written at once, and bytes of keys and values shipped
+// at once
+const MAX_RECORDS: usize = 1024;
+const MAX_BYTES: usize = 1 << 20;
+// how long a follower that has caught up waits before asking again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// how long a follower waits after failing to reach its primary
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// (namespace, key, value), `None` removes
+type Ops = Vec<(String, String, Option<String>)>;
+
+/// an id for a new version of a log
+pub(crate) fn new_log_id() -> u64 {
+    let mut random = [0; 8];
+    // the time alone almost never repeats either
+    let _ = getrandom::getrandom(&mut random);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    u64::from_le_bytes(random) ^ nanos
+}
+
+/// Answer a follower that has reached `from` in the log of `store`
+pub(crate) fn ship(store: &mut KvStore, from: Option<LogPosition>) -> Result<Response> {
+    if let Some(from) = from {
+        let offset = if from.log_id == store.log_id {
+            Some(from.offset)
+        } else {
+            store.offset_after(from.seq)?
+        };
+        if let Some(offset) = offset {
+            if let Some((writes, to)) = store.writes_since(offset, from.seq)? {
+                return Ok(Response::Records { to, writes });
+            }
+        }
+    }
+
+    snapshot_page(store, None)
+}
+
+/// Answer a follower wanting the page of a snapshot of `store` after `after`, or the first page
+pub(crate) fn snapshot_page(store: &mut KvStore, after: Option<(String, String)>) -> Result<Response> {
+    let at = store.position()?;
+    let mut pairs = Vec::new();
+    let mut bytes = 0;
+    for ns in store.namespaces() {
+        if ns == REPLICA_NS || after.as_ref().is_some_and(|(after_ns, _)| ns < *after_ns) {
+            continue;
+        }
+
+        let keys = store.keys_in(&ns, "")?;
+        let start = match &after {
+            Some((after_ns, after_key)) if *after_ns == ns => keys.partition_point(|k| k <= after_key),
+            _ => 0,
+        };
+        for key in &keys[start..] {
+            if pairs.len() == MAX_RECORDS || bytes >= MAX_BYTES {
+                return Ok(Response::Snapshot { at, pairs, more: true });
+            }
+            if let Some(value) = store.get_in(&ns, key.clone())? {
+                bytes += key.len() + value.len();
+                pairs.push((ns.clone(), key.clone(), value));
+            }
+        }
+    }
+
+    Ok(Response::Snapshot { at, pairs, more: false })
+}
+
+/// read the record at `offs` in the log `filename` from `r`
+fn next_entry(r: &mut impl BufRead, cipher: Option<&LogCipher>, filename: &Path, offs: u64) -> Result<LogEntry> {
+    match read_record(r, cipher, offs) {
+        Ok(entry) => Ok(entry),
+        Err(ReadError::Parse(e)) => Err(e).context(LogLookup { key: String::new(), filename: filename.to_owned(), offs }),
+        Err(ReadError::Authentication) => Err(KvsError::Authentication { filename: filename.to_owned(), offs }),
+        Err(ReadError::Checksum) => Err(KvsError::Checksum { filename: filename.to_owned(), offs }),
+    }
+}
+
+/// how a position is kept in `REPLICA_NS`
+fn encode_position(at: LogPosition) -> String {
+    format!("{} {} {}", at.log_id, at.offset, at.seq)
+}
+
+fn decode_position(s: &str) -> Option<LogPosition> {
+    let mut fields = s.split(' ').map(|f| f.parse().ok());
+    let mut next = || fields.next().flatten();
+    Some(LogPosition { log_id: next()?, offset: next()?, seq: next()? })
+}
+
+impl KvStore {
+    fn log_end(&mut self) -> Result<u64> {
+        self.log_f.seek(io::SeekFrom::End(0))
+            .context(GetPosition { filename: self.log_f_name.clone() })
+    }
+
+    /// the end of the log, as a follower that has applied everything in it would have reached
+    fn position(&mut self) -> Result<LogPosition> {
+        Ok(LogPosition { log_id: self.log_id, offset: self.log_end()?, seq: self.last_seq })
+    }
+
+    /// Where the write after write number `seq` starts in the log. `None` if the log doesn't
+    /// hold every write since: compaction has folded some of them into a checkpoint, the log
+    /// never reached `seq`, or it holds writes from before writes were numbered.
+    fn offset_after(&mut self, seq: u64) -> Result<Option<u64>> {
+        let end = self.log_end()?;
+        if seq >= self.last_seq {
+            return Ok(Some(end).filter(|_| seq == self.last_seq));
+        }
+
+        let mut offs = if self.cipher.is_some() { crypto::HEADER_LEN } else { 0 };
+        self.log_f.seek(io::SeekFrom::Start(offs))
+            .context(GetPosition { filename: self.log_f_name.clone() })?;
+        let mut r = BufReader::with_capacity(8192, &mut self.log_f);
+
+        // records compaction copied come after a checkpoint, and are no newer than it
+        let mut checkpointed = false;
+        while offs < end {
+            match next_entry(&mut r, self.cipher.as_ref(), &self.log_f_name, offs)? {
+                LogEntry::Checkpoint { seq: at, .. } if at > seq => return Ok(None),
+                LogEntry::Checkpoint { .. } => checkpointed = true,
+                LogEntry::Stamped { seq: at, .. } if at > seq => return Ok(Some(offs)),
+                LogEntry::Stamped { .. } | LogEntry::Retained { .. } => {}
+                _ if !checkpointed => return Ok(None),
+                _ => {}
+            }
+
+            offs = r.stream_position()
+                .context(GetPosition { filename: self.log_f_name.clone() })?;
+        }
+
+        Ok(Some(end))
+    }
+
+    /// The writes recorded in the log from `offs`, which is the start of a record just after
+    /// write number `seq`, and the position just past the last one. `None` if they can't be read
+    /// back any more, because `offs` is past the end or a value was in a blob file that has
+    /// since been collected.
+    fn writes_since(&mut self, offs: u64, seq: u64) -> Result<Option<(Vec<Ops>, LogPosition)>> {
+        let end = self.log_end()?;
+        if offs > end {
+            return Ok(None);
+        }
+
+        let first = if self.cipher.is_some() { crypto::HEADER_LEN } else { 0 };
+        let mut offs = offs.max(first);
+        let mut seq = seq;
+        self.log_f.seek(io::SeekFrom::Start(offs))
+            .context(GetPosition { filename: self.log_f_name.clone() })?;
+        let mut r = BufReader::with_capacity(8192, &mut self.log_f);
+
+        let mut writes = Vec::new();
+        let mut bytes = 0;
+        while offs < end && writes.len() < MAX_RECORDS && bytes < MAX_BYTES {
+            let entry = next_entry(&mut r, self.cipher.as_ref(), &self.log_f_name, offs)?;
+            if let Some((at, _)) = entry.stamp() {
+                seq = seq.max(at);
+            }
+
+            // earlier versions kept for history aren't writes
+            let changes = match entry {
+                LogEntry::Retained { .. } => Vec::new(),
+                _ => entry.ops(),
+            };
+            let mut ops = Vec::new();
+            for (ns, key, op) in changes {
+                // where a follower has got to is its own business
+                if ns == REPLICA_NS {
+                    continue;
+                }
+                let value = match op {
+                    Op::Set(value) => Some(value.to_owned()),
+                    Op::SetBlob(blob) => match self.blobs.read(key, &blob) {
+                        Ok(value) => Some(value),
+                        Err(_) => return Ok(None),
+                    },
+                    Op::Remove => None,
+                };
+                bytes += key.len() + value.as_ref().map_or(0, String::len);
+                ops.push((ns.to_owned(), key.to_owned(), value));
+            }
+            // nothing to ship for checkpoints and retained versions
+            if !ops.is_empty() {
+                writes.push(ops);
+            }
+
+            offs = r.stream_position()
+                .context(GetPosition { filename: self.log_f_name.clone() })?;
+        }
+
+        Ok(Some((writes, LogPosition { log_id: self.log_id, offset: offs, seq })))
+    }
+
+    /// does `key` have a value in `ns`?
+    fn contains(&mut self, ns: &str, key: &str) -> Result<bool> {
+        let space = match self.spaces.get(ns) {
+            Some(space) => space,
+            None => return Ok(false),
+        };
+        let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+        Ok(space.index.get(key, &mut NsKeys { reader: &mut reader, ns })?.is_some())
+    }
+
+    /// the position in `primary`'s log recorded by a follower of it
+    fn position_in(&mut self, primary: &str) -> Result<Option<LogPosition>> {
+        Ok(self.get_in(REPLICA_NS, primary.to_owned())?.as_deref().and_then(decode_position))
+    }
+
+    /// Apply `writes` shipped from `primary`, each as one write, recording `to` along with the
+    /// last of them
+    fn apply_writes(&mut self, primary: &str, writes: Vec<Ops>, to: LogPosition) -> Result<()> {
+        let count = writes.len();
+        for (i, write) in writes.into_iter().enumerate() {
+            let mut ops = Vec::with_capacity(write.len() + 1);
+            // a write replayed after a snapshot may remove keys that are already gone
+            let mut exists = HashMap::new();
+            for (ns, key, value) in write {
+                let at = (ns, key);
+                let existed = match exists.get(&at) {
+                    Some(&existed) => existed,
+                    None => self.contains(&at.0, &at.1)?,
+                };
+                exists.insert(at.clone(), value.is_some());
+                if value.is_some() || existed {
+                    ops.push((at.0, at.1, value));
+                }
+            }
+            if i + 1 == count {
+                ops.push((REPLICA_NS.to_owned(), primary.to_owned(), Some(encode_position(to))));
+            }
+            self.write_ops(ops)?;
+        }
+
+        Ok(())
+    }
+
+    /// Make the keys after `after` (or from the start) up to the last of `pairs` (or to the end,
+    /// without `more`) hold exactly `pairs`, a page of a snapshot of `primary` started at
+    /// `start`. The first page forgets the position recorded in `primary`'s log and the last one
+    /// records `start`, so a follower that stops part way through starts the snapshot again.
+    fn apply_page(&mut self, primary: &str, after: Option<&(String, String)>, pairs: Vec<(String, String, String)>, more: bool, start: LogPosition) -> Result<()> {
+        let until = pairs.last().filter(|_| more).map(|(ns, key, _)| (ns.clone(), key.clone()));
+        let mut wanted: HashMap<(String, String), String> = pairs.into_iter()
+            .map(|(ns, key, value)| ((ns, key), value))
+            .collect();
+
+        let mut ops = Vec::new();
+        if after.is_none() && self.contains(REPLICA_NS, primary)? {
+            ops.push((REPLICA_NS.to_owned(), primary.to_owned(), None));
+        }
+        for ns in self.namespaces() {
+            if ns == REPLICA_NS {
+                continue;
+            }
+            for key in self.keys_in(&ns, "")? {
+                let at = (ns.clone(), key);
+                if after.is_some_and(|after| at <= *after) || until.as_ref().is_some_and(|until| at > *until) {
+                    continue;
+                }
+                match wanted.remove(&at) {
+                    Some(new) => {
+                        if self.get_in(&at.0, at.1.clone())?.as_ref() != Some(&new) {
+                            ops.push((at.0, at.1, Some(new)));
+                        }
+                    }
+                    None => ops.push((at.0, at.1, None)),
+                }
+            }
+        }
+        ops.extend(wanted.into_iter().map(|((ns, key), value)| (ns, key, Some(value))));
+        if !more {
+            ops.push((REPLICA_NS.to_owned(), primary.to_owned(), Some(encode_position(start))));
+        }
+
+        // each key is changed at most once, so the changes can be written in any number of
+        // records as long as the position stays first or last
+        for chunk in ops.chunks(MAX_RECORDS) {
+            self.write_ops(chunk.to_vec())?;
+        }
+        Ok(())
+    }
+}
+
+/// How far a follower has got with its primary
+#[derive(Debug, Default)]
+struct Progress {
+    // the position reached in the primary's log, `None` until a snapshot has been applied
+    from: Option<LogPosition>,
+    // part way through a snapshot: where the log was when the first page was read, and the last
+    // key applied
+    snapshot: Option<(LogPosition, (String, String))>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    store: Mutex<KvStore>,
+    primary: String,
+    // the last request to the primary found nothing new
+    caught_up: AtomicBool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, KvStore> {
+        // writes are applied one record at a time, so a panic can't leave one half done
+        self.store.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Fetch the next writes or page of a snapshot from the primary and apply them, moving
+    /// `progress` past them. Returns whether more may be waiting.
+    fn sync(&self, client: &mut Option<KvsClient>, progress: &mut Progress) -> Result<bool> {
+        let c = match client {
+            Some(c) => c,
+            None => client.insert(KvsClient::connect(&self.primary)?),
+        };
+        let response = match &progress.snapshot {
+            Some((_, after)) => c.snapshot_page(after.clone())?,
+            None => c.replicate(progress.from)?,
+        };
+
+        let mut store = self.lock();
+        let applied = match response {
+            Response::Records { to, writes } => {
+                let more = !writes.is_empty();
+                store.apply_writes(&self.primary, writes, to).map(|_| {
+                    progress.from = Some(to);
+                    more
+                })
+            }
+            Response::Snapshot { at, pairs, more } => {
+                let (start, after) = match progress.snapshot.take() {
+                    Some((start, after)) => (start, Some(after)),
+                    None => (at, None),
+                };
+                let last = pairs.last().map(|(ns, key, _)| (ns.clone(), key.clone()));
+                store.apply_page(&self.primary, after.as_ref(), pairs, more, start).map(|_| {
+                    if more {
+                        progress.snapshot = last.or(after).map(|last| (start, last));
+                    } else {
+                        progress.from = Some(start);
+                    }
+                    true
+                })
+            }
+            _ => unreachable!("KvsClient only returns records or snapshots when replicating"),
+        };
+
+        match applied {
+            Ok(more) => {
+                self.caught_up.store(!more, Ordering::SeqCst);
+                Ok(more)
+            }
+            Err(e) => {
+                // some of the changes may have been applied, go back to the position recorded
+                // with the last ones that all were: applying them again does no harm
+                *progress = Progress { from: store.position_in(&self.primary).unwrap_or(None), snapshot: None };
+                Err(e)
+            }
+        }
+    }
+
+    fn respond(&self, request: Request) -> Response {
+        let mut store = self.lock();
+        let result = match request {
+            request @ (Request::Get { .. } | Request::Scan { .. } | Request::ScanHashes { .. } | Request::Namespaces) => read(&mut store, request),
+            Request::Replicate { from } => ship(&mut store, from),
+            Request::SnapshotPage { after } => snapshot_page(&mut store, Some(after)),
+            Request::Set { .. } | Request::Remove { .. } => {
+                return Response::Error { code: ErrorCode::NotLeader.code(), message: self.primary.clone() };
+            }
+            Request::AddMember { .. } | Request::RemoveMember { .. } => {
+                let message = "this server is not part of a cluster".to_owned();
+                return Response::Error { code: ErrorCode::BadRequest.code(), message };
+            }
+        };
+
+        result.unwrap_or_else(|e| Response::error(&e))
+    }
+}
+
+/// keep `shared` up to date with its primary for as long as anyone holds on to it
+fn follow(shared: Weak<Shared>, mut progress: Progress) {
+    let mut client = None;
+    loop {
+        let wait = match shared.upgrade() {
+            None => return,
+            Some(shared) => match shared.sync(&mut client, &mut progress) {
+                Ok(true) => continue,
+                Ok(false) => POLL_INTERVAL,
+                Err(e) => {
+                    log::warn!("replicating from {} failed: {}", shared.primary, e);
+                    client = None;
+                    RETRY_INTERVAL
+                }
+            },
+        };
+        thread::sleep(wait);
+    }
+}
+
+/// A read-only copy of the store served by another server, kept up to date by log shipping
+///
+/// The primary is a `KvsServer`, or another `Follower`. Clients talk to a follower with
+/// `KvsClient`: reads are answered from the follower's own store, which lags the primary's by
+/// however long the last writes took to arrive, and writes fail with `ErrorCode::NotLeader`
+/// naming the primary. The follower stops replicating once it and every clone of it is dropped.
+///
+/// ```no_run
+/// # use kvs::{Follower, KvStore};
+/// let follower = Follower::start(KvStore::open("/tmp/replica")?, "127.0.0.1:4000")?;
+/// follower.run("127.0.0.1:4001")?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Follower {
+    shared: Arc<Shared>,
+}
+
+impl Follower {
+    /// Start replicating from the server at `primary` into `store`. A store that has followed
+    /// `primary` before carries on from the position recorded in it, otherwise whatever it held
+    /// is replaced by the primary's contents.
+    pub fn start(mut store: KvStore, primary: impl Into<String>) -> Result<Self> {
+        let primary = primary.into();
+        let progress = Progress { from: store.position_in(&primary)?, snapshot: None };
+        let shared = Arc::new(Shared {
+            store: Mutex::new(store),
+            primary,
+            caught_up: AtomicBool::new(false),
+        });
+
+        let weak = Arc::downgrade(&shared);
+        thread::Builder::new()
+            .name("kvs-follower".to_owned())
+            .spawn(move || follow(weak, progress))
+            .context(ThreadSpawn)?;
+        Ok(Follower { shared })
+    }
+
+    /// The address of the server being followed
+    pub fn primary(&self) -> &str {
+        &self.shared.primary
+    }
+
+    /// Had this follower applied everything in the primary's log the last time it asked?
+    pub fn is_caught_up(&self) -> bool {
+        self.shared.caught_up.load(Ordering::SeqCst)
+    }
+
+    /// retrieve the value of `key` in namespace `ns` from this follower's copy
+    pub fn get(&self, ns: &str, key: String) -> Result<Option<String>> {
+        self.shared.lock().namespace(ns).get(key)
+    }
+
+    /// Listen on `addr` and serve clients until accepting a connection fails
+    pub fn run(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Bind)?;
+        self.serve(listener)
+    }
+
+    /// Listen on `addr` and serve clients with `pool` until accepting a connection fails
+    pub fn run_on(&self, addr: impl ToSocketAddrs, pool: &dyn ThreadPool) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Bind)?;
+        self.serve_on(listener, pool)
+    }
+
+    /// Serve clients connecting to `listener`, each on its own thread
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        self.serve_on(listener, &NaiveThreadPool)
+    }
+
+    /// Serve clients connecting to `listener`, each connection as a job on `pool`
+    pub fn serve_on(&self, listener: TcpListener, pool: &dyn ThreadPool) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream.context(Network)?;
+            let shared = self.shared.clone();
+            pool.execute(Box::new(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = serve_connection(stream, |request| shared.respond(request)) {
+                    log::warn!("connection from {:?} failed: {}", peer, e);
+                }
+            }));
+        }
+
+        Ok(())
+    }
+}
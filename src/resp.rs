@@ -0,0 +1,610 @@
+//! Redis protocol (RESP2 and RESP3) front end
+//!
+//! Maps the common string commands onto a `KvStore`, so redis-cli and Redis client libraries can
+//! talk to kvs. Keys live in the default namespace. Expiry times set with `SET .. EX/PX` are kept
+//! as unix milliseconds under the same key in the `EXPIRY_NS` namespace, written in the same
+//! batch as the value, so they survive restarts. Expired keys are removed lazily, by the next
+//! command that touches them.
+//!
+//! Values are stored as strings, so values that aren't valid UTF-8 are rejected.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use snafu::ResultExt;
+
+use crate::thread_pool::{NaiveThreadPool, ThreadPool};
+use crate::{Bind, KvStore, KvsError, Network, Result, WriteBatch};
+
+/// Namespace holding the expiry time of keys set with `EX` or `PX`
+pub const EXPIRY_NS: &str = "resp.expiry";
+
+// longest inline command or length line we accept, and the largest bulk string (as in Redis)
+const MAX_LINE: u64 = 64 << 10;
+const MAX_BULK: usize = 512 << 20;
+const MAX_ARGS: usize = 1 << 20;
+
+/// Serves a store to Redis clients
+#[derive(Debug, Clone)]
+pub struct RespServer {
+    store: Arc<Mutex<KvStore>>,
+}
+
+impl RespServer {
+    /// serve `store`
+    pub fn new(store: KvStore) -> Self {
+        RespServer { store: Arc::new(Mutex::new(store)) }
+    }
+
+    /// Listen on `addr` and serve clients until accepting a connection fails
+    pub fn run(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Bind)?;
+        self.serve(listener)
+    }
+
+    /// Listen on `addr` and serve clients with `pool` until accepting a connection fails
+    pub fn run_on(&self, addr: impl ToSocketAddrs, pool: &dyn ThreadPool) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Bind)?;
+        self.serve_on(listener, pool)
+    }
+
+    /// Serve clients connecting to `listener`, each on its own thread. Each command is applied
+    /// to the store on its own, so commands like `INCR` and `SET .. NX` are atomic.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        self.serve_on(listener, &NaiveThreadPool)
+    }
+
+    /// Serve clients connecting to `listener`, each connection as a job on `pool`. A connection
+    /// keeps its thread until it closes, so with a fixed size pool further clients wait for one.
+    pub fn serve_on(&self, listener: TcpListener, pool: &dyn ThreadPool) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream.context(Network)?;
+            let server = self.clone();
+            pool.execute(Box::new(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = server.handle(stream) {
+                    log::warn!("connection from {:?} failed: {}", peer, e);
+                }
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// answer commands on `stream` until the client hangs up or sends `QUIT`
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone().context(Network)?);
+        let mut writer = BufWriter::new(stream);
+        let mut conn = Conn { proto: 2, quit: false };
+
+        loop {
+            let args = match read_command(&mut reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    // we can't tell where the next command would start
+                    let reply = Reply::Error(format!("ERR Protocol error: {}", e));
+                    reply.write(&mut writer, conn.proto).context(Network)?;
+                    writer.flush().context(Network)?;
+                    return Err(e).context(Network);
+                }
+                Err(e) => return Err(e).context(Network),
+            };
+            if args.is_empty() {
+                continue;
+            }
+
+            let reply = {
+                let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+                conn.exec(&mut store, args)
+            };
+            reply.write(&mut writer, conn.proto).context(Network)?;
+            writer.flush().context(Network)?;
+
+            if conn.quit {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A reply to a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Int(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Simple("OK".to_owned())
+    }
+
+    fn bulk(s: impl Into<String>) -> Self {
+        Reply::Bulk(s.into().into_bytes())
+    }
+
+    fn value(v: Option<String>) -> Self {
+        v.map_or(Reply::Null, Reply::bulk)
+    }
+
+    /// encode for a client speaking RESP version `proto`
+    fn write(&self, w: &mut impl Write, proto: u8) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(w, "+{}\r\n", s),
+            Reply::Error(e) => write!(w, "-{}\r\n", e),
+            Reply::Int(i) => write!(w, ":{}\r\n", i),
+            Reply::Bulk(b) => {
+                write!(w, "${}\r\n", b.len())?;
+                w.write_all(b)?;
+                w.write_all(b"\r\n")
+            }
+            Reply::Null if proto >= 3 => w.write_all(b"_\r\n"),
+            Reply::Null => w.write_all(b"$-1\r\n"),
+            Reply::Array(items) => {
+                write!(w, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|i| i.write(w, proto))
+            }
+            Reply::Map(pairs) => {
+                // RESP2 has no maps, clients expect a flat array of keys and values instead
+                if proto >= 3 {
+                    write!(w, "%{}\r\n", pairs.len())?;
+                } else {
+                    write!(w, "*{}\r\n", pairs.len() * 2)?;
+                }
+                pairs.iter().try_for_each(|(k, v)| {
+                    k.write(w, proto)?;
+                    v.write(w, proto)
+                })
+            }
+        }
+    }
+}
+
+impl From<KvsError> for Reply {
+    fn from(e: KvsError) -> Self {
+        Reply::Error(format!("ERR {}", e))
+    }
+}
+
+// commands bail out early with the error reply to send
+type CmdResult = std::result::Result<Reply, Reply>;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// a line without its line ending, `None` at the end of the stream
+fn read_line(r: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    r.by_ref().take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits).ok()
+        .and_then(|d| d.parse::<usize>().ok())
+        .filter(|&n| n <= max)
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+/// the arguments of the next command, either an array of bulk strings or an inline command as
+/// typed into telnet. `None` at the end of the stream.
+fn read_command(r: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(r)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if line.first() != Some(&b'*') {
+        let args = line.split(|b| b.is_ascii_whitespace())
+            .filter(|a| !a.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let n = parse_len(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(n.min(64));
+    for _ in 0..n {
+        let line = read_line(r)?.ok_or_else(|| invalid("unexpected end of stream"))?;
+        if line.first() != Some(&b'$') {
+            return Err(invalid("expected '$'"));
+        }
+
+        let len = parse_len(&line[1..], MAX_BULK)?;
+        let mut arg = vec![0; len + 2];
+        r.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("bulk string too long"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+/// does `s` match the glob `pattern`? Supports `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+fn glob(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // the pattern just after the last `*` seen, and where in `s` that `*` stops matching; on a
+    // mismatch the `*` takes one more byte and matching picks up from there
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+        } else if let Some(next) = glob_one(pattern, p, s[i]) {
+            p = next;
+            i += 1;
+        } else if let Some((after, end)) = star {
+            p = after;
+            i = end + 1;
+            star = Some((after, i));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// if the part of `pattern` at `p` (anything but `*`) matches `c`, where the pattern continues
+fn glob_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'[' => {
+            let rest = &pattern[p + 1..];
+            let close = match rest.iter().skip(1).position(|&b| b == b']') {
+                Some(i) => i + 1,
+                // an unclosed bracket is matched literally
+                None => return if c == b'[' { Some(p + 1) } else { None },
+            };
+            let (negate, class) = match rest[..close].split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, &rest[..close]),
+            };
+
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    let (lo, hi) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+                    matched |= (lo..=hi).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+
+            if matched != negate { Some(p + close + 2) } else { None }
+        }
+        b'\\' if p + 1 < pattern.len() => if pattern[p + 1] == c { Some(p + 2) } else { None },
+        b => if b == c { Some(p + 1) } else { None },
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+fn text(arg: Vec<u8>) -> std::result::Result<String, Reply> {
+    String::from_utf8(arg).map_err(|_| Reply::Error("ERR kvs only stores valid UTF-8".to_owned()))
+}
+
+fn int(arg: &[u8]) -> std::result::Result<i64, Reply> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|a| a.parse().ok())
+        .ok_or_else(|| Reply::Error("ERR value is not an integer or out of range".to_owned()))
+}
+
+fn wrong_args(cmd: &str) -> Reply {
+    Reply::Error(format!("ERR wrong number of arguments for '{}' command", cmd.to_lowercase()))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_owned())
+}
+
+/// when `key` expires, in unix milliseconds
+fn expiry(store: &mut KvStore, key: &str) -> Result<Option<u64>> {
+    Ok(store.namespace(EXPIRY_NS).get(key.to_owned())?.and_then(|at| at.parse().ok()))
+}
+
+/// the value of `key`, removing it first if it has expired
+fn live_get(store: &mut KvStore, key: &str) -> Result<Option<String>> {
+    match expiry(store, key)? {
+        Some(at) if at <= now_ms() => {
+            let mut batch = WriteBatch::new();
+            batch.remove(EXPIRY_NS, key.to_owned());
+            if store.get(key.to_owned())?.is_some() {
+                batch.remove("", key.to_owned());
+            }
+            store.write(batch)?;
+            Ok(None)
+        }
+        _ => store.get(key.to_owned()),
+    }
+}
+
+/// the live keys matching `pattern`, in order
+fn live_keys(store: &mut KvStore, pattern: &[u8]) -> Result<Vec<String>> {
+    let expiries: HashMap<String, String> = store.namespace(EXPIRY_NS).scan("")?.into_iter().collect();
+    let now = now_ms();
+    Ok(store.keys("")?.into_iter()
+        .filter(|k| glob(pattern, k.as_bytes()))
+        .filter(|k| expiries.get(k).and_then(|at| at.parse::<u64>().ok()).is_none_or(|at| at > now))
+        .collect())
+}
+
+/// per connection state
+struct Conn {
+    proto: u8,
+    quit: bool,
+}
+
+impl Conn {
+    fn exec(&mut self, store: &mut KvStore, mut args: Vec<Vec<u8>>) -> Reply {
+        let cmd = String::from_utf8_lossy(&args.remove(0)).to_uppercase();
+        let result = match cmd.as_str() {
+            "PING" => match args.len() {
+                0 => Ok(Reply::Simple("PONG".to_owned())),
+                1 => Ok(Reply::Bulk(args.remove(0))),
+                _ => Err(wrong_args(&cmd)),
+            },
+            "HELLO" => self.hello(args),
+            "QUIT" => {
+                self.quit = true;
+                Ok(Reply::ok())
+            }
+            // redis-cli asks for command docs when it starts, an empty answer is fine
+            "COMMAND" => Ok(Reply::Array(Vec::new())),
+            "GET" if args.len() == 1 => get(store, args),
+            "SET" if args.len() >= 2 => set(store, args),
+            "DEL" if !args.is_empty() => del(store, args),
+            "EXISTS" if !args.is_empty() => exists(store, args),
+            "INCR" if args.len() == 1 => incr(store, args),
+            "KEYS" if args.len() == 1 => keys(store, args),
+            "SCAN" if !args.is_empty() => scan(store, args),
+            "MGET" if !args.is_empty() => mget(store, args),
+            "MSET" if !args.is_empty() && args.len().is_multiple_of(2) => mset(store, args),
+            "TTL" if args.len() == 1 => ttl(store, args),
+            "GET" | "SET" | "DEL" | "EXISTS" | "INCR" | "KEYS" | "SCAN" | "MGET" | "MSET" | "TTL" => {
+                Err(wrong_args(&cmd))
+            }
+            _ => {
+                let start: String = args.iter()
+                    .map(|a| format!("'{}' ", String::from_utf8_lossy(a)))
+                    .collect();
+                Err(Reply::Error(format!("ERR unknown command '{}', with args beginning with: {}", cmd.to_lowercase(), start)))
+            }
+        };
+
+        result.unwrap_or_else(|e| e)
+    }
+
+    /// `HELLO [protover ...]` switches protocol version and describes the server
+    fn hello(&mut self, args: Vec<Vec<u8>>) -> CmdResult {
+        if let Some(version) = args.first() {
+            match int(version) {
+                Ok(2) => self.proto = 2,
+                Ok(3) => self.proto = 3,
+                _ => return Err(Reply::Error("NOPROTO unsupported protocol version".to_owned())),
+            }
+        }
+
+        Ok(Reply::Map(vec![
+            (Reply::bulk("server"), Reply::bulk("kvs")),
+            (Reply::bulk("version"), Reply::bulk(env!("CARGO_PKG_VERSION"))),
+            (Reply::bulk("proto"), Reply::Int(self.proto.into())),
+            (Reply::bulk("mode"), Reply::bulk("standalone")),
+            (Reply::bulk("role"), Reply::bulk("master")),
+            (Reply::bulk("modules"), Reply::Array(Vec::new())),
+        ]))
+    }
+}
+
+fn get(store: &mut KvStore, mut args: Vec<Vec<u8>>) -> CmdResult {
+    let key = text(args.remove(0))?;
+    Ok(Reply::value(live_get(store, &key)?))
+}
+
+/// `SET key value [NX | XX] [GET] [EX seconds | PX milliseconds | KEEPTTL]`
+fn set(store: &mut KvStore, args: Vec<Vec<u8>>) -> CmdResult {
+    let mut args = args.into_iter();
+    let key = text(args.next().expect("checked arity"))?;
+    let value = text(args.next().expect("checked arity"))?;
+
+    let (mut nx, mut xx, mut get, mut keep_ttl) = (false, false, false, false);
+    let mut expire_at = None;
+    while let Some(opt) = args.next() {
+        match opt.to_ascii_uppercase().as_slice() {
+            b"NX" if !xx => nx = true,
+            b"XX" if !nx => xx = true,
+            b"GET" => get = true,
+            b"KEEPTTL" if expire_at.is_none() => keep_ttl = true,
+            unit @ b"EX" | unit @ b"PX" if expire_at.is_none() && !keep_ttl => {
+                let n = int(&args.next().ok_or_else(syntax_error)?)?;
+                let ms = if unit == b"EX" { n.checked_mul(1000) } else { Some(n) };
+                match ms {
+                    Some(ms) if ms > 0 => expire_at = Some(now_ms().saturating_add(ms as u64)),
+                    _ => return Err(Reply::Error("ERR invalid expire time in 'set' command".to_owned())),
+                }
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    let old = live_get(store, &key)?;
+    let reply = if get { Reply::value(old.clone()) } else { Reply::ok() };
+    if (nx && old.is_some()) || (xx && old.is_none()) {
+        return Ok(if get { reply } else { Reply::Null });
+    }
+
+    let mut batch = WriteBatch::new();
+    batch.set("", key.clone(), value);
+    match expire_at {
+        Some(at) => {
+            batch.set(EXPIRY_NS, key, at.to_string());
+        }
+        None if !keep_ttl && expiry(store, &key)?.is_some() => {
+            batch.remove(EXPIRY_NS, key);
+        }
+        None => {}
+    }
+    store.write(batch)?;
+
+    Ok(reply)
+}
+
+fn del(store: &mut KvStore, args: Vec<Vec<u8>>) -> CmdResult {
+    let mut batch = WriteBatch::new();
+    let mut seen = HashSet::new();
+    let mut removed = 0;
+    for key in args {
+        let key = text(key)?;
+        if !seen.insert(key.clone()) || live_get(store, &key)?.is_none() {
+            continue;
+        }
+
+        if expiry(store, &key)?.is_some() {
+            batch.remove(EXPIRY_NS, key.clone());
+        }
+        batch.remove("", key);
+        removed += 1;
+    }
+
+    if removed > 0 {
+        store.write(batch)?;
+    }
+    Ok(Reply::Int(removed))
+}
+
+fn exists(store: &mut KvStore, args: Vec<Vec<u8>>) -> CmdResult {
+    let mut count = 0;
+    for key in args {
+        if live_get(store, &text(key)?)?.is_some() {
+            count += 1;
+        }
+    }
+
+    Ok(Reply::Int(count))
+}
+
+fn incr(store: &mut KvStore, mut args: Vec<Vec<u8>>) -> CmdResult {
+    let key = text(args.remove(0))?;
+    let n = match live_get(store, &key)? {
+        Some(value) => int(value.as_bytes())?,
+        None => 0,
+    };
+    let n = n.checked_add(1)
+        .ok_or_else(|| Reply::Error("ERR increment or decrement would overflow".to_owned()))?;
+
+    // the expiry, if any, stays as it is
+    store.set(key, n.to_string())?;
+    Ok(Reply::Int(n))
+}
+
+fn keys(store: &mut KvStore, args: Vec<Vec<u8>>) -> CmdResult {
+    let keys = live_keys(store, &args[0])?;
+    Ok(Reply::Array(keys.into_iter().map(Reply::bulk).collect()))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// The cursor is a position in the sorted list of keys. Keys added or removed before the cursor
+/// between calls shift that position, so unlike Redis a key present for the whole scan can be
+/// skipped or returned twice.
+fn scan(store: &mut KvStore, args: Vec<Vec<u8>>) -> CmdResult {
+    let mut args = args.into_iter();
+    let cursor = usize::try_from(int(&args.next().expect("checked arity"))?)
+        .map_err(|_| Reply::Error("ERR invalid cursor".to_owned()))?;
+
+    let mut pattern = b"*".to_vec();
+    let mut count = 10;
+    while let Some(opt) = args.next() {
+        let value = args.next().ok_or_else(syntax_error)?;
+        match opt.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = value,
+            b"COUNT" => match usize::try_from(int(&value)?) {
+                Ok(n) if n > 0 => count = n,
+                _ => return Err(syntax_error()),
+            },
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    // filtering by pattern after picking the page, like Redis, so a page may come back empty
+    let keys = live_keys(store, b"*")?;
+    let end = cursor.saturating_add(count).min(keys.len());
+    let page = keys.get(cursor..end).unwrap_or_default().iter()
+        .filter(|k| glob(&pattern, k.as_bytes()))
+        .map(|k| Reply::bulk(k.as_str()))
+        .collect();
+    let next = if end >= keys.len() { 0 } else { end };
+
+    Ok(Reply::Array(vec![Reply::bulk(next.to_string()), Reply::Array(page)]))
+}
+
+fn mget(store: &mut KvStore, args: Vec<Vec<u8>>) -> CmdResult {
+    let mut values = Vec::with_capacity(args.len());
+    for key in args {
+        // a key that isn't UTF-8 can't be in the store
+        let value = match String::from_utf8(key) {
+            Ok(key) => live_get(store, &key)?,
+            Err(_) => None,
+        };
+        values.push(Reply::value(value));
+    }
+
+    Ok(Reply::Array(values))
+}
+
+fn mset(store: &mut KvStore, args: Vec<Vec<u8>>) -> CmdResult {
+    let mut batch = WriteBatch::new();
+    let mut cleared = HashSet::new();
+    let mut args = args.into_iter();
+    while let (Some(key), Some(value)) = (args.next(), args.next()) {
+        let (key, value) = (text(key)?, text(value)?);
+        if expiry(store, &key)?.is_some() && cleared.insert(key.clone()) {
+            batch.remove(EXPIRY_NS, key.clone());
+        }
+        batch.set("", key, value);
+    }
+
+    store.write(batch)?;
+    Ok(Reply::ok())
+}
+
+fn ttl(store: &mut KvStore, mut args: Vec<Vec<u8>>) -> CmdResult {
+    let key = text(args.remove(0))?;
+    if live_get(store, &key)?.is_none() {
+        return Ok(Reply::Int(-2));
+    }
+
+    Ok(Reply::Int(match expiry(store, &key)? {
+        Some(at) => at.saturating_sub(now_ms()).div_ceil(1000) as i64,
+        None => -1,
+    }))
+}
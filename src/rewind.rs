@@ -0,0 +1,114 @@
+//! Opening a store as it was at an earlier point
+//!
+//! Every write is stamped in the log with its sequence number and the time it was made, see
+//! `log`. Opening a store at an earlier point replays its log up to the last write at or before
+//! that point and stops, giving a read-only view of the store as it was just after that write.
+//! The files themselves are left untouched.
+//!
+//! Only what is still in the log can be rewound to. Compaction keeps just the latest value of
+//! each key, and garbage collection deletes blob files whose values have all been replaced, so
+//! going back further takes a backup or a copy of the log from before then.
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{KvStore, OpenOptions, RestoreTarget, Result};
+
+// changes copied into a new store at once by `copy_to`
+const COPY_BATCH: usize = 1000;
+
+/// How far `KvStore::open_at` replays the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    /// up to and including the write with this sequence number, see `KvStore::last_seq`
+    Seq(u64),
+    /// up to and including the last write made at or before this time
+    Time(SystemTime),
+}
+
+impl Until {
+    /// does the write numbered `seq`, made at `time`, come at or before this point
+    pub(crate) fn includes(self, seq: u64, time: u64) -> bool {
+        match self {
+            Until::Seq(until) => seq <= until,
+            Until::Time(until) => time <= millis(until),
+        }
+    }
+}
+
+fn millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// the time to stamp a write made now with
+pub(crate) fn now_millis() -> u64 {
+    millis(SystemTime::now())
+}
+
+impl OpenOptions {
+    /// Open the store in `path` as it was at `until`, with these options. The store is
+    /// read-only: writes fail with `ReadOnly`, and neither the log nor the blob files are changed.
+    /// Fails with `RewindCompacted` if the log has been compacted since `until`.
+    pub fn open_at(&self, path: impl Into<PathBuf>, until: Until) -> Result<KvStore> {
+        self.open_until(path, Some(until))
+    }
+
+    /// Open the store in `path` read-only, like `open_at` but replaying every write, to look at a
+    /// store without compaction or garbage collection changing its files.
+    pub fn open_read_only(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        self.open_until(path, Some(Until::Seq(u64::MAX)))
+    }
+}
+
+impl KvStore {
+    /// Open the store in `path` as it was at `until`, see `OpenOptions::open_at`
+    ///
+    /// ```
+    /// # use kvs::{KvStore, Until};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let mut store = KvStore::open(dir.path())?;
+    /// store.set("key1".to_owned(), "good".to_owned())?;
+    /// let good = store.last_seq();
+    /// store.set("key1".to_owned(), "garbage".to_owned())?;
+    ///
+    /// let mut before = KvStore::open_at(dir.path(), Until::Seq(good))?;
+    /// assert_eq!(before.get("key1".to_owned())?, Some("good".to_owned()));
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn open_at(path: impl Into<PathBuf>, until: Until) -> Result<KvStore> {
+        OpenOptions::new().open_at(path, until)
+    }
+
+    /// Sequence number of the last write made to the store, or the last one replayed by
+    /// `open_at`. 0 if there haven't been any.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Whether the store was opened by `open_at` or `OpenOptions::open_read_only`, and so can't be
+    /// written to
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Write every key and value into a new store in `path`, opened with `options`, for example to
+    /// carry on from a store rewound with `open_at`. `path` must not hold a store already.
+    pub fn copy_to(&mut self, options: &OpenOptions, path: impl Into<PathBuf>) -> Result<KvStore> {
+        let path = path.into();
+        let log = path.join("kvs.db");
+        if log.exists() {
+            return RestoreTarget { filename: log }.fail();
+        }
+
+        let mut copy = options.open(path)?;
+        for ns in self.namespaces() {
+            let pairs = self.scan_in(&ns, "")?;
+            let mut ops = pairs.into_iter().map(|(key, value)| (ns.clone(), key, Some(value))).peekable();
+            while ops.peek().is_some() {
+                copy.write_ops(ops.by_ref().take(COPY_BATCH).collect())?;
+            }
+        }
+
+        Ok(copy)
+    }
+}
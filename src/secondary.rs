@@ -0,0 +1,92 @@
+//! Secondary indexes from keys derived from values back to the keys holding them
+//!
+//! An index is defined by a function that extracts any number of secondary keys from a key and
+//! its value. Functions can't be stored in the log, so indexes only live in memory and are
+//! rebuilt from the live values whenever the store is opened or a new index is registered.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+/// Extracts secondary keys from a key and its value
+pub(crate) type IndexFn = Arc<dyn Fn(&str, &str) -> Vec<String> + Send + Sync>;
+
+/// An index registered with `OpenOptions::secondary_index`
+#[derive(Clone)]
+pub(crate) struct IndexDef {
+    pub ns: String,
+    pub name: String,
+    pub f: IndexFn,
+}
+
+impl fmt::Debug for IndexDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexDef").field("ns", &self.ns).field("name", &self.name).finish()
+    }
+}
+
+pub(crate) struct SecondaryIndex {
+    f: IndexFn,
+    // secondary key -> keys whose values produced it
+    entries: BTreeMap<String, BTreeSet<String>>,
+    // key -> the secondary keys its value produced, so they can be dropped when it changes
+    by_key: HashMap<String, Vec<String>>,
+}
+
+impl fmt::Debug for SecondaryIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecondaryIndex")
+            .field("entries", &self.entries.len())
+            .field("keys", &self.by_key.len())
+            .finish()
+    }
+}
+
+impl SecondaryIndex {
+    pub fn new(f: IndexFn) -> Self {
+        SecondaryIndex { f, entries: BTreeMap::new(), by_key: HashMap::new() }
+    }
+
+    /// the secondary keys `value` is indexed under
+    pub fn extract(&self, key: &str, value: &str) -> Vec<String> {
+        let mut secondary = (self.f)(key, value);
+        secondary.sort_unstable();
+        secondary.dedup();
+        secondary
+    }
+
+    /// index `key` under `secondary`, replacing whatever it was indexed under before
+    pub fn insert(&mut self, key: &str, secondary: Vec<String>) {
+        self.remove(key);
+        if secondary.is_empty() {
+            return;
+        }
+
+        for s in &secondary {
+            self.entries.entry(s.clone()).or_default().insert(key.to_owned());
+        }
+        self.by_key.insert(key.to_owned(), secondary);
+    }
+
+    /// stop indexing `key`
+    pub fn remove(&mut self, key: &str) {
+        for s in self.by_key.remove(key).unwrap_or_default() {
+            if let Some(keys) = self.entries.get_mut(&s) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.entries.remove(&s);
+                }
+            }
+        }
+    }
+
+    /// keys whose values are indexed under `secondary`, in order
+    pub fn get(&self, secondary: &str) -> Vec<String> {
+        self.entries.get(secondary).map_or_else(Vec::new, |keys| keys.iter().cloned().collect())
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.by_key.clear();
+    }
+}
@@ -0,0 +1,172 @@
+//! Serving a `KvStore` to `KvsClient`s over TCP
+
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+use snafu::ResultExt;
+use speedy::{IsEof, Readable, Writable};
+
+use crate::protocol::{read_message, write_message, ErrorCode, Hello, Request, Response, PROTOCOL_VERSION};
+use crate::replica;
+use crate::shard;
+use crate::thread_pool::{NaiveThreadPool, ThreadPool};
+use crate::{Bind, KvStore, KvsError, Network, Protocol, Result};
+
+// most keys in one page of a scan
+const MAX_PAGE: usize = 1000;
+
+/// Serves a store over TCP to `KvsClient`s
+#[derive(Debug, Clone)]
+pub struct KvsServer {
+    store: Arc<Mutex<KvStore>>,
+}
+
+impl KvsServer {
+    /// serve `store`
+    pub fn new(store: KvStore) -> Self {
+        KvsServer { store: Arc::new(Mutex::new(store)) }
+    }
+
+    /// Listen on `addr` and serve clients until accepting a connection fails
+    pub fn run(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Bind)?;
+        self.serve(listener)
+    }
+
+    /// Listen on `addr` and serve clients with `pool` until accepting a connection fails
+    pub fn run_on(&self, addr: impl ToSocketAddrs, pool: &dyn ThreadPool) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Bind)?;
+        self.serve_on(listener, pool)
+    }
+
+    /// Serve clients connecting to `listener`, each on its own thread. Requests are applied to
+    /// the store one at a time. A connection that misbehaves is dropped without affecting the
+    /// others.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        self.serve_on(listener, &NaiveThreadPool)
+    }
+
+    /// Serve clients connecting to `listener`, each connection as a job on `pool`. A connection
+    /// keeps its thread until it closes, so with a fixed size pool further clients wait for one.
+    pub fn serve_on(&self, listener: TcpListener, pool: &dyn ThreadPool) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream.context(Network)?;
+            let server = self.clone();
+            pool.execute(Box::new(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = server.handle(stream) {
+                    log::warn!("connection from {:?} failed: {}", peer, e);
+                }
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// answer requests on `stream` until the client hangs up
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        serve_connection(stream, |request| self.respond(request))
+    }
+
+    fn respond(&self, request: Request) -> Response {
+        // a connection that panicked part way through a request can't have left the store
+        // inconsistent on disk, so keep serving
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        let result = match request {
+            request @ (Request::Get { .. } | Request::Scan { .. } | Request::ScanHashes { .. } | Request::Namespaces) => read(&mut store, request),
+            Request::Set { ns, key, value } => store.namespace(&ns).set(key, value).map(|_| Response::Done),
+            Request::Remove { ns, key } => store.namespace(&ns).remove(key).map(|_| Response::Done),
+            Request::Replicate { from } => replica::ship(&mut store, from),
+            Request::SnapshotPage { after } => replica::snapshot_page(&mut store, Some(after)),
+            Request::AddMember { .. } | Request::RemoveMember { .. } => {
+                let message = "this server is not part of a cluster".to_owned();
+                return Response::Error { code: ErrorCode::BadRequest.code(), message };
+            }
+        };
+
+        result.unwrap_or_else(|e| Response::error(&e))
+    }
+}
+
+/// Answer a request that only reads `store`: a get, a page of a scan or the namespaces
+pub(crate) fn read(store: &mut KvStore, request: Request) -> Result<Response> {
+    match request {
+        Request::Get { ns, key } => store.namespace(&ns).get(key).map(Response::Value),
+        Request::Scan { ns, prefix, after, limit } => {
+            let keys = store.namespace(&ns).keys(&prefix)?;
+            let start = after.map_or(0, |after| keys.partition_point(|k| *k <= after));
+            let end = keys.len().min(start + (limit as usize).clamp(1, MAX_PAGE));
+
+            let mut pairs = Vec::with_capacity(end - start);
+            for key in &keys[start..end] {
+                if let Some(value) = store.namespace(&ns).get(key.clone())? {
+                    pairs.push((key.clone(), value));
+                }
+            }
+            Ok(Response::Pairs { pairs, more: end < keys.len() })
+        }
+        Request::ScanHashes { ns, ranges, after, limit } => {
+            let keys = store.namespace(&ns).keys("")?;
+            let start = after.map_or(0, |after| keys.partition_point(|k| *k <= after));
+            let limit = (limit as usize).clamp(1, MAX_PAGE);
+
+            let mut pairs = Vec::new();
+            let mut rest = keys[start..].iter();
+            for key in rest.by_ref() {
+                let h = shard::hash(&[&ns, key]);
+                if !ranges.iter().any(|&(first, last)| first <= h && h <= last) {
+                    continue;
+                }
+                if let Some(value) = store.namespace(&ns).get(key.clone())? {
+                    pairs.push((key.clone(), value));
+                }
+                if pairs.len() == limit {
+                    break;
+                }
+            }
+            Ok(Response::Pairs { pairs, more: rest.len() > 0 })
+        }
+        Request::Namespaces => Ok(Response::Names(store.namespaces())),
+        request => unreachable!("{:?} is not a read", request),
+    }
+}
+
+/// Speak the protocol on `stream`, answering each request with `respond`, until the client hangs
+/// up
+pub(crate) fn serve_connection(stream: TcpStream, mut respond: impl FnMut(Request) -> Response) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone().context(Network)?);
+    let mut writer = BufWriter::new(stream);
+
+    let hello = match Hello::read_from_stream(&mut reader) {
+        Ok(hello) => hello,
+        // connected and hung up straight away, like a health check
+        Err(e) if e.is_eof() => return Ok(()),
+        Err(e) => return Err(e).context(Protocol),
+    };
+    Hello::new().write_to_stream(&mut writer).context(Protocol)?;
+    if !hello.is_kvs() || hello.version != PROTOCOL_VERSION {
+        let message = format!("server speaks protocol version {}, not {}", PROTOCOL_VERSION, hello.version);
+        write_message(&mut writer, &Response::Error { code: ErrorCode::UnsupportedVersion.code(), message })?;
+        return writer.flush().context(Network);
+    }
+    writer.flush().context(Network)?;
+
+    loop {
+        let request = match read_message::<Request>(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e @ (KvsError::Protocol { .. } | KvsError::MessageTooLarge { .. })) => {
+                // tell the client why before giving up on the connection
+                let message = format!("could not decode request: {}", e);
+                write_message(&mut writer, &Response::Error { code: ErrorCode::BadRequest.code(), message })?;
+                writer.flush().context(Network)?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+
+        write_message(&mut writer, &respond(request))?;
+        writer.flush().context(Network)?;
+    }
+}
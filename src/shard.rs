@@ -0,0 +1,260 @@
+//! Spreading keys over several servers with consistent hashing
+//!
+//! Every server gets points on a ring of 64 bit hashes, `POINTS_PER_WEIGHT` for each unit of its
+//! weight. A key belongs to the server with the first point at or after the key's hash, wrapping
+//! around at the top. Adding a server only moves the keys just before its points to it, and
+//! removing one only moves its own keys, so about one key in n moves either way.
+//!
+//! Hashes have to agree between every client and every run, so they are FNV-1a with splitmix64's
+//! finalizer to spread similar strings apart, rather than the randomly seeded `std` hasher.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{KvsClient, KvsError, Result};
+
+const POINTS_PER_WEIGHT: u32 = 100;
+// keys moved at once by a migration
+const MIGRATE_PAGE: u32 = 1000;
+
+pub(crate) fn hash(parts: &[&str]) -> u64 {
+    let mut h = 0xcbf2_9ce4_8422_2325u64;
+    for part in parts {
+        // 0xff never appears in UTF-8, so it can't be mistaken for part of a string
+        for b in part.bytes().chain(Some(0xff)) {
+            h = (h ^ u64::from(b)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// Which server each key belongs to, by consistent hashing
+///
+/// ```
+/// # use kvs::HashRing;
+/// let mut ring = HashRing::new();
+/// ring.add("10.0.0.1:4000", 1).add("10.0.0.2:4000", 2);
+/// let owner = ring.node_for("", "key1").unwrap();
+/// assert!(owner == "10.0.0.1:4000" || owner == "10.0.0.2:4000");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HashRing {
+    nodes: BTreeMap<String, u32>,
+    points: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    /// A ring with no servers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the server at `addr` with `weight`, or change its weight. A server's share of the keys
+    /// is proportional to its weight; one with weight 0 gets none.
+    pub fn add(&mut self, addr: impl Into<String>, weight: u32) -> &mut Self {
+        self.nodes.insert(addr.into(), weight);
+        self.rebuild();
+        self
+    }
+
+    /// Take the server at `addr` out of the ring. Returns whether it was in it.
+    pub fn remove(&mut self, addr: &str) -> bool {
+        let removed = self.nodes.remove(addr).is_some();
+        self.rebuild();
+        removed
+    }
+
+    /// The servers in the ring and their weights, in address order
+    pub fn nodes(&self) -> Vec<(String, u32)> {
+        self.nodes.iter().map(|(addr, &weight)| (addr.clone(), weight)).collect()
+    }
+
+    /// The server `key` in namespace `ns` belongs to, `None` if the ring is empty
+    pub fn node_for(&self, ns: &str, key: &str) -> Option<&str> {
+        self.owner(hash(&[ns, key]))
+    }
+
+    fn owner(&self, h: u64) -> Option<&str> {
+        self.points.range(h..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, addr)| addr.as_str())
+    }
+
+    // the same nodes always give the same points, whatever order they were added in: if two
+    // points collide the lower address gets it
+    fn rebuild(&mut self) {
+        self.points.clear();
+        for (addr, &weight) in &self.nodes {
+            for i in 0..weight * POINTS_PER_WEIGHT {
+                self.points.entry(hash(&[addr, &i.to_string()])).or_insert_with(|| addr.clone());
+            }
+        }
+    }
+
+    /// the ranges of hashes, inclusive and in order, that each server owns in this ring but not
+    /// in `to`
+    fn losing(&self, to: &HashRing) -> BTreeMap<String, Vec<(u64, u64)>> {
+        // up to each point of either ring from the one before, both rings have a single owner:
+        // the one with the first point at or after it. Past the last point both wrap around.
+        let mut ends: BTreeSet<u64> = self.points.keys().chain(to.points.keys()).copied().collect();
+        if ends.iter().next_back().is_some_and(|&end| end < u64::MAX) {
+            ends.insert(u64::MAX);
+        }
+
+        let mut losing: BTreeMap<String, Vec<(u64, u64)>> = BTreeMap::new();
+        let mut start = 0;
+        for end in ends {
+            if let Some(from) = self.owner(end).filter(|&from| to.owner(end) != Some(from)) {
+                let ranges = losing.entry(from.to_owned()).or_default();
+                match ranges.last_mut() {
+                    Some(last) if last.1 + 1 == start => last.1 = end,
+                    _ => ranges.push((start, end)),
+                }
+            }
+            start = end.wrapping_add(1);
+        }
+        losing
+    }
+
+    /// Move every key whose server in this ring isn't its server in `to` over to the new one.
+    /// Servers losing part of the ring are only asked for the keys in the hash ranges they lose,
+    /// and each key is copied before it is removed, so a migration that fails part way can just
+    /// be run again. Returns how many keys were moved.
+    ///
+    /// Clients still using this ring won't find the keys that have moved, and anything they write
+    /// to a server that is losing keys after it has been scanned stays there, so writes should be
+    /// stopped until every client has switched to `to`.
+    pub fn migrate_to(&self, to: &HashRing) -> Result<usize> {
+        let mut targets: HashMap<String, KvsClient> = HashMap::new();
+        let mut moved = 0;
+        for (source, ranges) in self.losing(to) {
+            let mut from = KvsClient::connect(&source)?;
+            for ns in from.namespaces()? {
+                let mut after = None;
+                loop {
+                    let (pairs, more) = from.scan_hashes(&ns, &ranges, after.take(), MIGRATE_PAGE)?;
+                    after = pairs.last().map(|(key, _)| key.clone());
+
+                    for (key, value) in pairs {
+                        let owner = match to.node_for(&ns, &key) {
+                            Some(owner) if owner != source => owner,
+                            Some(_) => continue,
+                            None => return Err(KvsError::EmptyRing { key }),
+                        };
+
+                        let target = match targets.entry(owner.to_owned()) {
+                            Entry::Occupied(e) => e.into_mut(),
+                            Entry::Vacant(e) => e.insert(KvsClient::connect(owner)?),
+                        };
+                        target.set(&ns, key.clone(), value)?;
+                        match from.remove(&ns, key) {
+                            // removed since the scan, so there is nothing left to move
+                            Ok(()) | Err(KvsError::RemoveNonexistentKey { .. }) => moved += 1,
+                            Err(e) => return Err(e),
+                        }
+                    }
+
+                    if !more || after.is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(moved)
+    }
+}
+
+/// A client for keys spread over several `KvsServer`s by a `HashRing`
+///
+/// Each request goes to the server owning its key, connecting to it the first time. Each
+/// server is a separate store, so writes to keys on different servers aren't ordered with
+/// respect to each other.
+///
+/// ```no_run
+/// # use kvs::{HashRing, ShardedClient};
+/// let mut ring = HashRing::new();
+/// ring.add("10.0.0.1:4000", 1).add("10.0.0.2:4000", 1);
+/// let mut client = ShardedClient::new(ring);
+/// client.set("", "key1".to_owned(), "value1".to_owned())?;
+/// client.add_node("10.0.0.3:4000", 1)?;
+/// assert_eq!(client.get("", "key1".to_owned())?, Some("value1".to_owned()));
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug)]
+pub struct ShardedClient {
+    ring: HashRing,
+    conns: HashMap<String, KvsClient>,
+}
+
+impl ShardedClient {
+    /// send each key to its server in `ring`
+    pub fn new(ring: HashRing) -> Self {
+        ShardedClient { ring, conns: HashMap::new() }
+    }
+
+    /// The ring keys are sent by
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// run `f` with a connection to the server for `key` in `ns`
+    fn call<T>(&mut self, ns: &str, key: &str, f: impl FnOnce(&mut KvsClient) -> Result<T>) -> Result<T> {
+        let addr = self.ring.node_for(ns, key)
+            .ok_or_else(|| KvsError::EmptyRing { key: key.to_owned() })?
+            .to_owned();
+        let client = match self.conns.entry(addr.clone()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(KvsClient::connect(&addr)?),
+        };
+
+        let result = f(client);
+        // we can't tell what state the connection is in any more, use a new one next time
+        if let Err(KvsError::Network { .. } | KvsError::Protocol { .. }) = &result {
+            self.conns.remove(&addr);
+        }
+        result
+    }
+
+    /// retrieve the value of `key` in namespace `ns`. if no value, return None
+    pub fn get(&mut self, ns: &str, key: String) -> Result<Option<String>> {
+        self.call(ns, &key.clone(), |c| c.get(ns, key))
+    }
+
+    /// set `key` in namespace `ns` to `value`
+    pub fn set(&mut self, ns: &str, key: String, value: String) -> Result<()> {
+        self.call(ns, &key.clone(), |c| c.set(ns, key, value))
+    }
+
+    /// remove `key` from namespace `ns`
+    pub fn remove(&mut self, ns: &str, key: String) -> Result<()> {
+        self.call(ns, &key.clone(), |c| c.remove(ns, key))
+    }
+
+    /// Add the server at `addr` with `weight`, or change its weight, and move the keys it now
+    /// owns over to it with `HashRing::migrate_to`. Returns how many keys were moved.
+    pub fn add_node(&mut self, addr: impl Into<String>, weight: u32) -> Result<usize> {
+        let mut ring = self.ring.clone();
+        ring.add(addr, weight);
+        self.rebalance(ring)
+    }
+
+    /// Move the keys on the server at `addr` to the others with `HashRing::migrate_to`, and stop
+    /// using it. Returns how many keys were moved.
+    pub fn remove_node(&mut self, addr: &str) -> Result<usize> {
+        let mut ring = self.ring.clone();
+        ring.remove(addr);
+        self.rebalance(ring)
+    }
+
+    fn rebalance(&mut self, ring: HashRing) -> Result<usize> {
+        let moved = self.ring.migrate_to(&ring)?;
+        self.conns.retain(|addr, _| ring.nodes.contains_key(addr));
+        self.ring = ring;
+        Ok(moved)
+    }
+}
@@ -0,0 +1,382 @@
+//! A store handle that can be shared between threads
+//!
+//! Writes go through the one `KvStore`, behind a mutex, so they are made one at a time exactly as
+//! before. Reads never touch it: where each live value is, a record in the log or a blob, is also
+//! kept in an index split into shards, each behind its own `RwLock`, which the writer updates once
+//! a write has made it to the log. Readers take a shard's read lock to find a value, then read it
+//! through file handles of their own without moving any cursor, so they run in parallel with each
+//! other and only wait on a write to the same shard.
+//!
+//! When compaction or blob garbage collection moves values, the writer reopens the files and
+//! rebuilds the whole index while holding `files` exclusively. Readers hold `files` shared for as
+//! long as they use a location, so they never see a location in a file it has been moved out of.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+
+use snafu::ResultExt;
+
+use crate::blob::{BlobPtr, BlobReader};
+use crate::crypto::LogCipher;
+use crate::log::{read_record_at, Change, LogEntry, LogReader, NsKeys, Op};
+use crate::{for_each_live, BackupStats, KvStore, KvsError, OpenLog, OpenOptions, Result, Watcher, WriteBatch};
+
+const SHARDS: usize = 32;
+
+/// Where the current value of a key is
+#[derive(Debug, Clone, Copy)]
+enum Loc {
+    /// in the record at this offset in the log
+    Log(u64),
+    /// in a blob file
+    Blob(BlobPtr),
+}
+
+// (namespace, key) to where its value is
+type Shard = HashMap<(String, String), Loc>;
+
+/// Handles for reading the store's files
+#[derive(Debug)]
+struct Files {
+    log: File,
+    log_name: PathBuf,
+    cipher: Option<LogCipher>,
+    blobs: BlobReader,
+    // `KvStore::rewrites` when they were opened
+    rewrites: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    writer: Mutex<KvStore>,
+    // replaced, along with every shard, when values move
+    files: RwLock<Files>,
+    shards: Vec<RwLock<Shard>>,
+    hasher: RandomState,
+}
+
+/// A cloneable handle to a store for use from many threads at once
+///
+/// Reads run in parallel; writes are made one at a time. A write is visible to readers once it
+/// is in the log, and all the changes in a batch become visible together.
+///
+/// Only the location of each value is held in memory, values are read from disk when asked for.
+///
+/// ```
+/// # let dir = tempfile::TempDir::new().unwrap();
+/// let store = kvs::SharedKvStore::open(dir.path())?;
+/// let reader = store.clone();
+/// store.set("answer".to_owned(), "42".to_owned())?;
+/// let value = std::thread::spawn(move || reader.get("answer")).join().unwrap()?;
+/// assert_eq!(value.as_deref(), Some("42"));
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct SharedKvStore {
+    inner: Arc<Inner>,
+}
+
+/// A handle for the keys in one namespace of a `SharedKvStore`, from `SharedKvStore::namespace`
+#[derive(Debug, Clone)]
+pub struct SharedNamespace<'a> {
+    store: &'a SharedKvStore,
+    name: String,
+}
+
+impl KvStore {
+    /// where the current value of `key` in `ns` is
+    fn location(&mut self, ns: &str, key: &str) -> Result<Option<Loc>> {
+        let space = match self.spaces.get(ns) {
+            Some(space) => space,
+            None => return Ok(None),
+        };
+
+        let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+        let offs = match space.index.get(key, &mut NsKeys { reader: &mut reader, ns })? {
+            Some(offs) => offs,
+            None => return Ok(None),
+        };
+        Ok(Some(match space.blob_ptrs.get(key) {
+            Some(&blob) => Loc::Blob(blob),
+            None => Loc::Log(offs),
+        }))
+    }
+
+    /// where the current value of every key is
+    fn locations(&mut self) -> Result<Vec<((String, String), Loc)>> {
+        let mut found = Vec::new();
+        let mut reader = LogReader { f: &mut self.log_f, map: self.map.as_ref(), cipher: self.cipher.as_ref(), filename: &self.log_f_name };
+        for (ns, space) in &self.spaces {
+            for_each_live(ns, space, &mut reader, |offs, key, op| {
+                let loc = match op {
+                    Op::SetBlob(blob) => Loc::Blob(blob),
+                    _ => Loc::Log(offs),
+                };
+                found.push(((ns.clone(), key.to_owned()), loc));
+                Ok(())
+            })?;
+        }
+
+        Ok(found)
+    }
+
+    /// handles for reading the files as they are now
+    fn files(&self) -> Result<Files> {
+        let log_name = self.log_f_name.clone();
+        let mut log = File::open(&log_name)
+            .context(OpenLog { filename: log_name.clone() })?;
+        let cipher = match &self.cipher {
+            Some(c) => Some(LogCipher::read_header(c.key(), &mut log)
+                .map_err(|e| e.into_error(&log_name, c.key()))?),
+            None => None,
+        };
+
+        Ok(Files { log, log_name, cipher, blobs: self.blobs.reader()?, rewrites: self.rewrites })
+    }
+}
+
+impl Files {
+    /// the value of `key` in `ns`, found at `loc`
+    fn read(&self, ns: &str, key: &str, loc: Loc) -> Result<String> {
+        let offs = match loc {
+            Loc::Blob(blob) => return self.blobs.read(key, &blob),
+            Loc::Log(offs) => offs,
+        };
+
+        let entry: LogEntry = read_record_at(&self.log, self.cipher.as_ref(), offs)
+            .map_err(|e| e.lookup_error(&self.log_name, offs, key))?;
+        if entry.find(ns, key).is_none() {
+            return Err(KvsError::LogEntryKeyMismatch { key: key.to_owned(), found_key: entry.first_key().to_owned(), filename: self.log_name.clone(), offs });
+        }
+
+        match entry.take(ns, key) {
+            Some(Change::Set(value)) => Ok(value),
+            Some(Change::SetBlob(blob)) => self.blobs.read(key, &blob),
+            _ => Err(KvsError::LogEntryKindInvalid { offs, filename: self.log_name.clone(), key: key.to_owned(), found_key: key.to_owned() }),
+        }
+    }
+}
+
+impl SharedKvStore {
+    /// Share `store`, reading where every value is into memory
+    pub fn new(mut store: KvStore) -> Result<Self> {
+        let hasher = RandomState::new();
+        let mut shards = vec![Shard::new(); SHARDS];
+        for (k, loc) in store.locations()? {
+            shards[shard_of(&hasher, &k)].insert(k, loc);
+        }
+
+        let files = RwLock::new(store.files()?);
+        let shards = shards.into_iter().map(RwLock::new).collect();
+        Ok(SharedKvStore { inner: Arc::new(Inner { writer: Mutex::new(store), files, shards, hasher }) })
+    }
+
+    /// open existing or create KvStore from path
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::new(KvStore::open(path)?)
+    }
+
+    /// open existing or create KvStore from path, configured by `options`
+    pub fn open_with(options: &OpenOptions, path: impl Into<PathBuf>) -> Result<Self> {
+        Self::new(options.open(path)?)
+    }
+
+    /// A handle for the keys in namespace `name`. Namespaces are created by writing to them.
+    pub fn namespace(&self, name: &str) -> SharedNamespace<'_> {
+        SharedNamespace { store: self, name: name.to_owned() }
+    }
+
+    /// the value of `key` in the default namespace, if it has one
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.get_in("", key)
+    }
+
+    /// set `key` in the default namespace to `value`
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.write_ops(vec![(String::new(), key, Some(value))])
+    }
+
+    /// remove `key` from the default namespace, failing if it isn't present
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.write_ops(vec![(String::new(), key, None)])
+    }
+
+    /// Apply every change in `batch` atomically, as `KvStore::write` does
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.write_ops(batch.ops)
+    }
+
+    /// Every key and value in the default namespace whose key starts with `prefix`, in key order
+    pub fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.scan_in("", prefix)
+    }
+
+    /// Watch for changes to keys in the default namespace starting with `prefix`, as
+    /// `KvStore::watch` does
+    pub fn watch(&self, prefix: &str) -> Watcher {
+        self.writer().watch(prefix)
+    }
+
+    /// Back the store up into the directory `path`, as `KvStore::backup_to` does. Writes only
+    /// wait while the backup is started, not while the files are copied.
+    pub fn backup_to(&self, path: impl AsRef<Path>) -> Result<BackupStats> {
+        let backup = self.writer().backup()?;
+        backup.write_to(path)
+    }
+
+    fn writer(&self) -> MutexGuard<'_, KvStore> {
+        // a panic while writing leaves the store as it was before the write, or with the write
+        // made but not yet shown to readers, both of which are safe to carry on from
+        self.inner.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn files(&self) -> RwLockReadGuard<'_, Files> {
+        self.inner.files.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn shard(&self, k: &(String, String)) -> &RwLock<Shard> {
+        &self.inner.shards[shard_of(&self.inner.hasher, k)]
+    }
+
+    fn get_in(&self, ns: &str, key: &str) -> Result<Option<String>> {
+        let files = self.files();
+        let k = (ns.to_owned(), key.to_owned());
+        let loc = self.shard(&k).read().unwrap_or_else(|e| e.into_inner()).get(&k).copied();
+        match loc {
+            Some(loc) => Ok(Some(files.read(ns, key, loc)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn scan_in(&self, ns: &str, prefix: &str) -> Result<Vec<(String, String)>> {
+        let files = self.files();
+        let mut found = Vec::new();
+        {
+            // hold every shard at once, taken in the same order as writers take them, so the scan
+            // sees a single point in time
+            let shards: Vec<_> = self.inner.shards.iter()
+                .map(|s| s.read().unwrap_or_else(|e| e.into_inner()))
+                .collect();
+            for shard in &shards {
+                found.extend(shard.iter()
+                    .filter(|((n, k), _)| n == ns && k.starts_with(prefix))
+                    .map(|((_, k), &loc)| (k.clone(), loc)));
+            }
+        }
+
+        // records are never overwritten, so the values can be read once the shards are let go
+        found.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        found.into_iter()
+            .map(|(key, loc)| {
+                let value = files.read(ns, &key, loc)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    fn write_ops(&self, ops: Vec<(String, String, Option<String>)>) -> Result<()> {
+        let mut writer = self.writer();
+        let keys: BTreeSet<(String, String)> = ops.iter()
+            .map(|(ns, key, _)| (ns.clone(), key.clone()))
+            .collect();
+        // a write can fail after it is in the log, when compacting say, so the index is updated
+        // from the store whatever happened
+        let written = writer.write_ops(ops);
+        written.and(self.update(&mut writer, keys))
+    }
+
+    /// show readers where `keys` are now
+    fn update(&self, writer: &mut KvStore, keys: BTreeSet<(String, String)>) -> Result<()> {
+        if self.files().rewrites != writer.rewrites {
+            return self.rebuild(writer);
+        }
+
+        let mut locs = Vec::with_capacity(keys.len());
+        {
+            let files = self.files();
+            for k in keys {
+                let loc = writer.location(&k.0, &k.1)?;
+                if let Some(Loc::Blob(blob)) = loc {
+                    // opened now, so it can still be read if garbage collection deletes it
+                    files.blobs.open(blob.file)?;
+                }
+                locs.push((k, loc));
+            }
+        }
+
+        // lock every shard the write touches, in order, so readers see all of it or none of it
+        let touched: BTreeSet<usize> = locs.iter()
+            .map(|(k, _)| shard_of(&self.inner.hasher, k))
+            .collect();
+        let mut shards: HashMap<usize, _> = touched.into_iter()
+            .map(|i| (i, self.inner.shards[i].write().unwrap_or_else(|e| e.into_inner())))
+            .collect();
+        for (k, loc) in locs {
+            let shard = shards.get_mut(&shard_of(&self.inner.hasher, &k)).expect("shard is locked");
+            match loc {
+                Some(loc) => shard.insert(k, loc),
+                None => shard.remove(&k),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// values have moved, so start again from the files as they are now
+    fn rebuild(&self, writer: &mut KvStore) -> Result<()> {
+        let mut files = self.inner.files.write().unwrap_or_else(|e| e.into_inner());
+        let mut shards: Vec<_> = self.inner.shards.iter()
+            .map(|s| s.write().unwrap_or_else(|e| e.into_inner()))
+            .collect();
+
+        *files = writer.files()?;
+        for shard in &mut shards {
+            shard.clear();
+        }
+        for (k, loc) in writer.locations()? {
+            shards[shard_of(&self.inner.hasher, &k)].insert(k, loc);
+        }
+
+        Ok(())
+    }
+}
+
+fn shard_of(hasher: &RandomState, k: &(String, String)) -> usize {
+    (hasher.hash_one(k) % SHARDS as u64) as usize
+}
+
+impl SharedNamespace<'_> {
+    /// name of this namespace
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// the value of `key` in this namespace, if it has one
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.get_in(&self.name, key)
+    }
+
+    /// set `key` in this namespace to `value`
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.write_ops(vec![(self.name.clone(), key, Some(value))])
+    }
+
+    /// remove `key` from this namespace, failing if it isn't present
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.store.write_ops(vec![(self.name.clone(), key, None)])
+    }
+
+    /// Every key and value in this namespace whose key starts with `prefix`, in key order
+    pub fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.store.scan_in(&self.name, prefix)
+    }
+
+    /// Watch for changes to keys in this namespace starting with `prefix`
+    pub fn watch(&self, prefix: &str) -> Watcher {
+        self.store.writer().namespace(&self.name).watch(prefix)
+    }
+}
@@ -0,0 +1,223 @@
+//! Thread pools for running server connections
+//!
+//! - `NaiveThreadPool` starts a new thread for every job
+//! - `SharedQueueThreadPool` has a fixed set of threads taking jobs from one shared queue
+//! - `WorkStealingThreadPool` has a fixed set of threads, each with its own queue. Jobs spawned
+//!   from inside the pool go to the spawning thread's queue, and idle threads steal from the others.
+//!
+//! In every pool a job that panics only loses that job: the panic is reported and the thread
+//! goes back to running jobs, so the pool never shrinks.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use snafu::ResultExt;
+
+use crate::{Result, ThreadSpawn};
+
+/// A job for a pool to run
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Something that runs jobs on other threads
+pub trait ThreadPool: Send + Sync {
+    /// Start a pool with `threads` threads. Pools that don't keep threads around ignore it.
+    fn new(threads: usize) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Queue `job` to be run on one of the pool's threads
+    fn execute(&self, job: Job);
+
+    /// Queue `job` to be run on one of the pool's threads
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+        Self: Sized,
+    {
+        self.execute(Box::new(job))
+    }
+}
+
+/// run `job`, keeping a panic from unwinding into the pool's thread
+fn run(job: Job) {
+    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        // the panic hook has already reported it
+        log::error!("thread pool job panicked");
+    }
+}
+
+fn start_thread(name: String, f: impl FnOnce() + Send + 'static) -> Result<()> {
+    thread::Builder::new().name(name).spawn(f).context(ThreadSpawn)?;
+    Ok(())
+}
+
+/// Runs every job on a thread of its own
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: usize) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn execute(&self, job: Job) {
+        thread::spawn(job);
+    }
+}
+
+/// A fixed number of threads taking jobs from a single queue
+///
+/// Dropping the pool lets the threads exit once the jobs already queued are done.
+#[derive(Debug)]
+pub struct SharedQueueThreadPool {
+    // the mutex is only there to make the pool `Sync`
+    jobs: Mutex<Sender<Job>>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: usize) -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            start_thread(format!("kvs-pool-{}", i), move || shared_queue_worker(&rx))?;
+        }
+
+        Ok(SharedQueueThreadPool { jobs: Mutex::new(tx) })
+    }
+
+    fn execute(&self, job: Job) {
+        let jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        jobs.send(job).expect("pool threads outlive the pool");
+    }
+}
+
+fn shared_queue_worker(rx: &Mutex<Receiver<Job>>) {
+    loop {
+        // hold the lock only while taking a job, not while running it
+        let job = rx.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        match job {
+            Ok(job) => run(job),
+            Err(_) => return,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    // jobs spawned from outside the pool
+    injector: Mutex<VecDeque<Job>>,
+    // one per thread, pushed and popped at the back by its owner and stolen from the front
+    locals: Vec<Mutex<VecDeque<Job>>>,
+    // jobs queued but not yet taken, guarded so that idle threads can wait for it to change
+    pending: Mutex<usize>,
+    ready: Condvar,
+    shutdown: AtomicBool,
+}
+
+thread_local! {
+    // the queues and index of the work stealing pool the current thread belongs to, if any
+    static WORKER: RefCell<Option<(Arc<Queues>, usize)>> = const { RefCell::new(None) };
+}
+
+impl Queues {
+    fn push(&self, queue: &Mutex<VecDeque<Job>>, job: Job) {
+        queue.lock().unwrap_or_else(|e| e.into_inner()).push_back(job);
+        *self.pending.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.ready.notify_one();
+    }
+
+    /// a job for thread `me`: its own newest job, else the oldest from outside the pool, else the
+    /// oldest job of another thread
+    fn find(&self, me: usize) -> Option<Job> {
+        let lock = |q: &Mutex<VecDeque<Job>>| q.lock().unwrap_or_else(|e| e.into_inner()).pop_front();
+        self.locals[me].lock().unwrap_or_else(|e| e.into_inner()).pop_back()
+            .or_else(|| lock(&self.injector))
+            .or_else(|| (1..self.locals.len())
+                .map(|i| (me + i) % self.locals.len())
+                .find_map(|victim| lock(&self.locals[victim])))
+    }
+
+    fn worker(self: &Arc<Self>, me: usize) {
+        WORKER.with(|w| *w.borrow_mut() = Some((self.clone(), me)));
+
+        loop {
+            {
+                let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+                while *pending == 0 {
+                    if self.shutdown.load(Ordering::Acquire) {
+                        return;
+                    }
+                    pending = self.ready.wait(pending).unwrap_or_else(|e| e.into_inner());
+                }
+            }
+
+            // another thread may have taken the job we were woken for, then we just wait again
+            if let Some(job) = self.find(me) {
+                *self.pending.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+                run(job);
+            }
+        }
+    }
+}
+
+/// A fixed number of threads, each with its own queue, that steal work from each other when idle
+///
+/// Jobs spawned from outside the pool go to a shared queue. Jobs spawned by a job running in the
+/// pool go to the queue of the thread running it, which takes its newest job first while other
+/// threads steal its oldest. Dropping the pool lets the threads exit once every queued job is done.
+pub struct WorkStealingThreadPool {
+    queues: Arc<Queues>,
+}
+
+impl std::fmt::Debug for WorkStealingThreadPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WorkStealingThreadPool")
+            .field("threads", &self.queues.locals.len())
+            .finish()
+    }
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: usize) -> Result<Self> {
+        let threads = threads.max(1);
+        let queues = Arc::new(Queues {
+            locals: (0..threads).map(|_| Mutex::default()).collect(),
+            ..Queues::default()
+        });
+        for i in 0..threads {
+            let queues = queues.clone();
+            start_thread(format!("kvs-steal-{}", i), move || queues.worker(i))?;
+        }
+
+        Ok(WorkStealingThreadPool { queues })
+    }
+
+    fn execute(&self, job: Job) {
+        let mut job = Some(job);
+        WORKER.with(|w| {
+            if let Some((queues, me)) = &*w.borrow() {
+                if Arc::ptr_eq(queues, &self.queues) {
+                    queues.push(&queues.locals[*me], job.take().expect("job not queued yet"));
+                }
+            }
+        });
+        if let Some(job) = job {
+            self.queues.push(&self.queues.injector, job);
+        }
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        self.queues.shutdown.store(true, Ordering::Release);
+        // take the lock so no thread is between checking the flag and waiting
+        let _pending = self.queues.pending.lock().unwrap_or_else(|e| e.into_inner());
+        self.queues.ready.notify_all();
+    }
+}
@@ -0,0 +1,369 @@
+//! Exporting the keys and values of a store, and importing them into another
+//!
+//! An export holds one record per key: the namespace, the key and its current value, as a JSON
+//! array of objects, as JSON lines, or as CSV with a header row. Records are written as each key
+//! is read and applied as they are read back, so neither side holds the whole dataset in memory.
+//!
+//! Keys and values are text, which all three formats can carry as is. For tools that would
+//! rather not deal with control characters or embedded newlines, an export can encode keys and
+//! values as base64 instead; the fields are then named `key_b64` and `value_b64`, which is how an
+//! import tells them apart.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::str::FromStr;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde_json::Value;
+use snafu::ResultExt;
+
+use crate::{ExportWrite, ImportRead, ImportRecord, KvStore, KvsError, Result};
+
+// records written to the log at once by `import`
+const IMPORT_BATCH: usize = 1000;
+
+/// Layout of an export, see `KvStore::export`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// a JSON array of `{"ns": .., "key": .., "value": ..}` objects
+    Json,
+    /// one JSON object per line
+    Jsonl,
+    /// a `ns,key,value` header, then one row per key
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {:?}, expected json, jsonl or csv", s)),
+        }
+    }
+}
+
+/// How keys and values are written in an export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// as they are, escaped as the format requires
+    Text,
+    /// base64 encoded, in fields named `key_b64` and `value_b64`
+    Base64,
+}
+
+impl Encoding {
+    fn fields(self) -> (&'static str, &'static str) {
+        match self {
+            Encoding::Text => ("key", "value"),
+            Encoding::Base64 => ("key_b64", "value_b64"),
+        }
+    }
+
+    fn encode(self, s: &str) -> String {
+        match self {
+            Encoding::Text => s.to_owned(),
+            Encoding::Base64 => STANDARD.encode(s),
+        }
+    }
+
+    fn decode(self, s: &str, record: u64, what: &str) -> Result<String> {
+        if self == Encoding::Text {
+            return Ok(s.to_owned());
+        }
+
+        let bytes = STANDARD.decode(s)
+            .map_err(|e| KvsError::ImportRecord { record, reason: format!("{} is not base64: {}", what, e) })?;
+        String::from_utf8(bytes)
+            .map_err(|_| KvsError::ImportRecord { record, reason: format!("{} is not UTF-8 text", what) })
+    }
+}
+
+impl KvStore {
+    /// Write the current value of every key in every namespace to `out` in `format`, one
+    /// namespace after another and in key order within each. Earlier versions kept as history
+    /// are left out. Returns the number of records written.
+    ///
+    /// ```
+    /// # use kvs::{Encoding, Format, KvStore};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let mut store = KvStore::open(dir.path())?;
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    ///
+    /// let mut out = Vec::new();
+    /// store.export(&mut out, Format::Jsonl, Encoding::Text)?;
+    /// assert_eq!(out, b"{\"ns\":\"\",\"key\":\"key1\",\"value\":\"value1\"}\n");
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn export(&mut self, out: impl Write, format: Format, encoding: Encoding) -> Result<u64> {
+        let mut out = BufWriter::new(out);
+        let mut written = 0;
+        match format {
+            Format::Json => out.write_all(b"[").context(ExportWrite)?,
+            Format::Jsonl => {}
+            Format::Csv => {
+                let (key, value) = encoding.fields();
+                writeln!(out, "ns,{},{}", key, value).context(ExportWrite)?;
+            }
+        }
+
+        for ns in self.namespaces() {
+            for key in self.keys_in(&ns, "")? {
+                // straight from disk, so a large export doesn't churn the value cache
+                let value = match self.read_value(&ns, &key)? {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                let (key, value) = (encoding.encode(&key), encoding.encode(&value));
+                match format {
+                    Format::Json => {
+                        out.write_all(if written == 0 { b"\n" } else { b",\n" }).context(ExportWrite)?;
+                        write_json(&mut out, encoding, &ns, &key, &value).context(ExportWrite)?;
+                    }
+                    Format::Jsonl => {
+                        write_json(&mut out, encoding, &ns, &key, &value).context(ExportWrite)?;
+                        out.write_all(b"\n").context(ExportWrite)?;
+                    }
+                    Format::Csv => {
+                        writeln!(out, "{},{},{}", csv_field(&ns), csv_field(&key), csv_field(&value)).context(ExportWrite)?;
+                    }
+                }
+                written += 1;
+            }
+        }
+
+        if format == Format::Json {
+            out.write_all(b"\n]\n").context(ExportWrite)?;
+        }
+        out.flush().context(ExportWrite)?;
+        Ok(written)
+    }
+
+    /// Set every key in an export read from `input` in `format`, whichever `Encoding` it was
+    /// written with. Records are written to the log in batches, and compaction waits until
+    /// they have all been written, so a large import doesn't compact over and over. Records
+    /// before a bad one stay imported. Returns the number of records imported.
+    pub fn import(&mut self, input: impl Read, format: Format) -> Result<u64> {
+        let input = BufReader::new(input);
+        self.defer_compaction = true;
+        let imported = {
+            let mut importer = Importer { store: self, batch: Vec::new(), records: 0 };
+            let read = match format {
+                Format::Json => importer.read_json(input),
+                Format::Jsonl => importer.read_jsonl(input),
+                Format::Csv => importer.read_csv(input),
+            };
+            // what was read before a bad record is still written
+            let flushed = importer.flush();
+            read.and(flushed).map(|()| importer.records)
+        };
+        self.defer_compaction = false;
+
+        let imported = imported?;
+        self.maybe_compact()?;
+        Ok(imported)
+    }
+}
+
+fn write_json(out: &mut impl Write, encoding: Encoding, ns: &str, key: &str, value: &str) -> io::Result<()> {
+    let (key_field, value_field) = encoding.fields();
+    out.write_all(b"{\"ns\":")?;
+    serde_json::to_writer(&mut *out, ns)?;
+    write!(out, ",\"{}\":", key_field)?;
+    serde_json::to_writer(&mut *out, key)?;
+    write!(out, ",\"{}\":", value_field)?;
+    serde_json::to_writer(&mut *out, value)?;
+    out.write_all(b"}")
+}
+
+/// `s` quoted if it has to be, with quotes doubled
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Read one CSV row from `input`, which may span several lines if a quoted field holds a
+/// newline. `None` at the end of the input.
+fn csv_row(input: &mut impl BufRead, record: u64) -> Result<Option<Vec<String>>> {
+    let mut line = String::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+
+    loop {
+        line.clear();
+        if input.read_line(&mut line).context(ImportRead)? == 0 {
+            if quoted {
+                return ImportRecord { record, reason: "unterminated quoted field".to_owned() }.fail();
+            }
+            if fields.is_empty() && field.is_empty() {
+                return Ok(None);
+            }
+            break;
+        }
+
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' if quoted => quoted = false,
+                '"' if field.is_empty() => quoted = true,
+                ',' if !quoted => fields.push(mem::take(&mut field)),
+                '\r' | '\n' if !quoted => {}
+                c => field.push(c),
+            }
+        }
+
+        // blank lines between rows are skipped
+        let blank = fields.is_empty() && field.is_empty();
+        if !quoted && !blank {
+            break;
+        }
+    }
+
+    fields.push(field);
+    Ok(Some(fields))
+}
+
+/// Records read from an export, on their way to the store
+struct Importer<'a> {
+    store: &'a mut KvStore,
+    batch: Vec<(String, String, Option<String>)>,
+    records: u64,
+}
+
+impl Importer<'_> {
+    fn add(&mut self, ns: String, key: String, value: String) -> Result<()> {
+        self.batch.push((ns, key, Some(value)));
+        self.records += 1;
+        if self.batch.len() >= IMPORT_BATCH {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let batch = mem::take(&mut self.batch);
+        self.store.write_ops(batch)
+    }
+
+    fn add_json(&mut self, record: Value) -> Result<()> {
+        let n = self.records + 1;
+        let bad = |reason: &str| KvsError::ImportRecord { record: n, reason: reason.to_owned() };
+        let mut record = match record {
+            Value::Object(record) => record,
+            _ => return Err(bad("not an object")),
+        };
+
+        let mut field = |name: &str| match record.remove(name) {
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => Err(bad(&format!("{} is not a string", name))),
+            None => Ok(None),
+        };
+        let ns = field("ns")?.unwrap_or_default();
+        let (encoding, key, value) = match (field("key")?, field("value")?, field("key_b64")?, field("value_b64")?) {
+            (Some(key), Some(value), None, None) => (Encoding::Text, key, value),
+            (None, None, Some(key), Some(value)) => (Encoding::Base64, key, value),
+            _ => return Err(bad("expected key and value, or key_b64 and value_b64")),
+        };
+
+        let key = encoding.decode(&key, n, "key")?;
+        let value = encoding.decode(&value, n, "value")?;
+        self.add(ns, key, value)
+    }
+
+    fn json_error(&self, e: serde_json::Error) -> KvsError {
+        if e.is_io() {
+            KvsError::ImportRead { source: e.into() }
+        } else {
+            KvsError::ImportRecord { record: self.records + 1, reason: e.to_string() }
+        }
+    }
+
+    fn read_json(&mut self, input: impl Read) -> Result<()> {
+        let mut de = serde_json::Deserializer::from_reader(input);
+        let mut elements = Elements { importer: self, failed: None };
+        let read = (&mut de).deserialize_seq(&mut elements);
+        if let Some(e) = elements.failed {
+            return Err(e);
+        }
+
+        read.and_then(|()| de.end()).map_err(|e| self.json_error(e))
+    }
+
+    fn read_jsonl(&mut self, input: impl BufRead) -> Result<()> {
+        for line in input.lines() {
+            let line = line.context(ImportRead)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record = serde_json::from_str(&line).map_err(|e| self.json_error(e))?;
+            self.add_json(record)?;
+        }
+        Ok(())
+    }
+
+    fn read_csv(&mut self, mut input: impl BufRead) -> Result<()> {
+        let header = csv_row(&mut input, 0)?;
+        let encoding = match header.as_ref().map(|h| h.iter().map(String::as_str).collect::<Vec<_>>()).as_deref() {
+            None => return Ok(()),
+            Some(["ns", "key", "value"]) => Encoding::Text,
+            Some(["ns", "key_b64", "value_b64"]) => Encoding::Base64,
+            Some(_) => return ImportRecord { record: 0u64, reason: "expected a ns,key,value or ns,key_b64,value_b64 header".to_owned() }.fail(),
+        };
+
+        loop {
+            let n = self.records + 1;
+            let row = match csv_row(&mut input, n)? {
+                Some(row) => row,
+                None => return Ok(()),
+            };
+            let [ns, key, value]: [String; 3] = row.try_into()
+                .map_err(|row: Vec<String>| KvsError::ImportRecord { record: n, reason: format!("expected 3 fields, found {}", row.len()) })?;
+
+            let key = encoding.decode(&key, n, "key")?;
+            let value = encoding.decode(&value, n, "value")?;
+            self.add(ns, key, value)?;
+        }
+    }
+}
+
+/// Imports each element of a JSON array as it is parsed, rather than parsing the whole array first
+struct Elements<'i, 'a> {
+    importer: &'i mut Importer<'a>,
+    // why the import stopped, if it wasn't the JSON itself
+    failed: Option<KvsError>,
+}
+
+impl<'de> Visitor<'de> for &mut Elements<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("an array of records")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+        while let Some(record) = seq.next_element::<Value>()? {
+            if let Err(e) = self.importer.add_json(record) {
+                self.failed = Some(e);
+                return Err(de::Error::custom("import failed"));
+            }
+        }
+        Ok(())
+    }
+}
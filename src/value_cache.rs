@@ -0,0 +1,126 @@
+//! Byte bounded LRU cache of decoded values
+//!
+//! Entries are charged for the length of their namespace and key plus their value. Recency is tracked with a
+//! counter that is bumped on every access, and a `BTreeMap` from that counter to the key gives us
+//! the least recently used entry to evict.
+//!
+//! Values are cached by key rather than by log offset, so compaction and blob garbage collection,
+//! which move records around without changing them, leave cached values valid. Only `set` and
+//! `remove` need to invalidate.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Counters for the value cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ValueCacheStats {
+    /// `get`s answered from the cache
+    pub hits: u64,
+    /// `get`s that had to go to disk
+    pub misses: u64,
+    /// values currently cached
+    pub entries: usize,
+    /// bytes of keys and values currently cached
+    pub bytes: usize,
+    /// most bytes the cache will hold
+    pub capacity: usize,
+}
+
+#[derive(Debug)]
+struct CachedValue {
+    value: String,
+    last_used: u64,
+}
+
+// (namespace, key)
+type CacheKey = (String, String);
+
+fn size_of(key: &CacheKey, value: &str) -> usize {
+    key.0.len() + key.1.len() + value.len()
+}
+
+#[derive(Debug)]
+pub(crate) struct ValueCache {
+    capacity: usize,
+    bytes: usize,
+    tick: u64,
+    values: HashMap<CacheKey, CachedValue>,
+    by_use: BTreeMap<u64, CacheKey>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            bytes: 0,
+            tick: 0,
+            values: HashMap::new(),
+            by_use: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// look up `key` in namespace `ns`, counting a hit or a miss
+    pub fn get(&mut self, ns: &str, key: &str) -> Option<String> {
+        let tick = self.tick;
+        match self.values.get_mut(&(ns.to_owned(), key.to_owned())) {
+            Some(cached) => {
+                self.hits += 1;
+                self.tick += 1;
+                let key = self.by_use.remove(&cached.last_used).expect("cached key is tracked");
+                self.by_use.insert(tick, key);
+                cached.last_used = tick;
+                Some(cached.value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// cache `value` for `key` in namespace `ns`, evicting the least recently used values to make
+    /// room
+    pub fn insert(&mut self, ns: String, key: String, value: String) {
+        self.invalidate(&ns, &key);
+
+        let key = (ns, key);
+        let size = size_of(&key, &value);
+        if size > self.capacity {
+            return;
+        }
+
+        while self.bytes + size > self.capacity {
+            let (_, lru) = self.by_use.pop_first().expect("cache over capacity has entries");
+            let evicted = self.values.remove(&lru).expect("tracked key is cached");
+            self.bytes -= size_of(&lru, &evicted.value);
+        }
+
+        let tick = self.tick;
+        self.tick += 1;
+        self.bytes += size;
+        self.by_use.insert(tick, key.clone());
+        self.values.insert(key, CachedValue { value, last_used: tick });
+    }
+
+    /// forget any cached value for `key` in namespace `ns`
+    pub fn invalidate(&mut self, ns: &str, key: &str) {
+        let key = (ns.to_owned(), key.to_owned());
+        if let Some(cached) = self.values.remove(&key) {
+            self.by_use.remove(&cached.last_used);
+            self.bytes -= size_of(&key, &cached.value);
+        }
+    }
+
+    pub fn stats(&self) -> ValueCacheStats {
+        ValueCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.values.len(),
+            bytes: self.bytes,
+            capacity: self.capacity,
+        }
+    }
+}
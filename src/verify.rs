@@ -0,0 +1,364 @@
+//! Checking a store's files for damage, and salvaging what can still be read
+//!
+//! Verifying walks the whole log without relying on opening the store, so it also works on a log
+//! `KvStore::open` gives up on. After a damaged record it scans forward for the next sync marker
+//! that starts an intact record and carries on from there, see `log`; records written before
+//! framing have no marker, so in an old log everything after the damage is skipped. If every
+//! record can be read, the store is then opened read-only and its index checked against the last
+//! change to each key in the log, including reading every live value back. Nothing is changed.
+//!
+//! Repairing copies every record that can still be read into a fresh log, which replaces the
+//! damaged one. The damaged log is kept next to it rather than deleted, along with a report of
+//! the bytes that were skipped.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use snafu::ResultExt;
+use speedy::IsEof;
+
+use crate::crypto::{self, LogCipher};
+use crate::log::{read_record, write_record, LogEntry, LogReader, NsKeys, Op, ReadError, SYNC};
+use crate::mmap::LogMap;
+use crate::{sync_dir, GetPosition, KeyLoad, KvStore, KvsError, LogHeader, Mmap, NonceGeneration, OpenLog, OpenOptions, Repair, Result};
+
+/// Something wrong with a store, found by `OpenOptions::verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// A record couldn't be decoded
+    Unreadable {
+        /// offset of the record in the log
+        offs: u64,
+        /// why it couldn't be decoded
+        reason: String,
+    },
+    /// The log ends partway through a record, as when a write was cut short
+    Truncated {
+        /// offset of the record in the log
+        offs: u64,
+    },
+    /// A record doesn't match its checksum, or an encrypted one failed authentication, so it
+    /// was damaged or tampered with
+    Checksum {
+        /// offset of the record in the log
+        offs: u64,
+    },
+    /// The index points a key at a record for a different key
+    KeyMismatch {
+        /// namespace of the key
+        ns: String,
+        /// the key
+        key: String,
+        /// offset of the record
+        offs: u64,
+        /// a key the record is for
+        found_key: String,
+    },
+    /// The index points a key at a record that doesn't set it
+    KindInvalid {
+        /// namespace of the key
+        ns: String,
+        /// the key
+        key: String,
+        /// offset of the record
+        offs: u64,
+    },
+    /// The index disagrees with the log about where a key's current value is
+    IndexMismatch {
+        /// namespace of the key
+        ns: String,
+        /// the key
+        key: String,
+        /// the record the index points at, if any
+        index: Option<u64>,
+        /// the record that last set the key, if it wasn't removed since
+        log: Option<u64>,
+    },
+    /// The current value of a key couldn't be read, for example from a missing blob file
+    Value {
+        /// namespace of the key
+        ns: String,
+        /// the key
+        key: String,
+        /// why it couldn't be read
+        reason: String,
+    },
+    /// The store couldn't be opened to check its index
+    Open {
+        /// why it couldn't be opened
+        reason: String,
+    },
+    /// A compaction didn't finish and left its new log behind
+    LeftoverCompaction {
+        /// the unfinished log
+        filename: PathBuf,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Unreadable { offs, reason } => {
+                write!(f, "record at {} can't be read: {}", offs, reason)
+            }
+            Problem::Truncated { offs } => write!(f, "the log ends partway through the record at {}", offs),
+            Problem::Checksum { offs } => write!(f, "record at {} fails its checksum", offs),
+            Problem::KeyMismatch { ns, key, offs, found_key } => {
+                write!(f, "index points {:?} in {:?} at the record at {}, which is for {:?}", key, ns, offs, found_key)
+            }
+            Problem::KindInvalid { ns, key, offs } => {
+                write!(f, "index points {:?} in {:?} at the record at {}, which doesn't set it", key, ns, offs)
+            }
+            Problem::IndexMismatch { ns, key, index, log } => {
+                write!(f, "index has {:?} in {:?} at {:?}, but the log last set it at {:?}", key, ns, index, log)
+            }
+            Problem::Value { ns, key, reason } => write!(f, "value of {:?} in {:?} can't be read: {}", key, ns, reason),
+            Problem::Open { reason } => write!(f, "store can't be opened: {}", reason),
+            Problem::LeftoverCompaction { filename } => {
+                write!(f, "{} is left over from a compaction that didn't finish", filename.display())
+            }
+        }
+    }
+}
+
+/// What `OpenOptions::verify` or `OpenOptions::repair` found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// records in the log that could be read
+    pub records: u64,
+    /// everything found wrong, in the order it was found
+    pub problems: Vec<Problem>,
+    /// the byte ranges of the log that were skipped to get past damaged records
+    pub skipped: Vec<Range<u64>>,
+    /// where `repair` kept the damaged log, if it replaced it
+    pub damaged_log: Option<PathBuf>,
+    /// where `repair` wrote what it skipped, if it replaced the log
+    pub repair_report: Option<PathBuf>,
+}
+
+impl VerifyReport {
+    /// Whether nothing was found wrong
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// The offset of the first framed record at or after `from` that reads back whole
+fn resync(map: &LogMap, from: u64, cipher: Option<&LogCipher>) -> Option<u64> {
+    let bytes = map.from(from)?;
+    let mut start = 0;
+    while let Some(i) = bytes[start..].windows(SYNC.len()).position(|w| w == SYNC) {
+        let at = start + i;
+        if read_record::<LogEntry>(&bytes[at..], cipher, from + at as u64).is_ok() {
+            return Some(from + at as u64);
+        }
+        start = at + 1;
+    }
+    None
+}
+
+/// Call `f` with the offset of each record in `log` that can be read, counting them in `report`
+/// and noting the ones that can't, along with the bytes skipped to get past them
+fn walk<F>(log: &Path, cipher: Option<&LogCipher>, report: &mut VerifyReport, mut f: F) -> Result<()>
+    where F: FnMut(u64, LogEntry) -> Result<()>
+{
+    let log_f = File::open(log)
+        .context(OpenLog { filename: log.to_owned() })?;
+    let map = match LogMap::new(&log_f).context(Mmap { filename: log.to_owned() })? {
+        Some(map) => map,
+        None => return Ok(()),
+    };
+
+    let end = map.len();
+    let mut offs = if cipher.is_some() { crypto::HEADER_LEN } else { 0 };
+    while offs < end {
+        let mut rest = map.from(offs).expect("offset is inside the map");
+        let before = rest.len();
+        let problem = match read_record(&mut rest, cipher, offs) {
+            Ok(entry) => {
+                report.records += 1;
+                f(offs, entry)?;
+                offs += (before - rest.len()) as u64;
+                continue;
+            }
+            Err(ReadError::Parse(e)) if e.is_eof() => Problem::Truncated { offs },
+            Err(ReadError::Parse(e)) => Problem::Unreadable { offs, reason: e.to_string() },
+            Err(ReadError::Authentication) | Err(ReadError::Checksum) => Problem::Checksum { offs },
+        };
+
+        // carry on from the next record that is intact
+        let next = resync(&map, offs + 1, cipher).unwrap_or(end);
+        report.problems.push(problem);
+        report.skipped.push(offs..next);
+        offs = next;
+    }
+
+    Ok(())
+}
+
+impl OpenOptions {
+    /// the cipher of the log in `log`, which isn't empty
+    fn log_cipher(&self, log: &Path) -> Result<Option<LogCipher>> {
+        let key = match &self.key {
+            Some(k) => Some(k.load().context(KeyLoad)?),
+            None => None,
+        };
+        let mut log_f = File::open(log)
+            .context(OpenLog { filename: log.to_owned() })?;
+        KvStore::open_cipher(&mut log_f, log, key)
+    }
+
+    /// Check the store in `path`, opened with these options, for damage and inconsistencies.
+    /// The store is only read. Fails only if the log can't be read at all, or is encrypted with
+    /// a key other than these options give.
+    pub fn verify(&self, path: impl Into<PathBuf>) -> Result<VerifyReport> {
+        let path = path.into();
+        let log = path.join("kvs.db");
+        let mut report = VerifyReport::default();
+
+        let tmp = path.join("kvs.db.tmp");
+        if tmp.exists() {
+            report.problems.push(Problem::LeftoverCompaction { filename: tmp });
+        }
+        if fs::metadata(&log).map_or(true, |m| m.len() == 0) {
+            return Ok(report);
+        }
+
+        // the last change to each key, as the index should have it
+        let mut latest: HashMap<(String, String), (u64, bool)> = HashMap::new();
+        let cipher = self.log_cipher(&log)?;
+        walk(&log, cipher.as_ref(), &mut report, |offs, entry| {
+            // earlier versions kept for history don't change what is current
+            if let LogEntry::Retained { .. } = entry {
+                return Ok(());
+            }
+            for (ns, key, op) in entry.ops() {
+                let set = !matches!(op, Op::Remove);
+                latest.insert((ns.to_owned(), key.to_owned()), (offs, set));
+            }
+            Ok(())
+        })?;
+
+        // the index is only worth checking if it was built from the whole log
+        if !report.skipped.is_empty() {
+            return Ok(report);
+        }
+        let mut store = match self.open_read_only(&path) {
+            Ok(store) => store,
+            Err(e) => {
+                report.problems.push(Problem::Open { reason: e.to_string() });
+                return Ok(report);
+            }
+        };
+
+        let mut keys: Vec<_> = latest.into_iter().collect();
+        keys.sort_unstable();
+        for ((ns, key), (offs, set)) in keys {
+            let index = match store.spaces.get(&ns) {
+                Some(space) => {
+                    let mut reader = LogReader { f: &mut store.log_f, map: store.map.as_ref(), cipher: store.cipher.as_ref(), filename: &store.log_f_name };
+                    space.index.get(&key, &mut NsKeys { reader: &mut reader, ns: &ns })?
+                }
+                None => None,
+            };
+            let log = if set { Some(offs) } else { None };
+            if index != log {
+                report.problems.push(Problem::IndexMismatch { ns: ns.clone(), key: key.clone(), index, log });
+            }
+            if index.is_none() {
+                continue;
+            }
+
+            match store.read_value(&ns, &key) {
+                Ok(_) => {}
+                Err(KvsError::LogEntryKeyMismatch { found_key, offs, .. }) => {
+                    report.problems.push(Problem::KeyMismatch { ns, key, offs, found_key });
+                }
+                Err(KvsError::LogEntryKindInvalid { offs, .. }) => {
+                    report.problems.push(Problem::KindInvalid { ns, key, offs });
+                }
+                Err(e) => report.problems.push(Problem::Value { ns, key, reason: e.to_string() }),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Verify the store in `path` like `verify`, and if any records are damaged, copy every one
+    /// that can still be read into a fresh log that replaces the damaged one. The damaged log is
+    /// kept as `kvs.db.damaged` (or `kvs.db.damaged.1` and so on, if that is taken), with the
+    /// byte ranges skipped and the problems found listed in a `.report` file next to it. A
+    /// leftover `kvs.db.tmp` is removed. The report is of what was found before repairing.
+    pub fn repair(&self, path: impl Into<PathBuf>) -> Result<VerifyReport> {
+        let path = path.into();
+        let mut report = self.verify(&path)?;
+
+        let log = path.join("kvs.db");
+        let tmp = path.join("kvs.db.tmp");
+        if tmp.exists() {
+            fs::remove_file(&tmp).context(Repair { filename: tmp.clone() })?;
+        }
+        // the index is rebuilt whenever the store is opened, so only damage to the log needs fixing
+        if report.skipped.is_empty() {
+            return Ok(report);
+        }
+
+        let cipher = self.log_cipher(&log)?;
+        let new_cipher = match &cipher {
+            Some(c) => Some(LogCipher::generate(c.key()).context(NonceGeneration { filename: tmp.clone() })?),
+            None => None,
+        };
+        let mut tmp_log = fs::OpenOptions::new().create(true).truncate(true).write(true).open(&tmp)
+            .context(OpenLog { filename: tmp.clone() })?;
+        {
+            let mut w = BufWriter::new(&mut tmp_log);
+            if let Some(c) = &new_cipher {
+                c.write_header(&mut w).context(LogHeader { filename: tmp.clone() })?;
+            }
+
+            // already reported by `verify`
+            walk(&log, cipher.as_ref(), &mut VerifyReport::default(), |_, entry| {
+                let offs = w.stream_position().context(GetPosition { filename: tmp.clone() })?;
+                write_record(&mut w, new_cipher.as_ref(), offs, &entry).map_err(|e| entry.append_error(e))
+            })?;
+            w.flush().context(Repair { filename: tmp.clone() })?;
+        }
+        tmp_log.sync_all().context(Repair { filename: tmp.clone() })?;
+
+        let damaged = (0..)
+            .map(|n| match n {
+                0 => path.join("kvs.db.damaged"),
+                n => path.join(format!("kvs.db.damaged.{}", n)),
+            })
+            .find(|p| !p.exists())
+            .expect("some name is free");
+        // the damaged log stays in place until the salvaged one replaces it, so a crash part way
+        // leaves one or the other as `kvs.db`
+        fs::hard_link(&log, &damaged).context(Repair { filename: damaged.clone() })?;
+        sync_dir(&path).context(Repair { filename: path.clone() })?;
+        fs::rename(&tmp, &log).context(Repair { filename: tmp })?;
+        sync_dir(&path).context(Repair { filename: path.clone() })?;
+
+        let mut written = format!("Salvaged {} records from {}\n", report.records, damaged.display());
+        for range in &report.skipped {
+            written += &format!("skipped bytes {}..{} ({} bytes)\n", range.start, range.end, range.end - range.start);
+        }
+        for problem in &report.problems {
+            written += &format!("{}\n", problem);
+        }
+        let mut report_file = damaged.clone().into_os_string();
+        report_file.push(".report");
+        let report_file = PathBuf::from(report_file);
+        fs::write(&report_file, written).context(Repair { filename: report_file.clone() })?;
+        report.damaged_log = Some(damaged);
+        report.repair_report = Some(report_file);
+
+        // make sure what was salvaged opens
+        self.open_read_only(&path)?;
+        Ok(report)
+    }
+}
@@ -0,0 +1,178 @@
+//! Change notifications for `KvStore::watch`
+//!
+//! Each watcher has its own bounded queue. The store pushes events into it once a write is in
+//! the log (and synced, with `OpenOptions::sync_writes`), before any compaction the write sets
+//! off, and never blocks on a slow watcher: if a queue fills up the watcher is dropped from the
+//! store and told to resync the next time it reads, rather than being handed an incomplete stream.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::{KvsError, Result};
+
+/// A change to a watched key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// the key that changed
+    pub key: String,
+    /// did the key have a value before this change?
+    pub existed: bool,
+    /// the new value, `None` if the key was removed
+    pub value: Option<String>,
+    /// sequence number of the write that made this change, as recorded in the log (see
+    /// `KvStore::last_seq`). Changes made by one `WriteBatch` share it, and it carries on from
+    /// where it was when the store is reopened.
+    pub seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    events: VecDeque<WatchEvent>,
+    // the queue overflowed, the watcher needs to be told before anything else
+    lagged: bool,
+    // no more events will arrive
+    closed: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+impl Shared {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        // a panic while holding the lock can't leave the queue half updated
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Receives changes to the keys under a prefix, from `KvStore::watch`
+///
+/// Events arrive in the order the changes were made. Iterating blocks until the next event and
+/// ends once the store is dropped.
+#[derive(Debug)]
+pub struct Watcher {
+    shared: Arc<Shared>,
+    capacity: usize,
+}
+
+impl Watcher {
+    /// Wait for the next event. Returns `None` once the store has been dropped and every event
+    /// has been received, and `WatchLagged` if events were missed, after which the watcher is
+    /// closed.
+    pub fn recv(&self) -> Result<Option<WatchEvent>> {
+        let mut state = self.shared.state();
+        loop {
+            if let Some(event) = self.take(&mut state)? {
+                return Ok(Some(event));
+            }
+            if state.closed {
+                return Ok(None);
+            }
+            state = self.shared.ready.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Like `recv`, but gives up and returns `None` after `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<WatchEvent>> {
+        let mut state = self.shared.state();
+        if let Some(event) = self.take(&mut state)? {
+            return Ok(Some(event));
+        }
+        if state.closed {
+            return Ok(None);
+        }
+
+        let (mut state, _) = self.shared.ready.wait_timeout(state, timeout).unwrap_or_else(|e| e.into_inner());
+        self.take(&mut state)
+    }
+
+    /// The next event if there is one already, without waiting
+    pub fn try_recv(&self) -> Result<Option<WatchEvent>> {
+        self.take(&mut self.shared.state())
+    }
+
+    fn take(&self, state: &mut State) -> Result<Option<WatchEvent>> {
+        if state.lagged {
+            state.lagged = false;
+            state.closed = true;
+            return Err(KvsError::WatchLagged { capacity: self.capacity });
+        }
+
+        Ok(state.events.pop_front())
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv().transpose()
+    }
+}
+
+#[derive(Debug)]
+struct Subscription {
+    ns: String,
+    prefix: String,
+    capacity: usize,
+    shared: Arc<Shared>,
+}
+
+/// The store's side of every watcher
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers {
+    subs: Vec<Subscription>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&mut self, ns: &str, prefix: &str, capacity: usize) -> Watcher {
+        let shared = Arc::new(Shared::default());
+        self.subs.push(Subscription { ns: ns.to_owned(), prefix: prefix.to_owned(), capacity, shared: shared.clone() });
+        Watcher { shared, capacity }
+    }
+
+    /// does anyone want to hear about changes to `key` in `ns`?
+    pub fn wants(&self, ns: &str, key: &str) -> bool {
+        self.subs.iter().any(|s| s.ns == ns && key.starts_with(&s.prefix))
+    }
+
+    /// hand `events` (with the namespace they happened in) to everyone watching their keys
+    pub fn publish(&mut self, events: &[(String, WatchEvent)]) {
+        // watchers that have been dropped don't need telling
+        self.subs.retain(|s| Arc::strong_count(&s.shared) > 1);
+
+        self.subs.retain(|sub| {
+            let mut matching = events.iter()
+                .filter(|(ns, e)| *ns == sub.ns && e.key.starts_with(&sub.prefix))
+                .peekable();
+            if matching.peek().is_none() {
+                return true;
+            }
+
+            let mut state = sub.shared.state();
+            for (_, event) in matching {
+                if state.events.len() == sub.capacity {
+                    // there is no point keeping the rest, the watcher has to start over
+                    state.events.clear();
+                    state.lagged = true;
+                    break;
+                }
+                state.events.push_back(event.clone());
+            }
+            sub.shared.ready.notify_all();
+            !state.lagged
+        });
+    }
+}
+
+impl Drop for Subscribers {
+    fn drop(&mut self) {
+        for sub in &self.subs {
+            sub.shared.state().closed = true;
+            sub.shared.ready.notify_all();
+        }
+    }
+}
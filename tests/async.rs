@@ -0,0 +1,96 @@
+#![cfg(feature = "async")]
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{AsyncKvStore, KvStore, KvsError, Result};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+#[test]
+fn get_set_remove_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let rt = Runtime::new().expect("runtime");
+
+    rt.block_on(async {
+        let store = AsyncKvStore::open(temp_dir.path()).await?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        store.set("other".to_owned(), "value3".to_owned()).await?;
+        assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+
+        store.remove("key1".to_owned()).await?;
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        assert!(matches!(store.remove("key1".to_owned()).await, Err(KvsError::RemoveNonexistentKey { .. })));
+
+        assert_eq!(store.scan("key".to_owned()).await?, vec![("key2".to_owned(), "value2".to_owned())]);
+        Ok::<(), KvsError>(())
+    })?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn many_tasks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let rt = Runtime::new().expect("runtime");
+
+    rt.block_on(async {
+        let store = AsyncKvStore::open(temp_dir.path()).await?;
+        let tasks: Vec<_> = (0..16)
+            .map(|t| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    for i in 0..20 {
+                        let key = format!("task{}.key{}", t, i);
+                        store.set(key.clone(), format!("{}", i)).await?;
+                        assert_eq!(store.get(key).await?, Some(format!("{}", i)));
+                    }
+                    Ok::<(), KvsError>(())
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.expect("task panicked")?;
+        }
+
+        for t in 0..16 {
+            assert_eq!(store.scan(format!("task{}.", t)).await?.len(), 20);
+        }
+        Ok::<(), KvsError>(())
+    })
+}
+
+#[test]
+fn cancelled_write_is_not_made() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let rt = Runtime::new().expect("runtime");
+
+    rt.block_on(async {
+        let store = AsyncKvStore::open(temp_dir.path()).await?;
+
+        // keep the store busy so the write has to wait for it
+        let (locked, is_locked) = mpsc::channel();
+        let busy = store.clone();
+        let busy = tokio::spawn(async move {
+            busy.with(move |_| {
+                locked.send(()).expect("send");
+                thread::sleep(Duration::from_millis(300));
+                Ok(())
+            }).await
+        });
+        is_locked.recv().expect("store locked");
+
+        let set = store.set("key1".to_owned(), "value1".to_owned());
+        assert!(tokio::time::timeout(Duration::from_millis(20), set).await.is_err());
+
+        busy.await.expect("task panicked")?;
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        Ok::<(), KvsError>(())
+    })
+}
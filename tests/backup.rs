@@ -0,0 +1,189 @@
+use std::fs;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsError, OpenOptions, Result, SharedKvStore};
+use predicates::str::contains;
+use tempfile::TempDir;
+
+#[test]
+fn incremental_backups() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let big = "x".repeat(4096);
+
+    let mut store = OpenOptions::new().blob_threshold(1024).open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.namespace("users").set("alice".to_owned(), big.clone())?;
+
+    let first = store.backup_to(backup_dir.path())?;
+    assert_eq!(first.files, 2);
+    assert_eq!(first.bytes_copied, first.bytes_total);
+
+    // only what was appended since is copied
+    for i in 10..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let second = store.backup_to(backup_dir.path())?;
+    assert!(second.bytes_total > first.bytes_total);
+    assert_eq!(second.bytes_copied, second.bytes_total - first.bytes_total);
+
+    // also after reopening the store
+    drop(store);
+    let mut store = OpenOptions::new().blob_threshold(1024).open(temp_dir.path())?;
+    store.set("key20".to_owned(), "value20".to_owned())?;
+    let third = store.backup_to(backup_dir.path())?;
+    assert_eq!(third.bytes_copied, third.bytes_total - second.bytes_total);
+
+    // compaction writes a new log, which is copied in full
+    for i in 0..30 {
+        store.set("key0".to_owned(), format!("again{}", i))?;
+    }
+    let fourth = store.backup_to(backup_dir.path())?;
+    let log_len = fs::metadata(temp_dir.path().join("kvs.db")).expect("log").len();
+    assert_eq!(fourth.bytes_copied, log_len);
+
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut restored = KvStore::restore(backup_dir.path(), restored_dir.path())?;
+    assert_eq!(restored.get("key0".to_owned())?, Some("again29".to_owned()));
+    for i in 1..21 {
+        assert_eq!(restored.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(restored.namespace("users").get("alice".to_owned())?, Some(big));
+
+    // restoring never overwrites a store
+    assert!(matches!(
+        KvStore::restore(backup_dir.path(), restored_dir.path()),
+        Err(KvsError::RestoreTarget { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn damaged_backups_are_not_restored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.backup_to(backup_dir.path())?;
+
+    let log = backup_dir.path().join("kvs.db");
+    let mut bytes = fs::read(&log).expect("read backup");
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    fs::write(&log, &bytes).expect("write backup");
+
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStore::restore(backup_dir.path(), restored_dir.path()),
+        Err(KvsError::BackupChecksum { .. })
+    ));
+    assert!(fs::read_dir(restored_dir.path()).expect("read dir").next().is_none());
+
+    // the next backup notices the copy is damaged and starts over
+    let stats = store.backup_to(backup_dir.path())?;
+    assert_eq!(stats.bytes_copied, stats.bytes_total);
+    KvStore::restore(backup_dir.path(), restored_dir.path())?;
+
+    // a backup that never finished has no manifest
+    fs::remove_file(backup_dir.path().join("backup.manifest")).expect("remove manifest");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStore::restore(backup_dir.path(), other_dir.path()),
+        Err(KvsError::BackupIo { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn backup_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+
+    // keys are written in order, and overwritten often enough to compact now and then
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (store, stop) = (store.clone(), stop.clone());
+        thread::spawn(move || -> Result<u32> {
+            let mut i = 0;
+            while !stop.load(Ordering::SeqCst) {
+                store.set(format!("key{}", i), i.to_string())?;
+                store.set("latest".to_owned(), i.to_string())?;
+                i += 1;
+            }
+            Ok(i)
+        })
+    };
+
+    for round in 0..5 {
+        store.backup_to(backup_dir.path())?;
+
+        // whatever the backup caught is a consistent prefix of the writes
+        let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut restored = KvStore::restore(backup_dir.path(), restored_dir.path())?;
+        let latest: u32 = match restored.get("latest".to_owned())? {
+            Some(latest) => latest.parse().expect("a number"),
+            None => continue,
+        };
+        for i in 0..=latest {
+            assert_eq!(restored.get(format!("key{}", i))?, Some(i.to_string()), "round {}", round);
+        }
+        assert_eq!(restored.get(format!("key{}", latest + 2))?, None);
+    }
+
+    stop.store(true, Ordering::SeqCst);
+    let written = writer.join().unwrap()?;
+    assert!(written > 0);
+
+    Ok(())
+}
+
+#[test]
+fn cli_backup_restore() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs").unwrap()
+        .args(["backup"])
+        .arg(backup_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Backed up 1 files"));
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["restore"])
+        .arg(backup_dir.path())
+        .current_dir(&restored_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs").unwrap()
+        .args(["get", "key1"])
+        .current_dir(&restored_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["restore"])
+        .arg(backup_dir.path())
+        .current_dir(&restored_dir)
+        .assert()
+        .failure();
+}
@@ -0,0 +1,127 @@
+use kvs::{Cipher, EncryptionKey, KvStore, OpenOptions, Result};
+use std::path::Path;
+use tempfile::TempDir;
+
+fn blob_files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .filter(|n| n.starts_with("kvs.blob."))
+        .collect();
+    names.sort();
+    names
+}
+
+fn log_size(dir: &Path) -> u64 {
+    std::fs::metadata(dir.join("kvs.db")).unwrap().len()
+}
+
+// Values over the threshold should end up outside of the main log.
+#[test]
+fn large_values_in_blob_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let big = "x".repeat(64 * 1024);
+
+    let mut store = OpenOptions::new().blob_threshold(1024).open(temp_dir.path())?;
+    store.set("big".to_owned(), big.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert!(log_size(temp_dir.path()) < 1024);
+    assert_eq!(blob_files(temp_dir.path()).len(), 1);
+    assert_eq!(store.get("big".to_owned())?, Some(big.clone()));
+    drop(store);
+
+    // the threshold only decides where new values go, existing blobs are readable regardless
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(big));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    store.remove("big".to_owned())?;
+    assert_eq!(store.get("big".to_owned())?, None);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, None);
+    assert!(blob_files(temp_dir.path()).is_empty());
+
+    Ok(())
+}
+
+// Overwriting large values should not leave an ever growing pile of blob files behind.
+#[test]
+fn blob_garbage_collection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut opts = OpenOptions::new();
+    opts.blob_threshold(1024).blob_file_size(16 * 1024);
+
+    let mut store = opts.open(temp_dir.path())?;
+    // a value that is never overwritten keeps being moved forward by garbage collection
+    store.set("stable".to_owned(), "s".repeat(4096))?;
+    for iter in 0..200 {
+        for key_id in 0..4 {
+            store.set(format!("key{}", key_id), format!("{:04}", iter).repeat(1024))?;
+        }
+    }
+
+    assert!(blob_files(temp_dir.path()).len() <= 4, "{:?}", blob_files(temp_dir.path()));
+    drop(store);
+
+    let mut store = opts.open(temp_dir.path())?;
+    assert_eq!(store.get("stable".to_owned())?, Some("s".repeat(4096)));
+    for key_id in 0..4 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("0199".repeat(1024)));
+    }
+
+    Ok(())
+}
+
+// Compacting the main log must not rewrite blob files.
+#[test]
+fn compaction_keeps_blobs_in_place() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let big = "y".repeat(256 * 1024);
+
+    let mut store = OpenOptions::new().blob_threshold(1024).open(temp_dir.path())?;
+    store.set("big".to_owned(), big.clone())?;
+    let blobs = blob_files(temp_dir.path());
+    let blob_path = temp_dir.path().join(&blobs[0]);
+    let blob_len = std::fs::metadata(&blob_path).unwrap().len();
+
+    let mut compacted = false;
+    let mut last_log = log_size(temp_dir.path());
+    for iter in 0..100 {
+        store.set("small".to_owned(), format!("{}", iter))?;
+        let new_log = log_size(temp_dir.path());
+        compacted |= new_log < last_log;
+        last_log = new_log;
+    }
+
+    // compaction happened, and the blob was not copied along with it
+    assert!(compacted);
+    assert_eq!(blob_files(temp_dir.path()), blobs);
+    assert_eq!(std::fs::metadata(&blob_path).unwrap().len(), blob_len);
+    assert_eq!(store.get("big".to_owned())?, Some(big));
+
+    Ok(())
+}
+
+#[test]
+fn encrypted_blobs_rekey() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let k1 = EncryptionKey::new(Cipher::ChaCha20Poly1305, [1; 32]);
+    let k2 = EncryptionKey::new(Cipher::Aes256Gcm, [2; 32]);
+    let big = "secret!".repeat(1024);
+
+    let mut store = OpenOptions::new().blob_threshold(1024).encryption_key(k1).open(temp_dir.path())?;
+    store.set("big".to_owned(), big.clone())?;
+    for name in blob_files(temp_dir.path()) {
+        let raw = std::fs::read(temp_dir.path().join(name)).unwrap();
+        assert!(!raw.windows(7).any(|w| w == b"secret!"));
+    }
+
+    store.rekey(Some(k2.clone()))?;
+    drop(store);
+
+    let mut store = OpenOptions::new().encryption_key(k2).open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(big));
+
+    Ok(())
+}
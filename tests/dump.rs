@@ -0,0 +1,153 @@
+use std::process::Command;
+
+use assert_cmd::prelude::*;
+use kvs::{ChangeKind, DumpOptions, DumpRecord, KvStore, NamespaceOptions, OpenOptions, Result, Status};
+use predicates::prelude::*;
+use predicates::str::contains;
+use tempfile::TempDir;
+
+fn dump(store: &mut KvStore, options: &DumpOptions) -> Result<Vec<DumpRecord>> {
+    let mut changes = Vec::new();
+    store.dump(options, |change| {
+        changes.push(change);
+        Ok(())
+    })?;
+    Ok(changes)
+}
+
+#[test]
+fn dump_shows_what_the_index_points_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = OpenOptions::new().blob_threshold(1024).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "y".repeat(100))?;
+    store.namespace("users").set("alice".to_owned(), "x".repeat(4096))?;
+    store.remove("key2".to_owned())?;
+
+    let changes = dump(&mut store, &DumpOptions::new())?;
+    let summary: Vec<_> = changes.iter().map(|c| (c.kind, c.key.as_str(), c.status)).collect();
+    assert_eq!(summary, vec![
+        (ChangeKind::Set, "key1", Some(Status::Superseded)),
+        (ChangeKind::Set, "key1", Some(Status::Live)),
+        (ChangeKind::Set, "key2", Some(Status::Superseded)),
+        (ChangeKind::SetBlob, "alice", Some(Status::Live)),
+        (ChangeKind::Remove, "key2", Some(Status::Live)),
+    ]);
+    assert_eq!(changes[0].offs, 0);
+    assert_eq!(changes[1].offs, changes[0].size);
+    assert!(changes.iter().all(|c| c.record == "Stamped" && c.seq.is_some()));
+    assert_eq!(changes[3].ns, "users");
+    assert!(changes[3].preview.contains("blob file"));
+    // long values are cut short
+    assert!(changes[2].preview.len() < 100 && changes[2].preview.ends_with("..."));
+
+    Ok(())
+}
+
+#[test]
+fn dump_filters_and_compacted_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut history = NamespaceOptions::new();
+    history.keep_versions(2).compact_after(5);
+    let mut store = OpenOptions::new().namespace("audit", history).open(temp_dir.path())?;
+    for i in 0..6 {
+        store.namespace("audit").set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    // compaction left a checkpoint and the kept versions
+    let changes = dump(&mut store, &DumpOptions::new())?;
+    assert_eq!(changes[0].kind, ChangeKind::Checkpoint);
+    assert_eq!(changes[0].status, None);
+    let audit: Vec<_> = changes.iter().filter(|c| c.ns == "audit").map(|c| (c.record, c.status)).collect();
+    assert_eq!(audit, vec![("Retained", Some(Status::History)), ("Stamped", Some(Status::Live))]);
+
+    let mut options = DumpOptions::new();
+    options.namespace("audit");
+    assert_eq!(dump(&mut store, &options)?.len(), 2);
+
+    let mut options = DumpOptions::new();
+    options.key("other");
+    assert_eq!(dump(&mut store, &options)?.len(), 1);
+
+    let mut options = DumpOptions::new();
+    options.kind(ChangeKind::Checkpoint).kind(ChangeKind::Remove);
+    assert_eq!(dump(&mut store, &options)?.len(), 1);
+
+    // only the records starting in the range
+    let last = changes.last().unwrap();
+    let mut options = DumpOptions::new();
+    options.offsets(last.offs..last.offs + 1);
+    assert_eq!(dump(&mut store, &options)?, vec![last.clone()]);
+    options.offsets(1..last.offs);
+    assert_eq!(dump(&mut store, &options)?.len(), changes.len() - 2);
+
+    Ok(())
+}
+
+// Only the last remove of a key that has been removed more than once is live.
+#[test]
+fn dump_only_the_last_remove_is_live() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let changes = dump(&mut store, &DumpOptions::new())?;
+    let summary: Vec<_> = changes.iter().map(|c| (c.kind, c.status)).collect();
+    assert_eq!(summary, vec![
+        (ChangeKind::Set, Some(Status::Superseded)),
+        (ChangeKind::Remove, Some(Status::Superseded)),
+        (ChangeKind::Set, Some(Status::Superseded)),
+        (ChangeKind::Remove, Some(Status::Live)),
+    ]);
+
+    // a later change outside the range asked for still counts
+    let mut options = DumpOptions::new();
+    options.kind(ChangeKind::Remove).offsets(0..changes[2].offs);
+    let removes: Vec<_> = dump(&mut store, &options)?.into_iter().map(|c| c.status).collect();
+    assert_eq!(removes, vec![Some(Status::Superseded)]);
+
+    Ok(())
+}
+
+#[test]
+fn cli_dump() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    for value in &["value1", "value2"] {
+        Command::cargo_bin("kvs").unwrap()
+            .args(["set", "key1", value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs").unwrap()
+        .args(["set", "key2", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["dump", "--key", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("superseded\t\"value1\"\n").and(contains("live\t\"value2\"\n")).and(contains("key2").not()));
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["dump", "--kind", "remove"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("");
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["dump", "--kind", "nothing"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
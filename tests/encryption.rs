@@ -0,0 +1,143 @@
+use assert_cmd::prelude::*;
+use kvs::{Cipher, EncryptionKey, KvStore, KvsError, OpenOptions, Result};
+use predicates::ord::eq;
+use predicates::str::PredicateStrExt;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn key(b: u8) -> EncryptionKey {
+    EncryptionKey::new(Cipher::ChaCha20Poly1305, [b; 32])
+}
+
+// Values should round trip through an encrypted store and never appear in the log in plain text.
+#[test]
+fn encrypted_round_trip() -> Result<()> {
+    for cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let k = EncryptionKey::new(*cipher, [3; 32]);
+
+        let mut store = OpenOptions::new().encryption_key(k.clone()).open(temp_dir.path())?;
+        store.set("key1".to_owned(), "secret-value".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        drop(store);
+
+        let raw = std::fs::read(temp_dir.path().join("kvs.db")).unwrap();
+        assert!(!raw.windows(b"secret-value".len()).any(|w| w == b"secret-value"));
+
+        let mut store = OpenOptions::new().encryption_key(k).open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("secret-value".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn wrong_or_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = OpenOptions::new().encryption_key(key(1)).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    match OpenOptions::new().encryption_key(key(2)).open(temp_dir.path()) {
+        Err(KvsError::Authentication { offs: 0, .. }) => {}
+        other => panic!("expected authentication failure, got {:?}", other),
+    }
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::EncryptionKeyRequired { .. }) => {}
+        other => panic!("expected missing key error, got {:?}", other),
+    }
+
+    let aes = EncryptionKey::new(Cipher::Aes256Gcm, [1; 32]);
+    match OpenOptions::new().encryption_key(aes).open(temp_dir.path()) {
+        Err(KvsError::CipherMismatch { found: Cipher::ChaCha20Poly1305, .. }) => {}
+        other => panic!("expected cipher mismatch, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Flipping a bit in a record must be reported as an authentication failure.
+#[test]
+fn tampered_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = OpenOptions::new().encryption_key(key(1)).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("kvs.db");
+    let mut raw = std::fs::read(&path).unwrap();
+    let last = raw.len() - 1;
+    raw[last] ^= 0x40;
+    std::fs::write(&path, raw).unwrap();
+
+    match OpenOptions::new().encryption_key(key(1)).open(temp_dir.path()) {
+        Err(KvsError::Authentication { offs, .. }) => assert!(offs > 0),
+        other => panic!("expected authentication failure, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn rekey() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // start out in plain text
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        OpenOptions::new().encryption_key(key(1)).open(temp_dir.path()),
+        Err(KvsError::LogNotEncrypted { .. })
+    ));
+
+    store.rekey(Some(key(1)))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rekey(Some(key(2)))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    assert!(OpenOptions::new().encryption_key(key(1)).open(temp_dir.path()).is_err());
+    let mut store = OpenOptions::new().encryption_key(key(2)).open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.rekey(None)?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn cli_key_file() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key_file = temp_dir.path().join("key");
+    std::fs::write(&key_file, "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff\n").unwrap();
+    let key_arg = key_file.to_str().unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--key-file", key_arg, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--key-file", key_arg])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
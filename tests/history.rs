@@ -0,0 +1,132 @@
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsError, NamespaceOptions, OpenOptions, Result, Until};
+use predicates::prelude::*;
+use predicates::str::contains;
+use tempfile::TempDir;
+
+fn values(history: Vec<kvs::Version>) -> Vec<Option<String>> {
+    history.into_iter().map(|v| v.value).collect()
+}
+
+#[test]
+fn compaction_keeps_last_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut history = NamespaceOptions::new();
+    history.keep_versions(3).compact_after(5);
+    let mut opts = OpenOptions::new();
+    // small blob files so old values are spread over several, and some get collected
+    opts.namespace("", history).blob_threshold(64).blob_file_size(1024);
+
+    let big = |i: usize| format!("{}{}", i, "x".repeat(100));
+    let mut store = opts.open(temp_dir.path())?;
+    let mut seqs = Vec::new();
+    for i in 0..20 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+        seqs.push(store.last_seq());
+        store.set("big".to_owned(), big(i))?;
+    }
+    store.set("gone".to_owned(), "value".to_owned())?;
+    let gone_seq = store.last_seq();
+    store.remove("gone".to_owned())?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        let kept = store.history("key1".to_owned())?;
+        assert!(kept.len() >= 3 && kept.len() < 20, "{} versions kept", kept.len());
+        assert_eq!(kept.last().unwrap().value, Some("value19".to_owned()));
+        assert_eq!(kept.last().unwrap().seq, seqs[19]);
+        assert!(kept.windows(2).all(|w| w[0].seq < w[1].seq && w[0].time <= w[1].time));
+        assert_eq!(store.get_at("key1".to_owned(), Until::Seq(seqs[18]))?, Some("value18".to_owned()));
+        assert_eq!(store.get_at("key1".to_owned(), Until::Seq(seqs[19] + 1))?, Some("value19".to_owned()));
+        // dropped by compaction
+        assert_eq!(store.get_at("key1".to_owned(), Until::Seq(seqs[0]))?, None);
+
+        let bigs = values(store.history("big".to_owned())?);
+        assert!(bigs.len() >= 3);
+        assert_eq!(bigs[bigs.len() - 3..], [Some(big(17)), Some(big(18)), Some(big(19))]);
+
+        assert_eq!(store.get("gone".to_owned())?, None);
+        assert_eq!(values(store.history("gone".to_owned())?), vec![Some("value".to_owned()), None]);
+        assert_eq!(store.get_at("gone".to_owned(), Until::Seq(gone_seq))?, Some("value".to_owned()));
+        Ok(())
+    };
+    check(&mut store)?;
+
+    // earlier versions are written back out by compaction, so they survive reopening
+    drop(store);
+    let mut store = opts.open(temp_dir.path())?;
+    check(&mut store)?;
+    for i in 20..30 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let kept = values(store.history("key1".to_owned())?);
+    assert!(kept.len() < 10);
+    assert_eq!(kept.last(), Some(&Some("value29".to_owned())));
+    assert_eq!(store.get("big".to_owned())?, Some(big(19)));
+
+    Ok(())
+}
+
+#[test]
+fn compaction_keeps_recent_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut history = NamespaceOptions::new();
+    history.keep_for(Duration::from_millis(300)).compact_after(4);
+    let mut store = OpenOptions::new().namespace("audit", history).open(temp_dir.path())?;
+
+    store.namespace("audit").set("key1".to_owned(), "old1".to_owned())?;
+    store.namespace("audit").set("key1".to_owned(), "old2".to_owned())?;
+    let before = SystemTime::now();
+    thread::sleep(Duration::from_millis(400));
+    for i in 0..5 {
+        store.namespace("audit").set("key1".to_owned(), format!("new{}", i))?;
+    }
+
+    let kept = values(store.namespace("audit").history("key1".to_owned())?);
+    assert_eq!(kept, (0..5).map(|i| Some(format!("new{}", i))).collect::<Vec<_>>());
+    assert_eq!(store.namespace("audit").get_at("key1".to_owned(), Until::Time(before))?, None);
+    assert_eq!(store.namespace("audit").get_at("key1".to_owned(), Until::Time(SystemTime::now()))?, Some("new4".to_owned()));
+
+    // other namespaces don't keep history
+    store.set("key1".to_owned(), "value".to_owned())?;
+    assert!(matches!(store.history("key1".to_owned()), Err(KvsError::NoHistory { .. })));
+    assert!(matches!(store.get_at("key1".to_owned(), Until::Seq(1)), Err(KvsError::NoHistory { .. })));
+    assert_eq!(store.namespace("audit").history("nothing".to_owned())?, vec![]);
+
+    Ok(())
+}
+
+#[test]
+fn cli_history() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    for value in &["value1", "value2"] {
+        Command::cargo_bin("kvs").unwrap()
+            .args(["set", "key1", value, "--keep-versions", "5"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs").unwrap()
+        .args(["rm", "key1", "--keep-versions", "5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["history", "key1", "--keep-versions", "5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1\t").and(contains("\tvalue1\n")).and(contains("\tvalue2\n")).and(contains("3\t")).and(contains("\t(removed)\n")));
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["history", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("NoHistory"));
+}
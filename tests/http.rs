@@ -0,0 +1,158 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use kvs::{HttpServer, KvStore, Result};
+use serde_json::Value;
+use tempfile::TempDir;
+
+struct Reply {
+    status: u16,
+    etag: Option<String>,
+    body: Value,
+}
+
+fn start(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let server = HttpServer::new(store);
+    thread::spawn(move || server.serve(listener));
+    Ok(addr)
+}
+
+/// send one request on its own connection and read the whole reply
+fn request(addr: SocketAddr, method: &str, target: &str, headers: &[(&str, &str)], body: &str) -> Reply {
+    let mut stream = TcpStream::connect(addr).expect("connect");
+    let mut raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", method, target);
+    for (name, value) in headers {
+        raw.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    raw.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    stream.write_all(raw.as_bytes()).expect("send");
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).expect("read");
+    let (head, body) = reply.split_once("\r\n\r\n").expect("end of headers");
+    let mut lines = head.split("\r\n");
+    let status = lines.next().expect("status line").split(' ').nth(1).expect("status").parse().expect("status code");
+    let etag = lines
+        .filter_map(|l| l.split_once(": "))
+        .find(|(name, _)| name.eq_ignore_ascii_case("etag"))
+        .map(|(_, v)| v.to_owned());
+    let body = if body.is_empty() { Value::Null } else { serde_json::from_str(body).expect("json body") };
+
+    Reply { status, etag, body }
+}
+
+#[test]
+fn get_put_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start(&temp_dir)?;
+
+    let r = request(addr, "GET", "/keys/key1", &[], "");
+    assert_eq!(r.status, 404);
+    assert_eq!(r.body["kind"], "KeyNotFound");
+
+    let r = request(addr, "PUT", "/keys/key1", &[], r#"{"value": "value1"}"#);
+    assert_eq!(r.status, 201);
+    let r = request(addr, "PUT", "/keys/key1", &[], r#"{"value": "value2"}"#);
+    assert_eq!(r.status, 200);
+
+    let r = request(addr, "GET", "/keys/key1", &[], "");
+    assert_eq!(r.status, 200);
+    assert_eq!(r.body["value"], "value2");
+    assert!(r.etag.is_some());
+
+    // keys are percent-decoded and namespaces are separate
+    let r = request(addr, "PUT", "/keys/a%20b?ns=other", &[], r#"{"value": "spaced"}"#);
+    assert_eq!(r.status, 201);
+    assert_eq!(request(addr, "GET", "/keys/a%20b", &[], "").status, 404);
+    assert_eq!(request(addr, "GET", "/keys/a%20b?ns=other", &[], "").body["key"], "a b");
+
+    assert_eq!(request(addr, "DELETE", "/keys/key1", &[], "").status, 204);
+    let r = request(addr, "DELETE", "/keys/key1", &[], "");
+    assert_eq!(r.status, 404);
+    assert_eq!(r.body["kind"], "KeyNotFound");
+
+    assert_eq!(request(addr, "PUT", "/keys/key1", &[], "not json").status, 400);
+    assert_eq!(request(addr, "PUT", "/keys/key1", &[], r#"{"value": 1}"#).status, 400);
+    assert_eq!(request(addr, "POST", "/keys/key1", &[], "").status, 405);
+    assert_eq!(request(addr, "GET", "/nowhere", &[], "").status, 404);
+
+    let r = request(addr, "GET", "/stats", &[], "");
+    assert_eq!(r.status, 200);
+    assert_eq!(r.body["namespaces"], serde_json::json!(["other"]));
+
+    Ok(())
+}
+
+#[test]
+fn conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start(&temp_dir)?;
+
+    let r = request(addr, "PUT", "/keys/key1", &[("If-None-Match", "*")], r#"{"value": "value1"}"#);
+    assert_eq!(r.status, 201);
+    let tag = r.etag.expect("etag");
+    let r = request(addr, "PUT", "/keys/key1", &[("If-None-Match", "*")], r#"{"value": "again"}"#);
+    assert_eq!(r.status, 412);
+
+    // a stale tag is refused, the current one accepted
+    let r = request(addr, "PUT", "/keys/key1", &[("If-Match", &tag)], r#"{"value": "value2"}"#);
+    assert_eq!(r.status, 200);
+    let r = request(addr, "PUT", "/keys/key1", &[("If-Match", &tag)], r#"{"value": "value3"}"#);
+    assert_eq!(r.status, 412);
+    assert_eq!(r.body["kind"], "PreconditionFailed");
+    assert_eq!(request(addr, "GET", "/keys/key1", &[], "").body["value"], "value2");
+
+    let current = request(addr, "GET", "/keys/key1", &[], "").etag.expect("etag");
+    assert_eq!(request(addr, "GET", "/keys/key1", &[("If-None-Match", &current)], "").status, 304);
+    assert_eq!(request(addr, "DELETE", "/keys/key1", &[("If-Match", &tag)], "").status, 412);
+    assert_eq!(request(addr, "DELETE", "/keys/key1", &[("If-Match", &current)], "").status, 204);
+    assert_eq!(request(addr, "PUT", "/keys/key1", &[("If-Match", "*")], r#"{"value": "v"}"#).status, 412);
+
+    // writing the same value again still changes the tag
+    let r = request(addr, "PUT", "/keys/key1", &[], r#"{"value": "v"}"#);
+    assert_eq!(r.status, 201);
+    let first = r.etag.expect("etag");
+    let r = request(addr, "PUT", "/keys/key1", &[("If-Match", &first)], r#"{"value": "v"}"#);
+    assert_eq!(r.status, 200);
+    assert_ne!(r.etag.as_ref(), Some(&first));
+    assert_eq!(request(addr, "PUT", "/keys/key1", &[("If-Match", &first)], r#"{"value": "v"}"#).status, 412);
+
+    Ok(())
+}
+
+#[test]
+fn list_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start(&temp_dir)?;
+
+    for i in 0..25 {
+        let body = format!(r#"{{"value": "value{}"}}"#, i);
+        assert_eq!(request(addr, "PUT", &format!("/keys/user.{:02}", i), &[], &body).status, 201);
+    }
+    request(addr, "PUT", "/keys/other", &[], r#"{"value": "x"}"#);
+
+    let mut keys = Vec::new();
+    let mut target = "/keys?prefix=user.&limit=10".to_owned();
+    loop {
+        let r = request(addr, "GET", &target, &[], "");
+        assert_eq!(r.status, 200);
+        let page = r.body["keys"].as_array().expect("keys");
+        assert!(page.len() <= 10);
+        keys.extend(page.iter().map(|item| item["key"].as_str().expect("key").to_owned()));
+        match r.body["next"].as_str() {
+            Some(next) => target = format!("/keys?prefix=user.&limit=10&cursor={}", next),
+            None => break,
+        }
+    }
+
+    let expected: Vec<String> = (0..25).map(|i| format!("user.{:02}", i)).collect();
+    assert_eq!(keys, expected);
+    assert_eq!(request(addr, "GET", "/keys?limit=0", &[], "").status, 400);
+    assert_eq!(request(addr, "GET", "/keys", &[], "").body["keys"].as_array().expect("keys").len(), 26);
+
+    Ok(())
+}
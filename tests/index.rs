@@ -0,0 +1,87 @@
+use kvs::{IndexMode, KvStore, KvsError, OpenOptions, Result};
+use tempfile::TempDir;
+
+fn compact(hash_bytes: usize) -> OpenOptions {
+    let mut opts = OpenOptions::new();
+    opts.index_mode(IndexMode::Compact { hash_bytes });
+    opts
+}
+
+// The compact index should behave exactly like the full one.
+#[test]
+fn compact_index_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = compact(4);
+
+    let mut store = opts.open(temp_dir.path())?;
+    for key_id in 0..5000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..5000).step_by(3) {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    for key_id in (0..5000).step_by(5) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(store.remove("missing".to_owned()).is_err());
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..5000 {
+            let expected = if key_id % 5 == 0 {
+                None
+            } else if key_id % 3 == 0 {
+                Some(format!("new{}", key_id))
+            } else {
+                Some(format!("value{}", key_id))
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        assert_eq!(store.get("missing".to_owned())?, None);
+        Ok(())
+    };
+
+    check(&mut store)?;
+    drop(store);
+
+    // reopen with both kinds of index, the on disk format is the same
+    check(&mut opts.open(temp_dir.path())?)?;
+    check(&mut KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+#[test]
+fn index_memory_is_reported() -> Result<()> {
+    let full_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compact_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut full = KvStore::open(full_dir.path())?;
+    let mut small = compact(4).open(compact_dir.path())?;
+    for key_id in 0..10_000 {
+        let key = format!("a-rather-long-key-name-{:08}", key_id);
+        full.set(key.clone(), "v".to_owned())?;
+        small.set(key, "v".to_owned())?;
+    }
+
+    let full_stats = full.index_stats();
+    let small_stats = small.index_stats();
+    assert_eq!(full_stats.keys, 10_000);
+    assert_eq!(small_stats.keys, 10_000);
+    assert_eq!(small_stats.mode, IndexMode::Compact { hash_bytes: 4 });
+    // 10 byte slots with the table at least half full
+    assert!(small_stats.bytes_per_key() <= 20.0, "{:?}", small_stats);
+    assert!(full_stats.bytes_per_key() > small_stats.bytes_per_key() * 2.0, "{:?} {:?}", full_stats, small_stats);
+
+    Ok(())
+}
+
+#[test]
+fn invalid_hash_bytes() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for &hash_bytes in &[0, 3, 9] {
+        match compact(hash_bytes).open(temp_dir.path()) {
+            Err(KvsError::IndexHashBytes { .. }) => {}
+            other => panic!("expected IndexHashBytes, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use kvs::{Cipher, EncryptionKey, OpenOptions, Result};
+use tempfile::TempDir;
+
+fn mmap() -> OpenOptions {
+    let mut opts = OpenOptions::new();
+    opts.mmap(true);
+    opts
+}
+
+// Reads should see records before and after the map is extended, and after compaction replaces
+// the mapped file.
+#[test]
+fn mmap_reads_follow_growth_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = mmap().open(temp_dir.path())?;
+
+    // ~3 MiB of records, enough to remap a few times
+    let value = "v".repeat(1000);
+    for key_id in 0..3000 {
+        store.set(format!("key{}", key_id), format!("{}{}", value, key_id))?;
+        if key_id % 97 == 0 {
+            for check in (0..=key_id).step_by(13) {
+                assert_eq!(store.get(format!("key{}", check))?, Some(format!("{}{}", value, check)));
+            }
+        }
+    }
+
+    // overwrite enough to compact
+    for iter in 0..50 {
+        store.set("key0".to_owned(), format!("{}", iter))?;
+        assert_eq!(store.get("key0".to_owned())?, Some(format!("{}", iter)));
+    }
+    for key_id in 1..3000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("{}{}", value, key_id)));
+    }
+    drop(store);
+
+    let mut store = mmap().open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("49".to_owned()));
+    assert_eq!(store.get("key2999".to_owned())?, Some(format!("{}2999", value)));
+
+    Ok(())
+}
+
+#[test]
+fn mmap_encrypted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut opts = mmap();
+    opts.encryption_key(EncryptionKey::new(Cipher::ChaCha20Poly1305, [9; 32]));
+
+    let mut store = opts.open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let mut store = opts.open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    Ok(())
+}
+
+// An empty store can't be mapped, that shouldn't stop it from working.
+#[test]
+fn mmap_empty_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = mmap().open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
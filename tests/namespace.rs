@@ -0,0 +1,152 @@
+use assert_cmd::prelude::*;
+use kvs::{IndexMode, KvStore, KvsError, NamespaceOptions, OpenOptions, Result, WriteBatch};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+// The same key in different namespaces should hold different values, and survive reopening.
+#[test]
+fn namespaces_are_isolated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "default".to_owned())?;
+    store.namespace("users").set("key1".to_owned(), "users".to_owned())?;
+    store.namespace("orders").set("key2".to_owned(), "orders".to_owned())?;
+
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.namespace("users").get("key2".to_owned())?, None);
+    store.namespace("users").remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert!(store.namespace("orders").remove("key1".to_owned()).is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.namespaces(), vec!["".to_owned(), "orders".to_owned()]);
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.namespace("users").get("key1".to_owned())?, None);
+    assert_eq!(store.namespace("orders").get("key2".to_owned())?, Some("orders".to_owned()));
+
+    Ok(())
+}
+
+// A batch that fails part way through shouldn't leave any of its changes behind.
+#[test]
+fn batches_are_atomic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.namespace("pending").set("alice".to_owned(), "1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("users", "bob".to_owned(), "2".to_owned())
+        .remove("pending", "bob".to_owned());
+    match store.write(batch) {
+        Err(KvsError::RemoveNonexistentKey { key }) => assert_eq!(key, "bob"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(store.namespace("users").get("bob".to_owned())?, None);
+
+    // removing a key set earlier in the same batch is fine
+    let mut batch = WriteBatch::new();
+    batch.set("users", "alice".to_owned(), "1".to_owned())
+        .remove("pending", "alice".to_owned())
+        .set("audit", "alice".to_owned(), "moved".to_owned())
+        .remove("audit", "alice".to_owned())
+        .set("audit", "log".to_owned(), "alice".to_owned());
+    assert_eq!(batch.len(), 5);
+    store.write(batch)?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.namespace("users").get("alice".to_owned())?, Some("1".to_owned()));
+        assert_eq!(store.namespace("pending").get("alice".to_owned())?, None);
+        assert_eq!(store.namespace("audit").get("alice".to_owned())?, None);
+        assert_eq!(store.namespace("audit").get("log".to_owned())?, Some("alice".to_owned()));
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+    check(&mut KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// Batches written under a compact index must still compact and reopen correctly, including
+// records where only some of the keys are still live.
+#[test]
+fn namespace_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut sessions = NamespaceOptions::new();
+    sessions.index_mode(IndexMode::Compact { hash_bytes: 4 }).compact_after(1_000_000);
+    let mut opts = OpenOptions::new();
+    opts.namespace("sessions", sessions);
+
+    let mut store = opts.open(temp_dir.path())?;
+    for batch_id in 0..100 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..10 {
+            batch.set("sessions", format!("key{}", batch_id * 10 + key_id), format!("value{}", batch_id));
+        }
+        store.write(batch)?;
+    }
+    for key_id in (0..1000).step_by(2) {
+        store.namespace("sessions").set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    assert_eq!(store.namespace("sessions").index_stats().mode, IndexMode::Compact { hash_bytes: 4 });
+    assert_eq!(store.namespace("sessions").index_stats().keys, 1000);
+    assert_eq!(store.index_stats().mode, IndexMode::Full);
+
+    // 500 overwrites in "sessions" don't compact, 20 in the default namespace do
+    let db = temp_dir.path().join("kvs.db");
+    let size = || std::fs::metadata(&db).expect("log exists").len();
+    let before = size();
+    for iter in 0..21 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    assert!(size() < before);
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..1000 {
+            let expected = if key_id % 2 == 0 { "new".to_owned() } else { format!("value{}", key_id / 10) };
+            assert_eq!(store.namespace("sessions").get(format!("key{}", key_id))?, Some(expected));
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+    check(&mut opts.open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+#[test]
+fn cli_namespace() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs").unwrap()
+        .args(["set", "key1", "value1", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs").unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs").unwrap()
+        .args(["get", "key1", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs").unwrap()
+        .args(["rm", "key1", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs").unwrap()
+        .args(["rm", "key1", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("Key not found"));
+}
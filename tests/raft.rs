@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, Result};
+use tempfile::TempDir;
+
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    listener.local_addr().expect("local addr").to_string()
+}
+
+/// a kvs-server process running as a cluster node
+struct Node {
+    dir: TempDir,
+    raft: String,
+    client: String,
+    child: Option<Child>,
+}
+
+impl Node {
+    fn new() -> Node {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        Node { dir, raft: free_addr(), client: free_addr(), child: None }
+    }
+
+    fn member(&self) -> String {
+        format!("{}={}", self.raft, self.client)
+    }
+
+    /// start the node, with `cluster` if it's founding a new one
+    fn start(&mut self, cluster: &str, snapshot_after: u64) {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--addr", &self.client, "--raft-addr", &self.raft])
+            .args(["--snapshot-after", &snapshot_after.to_string()])
+            .current_dir(self.dir.path())
+            .stderr(Stdio::null());
+        if !cluster.is_empty() {
+            cmd.args(["--cluster", cluster]);
+        }
+        self.child = Some(cmd.spawn().expect("start kvs-server"));
+    }
+
+    fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            child.kill().expect("stop server");
+            child.wait().expect("server exits");
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.child.is_some()
+    }
+
+    fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+fn start_cluster(n: usize, snapshot_after: u64) -> Vec<Node> {
+    let mut nodes: Vec<Node> = (0..n).map(|_| Node::new()).collect();
+    let cluster = nodes.iter().map(Node::member).collect::<Vec<_>>().join(",");
+    for node in &mut nodes {
+        node.start(&cluster, snapshot_after);
+    }
+    nodes
+}
+
+/// retry `f` against each running node until one of them, the leader, does it
+fn on_leader<T>(nodes: &[Node], mut f: impl FnMut(&mut KvsClient) -> Result<T>) -> (usize, T) {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        for (i, node) in nodes.iter().enumerate().filter(|(_, n)| n.running()) {
+            if let Ok(result) = KvsClient::connect(&node.client).and_then(|mut c| f(&mut c)) {
+                return (i, result);
+            }
+        }
+        assert!(Instant::now() < deadline, "no leader was elected");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn leader(nodes: &[Node]) -> usize {
+    on_leader(nodes, |c| c.get("", "probe".to_owned())).0
+}
+
+#[test]
+fn leader_failover_keeps_acknowledged_writes() {
+    let mut nodes = start_cluster(3, 10_000);
+    let first = leader(&nodes);
+
+    // keep writing through whichever node leads, remembering every write that was acknowledged
+    let addrs: Vec<String> = nodes.iter().map(|n| n.client.clone()).collect();
+    let acked = Arc::new(Mutex::new(BTreeMap::new()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let acked = acked.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut i = 0;
+            while !stop.load(Ordering::SeqCst) {
+                let key = format!("key{}", i);
+                for addr in &addrs {
+                    let set = KvsClient::connect(addr).and_then(|mut c| c.set("", key.clone(), i.to_string()));
+                    if set.is_ok() {
+                        acked.lock().unwrap().insert(key.clone(), i.to_string());
+                        break;
+                    }
+                }
+                i += 1;
+            }
+        })
+    };
+
+    thread::sleep(Duration::from_millis(500));
+    nodes[first].kill();
+    let acked_before_kill = acked.lock().unwrap().len();
+    assert!(acked_before_kill > 0);
+
+    let second = leader(&nodes);
+    assert_ne!(first, second);
+    thread::sleep(Duration::from_millis(500));
+    stop.store(true, Ordering::SeqCst);
+    writer.join().expect("writer panicked");
+
+    let acked = acked.lock().unwrap().clone();
+    assert!(acked.len() > acked_before_kill, "writes continue under the new leader");
+    for (key, value) in &acked {
+        let (_, found) = on_leader(&nodes, |c| c.get("", key.clone()));
+        assert_eq!(found.as_ref(), Some(value), "acknowledged write to {} was lost", key);
+    }
+
+    // the old leader catches up when it comes back, and can take over from the new one
+    let cluster = nodes.iter().map(Node::member).collect::<Vec<_>>().join(",");
+    nodes[first].start(&cluster, 10_000);
+    thread::sleep(Duration::from_secs(2));
+    nodes[second].kill();
+    for (key, value) in &acked {
+        let (_, found) = on_leader(&nodes, |c| c.get("", key.clone()));
+        assert_eq!(found.as_ref(), Some(value));
+    }
+}
+
+#[test]
+fn membership_changes_and_snapshots() {
+    let mut nodes = start_cluster(3, 20);
+    for i in 0..100 {
+        on_leader(&nodes, |c| c.set("", format!("key{}", i), i.to_string()));
+    }
+
+    // the new node has missed entries that are only in snapshots by now
+    let mut joining = Node::new();
+    joining.start("", 20);
+    let (raft, client) = (joining.raft.clone(), joining.client.clone());
+    let (first, ()) = on_leader(&nodes, |c| c.add_member(raft.clone(), client.clone()));
+    nodes.push(joining);
+    assert!(nodes[first].dir().join("raft.snapshot").exists());
+
+    // swap out both the other founding nodes, leaving the leader and the new node
+    for i in (0..3).filter(|&i| i != first) {
+        let raft = nodes[i].raft.clone();
+        on_leader(&nodes, |c| c.remove_member(raft.clone()));
+        nodes[i].kill();
+    }
+
+    // writes now need the new node to acknowledge them
+    on_leader(&nodes, |c| c.set("", "after".to_owned(), "changes".to_owned()));
+    for i in 0..100 {
+        let (_, found) = on_leader(&nodes, |c| c.get("", format!("key{}", i)));
+        assert_eq!(found, Some(i.to_string()));
+    }
+    assert!(nodes[3].dir().join("raft.snapshot").exists(), "the new node was sent a snapshot");
+}
+
+// A snapshot bigger than one message reaches a new node in pieces, and is all of the store.
+#[test]
+fn large_snapshots() {
+    let mut nodes = start_cluster(1, 10);
+    let value = "v".repeat(64 * 1024);
+    for i in 0..40 {
+        on_leader(&nodes, |c| c.set("", format!("key{}", i), format!("{}{}", i, value)));
+    }
+
+    let mut joining = Node::new();
+    joining.start("", 10);
+    let (raft, client) = (joining.raft.clone(), joining.client.clone());
+    on_leader(&nodes, |c| c.add_member(raft.clone(), client.clone()));
+    nodes.push(joining);
+    let raft = nodes[0].raft.clone();
+    on_leader(&nodes, |c| c.remove_member(raft.clone()));
+    nodes[0].kill();
+
+    for i in 0..40 {
+        let (_, found) = on_leader(&nodes, |c| c.get("", format!("key{}", i)));
+        assert_eq!(found, Some(format!("{}{}", i, value)));
+    }
+    let len = std::fs::metadata(nodes[1].dir().join("raft.snapshot")).expect("the new node was sent a snapshot").len();
+    assert!(len > 2 << 20, "the snapshot took more than one piece");
+}
+
+#[test]
+fn whole_cluster_restart() {
+    let mut nodes = start_cluster(3, 30);
+    for i in 0..50 {
+        on_leader(&nodes, |c| c.set("users", format!("key{}", i), i.to_string()));
+    }
+    on_leader(&nodes, |c| c.remove("users", "key7".to_owned()));
+
+    let cluster = nodes.iter().map(Node::member).collect::<Vec<_>>().join(",");
+    for node in &mut nodes {
+        node.kill();
+    }
+    for node in &mut nodes {
+        node.start(&cluster, 30);
+    }
+
+    for i in 0..50 {
+        let (_, found) = on_leader(&nodes, |c| c.get("users", format!("key{}", i)));
+        assert_eq!(found, if i == 7 { None } else { Some(i.to_string()) });
+    }
+}
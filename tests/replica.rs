@@ -0,0 +1,216 @@
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use assert_cmd::prelude::*;
+use kvs::{ErrorCode, Follower, KvStore, KvsClient, KvsError, KvsServer, NamespaceOptions, OpenOptions, Result};
+use tempfile::TempDir;
+
+// serve `store` in the background, returning its address
+fn spawn_server(store: KvStore) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let server = KvsServer::new(store);
+    thread::spawn(move || server.serve(listener));
+    addr
+}
+
+fn spawn_follower(follower: &Follower) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let follower = follower.clone();
+    thread::spawn(move || follower.serve(listener));
+    addr
+}
+
+// wait for `follower` to have `expected` as the value of `key`
+fn wait_for(follower: &Follower, ns: &str, key: &str, expected: Option<&str>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while follower.get(ns, key.to_owned())?.as_deref() != expected {
+        assert!(Instant::now() < deadline, "{} never became {:?}", key, expected);
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+#[test]
+fn follower_replicates_and_is_read_only() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = spawn_server(OpenOptions::new().blob_threshold(100).open(primary_dir.path())?);
+
+    // whatever the follower had before is replaced
+    let mut store = KvStore::open(follower_dir.path())?;
+    store.set("stale".to_owned(), "value".to_owned())?;
+    let follower = Follower::start(store, primary.to_string())?;
+
+    let mut client = KvsClient::connect(primary)?;
+    let large = "x".repeat(1000);
+    client.set("", "key1".to_owned(), "value1".to_owned())?;
+    client.set("users", "alice".to_owned(), large.clone())?;
+    client.set("", "key2".to_owned(), "value2".to_owned())?;
+    client.remove("", "key2".to_owned())?;
+    client.set("", "last".to_owned(), "value".to_owned())?;
+
+    wait_for(&follower, "", "last", Some("value"))?;
+    assert_eq!(follower.get("", "key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(follower.get("users", "alice".to_owned())?, Some(large));
+    assert_eq!(follower.get("", "key2".to_owned())?, None);
+    assert_eq!(follower.get("", "stale".to_owned())?, None);
+
+    // clients can read from the follower, but are sent to the primary to write
+    let mut replica = KvsClient::connect(spawn_follower(&follower))?;
+    assert_eq!(replica.get("", "key1".to_owned())?, Some("value1".to_owned()));
+    match replica.set("", "key1".to_owned(), "other".to_owned()) {
+        Err(KvsError::Server { code: ErrorCode::NotLeader, message }) => assert_eq!(message, primary.to_string()),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    Ok(())
+}
+
+// Compaction rewrites the primary's log under the follower, which has to start over from a
+// snapshot.
+#[test]
+fn follower_survives_compaction() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut frequent = NamespaceOptions::new();
+    frequent.compact_after(20);
+    let primary = spawn_server(OpenOptions::new().namespace("", frequent).open(primary_dir.path())?);
+    let follower = Follower::start(KvStore::open(follower_dir.path())?, primary.to_string())?;
+
+    let mut client = KvsClient::connect(primary)?;
+    for round in 0..20 {
+        for i in 0..10 {
+            client.set("", format!("key{}", i), format!("value{}.{}", i, round))?;
+        }
+        client.remove("", format!("key{}", round % 10))?;
+        if round % 5 == 0 {
+            wait_for(&follower, "", &format!("key{}", round % 10), None)?;
+        }
+    }
+
+    wait_for(&follower, "", "key0", Some("value0.19"))?;
+    for i in 0..10 {
+        let expected = if i == 9 { None } else { Some(format!("value{}.19", i)) };
+        assert_eq!(follower.get("", format!("key{}", i))?, expected);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !follower.is_caught_up() {
+        assert!(Instant::now() < deadline);
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn followers_can_be_chained() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().expect("unable to create temporary working directory")).collect();
+    let primary = spawn_server(KvStore::open(dirs[0].path())?);
+    let middle = Follower::start(KvStore::open(dirs[1].path())?, primary.to_string())?;
+    let middle_addr = spawn_follower(&middle);
+    let last = Follower::start(KvStore::open(dirs[2].path())?, middle_addr.to_string())?;
+    assert_eq!(last.primary(), middle_addr.to_string());
+
+    let mut client = KvsClient::connect(primary)?;
+    for i in 0..100 {
+        client.set("", format!("key{}", i), i.to_string())?;
+    }
+    client.remove("", "key0".to_owned())?;
+
+    wait_for(&last, "", "key99", Some("99"))?;
+    wait_for(&last, "", "key0", None)?;
+    assert_eq!(last.get("", "key50".to_owned())?, Some("50".to_owned()));
+
+    Ok(())
+}
+
+// A snapshot bigger than a page arrives in several, and still replaces what the follower had.
+#[test]
+fn snapshots_are_sent_in_pages() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(primary_dir.path())?;
+    for i in 0..3000 {
+        store.set(format!("key{:04}", i), i.to_string())?;
+    }
+    store.namespace("users").set("alice".to_owned(), "admin".to_owned())?;
+    let primary = spawn_server(store);
+
+    let mut store = KvStore::open(follower_dir.path())?;
+    store.set("key1500x".to_owned(), "stale".to_owned())?;
+    store.set("key9999".to_owned(), "stale".to_owned())?;
+    let follower = Follower::start(store, primary.to_string())?;
+
+    wait_for(&follower, "users", "alice", Some("admin"))?;
+    for i in (0..3000).step_by(7) {
+        assert_eq!(follower.get("", format!("key{:04}", i))?, Some(i.to_string()));
+    }
+    assert_eq!(follower.get("", "key1500x".to_owned())?, None);
+    assert_eq!(follower.get("", "key9999".to_owned())?, None);
+
+    Ok(())
+}
+
+// a kvs-server running in the background, stopped when dropped so a failing test doesn't leave
+// it behind
+struct Primary(Child);
+
+impl Drop for Primary {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// start kvs-server in `dir` listening on `addr`
+fn start_primary(dir: &TempDir, addr: &str) -> Primary {
+    let server = Command::cargo_bin("kvs-server").unwrap()
+        .args(["--addr", addr])
+        .current_dir(dir)
+        .spawn()
+        .expect("start server");
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    Primary(server)
+}
+
+// A follower keeps its position in its own store, and finds its place in a restarted primary's
+// log by sequence number, so neither restart costs it a snapshot.
+#[test]
+fn follower_resumes_after_restarts() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()).expect("free port").to_string();
+
+    let server = start_primary(&primary_dir, &addr);
+    KvsClient::connect(&addr)?.set("", "key1".to_owned(), "value1".to_owned())?;
+    let follower = Follower::start(KvStore::open(follower_dir.path())?, addr.clone())?;
+    wait_for(&follower, "", "key1", Some("value1"))?;
+    drop(follower);
+    // give the follower's thread time to let go of the store
+    thread::sleep(Duration::from_millis(500));
+
+    // a snapshot would remove this
+    let mut store = KvStore::open(follower_dir.path())?;
+    store.set("local".to_owned(), "kept".to_owned())?;
+
+    drop(server);
+    let _server = start_primary(&primary_dir, &addr);
+    KvsClient::connect(&addr)?.set("", "key2".to_owned(), "value2".to_owned())?;
+
+    let follower = Follower::start(store, addr.clone())?;
+    wait_for(&follower, "", "key2", Some("value2"))?;
+    assert_eq!(follower.get("", "key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(follower.get("", "local".to_owned())?, Some("kept".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use kvs::{KvStore, RespServer, Result};
+use tempfile::TempDir;
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn connect(temp_dir: &TempDir) -> Result<Client> {
+        let store = KvStore::open(temp_dir.path())?;
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let server = RespServer::new(store);
+        thread::spawn(move || server.serve(listener));
+
+        let writer = TcpStream::connect(addr).expect("connect");
+        Ok(Client { reader: BufReader::new(writer.try_clone().expect("clone")), writer })
+    }
+
+    /// send `raw` as is and return the raw reply
+    fn raw(&mut self, raw: &str) -> String {
+        self.writer.write_all(raw.as_bytes()).expect("send");
+        self.reply()
+    }
+
+    /// send a command as an array of bulk strings and return the raw reply
+    fn cmd(&mut self, args: &[&str]) -> String {
+        let mut raw = format!("*{}\r\n", args.len());
+        for arg in args {
+            raw.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.raw(&raw)
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).expect("read");
+        line
+    }
+
+    fn reply(&mut self) -> String {
+        let line = self.line();
+        let count = |line: &str| line[1..].trim_end().parse::<i64>().expect("length");
+        match line.as_bytes()[0] {
+            b'$' if count(&line) >= 0 => {
+                let mut data = vec![0; count(&line) as usize + 2];
+                self.reader.read_exact(&mut data).expect("read");
+                line + &String::from_utf8(data).expect("utf-8")
+            }
+            b'*' | b'%' => {
+                let items = count(&line) * if line.starts_with('%') { 2 } else { 1 };
+                (0..items).fold(line, |acc, _| acc + &self.reply())
+            }
+            _ => line,
+        }
+    }
+}
+
+#[test]
+fn resp_strings() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut c = Client::connect(&temp_dir)?;
+
+    assert_eq!(c.cmd(&["PING"]), "+PONG\r\n");
+    assert_eq!(c.raw("PING hello\r\n"), "$5\r\nhello\r\n");
+    assert_eq!(c.cmd(&["GET", "key1"]), "$-1\r\n");
+    assert_eq!(c.cmd(&["set", "key1", "value1"]), "+OK\r\n");
+    assert_eq!(c.cmd(&["GET", "key1"]), "$6\r\nvalue1\r\n");
+    assert_eq!(c.cmd(&["SET", "key1", "other", "NX"]), "$-1\r\n");
+    assert_eq!(c.cmd(&["SET", "key2", "other", "XX"]), "$-1\r\n");
+    assert_eq!(c.cmd(&["SET", "key1", "value2", "XX", "GET"]), "$6\r\nvalue1\r\n");
+    assert_eq!(c.cmd(&["EXISTS", "key1", "key2", "key1"]), ":2\r\n");
+
+    assert_eq!(c.cmd(&["MSET", "a", "1", "b", "2"]), "+OK\r\n");
+    assert_eq!(c.cmd(&["MGET", "a", "missing", "b"]), "*3\r\n$1\r\n1\r\n$-1\r\n$1\r\n2\r\n");
+    assert_eq!(c.cmd(&["INCR", "a"]), ":2\r\n");
+    assert_eq!(c.cmd(&["INCR", "counter"]), ":1\r\n");
+    assert_eq!(c.cmd(&["INCR", "key1"]), "-ERR value is not an integer or out of range\r\n");
+
+    assert_eq!(c.cmd(&["KEYS", "*"]), "*4\r\n$1\r\na\r\n$1\r\nb\r\n$7\r\ncounter\r\n$4\r\nkey1\r\n");
+    assert_eq!(c.cmd(&["KEYS", "[ab]"]), "*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+    assert_eq!(c.cmd(&["KEYS", "*e?[0-9]"]), "*1\r\n$4\r\nkey1\r\n");
+    assert_eq!(c.cmd(&["KEYS", "c*t*r"]), "*1\r\n$7\r\ncounter\r\n");
+    // a pattern that would take exponential time to fail by backtracking
+    let long = "a".repeat(200);
+    assert_eq!(c.cmd(&["SET", &long, "1"]), "+OK\r\n");
+    assert_eq!(c.cmd(&["KEYS", "*a*a*a*a*a*a*a*a*a*b"]), "*0\r\n");
+    assert_eq!(c.cmd(&["DEL", &long]), ":1\r\n");
+    assert_eq!(c.cmd(&["SCAN", "0", "COUNT", "3"]), "*2\r\n$1\r\n3\r\n*3\r\n$1\r\na\r\n$1\r\nb\r\n$7\r\ncounter\r\n");
+    assert_eq!(c.cmd(&["SCAN", "3", "COUNT", "3"]), "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey1\r\n");
+
+    assert_eq!(c.cmd(&["DEL", "a", "a", "missing", "b"]), ":2\r\n");
+    assert_eq!(c.cmd(&["EXISTS", "a", "b"]), ":0\r\n");
+
+    Ok(())
+}
+
+#[test]
+fn resp_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut c = Client::connect(&temp_dir)?;
+
+    assert_eq!(c.cmd(&["TTL", "key1"]), ":-2\r\n");
+    assert_eq!(c.cmd(&["SET", "key1", "value1"]), "+OK\r\n");
+    assert_eq!(c.cmd(&["TTL", "key1"]), ":-1\r\n");
+    assert_eq!(c.cmd(&["SET", "key1", "value1", "EX", "100"]), "+OK\r\n");
+    assert_eq!(c.cmd(&["TTL", "key1"]), ":100\r\n");
+    assert_eq!(c.cmd(&["INCR", "key1"]), "-ERR value is not an integer or out of range\r\n");
+    assert_eq!(c.cmd(&["SET", "key1", "value2", "KEEPTTL"]), "+OK\r\n");
+    assert_eq!(c.cmd(&["TTL", "key1"]), ":100\r\n");
+    assert_eq!(c.cmd(&["SET", "key1", "value3"]), "+OK\r\n");
+    assert_eq!(c.cmd(&["TTL", "key1"]), ":-1\r\n");
+    assert_eq!(c.cmd(&["SET", "key1", "value1", "EX", "0"]), "-ERR invalid expire time in 'set' command\r\n");
+
+    assert_eq!(c.cmd(&["SET", "key2", "value2", "PX", "50"]), "+OK\r\n");
+    assert_eq!(c.cmd(&["GET", "key2"]), "$6\r\nvalue2\r\n");
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(c.cmd(&["GET", "key2"]), "$-1\r\n");
+    assert_eq!(c.cmd(&["TTL", "key2"]), ":-2\r\n");
+    assert_eq!(c.cmd(&["KEYS", "*"]), "*1\r\n$4\r\nkey1\r\n");
+
+    Ok(())
+}
+
+#[test]
+fn resp_errors_and_resp3() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut c = Client::connect(&temp_dir)?;
+
+    assert_eq!(c.cmd(&["FLUSHALL", "now"]), "-ERR unknown command 'flushall', with args beginning with: 'now' \r\n");
+    assert_eq!(c.cmd(&["GET"]), "-ERR wrong number of arguments for 'get' command\r\n");
+    assert_eq!(c.cmd(&["SET", "k", "v", "EX"]), "-ERR syntax error\r\n");
+    assert_eq!(c.cmd(&["HELLO", "4"]), "-NOPROTO unsupported protocol version\r\n");
+
+    // RESP3 replies with a map, and a different null
+    let hello = c.cmd(&["HELLO", "3"]);
+    assert!(hello.starts_with("%6\r\n$6\r\nserver\r\n$3\r\nkvs\r\n"), "{}", hello);
+    assert_eq!(c.cmd(&["GET", "missing"]), "_\r\n");
+
+    // a malformed request closes the connection
+    assert_eq!(c.raw("*1\r\n+PING\r\n"), "-ERR Protocol error: expected '$'\r\n");
+    assert_eq!(c.line(), "");
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsError, OpenOptions, Result, Until, WriteBatch};
+use predicates::str::contains;
+use tempfile::TempDir;
+
+#[test]
+fn open_at_sequence_number() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let big = "x".repeat(4096);
+    let opts = {
+        let mut opts = OpenOptions::new();
+        opts.blob_threshold(1024);
+        opts
+    };
+
+    let mut store = opts.open(temp_dir.path())?;
+    assert_eq!(store.last_seq(), 0);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("", "key2".to_owned(), "value2".to_owned())
+        .set("users", "alice".to_owned(), big.clone());
+    store.write(batch)?;
+    assert_eq!(store.last_seq(), 2);
+    store.remove("key1".to_owned())?;
+    store.namespace("users").set("alice".to_owned(), "small".to_owned())?;
+    store.set("key3".to_owned(), big.clone())?;
+    drop(store);
+
+    let at = opts.open_at(temp_dir.path(), Until::Seq(0))?;
+    assert!(at.namespaces().is_empty());
+
+    let mut at = opts.open_at(temp_dir.path(), Until::Seq(1))?;
+    assert_eq!(at.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(at.get("key2".to_owned())?, None);
+
+    // a batch is all there or not at all
+    let mut at = opts.open_at(temp_dir.path(), Until::Seq(2))?;
+    assert_eq!(at.last_seq(), 2);
+    assert_eq!(at.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(at.namespace("users").get("alice".to_owned())?, Some(big.clone()));
+
+    let mut at = opts.open_at(temp_dir.path(), Until::Seq(4))?;
+    assert_eq!(at.get("key1".to_owned())?, None);
+    assert_eq!(at.namespace("users").get("alice".to_owned())?, Some("small".to_owned()));
+    assert_eq!(at.get("key3".to_owned())?, None);
+
+    // the view can't be changed, and leaves the files alone
+    assert!(at.is_read_only());
+    assert!(matches!(at.set("key1".to_owned(), "value".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(at.remove("key2".to_owned()), Err(KvsError::ReadOnly)));
+    drop(at);
+
+    let mut store = opts.open(temp_dir.path())?;
+    assert!(!store.is_read_only());
+    assert_eq!(store.last_seq(), 5);
+    assert_eq!(store.get("key3".to_owned())?, Some(big));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.last_seq(), 6);
+
+    Ok(())
+}
+
+#[test]
+fn open_at_time_and_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("config".to_owned(), "good".to_owned())?;
+    thread::sleep(Duration::from_millis(20));
+    let good = SystemTime::now();
+    thread::sleep(Duration::from_millis(20));
+    store.set("config".to_owned(), "garbage".to_owned())?;
+
+    let mut at = KvStore::open_at(temp_dir.path(), Until::Time(good))?;
+    assert_eq!(at.get("config".to_owned())?, Some("good".to_owned()));
+    assert_eq!(at.last_seq(), 1);
+
+    // carry on from the good version in a new store
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut copy = at.copy_to(&OpenOptions::new(), copy_dir.path())?;
+    copy.set("other".to_owned(), "value".to_owned())?;
+    assert_eq!(copy.get("config".to_owned())?, Some("good".to_owned()));
+    assert!(matches!(
+        at.copy_to(&OpenOptions::new(), copy_dir.path()),
+        Err(KvsError::RestoreTarget { .. })
+    ));
+
+    // compaction forgets everything before it
+    for i in 0..30 {
+        store.set("config".to_owned(), format!("again{}", i))?;
+    }
+    let compacted = store.last_seq();
+    assert!(matches!(
+        KvStore::open_at(temp_dir.path(), Until::Seq(1)),
+        Err(KvsError::RewindCompacted { .. })
+    ));
+    store.set("config".to_owned(), "latest".to_owned())?;
+
+    // but what came after can still be rewound to, also once the store is reopened
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_seq(), compacted + 1);
+    let mut at = KvStore::open_at(temp_dir.path(), Until::Seq(store.last_seq() - 1))?;
+    assert_eq!(at.get("config".to_owned())?, Some("again29".to_owned()));
+    assert_eq!(store.get("config".to_owned())?, Some("latest".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn cli_rewind() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let rewound_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    for value in &["good", "garbage"] {
+        Command::cargo_bin("kvs").unwrap()
+            .args(["set", "key1", value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["rewind", "--seq", "1"])
+        .arg(rewound_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Rewound to write 1"));
+    Command::cargo_bin("kvs").unwrap()
+        .args(["get", "key1"])
+        .current_dir(&rewound_dir)
+        .assert()
+        .success()
+        .stdout("good\n");
+    Command::cargo_bin("kvs").unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("garbage\n");
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["rewind"])
+        .arg(rewound_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
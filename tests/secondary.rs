@@ -0,0 +1,93 @@
+use kvs::{KvStore, KvsError, OpenOptions, Result, WriteBatch};
+use tempfile::TempDir;
+
+// values look like "name,email"
+fn email(_key: &str, value: &str) -> Vec<String> {
+    value.split(',').nth(1).map(str::to_owned).into_iter().collect()
+}
+
+fn emails() -> OpenOptions {
+    let mut opts = OpenOptions::new();
+    opts.secondary_index("", "email", email);
+    opts
+}
+
+fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+}
+
+// The index should follow sets, overwrites and removes, and be rebuilt when reopening.
+#[test]
+fn index_follows_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = emails().open(temp_dir.path())?;
+
+    store.set("1".to_owned(), "alice,a@example.com".to_owned())?;
+    store.set("2".to_owned(), "bob,b@example.com".to_owned())?;
+    store.set("3".to_owned(), "carol,a@example.com".to_owned())?;
+    store.set("4".to_owned(), "dave".to_owned())?;
+    assert_eq!(store.get_by_index("email", "a@example.com")?, pairs(&[("1", "alice,a@example.com"), ("3", "carol,a@example.com")]));
+
+    store.set("1".to_owned(), "alice,alice@example.com".to_owned())?;
+    store.remove("3".to_owned())?;
+    assert!(store.remove("3".to_owned()).is_err());
+    assert_eq!(store.get_by_index("email", "a@example.com")?, vec![]);
+    assert_eq!(store.get_by_index("email", "alice@example.com")?, pairs(&[("1", "alice,alice@example.com")]));
+
+    // enough overwrites to compact
+    for iter in 0..30 {
+        store.set("2".to_owned(), format!("bob,b{}@example.com", iter))?;
+    }
+    assert_eq!(store.get_by_index("email", "b29@example.com")?, pairs(&[("2", "bob,b29@example.com")]));
+    assert_eq!(store.get_by_index("email", "b28@example.com")?, vec![]);
+    drop(store);
+
+    let mut store = emails().open(temp_dir.path())?;
+    assert_eq!(store.get_by_index("email", "alice@example.com")?, pairs(&[("1", "alice,alice@example.com")]));
+    assert_eq!(store.get_by_index("email", "b29@example.com")?, pairs(&[("2", "bob,b29@example.com")]));
+
+    Ok(())
+}
+
+// Registering an index on an open store indexes the values already there, including blobs.
+#[test]
+fn register_builds_from_existing_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = OpenOptions::new().blob_threshold(32).open(temp_dir.path())?;
+    store.set("1".to_owned(), "alice,a@example.com".to_owned())?;
+    store.set("2".to_owned(), format!("{},a@example.com", "b".repeat(40)))?;
+    store.namespace("other").set("3".to_owned(), "carol,a@example.com".to_owned())?;
+
+    assert!(matches!(store.get_by_index("email", "a@example.com"), Err(KvsError::UnknownIndex { .. })));
+    store.register_index("email", email)?;
+    let found: Vec<String> = store.get_by_index("email", "a@example.com")?.into_iter().map(|(k, _)| k).collect();
+    assert_eq!(found, vec!["1".to_owned(), "2".to_owned()]);
+
+    store.namespace("other").register_index("email", email)?;
+    assert_eq!(store.namespace("other").get_by_index("email", "a@example.com")?, pairs(&[("3", "carol,a@example.com")]));
+
+    Ok(())
+}
+
+// Batches update the index all at once, and a rejected batch leaves it untouched.
+#[test]
+fn index_with_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.register_index("email", email)?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("", "1".to_owned(), "alice,a@example.com".to_owned())
+        .remove("", "2".to_owned());
+    assert!(store.write(batch).is_err());
+    assert_eq!(store.get_by_index("email", "a@example.com")?, vec![]);
+
+    let mut batch = WriteBatch::new();
+    batch.set("", "1".to_owned(), "alice,a@example.com".to_owned())
+        .set("", "2".to_owned(), "bob,a@example.com".to_owned())
+        .remove("", "1".to_owned());
+    store.write(batch)?;
+    assert_eq!(store.get_by_index("email", "a@example.com")?, pairs(&[("2", "bob,a@example.com")]));
+
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use assert_cmd::prelude::*;
+use kvs::{ErrorCode, KvStore, KvsClient, KvsError, KvsServer, Result, Until};
+use predicates::str::contains;
+use tempfile::TempDir;
+
+// serve a fresh store in the background, returning its address
+fn spawn_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let server = KvsServer::new(store);
+    thread::spawn(move || server.serve(listener));
+    Ok(addr)
+}
+
+#[test]
+fn client_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("", "key1".to_owned(), "value1".to_owned())?;
+    client.set("users", "key1".to_owned(), "alice".to_owned())?;
+    assert_eq!(client.get("", "key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("users", "key1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(client.get("", "key2".to_owned())?, None);
+
+    client.remove("", "key1".to_owned())?;
+    match client.remove("", "key1".to_owned()) {
+        Err(KvsError::RemoveNonexistentKey { key }) => assert_eq!(key, "key1"),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // a second client sees the same store
+    let mut other = KvsClient::connect(addr)?;
+    assert_eq!(other.get("", "key1".to_owned())?, None);
+    assert_eq!(other.get("users", "key1".to_owned())?, Some("alice".to_owned()));
+
+    Ok(())
+}
+
+// Errors the client can act on keep their own code rather than showing up as storage failures.
+#[test]
+fn errors_keep_their_codes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_at(temp_dir.path(), Until::Seq(1))?;
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let server = KvsServer::new(store);
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("", "key1".to_owned())?, Some("value1".to_owned()));
+    match client.set("", "key1".to_owned(), "value2".to_owned()) {
+        Err(KvsError::ReadOnly) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    assert_eq!(ErrorCode::of(&KvsError::Cancelled), ErrorCode::Cancelled);
+    for code in 1..10 {
+        assert_eq!(ErrorCode::from_code(code).code(), code);
+    }
+
+    Ok(())
+}
+
+// A client speaking another version is told so before the connection is closed.
+#[test]
+fn version_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut stream = TcpStream::connect(addr).expect("connect");
+    stream.write_all(b"KVS\0").expect("write");
+    stream.write_all(&99u32.to_le_bytes()).expect("write");
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).expect("read");
+    // the server's hello, then the length of an error response (variant 2) with its code
+    assert_eq!(&reply[..4], b"KVS\0");
+    assert_eq!(reply[4..8], kvs::PROTOCOL_VERSION.to_le_bytes());
+    assert_eq!(reply[8..12], (reply.len() as u32 - 12).to_le_bytes());
+    assert_eq!(reply[12..16], 2u32.to_le_bytes());
+    assert_eq!(reply[16..18], ErrorCode::UnsupportedVersion.code().to_le_bytes());
+
+    // the server keeps serving everyone else
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("", "key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Lengths from the wire are checked before anything is allocated for them.
+#[test]
+fn oversized_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let hello = |stream: &mut TcpStream| {
+        stream.write_all(b"KVS\0").expect("write");
+        stream.write_all(&kvs::PROTOCOL_VERSION.to_le_bytes()).expect("write");
+    };
+    // the server's hello, then an error response (variant 2) with its code
+    let bad_request = |reply: &[u8]| {
+        assert_eq!(reply[12..16], 2u32.to_le_bytes());
+        assert_eq!(reply[16..18], ErrorCode::BadRequest.code().to_le_bytes());
+    };
+
+    // a message longer than any request
+    let mut stream = TcpStream::connect(addr).expect("connect");
+    hello(&mut stream);
+    stream.write_all(&u32::MAX.to_le_bytes()).expect("write");
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).expect("read");
+    bad_request(&reply);
+
+    // a `ScanHashes` (variant 8) in an empty namespace claiming u32::MAX ranges
+    let mut stream = TcpStream::connect(addr).expect("connect");
+    hello(&mut stream);
+    stream.write_all(&12u32.to_le_bytes()).expect("write");
+    stream.write_all(&8u32.to_le_bytes()).expect("write");
+    stream.write_all(&0u32.to_le_bytes()).expect("write");
+    stream.write_all(&u32::MAX.to_le_bytes()).expect("write");
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).expect("read");
+    bad_request(&reply);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("", "key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("", "key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn cli_client_server() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()).expect("free port").to_string();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap()
+        .args(["--addr", &addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .expect("start server");
+    for _ in 0..100 {
+        if TcpStream::connect(&addr).is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", &addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["get", "key1"]).assert().success().stdout("value1\n");
+    client(&["get", "key1", "--ns", "other"]).assert().success().stdout(contains("Key not found"));
+    client(&["rm", "key1"]).assert().success();
+    client(&["rm", "key1"]).assert().failure().stdout(contains("Key not found"));
+
+    server.kill().expect("stop server");
+    server.wait().expect("server exits");
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener};
+use std::process::Command;
+use std::thread;
+
+use assert_cmd::prelude::*;
+use kvs::{HashRing, KvStore, KvsClient, KvsServer, Result, ShardedClient};
+use predicates::str::contains;
+use tempfile::TempDir;
+
+// serve a fresh store in the background, returning its address
+fn spawn_server(temp_dir: &TempDir) -> Result<String> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr: SocketAddr = listener.local_addr().expect("local addr");
+    let server = KvsServer::new(store);
+    thread::spawn(move || server.serve(listener));
+    Ok(addr.to_string())
+}
+
+fn owners(ring: &HashRing, keys: &[String]) -> Vec<String> {
+    keys.iter().map(|k| ring.node_for("", k).unwrap().to_owned()).collect()
+}
+
+#[test]
+fn ring_spreads_keys_by_weight() {
+    let keys: Vec<String> = (0..10_000).map(|i| format!("key{}", i)).collect();
+    let mut ring = HashRing::new();
+    assert_eq!(ring.node_for("", "key1"), None);
+    ring.add("a", 1).add("b", 1).add("c", 2);
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for owner in owners(&ring, &keys) {
+        *counts.entry(owner).or_default() += 1;
+    }
+    assert!((4000..6000).contains(&counts["c"]), "{:?}", counts);
+    assert!((1700..3300).contains(&counts["a"]), "{:?}", counts);
+    assert!((1700..3300).contains(&counts["b"]), "{:?}", counts);
+
+    // the order servers are added in doesn't matter
+    let mut other = HashRing::new();
+    other.add("c", 2).add("b", 1).add("a", 1);
+    assert_eq!(ring, other);
+
+    // a new server only takes keys, about its share of them
+    let before = owners(&ring, &keys);
+    ring.add("d", 1);
+    let after = owners(&ring, &keys);
+    let moved: Vec<_> = before.iter().zip(&after).filter(|(b, a)| b != a).collect();
+    assert!(moved.iter().all(|(_, a)| *a == "d"));
+    assert!((1200..2800).contains(&moved.len()), "{} moved", moved.len());
+
+    // and removing it puts them back where they were
+    assert!(ring.remove("d"));
+    assert!(!ring.remove("d"));
+    assert_eq!(owners(&ring, &keys), before);
+}
+
+#[test]
+fn sharded_client_moves_only_affected_keys() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().expect("unable to create temporary working directory")).collect();
+    let addrs = dirs.iter().map(spawn_server).collect::<Result<Vec<_>>>()?;
+
+    let mut ring = HashRing::new();
+    ring.add(addrs[0].clone(), 1).add(addrs[1].clone(), 1);
+    let mut client = ShardedClient::new(ring);
+    for i in 0..200 {
+        client.set("", format!("key{}", i), i.to_string())?;
+        client.set("users", format!("user{}", i), i.to_string())?;
+    }
+
+    // every key is on the server the ring says, and nowhere else
+    let check = |ring: &HashRing| -> Result<()> {
+        for addr in &addrs {
+            let mut server = KvsClient::connect(addr)?;
+            for ns in server.namespaces()? {
+                for (key, _) in server.scan(&ns, "")? {
+                    assert_eq!(ring.node_for(&ns, &key), Some(addr.as_str()), "{} is on the wrong server", key);
+                }
+            }
+        }
+        Ok(())
+    };
+    check(client.ring())?;
+
+    let mut bigger = client.ring().clone();
+    bigger.add(addrs[2].clone(), 1);
+    let expected = (0..200)
+        .flat_map(|i| vec![("", format!("key{}", i)), ("users", format!("user{}", i))])
+        .filter(|(ns, key)| client.ring().node_for(ns, key) != bigger.node_for(ns, key))
+        .count();
+    assert!(expected > 0);
+    assert_eq!(client.add_node(addrs[2].clone(), 1)?, expected);
+    check(client.ring())?;
+
+    client.remove_node(&addrs[0])?;
+    check(client.ring())?;
+    assert!(KvsClient::connect(&addrs[0])?.namespaces()?.is_empty());
+    for i in 0..200 {
+        assert_eq!(client.get("", format!("key{}", i))?, Some(i.to_string()));
+        assert_eq!(client.get("users", format!("user{}", i))?, Some(i.to_string()));
+    }
+
+    Ok(())
+}
+
+// Only keys in the hash ranges that move are looked at, so running a migration again once it
+// has finished finds nothing left to do.
+#[test]
+fn migration_can_be_run_again() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().expect("unable to create temporary working directory")).collect();
+    let addrs = dirs.iter().map(spawn_server).collect::<Result<Vec<_>>>()?;
+
+    let mut from = HashRing::new();
+    from.add(addrs[0].clone(), 1).add(addrs[1].clone(), 1);
+    let mut client = ShardedClient::new(from.clone());
+    for i in 0..200 {
+        client.set("", format!("key{}", i), i.to_string())?;
+    }
+
+    let mut to = from.clone();
+    to.remove(&addrs[0]);
+    to.add(addrs[2].clone(), 2);
+    assert!(from.migrate_to(&to)? > 0);
+    assert_eq!(from.migrate_to(&to)?, 0);
+
+    let mut client = ShardedClient::new(to);
+    for i in 0..200 {
+        assert_eq!(client.get("", format!("key{}", i))?, Some(i.to_string()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn cli_migrate() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().expect("unable to create temporary working directory")).collect();
+    let addrs = dirs.iter().map(spawn_server).collect::<Result<Vec<_>>>()?;
+    let from = format!("{},{}", addrs[0], addrs[1]);
+    let to = format!("{},{}=2", addrs[1], addrs[2]);
+
+    for i in 0..20 {
+        Command::cargo_bin("kvs-client").unwrap()
+            .args(["--shards", &from, "set", &format!("key{}", i), &format!("value{}", i)])
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client").unwrap()
+        .args(["migrate", "--from", &from, "--to", &to])
+        .assert()
+        .success()
+        .stdout(contains("Moved"));
+
+    for i in 0..20 {
+        Command::cargo_bin("kvs-client").unwrap()
+            .args(["--shards", &to, "get", &format!("key{}", i)])
+            .assert()
+            .success()
+            .stdout(format!("value{}\n", i));
+    }
+
+    Command::cargo_bin("kvs-client").unwrap()
+        .args(["get", "key1", "--shards", "127.0.0.1:1=x"])
+        .assert()
+        .failure()
+        .stderr(contains("expected a weight"));
+
+    Ok(())
+}
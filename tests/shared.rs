@@ -0,0 +1,155 @@
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use kvs::{KvStore, KvsError, NamespaceOptions, OpenOptions, Result, SharedKvStore, WriteBatch};
+use tempfile::TempDir;
+
+#[test]
+fn shared_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.namespace("users").set("alice".to_owned(), "1".to_owned())?;
+    }
+
+    // existing values are visible straight away
+    let store = SharedKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.namespace("users").get("alice")?, Some("1".to_owned()));
+    assert_eq!(store.get("alice")?, None);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::RemoveNonexistentKey { .. })));
+    store.namespace("users").set("bob".to_owned(), "2".to_owned())?;
+    assert_eq!(store.scan("key")?, vec![("key2".to_owned(), "value2".to_owned())]);
+    assert_eq!(store.namespace("users").scan("")?.len(), 2);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.namespace("users").get("bob".to_owned())?, Some("2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn parallel_readers_and_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "0".to_owned())?;
+    }
+
+    let start = Arc::new(Barrier::new(5));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let start = start.clone();
+            thread::spawn(move || {
+                start.wait();
+                for round in 0..50 {
+                    for i in 0..100 {
+                        let value = store.get(&format!("key{}", i)).expect("read").expect("key is never removed");
+                        assert!(value.parse::<u32>().expect("number") <= 5, "round {}", round);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    start.wait();
+    for gen in 1..=5 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), gen.to_string())?;
+        }
+    }
+    for reader in readers {
+        reader.join().expect("reader panicked");
+    }
+
+    assert!(store.scan("key")?.iter().all(|(_, v)| v == "5"));
+    Ok(())
+}
+
+#[test]
+fn batches_are_seen_whole() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "0".to_owned())?;
+    store.set("b".to_owned(), "0".to_owned())?;
+
+    // a and b are always moved together, so a reader may never see them differ
+    let reader = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for _ in 0..2000 {
+                let (a, b) = store.scan("").expect("scan").into_iter().fold((None, None), |(a, b), (k, v)| match k.as_str() {
+                    "a" => (Some(v), b),
+                    "b" => (a, Some(v)),
+                    _ => (a, b),
+                });
+                assert_eq!(a, b);
+            }
+        })
+    };
+
+    for i in 1..200 {
+        let mut batch = WriteBatch::new();
+        batch.set("", "a".to_owned(), i.to_string()).set("", "b".to_owned(), i.to_string());
+        store.write(batch)?;
+    }
+    reader.join().expect("reader panicked");
+
+    // a failing batch changes nothing
+    let mut batch = WriteBatch::new();
+    batch.set("", "a".to_owned(), "x".to_owned()).remove("", "missing".to_owned());
+    assert!(store.write(batch).is_err());
+    assert_eq!(store.get("a")?, Some("199".to_owned()));
+
+    Ok(())
+}
+
+// Values are read from disk, from the log or blob files, even as compaction and blob garbage
+// collection move them around.
+#[test]
+fn reads_follow_values_as_they_move() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut ns = NamespaceOptions::new();
+    ns.compact_after(50);
+    let mut opts = OpenOptions::new();
+    opts.blob_threshold(64).blob_file_size(4096).namespace("", ns);
+    let store = SharedKvStore::open_with(&opts, temp_dir.path())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+
+    let reader = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for _ in 0..500 {
+                assert_eq!(store.get("small")?, Some("value".to_owned()));
+                if let Some(large) = store.get("large")? {
+                    assert_eq!(large.len(), 1000);
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let log = temp_dir.path().join("kvs.db");
+    let start = std::fs::metadata(&log).expect("log").len();
+    let mut compacted = false;
+    for i in 0..200 {
+        store.set("large".to_owned(), i.to_string().repeat(1000)[..1000].to_owned())?;
+        compacted |= std::fs::metadata(&log).expect("log").len() <= start + 100;
+    }
+    reader.join().expect("reader panicked")?;
+    assert!(compacted);
+
+    let large = "199".repeat(1000)[..1000].to_owned();
+    assert_eq!(store.get("large")?, Some(large.clone()));
+    assert_eq!(store.scan("")?, vec![("large".to_owned(), large), ("small".to_owned(), "value".to_owned())]);
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Barrier};
+use std::time::Duration;
+
+use kvs::{NaiveThreadPool, Result, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool};
+
+const THREADS: usize = 4;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn runs_every_job<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(THREADS)?;
+    let count = Arc::new(AtomicUsize::new(0));
+    let (done, finished) = mpsc::channel();
+    for _ in 0..1000 {
+        let count = count.clone();
+        let done = done.clone();
+        pool.spawn(move || {
+            count.fetch_add(1, Ordering::SeqCst);
+            done.send(()).expect("send");
+        });
+    }
+
+    for _ in 0..1000 {
+        finished.recv_timeout(TIMEOUT).expect("job ran");
+    }
+    assert_eq!(count.load(Ordering::SeqCst), 1000);
+    Ok(())
+}
+
+/// after many panicking jobs, the pool can still run `THREADS` jobs at once
+fn survives_panics<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(THREADS)?;
+    for _ in 0..THREADS * 4 {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+
+    // each of these only finishes once all of them are running
+    let together = Arc::new(Barrier::new(THREADS));
+    let (done, finished) = mpsc::channel();
+    for _ in 0..THREADS {
+        let together = together.clone();
+        let done = done.clone();
+        pool.spawn(move || {
+            together.wait();
+            done.send(()).expect("send");
+        });
+    }
+
+    for _ in 0..THREADS {
+        finished.recv_timeout(TIMEOUT).expect("pool lost threads to panics");
+    }
+    Ok(())
+}
+
+#[test]
+fn naive_pool() -> Result<()> {
+    runs_every_job::<NaiveThreadPool>()?;
+    survives_panics::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_pool() -> Result<()> {
+    runs_every_job::<SharedQueueThreadPool>()?;
+    survives_panics::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn work_stealing_pool() -> Result<()> {
+    runs_every_job::<WorkStealingThreadPool>()?;
+    survives_panics::<WorkStealingThreadPool>()?;
+
+    // a job's children go on its own thread's queue, so while it waits for them they can only
+    // run if another thread steals them
+    let pool = Arc::new(WorkStealingThreadPool::new(THREADS)?);
+    let (done, finished) = mpsc::channel();
+    let parent_pool = pool.clone();
+    pool.spawn(move || {
+        let (child_done, children_finished) = mpsc::channel();
+        for _ in 0..10 {
+            let child_done = child_done.clone();
+            parent_pool.spawn(move || child_done.send(()).expect("send"));
+        }
+        for _ in 0..10 {
+            children_finished.recv_timeout(TIMEOUT).expect("child was stolen");
+        }
+        done.send(()).expect("send");
+    });
+
+    finished.recv_timeout(TIMEOUT * 2).expect("parent finished");
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::process::Command;
+
+use assert_cmd::prelude::*;
+use kvs::{Encoding, Format, KvStore, KvsError, NamespaceOptions, OpenOptions, Result};
+use predicates::str::contains;
+use tempfile::TempDir;
+
+fn contents(store: &mut KvStore) -> Result<Vec<(String, String, String)>> {
+    let mut all = Vec::new();
+    for ns in store.namespaces() {
+        for key in store.namespace(&ns).keys("")? {
+            let value = store.namespace(&ns).get(key.clone())?.expect("listed key has a value");
+            all.push((ns.clone(), key, value));
+        }
+    }
+    Ok(all)
+}
+
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = OpenOptions::new().blob_threshold(1024).open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    store.set("comma,key".to_owned(), "a \"quoted\"\nmulti-line\r\nvalue".to_owned())?;
+    store.set("binary".to_owned(), "\0\u{1}\u{7f}\t".to_owned())?;
+    store.set("empty".to_owned(), "".to_owned())?;
+    store.namespace("users").set("ålice".to_owned(), "x".repeat(4096))?;
+    let expected = contents(&mut store)?;
+
+    for &format in &[Format::Json, Format::Jsonl, Format::Csv] {
+        for &encoding in &[Encoding::Text, Encoding::Base64] {
+            let mut out = Vec::new();
+            assert_eq!(store.export(&mut out, format, encoding)?, 5);
+            if encoding == Encoding::Base64 {
+                assert!(!out.contains(&0), "{:?} {:?}", format, encoding);
+            }
+
+            let import_dir = TempDir::new().expect("unable to create temporary working directory");
+            let mut imported = KvStore::open(import_dir.path())?;
+            assert_eq!(imported.import(&out[..], format)?, 5);
+            assert_eq!(contents(&mut imported)?, expected, "{:?} {:?}", format, encoding);
+        }
+    }
+
+    // an empty store exports to something an import accepts
+    let empty_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut empty = KvStore::open(empty_dir.path())?;
+    for &format in &[Format::Json, Format::Jsonl, Format::Csv] {
+        let mut out = Vec::new();
+        assert_eq!(empty.export(&mut out, format, Encoding::Text)?, 0);
+        assert_eq!(store.import(&out[..], format)?, 0);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn large_import_compacts_once_at_the_end() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut ns = NamespaceOptions::new();
+    ns.compact_after(20);
+    let mut store = OpenOptions::new().namespace("", ns).open(temp_dir.path())?;
+
+    // the same few keys over and over, as a JSON array of several batches
+    let records: Vec<String> = (0..5000)
+        .map(|i| format!("{{\"key\":\"key{}\",\"value\":\"value{}\"}}", i % 10, i))
+        .collect();
+    let input = format!("[{}]", records.join(",\n"));
+    assert_eq!(store.import(input.as_bytes(), Format::Json)?, 5000);
+
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", 4990 + i)));
+    }
+    // compacted down to the last value of each key
+    let log_len = fs::metadata(temp_dir.path().join("kvs.db")).expect("log").len();
+    assert!(log_len < 2000, "log is {} bytes", log_len);
+
+    // records before a bad one stay imported
+    let input = "{\"key\":\"good\",\"value\":\"1\"}\n{\"key\":\"bad\"}\n{\"key\":\"after\",\"value\":\"2\"}\n";
+    assert!(matches!(store.import(input.as_bytes(), Format::Jsonl), Err(KvsError::ImportRecord { record: 2, .. })));
+    assert_eq!(store.get("good".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("after".to_owned())?, None);
+
+    let input = "ns,key_b64,value_b64\n,a2V5,bm90IGJhc2U2NA!\n";
+    assert!(matches!(store.import(input.as_bytes(), Format::Csv), Err(KvsError::ImportRecord { record: 1, .. })));
+    assert!(matches!(store.import(&b"[{\"key\":\"k\",\"value\":\"v\"}"[..], Format::Json), Err(KvsError::ImportRecord { .. })));
+
+    Ok(())
+}
+
+#[test]
+fn cli_export_import() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let import_dir = TempDir::new().expect("unable to create temporary working directory");
+    let export = temp_dir.path().join("export.csv");
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["set", "key1", "value,1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs").unwrap()
+        .args(["export", "--format", "csv"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("ns,key,value\n,key1,\"value,1\"\n");
+    Command::cargo_bin("kvs").unwrap()
+        .args(["export", "--format", "csv", "--base64"])
+        .arg(&export)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Exported 1 records"));
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["import", "--format", "csv"])
+        .arg(&export)
+        .current_dir(&import_dir)
+        .assert()
+        .success()
+        .stdout("Imported 1 records\n");
+    Command::cargo_bin("kvs").unwrap()
+        .args(["get", "key1"])
+        .current_dir(&import_dir)
+        .assert()
+        .success()
+        .stdout("value,1\n");
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["export", "--format", "xml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
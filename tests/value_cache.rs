@@ -0,0 +1,80 @@
+use kvs::{KvStore, OpenOptions, Result};
+use tempfile::TempDir;
+
+#[test]
+fn hits_and_misses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = OpenOptions::new().value_cache(1024).open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..10 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    // missing keys are never cached
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let stats = store.value_cache_stats().unwrap();
+    assert_eq!(stats.hits, 9);
+    assert_eq!(stats.misses, 3);
+    assert_eq!(stats.entries, 1);
+    assert_eq!(stats.bytes, "key1value1".len());
+
+    assert!(KvStore::open(TempDir::new().unwrap().path())?.value_cache_stats().is_none());
+
+    Ok(())
+}
+
+// Cached values must never be returned once they have been overwritten or removed.
+#[test]
+fn set_and_remove_invalidate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = OpenOptions::new().value_cache(1024).open(temp_dir.path())?;
+
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+        assert_eq!(store.get("key1".to_owned())?, Some(format!("{}", iter)));
+        assert_eq!(store.get("key1".to_owned())?, Some(format!("{}", iter)));
+    }
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.value_cache_stats().unwrap().entries, 0);
+
+    Ok(())
+}
+
+#[test]
+fn evicts_least_recently_used() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // room for exactly three 10 byte entries
+    let mut store = OpenOptions::new().value_cache(30).open(temp_dir.path())?;
+
+    for key_id in 0..4 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..3 {
+        store.get(format!("key{}", key_id))?;
+    }
+    // touch key0 so key1 is now the least recently used
+    store.get("key0".to_owned())?;
+    store.get("key3".to_owned())?;
+
+    let stats = store.value_cache_stats().unwrap();
+    assert_eq!(stats.entries, 3);
+    assert!(stats.bytes <= stats.capacity);
+
+    let misses = stats.misses;
+    store.get("key0".to_owned())?;
+    store.get("key3".to_owned())?;
+    assert_eq!(store.value_cache_stats().unwrap().misses, misses);
+    store.get("key1".to_owned())?;
+    assert_eq!(store.value_cache_stats().unwrap().misses, misses + 1);
+
+    // values bigger than the whole cache are not cached
+    store.set("big".to_owned(), "x".repeat(100))?;
+    store.get("big".to_owned())?;
+    assert!(store.value_cache_stats().unwrap().bytes <= 30);
+
+    Ok(())
+}
@@ -0,0 +1,224 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use assert_cmd::prelude::*;
+use kvs::{Cipher, DumpOptions, EncryptionKey, KvStore, KvsError, OpenOptions, Problem, Result};
+use predicates::str::contains;
+use tempfile::TempDir;
+
+/// write key0..key9, returning the offset of each record
+fn fill(opts: &OpenOptions, path: &Path) -> Result<Vec<u64>> {
+    let mut store = opts.open(path)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let mut offsets = Vec::new();
+    store.dump(&DumpOptions::new(), |change| {
+        offsets.push(change.offs);
+        Ok(())
+    })?;
+    Ok(offsets)
+}
+
+fn damage(path: &Path, offs: u64) {
+    let log = path.join("kvs.db");
+    let mut bytes = fs::read(&log).expect("read log");
+    bytes[offs as usize] ^= 0xff;
+    fs::write(&log, &bytes).expect("write log");
+}
+
+#[test]
+fn verify_clean_store_and_leftovers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = OpenOptions::new();
+    assert!(opts.verify(temp_dir.path())?.is_clean());
+    fill(&opts, temp_dir.path())?;
+
+    let report = opts.verify(temp_dir.path())?;
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert_eq!(report.records, 10);
+
+    // an interrupted compaction
+    let tmp = temp_dir.path().join("kvs.db.tmp");
+    fs::write(&tmp, b"partial").expect("write tmp");
+    assert_eq!(opts.verify(temp_dir.path())?.problems, vec![Problem::LeftoverCompaction { filename: tmp.clone() }]);
+    let report = opts.repair(temp_dir.path())?;
+    assert_eq!(report.damaged_log, None);
+    assert!(!tmp.exists());
+
+    // a write cut short is ignored by opening, but still reported
+    let log = temp_dir.path().join("kvs.db");
+    let len = fs::metadata(&log).expect("log").len();
+    let f = fs::OpenOptions::new().write(true).open(&log).expect("open log");
+    f.set_len(len - 3).expect("truncate");
+    let report = opts.repair(temp_dir.path())?;
+    assert!(matches!(report.problems[..], [Problem::Truncated { .. }]));
+    assert_eq!(report.records, 9);
+    assert!(opts.verify(temp_dir.path())?.is_clean());
+    assert_eq!(opts.open(temp_dir.path())?.get("key8".to_owned())?, Some("value8".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn repair_salvages_readable_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = OpenOptions::new();
+    let offsets = fill(&opts, temp_dir.path())?;
+
+    // the sync marker of key6's record is garbage
+    damage(temp_dir.path(), offsets[6]);
+    assert!(matches!(opts.open(temp_dir.path()), Err(KvsError::LogParse { .. })));
+    let report = opts.verify(temp_dir.path())?;
+    assert_eq!(report.records, 9);
+    assert!(matches!(&report.problems[..], [Problem::Unreadable { offs, .. }] if *offs == offsets[6]));
+    assert_eq!(report.skipped, vec![offsets[6]..offsets[7]]);
+
+    let report = opts.repair(temp_dir.path())?;
+    let damaged_log = report.damaged_log.expect("log replaced");
+    assert_eq!(damaged_log, temp_dir.path().join("kvs.db.damaged"));
+    assert!(damaged_log.exists());
+    let written = fs::read_to_string(report.repair_report.expect("report written")).expect("read report");
+    assert!(written.contains(&format!("skipped bytes {}..{}", offsets[6], offsets[7])), "{}", written);
+
+    // everything after the damage is recovered too
+    let mut store = opts.open(temp_dir.path())?;
+    for i in (0..10).filter(|&i| i != 6) {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key6".to_owned())?, None);
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    drop(store);
+    assert!(opts.verify(temp_dir.path())?.is_clean());
+
+    Ok(())
+}
+
+#[test]
+fn repair_skips_records_failing_their_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut opts = OpenOptions::new();
+    opts.encryption_key(EncryptionKey::new(Cipher::ChaCha20Poly1305, [7; 32]));
+    let offsets = fill(&opts, temp_dir.path())?;
+
+    // somewhere inside key3's sealed record
+    damage(temp_dir.path(), offsets[3] + 20);
+    assert!(matches!(opts.open(temp_dir.path()), Err(KvsError::Authentication { .. })));
+    let report = opts.verify(temp_dir.path())?;
+    assert_eq!(report.records, 9);
+    assert!(matches!(&report.problems[..], [Problem::Checksum { offs }] if *offs == offsets[3]));
+
+    opts.repair(temp_dir.path())?;
+    let mut store = opts.open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    for i in (0..10).filter(|&i| i != 3) {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // without the key there's nothing to check against
+    assert!(matches!(
+        OpenOptions::new().verify(temp_dir.path()),
+        Err(KvsError::EncryptionKeyRequired { .. })
+    ));
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn cli_verify() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let offsets = fill(&OpenOptions::new(), temp_dir.path()).expect("fill");
+
+    Command::cargo_bin("kvs").unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Checked 10 records, found 0 problems\n");
+
+    damage(temp_dir.path(), offsets[8]);
+    Command::cargo_bin("kvs").unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(format!("record at {} can't be read", offsets[8])));
+    Command::cargo_bin("kvs").unwrap()
+        .args(["verify", "--repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Salvaged 9 records"));
+    Command::cargo_bin("kvs").unwrap()
+        .args(["get", "key7"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value7\n");
+    Command::cargo_bin("kvs").unwrap()
+        .args(["get", "key9"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value9\n");
+}
+
+#[test]
+fn repair_resyncs_after_damage_anywhere() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = OpenOptions::new();
+    let offsets = fill(&opts, temp_dir.path())?;
+
+    // a bad length in key2's frame, a bad value in key5's and a torn write at the end
+    damage(temp_dir.path(), offsets[2] + 9);
+    damage(temp_dir.path(), offsets[6] - 2);
+    let log = temp_dir.path().join("kvs.db");
+    let len = fs::metadata(&log).expect("log").len();
+    fs::OpenOptions::new().write(true).open(&log).expect("open log").set_len(len - 1).expect("truncate");
+    assert!(matches!(opts.open(temp_dir.path()), Err(KvsError::Checksum { offs, .. }) if offs == offsets[2]));
+
+    let report = opts.repair(temp_dir.path())?;
+    assert_eq!(report.records, 7);
+    assert_eq!(report.skipped, vec![offsets[2]..offsets[3], offsets[5]..offsets[6], offsets[9]..len - 1]);
+    assert!(matches!(report.problems[2], Problem::Truncated { offs } if offs == offsets[9]));
+
+    let mut store = opts.open(temp_dir.path())?;
+    for i in 0..10 {
+        let expected = if [2, 5, 9].contains(&i) { None } else { Some(format!("value{}", i)) };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+    drop(store);
+    assert!(opts.verify(temp_dir.path())?.is_clean());
+
+    Ok(())
+}
+
+#[test]
+fn unframed_records_are_still_read() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // a `Set` record from before framing: the variant, then the key and value with their lengths
+    let mut legacy = Vec::new();
+    for (key, value) in &[("old1", "value1"), ("old2", "value2")] {
+        legacy.extend_from_slice(&0u32.to_le_bytes());
+        for s in &[key, value] {
+            legacy.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            legacy.extend_from_slice(s.as_bytes());
+        }
+    }
+    fs::write(temp_dir.path().join("kvs.db"), &legacy).expect("write log");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("old1".to_owned())?, Some("value1".to_owned()));
+    store.set("new".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("old2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("new".to_owned())?, Some("value".to_owned()));
+    assert!(OpenOptions::new().verify(temp_dir.path())?.is_clean());
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use std::thread;
+
+use kvs::{KvStore, KvsError, OpenOptions, Result, WatchEvent, WriteBatch};
+use tempfile::TempDir;
+
+fn event(key: &str, existed: bool, value: Option<&str>, seq: u64) -> WatchEvent {
+    WatchEvent { key: key.to_owned(), existed, value: value.map(str::to_owned), seq }
+}
+
+// Only keys under the prefix (and in the namespace) should be reported, in order.
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch("config/");
+    let other = store.namespace("other").watch("");
+
+    store.set("config/port".to_owned(), "4000".to_owned())?;
+    store.set("data/1".to_owned(), "x".to_owned())?;
+    store.set("config/port".to_owned(), "4001".to_owned())?;
+    store.remove("config/port".to_owned())?;
+    assert!(store.remove("config/port".to_owned()).is_err());
+    store.namespace("other").set("config/port".to_owned(), "1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("", "config/a".to_owned(), "a".to_owned())
+        .set("", "config/b".to_owned(), "b".to_owned());
+    store.write(batch)?;
+
+    assert_eq!(watcher.try_recv()?, Some(event("config/port", false, Some("4000"), 1)));
+    assert_eq!(watcher.try_recv()?, Some(event("config/port", true, Some("4001"), 3)));
+    assert_eq!(watcher.try_recv()?, Some(event("config/port", true, None, 4)));
+    assert_eq!(watcher.try_recv()?, Some(event("config/a", false, Some("a"), 6)));
+    assert_eq!(watcher.try_recv()?, Some(event("config/b", false, Some("b"), 6)));
+    assert_eq!(watcher.try_recv()?, None);
+    assert_eq!(other.try_recv()?, Some(event("config/port", false, Some("1"), 5)));
+
+    // dropping the store ends the stream
+    drop(store);
+    assert_eq!(watcher.recv()?, None);
+
+    Ok(())
+}
+
+// A watcher that doesn't keep up is told to resync instead of silently missing events.
+#[test]
+fn lagging_watcher_resyncs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = OpenOptions::new().watch_capacity(4).open(temp_dir.path())?;
+    let slow = store.watch("");
+    let fast = store.watch("");
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        assert_eq!(fast.try_recv()?.map(|e| e.seq), Some(key_id + 1));
+    }
+
+    match slow.try_recv() {
+        Err(KvsError::WatchLagged { capacity }) => assert_eq!(capacity, 4),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(slow.try_recv()?, None);
+
+    // resync by reading the current state and watching again
+    let slow = store.watch("");
+    assert_eq!(store.get("key9".to_owned())?, Some("value".to_owned()));
+    store.set("key0".to_owned(), "new".to_owned())?;
+    assert_eq!(slow.try_recv()?, Some(event("key0", true, Some("new"), 11)));
+
+    Ok(())
+}
+
+#[test]
+fn watch_from_another_thread() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch("key");
+
+    let handle = thread::spawn(move || watcher.map(|e| e.map(|e| e.key)).collect::<Result<Vec<_>>>());
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let keys = handle.join().expect("watcher thread panicked")?;
+    assert_eq!(keys, (0..100).map(|key_id| format!("key{}", key_id)).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+// With synced writes an event means the change is on disk.
+#[test]
+fn synced_writes_are_published() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = OpenOptions::new().sync_writes(true).blob_threshold(16).open(temp_dir.path())?;
+    let watcher = store.watch("");
+
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), "x".repeat(64))?;
+    let keys: Vec<_> = (0..2).map(|_| watcher.try_recv().map(|e| e.map(|e| e.key))).collect::<Result<_>>()?;
+    assert_eq!(keys, vec![Some("small".to_owned()), Some("large".to_owned())]);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some("x".repeat(64)));
+
+    Ok(())
+}
+
+// Events carry the sequence number their write has in the log, which carries on across reopens.
+#[test]
+fn seq_follows_the_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch("");
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(watcher.try_recv()?, Some(event("key2", false, Some("value2"), 2)));
+    assert_eq!(store.last_seq(), 2);
+
+    Ok(())
+}